@@ -1,8 +1,8 @@
 use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
 use rand::rngs::SmallRng;
 use rand::*;
-use ritekv::{SledStore, Store, DiskStore};
-use sled;
+use ritekv::{DiskStore, DiskStoreOptions, SledStore, Store, SyncMode};
+use std::thread;
 use tempfile::TempDir;
 
 fn set_bench(c: &mut Criterion) {
@@ -15,7 +15,7 @@ fn set_bench(c: &mut Criterion) {
                 },
                 |(mut store, _temp_dir)| {
                     for i in 1..(1 << 12) {
-                        store.set(format!("key{}", i), "value".to_string()).unwrap();
+                        store.set(format!("key{}", i), "value").unwrap();
                     }
                 },
                 BatchSize::SmallInput,
@@ -29,7 +29,7 @@ fn set_bench(c: &mut Criterion) {
             },
             |(mut db, _temp_dir)| {
                 for i in 1..(1 << 12) {
-                    db.set(format!("key{}", i), "value".to_string()).unwrap();
+                    db.set(format!("key{}", i), "value").unwrap();
                 }
             },
             BatchSize::SmallInput,
@@ -38,15 +38,52 @@ fn set_bench(c: &mut Criterion) {
     group.finish();
 }
 
+/// Durable writes from several threads, which share their flushes through group commit.
+fn durable_set_bench(c: &mut Criterion) {
+    const WRITES: usize = 1 << 9;
+
+    let mut group = c.benchmark_group("durable_set_bench");
+    group.sample_size(10);
+    for threads in &[1, 4, 16] {
+        group.bench_with_input(format!("ritekv::DiskStore_{}", threads), threads, |b, &threads| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().unwrap();
+                    let options =
+                        DiskStoreOptions { sync: SyncMode::Always, ..DiskStoreOptions::default() };
+                    (DiskStore::open_with_options(temp_dir.path(), options).unwrap(), temp_dir)
+                },
+                |(store, _temp_dir)| {
+                    let handles: Vec<_> = (0..threads)
+                        .map(|t| {
+                            let mut store = store.clone();
+                            thread::spawn(move || {
+                                for i in 0..WRITES / threads {
+                                    store.set(format!("key{}-{}", t, i), "value").unwrap();
+                                }
+                            })
+                        })
+                        .collect();
+                    for handle in handles {
+                        handle.join().unwrap();
+                    }
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
 fn get_bench(c: &mut Criterion) {
     let mut group = c.benchmark_group("get_bench");
-        for i in &vec![8, 12, 16] {
+        for i in &[8, 12, 16] {
             group.bench_with_input(format!("ritekv::DiskStore_{}", i), i, |b, i| {
                 let temp_dir = TempDir::new().unwrap();
                 let mut store = DiskStore::open(temp_dir.path()).unwrap();
                 for key_i in 1..(1 << i) {
                     store
-                        .set(format!("key{}", key_i), "value".to_string())
+                        .set(format!("key{}", key_i), "value")
                         .unwrap();
                 }
                 let mut rng = SmallRng::from_seed([0; 32]);
@@ -57,12 +94,12 @@ fn get_bench(c: &mut Criterion) {
                 })
             });
         }
-    for i in &vec![8, 12, 16] {
+    for i in &[8, 12, 16] {
         group.bench_with_input(format!("sled_{}", i), i, |b, i| {
             let temp_dir = TempDir::new().unwrap();
             let mut db = SledStore::open(sled::open(&temp_dir).unwrap());
             for key_i in 1..(1 << i) {
-                db.set(format!("key{}", key_i), "value".to_string()).unwrap();
+                db.set(format!("key{}", key_i), "value").unwrap();
             }
             let mut rng = SmallRng::from_seed([0; 32]);
             b.iter(|| {
@@ -73,5 +110,5 @@ fn get_bench(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, set_bench, get_bench);
+criterion_group!(benches, set_bench, durable_set_bench, get_bench);
 criterion_main!(benches);
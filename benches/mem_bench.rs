@@ -0,0 +1,95 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use parking_lot::RwLock;
+use ritekv::{BatchStore, MemStore, ShardedMemStore, Store};
+use std::sync::Arc;
+use std::thread;
+
+const WRITES: usize = 1 << 14;
+
+/// Writes from several threads: through the single lock of `MemStore`, or spread over the
+/// shards of a `ShardedMemStore`.
+fn concurrent_set_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_set_bench");
+    for threads in &[1, 4, 16] {
+        group.bench_with_input(format!("ritekv::MemStore_{}", threads), threads, |b, &threads| {
+            b.iter_batched(
+                || Arc::new(RwLock::new(MemStore::open())),
+                |store| {
+                    let handles: Vec<_> = (0..threads)
+                        .map(|t| {
+                            let store = Arc::clone(&store);
+                            thread::spawn(move || {
+                                for i in 0..WRITES / threads {
+                                    store.write().set(format!("key{}-{}", t, i), "value").unwrap();
+                                }
+                            })
+                        })
+                        .collect();
+                    for handle in handles {
+                        handle.join().unwrap();
+                    }
+                },
+                BatchSize::PerIteration,
+            )
+        });
+        for shards in &[1, 16] {
+            let name = format!("ritekv::ShardedMemStore_{}shards_{}", shards, threads);
+            group.bench_with_input(name, threads, |b, &threads| {
+                b.iter_batched(
+                    || ShardedMemStore::open_with_shards(*shards),
+                    |store| {
+                        let handles: Vec<_> = (0..threads)
+                            .map(|t| {
+                                let mut store = store.clone();
+                                thread::spawn(move || {
+                                    for i in 0..WRITES / threads {
+                                        store.set(format!("key{}-{}", t, i), "value").unwrap();
+                                    }
+                                })
+                            })
+                            .collect();
+                        for handle in handles {
+                            handle.join().unwrap();
+                        }
+                    },
+                    BatchSize::PerIteration,
+                )
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Batches of writes, which lock each shard once.
+fn set_batch_bench(c: &mut Criterion) {
+    let keys: Vec<Vec<u8>> = (0..WRITES).map(|i| format!("key{}", i).into_bytes()).collect();
+    let mut group = c.benchmark_group("set_batch_bench");
+    group.bench_function("ritekv::MemStore", |b| {
+        b.iter_batched(
+            MemStore::open,
+            |mut store| store.set_batch(&keys, &keys).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("ritekv::ShardedMemStore", |b| {
+        b.iter_batched(
+            ShardedMemStore::open,
+            |mut store| store.set_batch(&keys, &keys).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+/// Reads of a large value: copied out by `get`, shared by `get_ref`.
+fn get_large_bench(c: &mut Criterion) {
+    let mut store = MemStore::open();
+    store.set("key", vec![0; 1 << 20]).unwrap();
+    let mut group = c.benchmark_group("get_large_bench");
+    group.bench_function("ritekv::MemStore::get", |b| b.iter(|| store.get("key").unwrap()));
+    group.bench_function("ritekv::MemStore::get_ref", |b| b.iter(|| store.get_ref("key").unwrap()));
+    group.finish();
+}
+
+criterion_group!(benches, concurrent_set_bench, set_batch_bench, get_large_bench);
+criterion_main!(benches);
@@ -40,7 +40,7 @@ fn main() {
     let mut sum = Duration::new(0, 0);
     for i in 0..N {
         let t = Instant::now();
-        hm.set(i.to_string(), i.to_string());
+        hm.set(i.to_string(), i.to_string()).unwrap();
         let took = t.elapsed();
         mx = mx.max(took.as_secs_f64());
         sum += took;
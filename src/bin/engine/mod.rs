@@ -0,0 +1,56 @@
+//! The flags picking a store, shared by the server binaries.
+
+use clap::{Arg, ArgMatches};
+use ritekv::server::{Server, ServerOptions};
+use ritekv::{
+    BatchStore, DiskStore, LsmStore, MemStore, OrderedMemStore, Result, ScanStore, ShardedMemStore,
+    SledStore, Store,
+};
+
+const ENGINES: &[&str] =
+    &["memstore", "shardedmemstore", "orderedmemstore", "diskstore", "lsmstore", "sledstore"];
+
+/// The `--engine` and `--dir` flags.
+pub fn args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("engine")
+            .long("engine")
+            .value_name("ENGINE")
+            .possible_values(ENGINES)
+            .default_value("diskstore")
+            .help("The storage engine"),
+        Arg::with_name("dir")
+            .long("dir")
+            .value_name("DIR")
+            .default_value(".")
+            .help("The data directory; memstore only persists to it when given explicitly"),
+    ]
+}
+
+/// Runs a server of whichever store the flags pick.
+pub trait Serve {
+    fn serve<S: Store + BatchStore + ScanStore + 'static>(self, server: Server<S>) -> Result<()>;
+}
+
+/// Opens the store the flags pick, and serves it.
+///
+/// The stores that persist their data are served without expiries, which would not survive
+/// a restart.
+pub fn run(matches: &ArgMatches, serve: impl Serve) -> Result<()> {
+    let dir = matches.value_of("dir").unwrap();
+    let persistent = ServerOptions { expiries: false };
+    match matches.value_of("engine").unwrap() {
+        "memstore" if matches.occurrences_of("dir") > 0 => {
+            serve.serve(Server::with_options(MemStore::open_persistent(dir)?, persistent))
+        }
+        "memstore" => serve.serve(Server::new(MemStore::open())),
+        "shardedmemstore" => serve.serve(Server::new(ShardedMemStore::open())),
+        "orderedmemstore" => serve.serve(Server::new(OrderedMemStore::open())),
+        "diskstore" => serve.serve(Server::with_options(DiskStore::open(dir)?, persistent)),
+        "lsmstore" => serve.serve(Server::with_options(LsmStore::open(dir)?, persistent)),
+        "sledstore" => {
+            serve.serve(Server::with_options(SledStore::open(sled::open(dir)?), persistent))
+        }
+        _ => unreachable!("clap checks the engine"),
+    }
+}
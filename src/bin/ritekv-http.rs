@@ -0,0 +1,44 @@
+//! Serves a store over HTTP, with a JSON API; see `ritekv::server::Server::serve_http`.
+//!
+//! ```text
+//! ritekv-http --engine diskstore --dir data --addr 127.0.0.1:8080
+//! ```
+
+mod engine;
+
+use clap::{App, Arg};
+use ritekv::server::Server;
+use ritekv::{BatchStore, Result, ScanStore, Store};
+
+use std::net::TcpListener;
+use std::process;
+
+fn main() {
+    let matches = App::new("ritekv-http")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Serves a RiteKV store over HTTP")
+        .arg(
+            Arg::with_name("addr")
+                .long("addr")
+                .value_name("IP:PORT")
+                .default_value("127.0.0.1:8080")
+                .help("The address to listen on"),
+        )
+        .args(&engine::args())
+        .get_matches();
+
+    if let Err(err) = engine::run(&matches, Addr(matches.value_of("addr").unwrap())) {
+        eprintln!("ritekv-http: {}", err);
+        process::exit(1);
+    }
+}
+
+struct Addr<'a>(&'a str);
+
+impl engine::Serve for Addr<'_> {
+    fn serve<S: Store + BatchStore + ScanStore + 'static>(self, server: Server<S>) -> Result<()> {
+        let listener = TcpListener::bind(self.0)?;
+        eprintln!("ritekv-http listening on {}", listener.local_addr()?);
+        server.serve_http(listener)
+    }
+}
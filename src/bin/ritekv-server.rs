@@ -0,0 +1,73 @@
+//! Serves a store over the Redis protocol, and optionally the native protocol of
+//! `ritekv::client::RemoteStore`.
+//!
+//! ```text
+//! ritekv-server --engine diskstore --dir data --addr 127.0.0.1:6379 --native-addr 127.0.0.1:6380
+//! ```
+
+mod engine;
+
+use clap::{App, Arg};
+use ritekv::server::Server;
+use ritekv::{BatchStore, Result, ScanStore, Store};
+
+use std::net::TcpListener;
+use std::process;
+use std::thread;
+
+fn main() {
+    let matches = App::new("ritekv-server")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Serves a RiteKV store to Redis clients")
+        .arg(
+            Arg::with_name("addr")
+                .long("addr")
+                .value_name("IP:PORT")
+                .default_value("127.0.0.1:6379")
+                .help("The address to listen on"),
+        )
+        .arg(
+            Arg::with_name("native-addr")
+                .long("native-addr")
+                .value_name("IP:PORT")
+                .help("The address to listen on for native clients, if any"),
+        )
+        .args(&engine::args())
+        .get_matches();
+
+    let addrs =
+        Addrs { resp: matches.value_of("addr").unwrap(), native: matches.value_of("native-addr") };
+    if let Err(err) = engine::run(&matches, addrs) {
+        eprintln!("ritekv-server: {}", err);
+        process::exit(1);
+    }
+}
+
+struct Addrs<'a> {
+    resp: &'a str,
+    native: Option<&'a str>,
+}
+
+impl engine::Serve for Addrs<'_> {
+    fn serve<S: Store + BatchStore + ScanStore + 'static>(self, server: Server<S>) -> Result<()> {
+        let listener = TcpListener::bind(self.resp)?;
+        eprintln!("ritekv-server listening on {}", listener.local_addr()?);
+        let native = match self.native {
+            Some(addr) => {
+                let listener = TcpListener::bind(addr)?;
+                eprintln!(
+                    "ritekv-server listening for native clients on {}",
+                    listener.local_addr()?
+                );
+                let server = server.clone();
+                Some(thread::spawn(move || server.serve_native(listener)))
+            }
+            None => None,
+        };
+        server.serve(listener)?;
+        match native {
+            Some(native) => native.join().expect("the native server panicked"),
+            None => Ok(()),
+        }
+    }
+}
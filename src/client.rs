@@ -0,0 +1,586 @@
+//! A client of `ritekv-server`, through its native protocol.
+//!
+//! A [`RemoteStore`] implements the store traits, so code written against an embedded store
+//! runs against a remote one unchanged. It keeps a pool of connections, shared by its
+//! clones, and can send many requests at once with a [`Pipeline`], or apply writes only if
+//! keys hold given values with a [`Transaction`].
+//!
+//! ```no_run
+//! use ritekv::client::RemoteStore;
+//! use ritekv::Store;
+//! # fn main() -> ritekv::Result<()> {
+//! let mut store = RemoteStore::connect("127.0.0.1:6380")?;
+//! store.set("beep", "boop")?;
+//!
+//! let mut txn = store.transaction();
+//! txn.check("beep", "boop").set("beep", "bop");
+//! assert!(txn.commit()?);
+//! # Ok(()) }
+//! ```
+
+pub use crate::wire::Response;
+
+use crate::result::{KvsError, Result};
+use crate::storage::{BatchStore, ScanStore, Store};
+use crate::wire::{self, Request, TxnWrite};
+
+use parking_lot::{Condvar, Mutex};
+
+use std::fmt::Display;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::ops::RangeBounds;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Options for connecting a `RemoteStore`.
+#[derive(Clone, Debug)]
+pub struct RemoteStoreOptions {
+    /// The most connections open at once; calls beyond it wait for one to be free. Defaults
+    /// to 8.
+    pub max_connections: usize,
+    /// How long a read or a write of a connection may block before it fails. Defaults to
+    /// none, for no limit.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for RemoteStoreOptions {
+    fn default() -> Self {
+        RemoteStoreOptions { max_connections: 8, timeout: None }
+    }
+}
+
+/// A store on a `ritekv-server`, see [`Server::serve_native`](crate::server::Server::serve_native).
+///
+/// A connection that fails is closed, and the next call opens a new one, but the failed
+/// call is not retried, since its write may or may not have been applied. Errors of the
+/// server's store come back as `KvsError::EmptyKey` and `KvsError::InvalidData` where they
+/// were those, and as `KvsError::Internal` otherwise.
+#[derive(Clone)]
+pub struct RemoteStore {
+    pool: Arc<Pool>,
+}
+
+impl RemoteStore {
+    /// Connects to the server at `addr`, with the default options.
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<RemoteStore> {
+        RemoteStore::connect_with_options(addr, RemoteStoreOptions::default())
+    }
+
+    /// Connects to the server at `addr`.
+    ///
+    /// # Errors
+    ///
+    /// It fails if `addr` cannot be resolved, or if the server does not answer a ping.
+    pub fn connect_with_options(
+        addr: impl ToSocketAddrs,
+        options: RemoteStoreOptions,
+    ) -> Result<RemoteStore> {
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        if addrs.is_empty() {
+            return Err(KvsError::InvalidData("address resolves to nothing".to_string()));
+        }
+        let pool = Pool {
+            addrs,
+            options,
+            state: Mutex::new(PoolState { idle: Vec::new(), open: 0 }),
+            available: Condvar::new(),
+        };
+        let store = RemoteStore { pool: Arc::new(pool) };
+        store.ping()?;
+        Ok(store)
+    }
+
+    /// Checks that the server answers.
+    pub fn ping(&self) -> Result<()> {
+        self.request(Request::Ping).and_then(expect_done)
+    }
+
+    /// Starts a batch of requests sent at once.
+    pub fn pipeline(&self) -> Pipeline<'_> {
+        Pipeline { store: self, requests: Vec::new() }
+    }
+
+    /// Starts a transaction.
+    pub fn transaction(&self) -> Transaction<'_> {
+        Transaction { store: self, checks: Vec::new(), writes: Vec::new() }
+    }
+
+    fn request(&self, request: Request) -> Result<Response> {
+        let mut responses = self.call(vec![request])?;
+        responses.pop().unwrap_or_else(|| Err(unexpected()))
+    }
+
+    /// Sends `requests` over a connection of the pool, and reads their responses.
+    fn call(&self, requests: Vec<Request>) -> Result<Vec<Result<Response>>> {
+        let mut connection = self.pool.checkout()?;
+        match connection.call(&requests) {
+            Ok(responses) => {
+                self.pool.checkin(connection);
+                Ok(responses)
+            }
+            Err(err) => {
+                // what is left of the responses on the stream cannot be told apart anymore
+                self.pool.discard();
+                Err(err)
+            }
+        }
+    }
+}
+
+impl Display for RemoteStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "remotestore")
+    }
+}
+
+impl Store for RemoteStore {
+    fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        match self.request(Request::Get(key.as_ref().to_vec()))? {
+            Response::Value(value) => Ok(value),
+            _ => Err(unexpected()),
+        }
+    }
+
+    fn set(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
+        let request = Request::Set(key.as_ref().to_vec(), value.as_ref().to_vec());
+        self.request(request).and_then(expect_done)
+    }
+
+    fn remove(&mut self, key: impl AsRef<[u8]>) -> Result<()> {
+        self.request(Request::Remove(key.as_ref().to_vec())).and_then(expect_done)
+    }
+
+    fn contains(&mut self, key: impl AsRef<[u8]>) -> Result<bool> {
+        match self.request(Request::Contains(key.as_ref().to_vec()))? {
+            Response::Bool(found) => Ok(found),
+            _ => Err(unexpected()),
+        }
+    }
+}
+
+impl BatchStore for RemoteStore {
+    fn get_batch(&self, keys: impl AsRef<[Vec<u8>]>) -> Result<Vec<Option<Vec<u8>>>> {
+        match self.request(Request::GetBatch(keys.as_ref().to_vec()))? {
+            Response::Values(values) => Ok(values),
+            _ => Err(unexpected()),
+        }
+    }
+
+    fn set_batch(
+        &mut self,
+        keys: impl AsRef<[Vec<u8>]>,
+        values: impl AsRef<[Vec<u8>]>,
+    ) -> Result<()> {
+        let request = Request::SetBatch(keys.as_ref().to_vec(), values.as_ref().to_vec());
+        self.request(request).and_then(expect_done)
+    }
+
+    fn remove_batch(&mut self, keys: impl AsRef<[Vec<u8>]>) -> Result<()> {
+        self.request(Request::RemoveBatch(keys.as_ref().to_vec())).and_then(expect_done)
+    }
+}
+
+impl ScanStore for RemoteStore {
+    /// Scans on the server, which sends all the pairs in one response, so scans of more
+    /// than 1 GiB fail; scan them in pages with `scan_limit` instead.
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan_limit(range, usize::MAX)
+    }
+
+    /// Scans on the server, which only sends the first `limit` pairs.
+    fn scan_limit(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let (start, end) = (range.start_bound().cloned(), range.end_bound().cloned());
+        match self.request(Request::Scan(start, end, limit))? {
+            Response::Pairs(pairs) => Ok(pairs),
+            _ => Err(unexpected()),
+        }
+    }
+}
+
+/// Requests sent to the server at once, without waiting for each response before sending
+/// the next, see [`RemoteStore::pipeline`].
+///
+/// The requests run in order, but requests of other connections may run in between them.
+pub struct Pipeline<'a> {
+    store: &'a RemoteStore,
+    requests: Vec<Request>,
+}
+
+impl Pipeline<'_> {
+    /// Adds a `get`, answered with `Response::Value`.
+    pub fn get(&mut self, key: impl AsRef<[u8]>) -> &mut Self {
+        self.push(Request::Get(key.as_ref().to_vec()))
+    }
+
+    /// Adds a `set`, answered with `Response::Done`.
+    pub fn set(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> &mut Self {
+        self.push(Request::Set(key.as_ref().to_vec(), value.as_ref().to_vec()))
+    }
+
+    /// Adds a `remove`, answered with `Response::Done`.
+    pub fn remove(&mut self, key: impl AsRef<[u8]>) -> &mut Self {
+        self.push(Request::Remove(key.as_ref().to_vec()))
+    }
+
+    /// Adds a `contains`, answered with `Response::Bool`.
+    pub fn contains(&mut self, key: impl AsRef<[u8]>) -> &mut Self {
+        self.push(Request::Contains(key.as_ref().to_vec()))
+    }
+
+    /// Adds a `get_batch`, answered with `Response::Values`.
+    pub fn get_batch(&mut self, keys: impl AsRef<[Vec<u8>]>) -> &mut Self {
+        self.push(Request::GetBatch(keys.as_ref().to_vec()))
+    }
+
+    /// Adds a `set_batch`, answered with `Response::Done`.
+    pub fn set_batch(
+        &mut self,
+        keys: impl AsRef<[Vec<u8>]>,
+        values: impl AsRef<[Vec<u8>]>,
+    ) -> &mut Self {
+        self.push(Request::SetBatch(keys.as_ref().to_vec(), values.as_ref().to_vec()))
+    }
+
+    /// Adds a `remove_batch`, answered with `Response::Done`.
+    pub fn remove_batch(&mut self, keys: impl AsRef<[Vec<u8>]>) -> &mut Self {
+        self.push(Request::RemoveBatch(keys.as_ref().to_vec()))
+    }
+
+    /// Adds a `scan`, answered with `Response::Pairs`.
+    pub fn scan(&mut self, range: impl RangeBounds<Vec<u8>>) -> &mut Self {
+        self.scan_limit(range, usize::MAX)
+    }
+
+    /// Adds a `scan_limit`, answered with `Response::Pairs`.
+    pub fn scan_limit(&mut self, range: impl RangeBounds<Vec<u8>>, limit: usize) -> &mut Self {
+        let (start, end) = (range.start_bound().cloned(), range.end_bound().cloned());
+        self.push(Request::Scan(start, end, limit))
+    }
+
+    /// The number of requests added.
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    /// Whether no request was added.
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Sends the requests, and returns the response or the error of each, in order.
+    ///
+    /// # Errors
+    ///
+    /// It fails if the connection does, in which case some of the requests may have run.
+    pub fn execute(self) -> Result<Vec<Result<Response>>> {
+        if self.requests.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.store.call(self.requests)
+    }
+
+    fn push(&mut self, request: Request) -> &mut Self {
+        self.requests.push(request);
+        self
+    }
+}
+
+/// Writes applied together, only if keys hold given values, see [`RemoteStore::transaction`].
+///
+/// No request of another client runs in between the checks and the writes. The last write of
+/// each key wins; the sets are applied as one batch of the store, then the removes as
+/// another, so a store error in between leaves the sets without the removes.
+pub struct Transaction<'a> {
+    store: &'a RemoteStore,
+    checks: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    writes: Vec<TxnWrite>,
+}
+
+impl Transaction<'_> {
+    /// Only commits if `key` holds `value`.
+    pub fn check(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> &mut Self {
+        self.checks.push((key.as_ref().to_vec(), Some(value.as_ref().to_vec())));
+        self
+    }
+
+    /// Only commits if `key` is absent.
+    pub fn check_absent(&mut self, key: impl AsRef<[u8]>) -> &mut Self {
+        self.checks.push((key.as_ref().to_vec(), None));
+        self
+    }
+
+    /// Sets `key` on commit.
+    pub fn set(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> &mut Self {
+        self.writes.push(TxnWrite::Set(key.as_ref().to_vec(), value.as_ref().to_vec()));
+        self
+    }
+
+    /// Removes `key` on commit.
+    pub fn remove(&mut self, key: impl AsRef<[u8]>) -> &mut Self {
+        self.writes.push(TxnWrite::Remove(key.as_ref().to_vec()));
+        self
+    }
+
+    /// Applies the writes if every check holds, and returns whether it did.
+    pub fn commit(self) -> Result<bool> {
+        match self.store.request(Request::Transaction(self.checks, self.writes))? {
+            Response::Bool(committed) => Ok(committed),
+            _ => Err(unexpected()),
+        }
+    }
+}
+
+struct Pool {
+    addrs: Vec<SocketAddr>,
+    options: RemoteStoreOptions,
+    state: Mutex<PoolState>,
+    available: Condvar,
+}
+
+struct PoolState {
+    idle: Vec<Connection>,
+    // idle connections and the ones in use
+    open: usize,
+}
+
+impl Pool {
+    /// Takes an idle connection, or opens one, waiting for one to be free if too many are
+    /// open.
+    fn checkout(&self) -> Result<Connection> {
+        let mut state = self.state.lock();
+        loop {
+            if let Some(connection) = state.idle.pop() {
+                return Ok(connection);
+            }
+            if state.open < self.options.max_connections.max(1) {
+                state.open += 1;
+                break;
+            }
+            self.available.wait(&mut state);
+        }
+        drop(state);
+        Connection::open(&self.addrs, self.options.timeout).inspect_err(|_| self.discard())
+    }
+
+    fn checkin(&self, connection: Connection) {
+        self.state.lock().idle.push(connection);
+        self.available.notify_one();
+    }
+
+    /// Forgets a connection taken out with `checkout`, which is closed.
+    fn discard(&self) {
+        self.state.lock().open -= 1;
+        self.available.notify_one();
+    }
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl Connection {
+    fn open(addrs: &[SocketAddr], timeout: Option<Duration>) -> Result<Connection> {
+        let stream = TcpStream::connect(addrs)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)?;
+        Ok(Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+
+    fn call(&mut self, requests: &[Request]) -> Result<Vec<Result<Response>>> {
+        let Connection { reader, writer } = self;
+        // all encoded upfront, so that a request too large to send fails before any is sent
+        let frames = requests.iter().map(Request::to_frame).collect::<io::Result<Vec<_>>>()?;
+        let send = move || -> Result<()> {
+            for frame in frames {
+                writer.write_all(&frame)?;
+            }
+            writer.flush()?;
+            Ok(())
+        };
+        let mut receive = move || -> Result<Vec<Result<Response>>> {
+            let closed = || KvsError::IOError(io::ErrorKind::UnexpectedEof.into());
+            (0..requests.len())
+                .map(|_| wire::decode_response(&wire::read_frame(reader)?.ok_or_else(closed)?))
+                .collect()
+        };
+        if requests.len() == 1 {
+            send()?;
+            return receive();
+        }
+        // the server answers while the requests are still being sent, so they are sent from
+        // another thread, lest both ends wait on each other with full buffers
+        thread::scope(|scope| {
+            let sender = scope.spawn(send);
+            let responses = receive();
+            sender.join().expect("sending requests panicked")?;
+            responses
+        })
+    }
+}
+
+fn expect_done(response: Response) -> Result<()> {
+    match response {
+        Response::Done => Ok(()),
+        _ => Err(unexpected()),
+    }
+}
+
+fn unexpected() -> KvsError {
+    KvsError::InvalidData("Protocol error: unexpected response".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Server;
+    use crate::storage::{DiskStore, MemStore};
+
+    use std::net::TcpListener;
+    use tempfile::TempDir;
+
+    /// Serves `store` on localhost, with the native protocol.
+    fn serve<S: Store + BatchStore + ScanStore + 'static>(store: S) -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let server = Server::new(store);
+        thread::spawn(move || server.serve_native(listener));
+        Ok(addr)
+    }
+
+    /// A server of a `DiskStore`, which outlives the clients connected by `open`.
+    struct RemoteFixture {
+        _dir: TempDir,
+        addr: SocketAddr,
+    }
+
+    impl RemoteFixture {
+        fn new() -> RemoteFixture {
+            let dir = TempDir::new().unwrap();
+            let addr = serve(DiskStore::open(dir.path()).unwrap()).unwrap();
+            RemoteFixture { _dir: dir, addr }
+        }
+    }
+
+    impl crate::testing::Fixture for RemoteFixture {
+        type Store = RemoteStore;
+
+        const PERSISTENT: bool = true;
+
+        fn open(&mut self) -> Result<RemoteStore> {
+            RemoteStore::connect(self.addr)
+        }
+    }
+
+    crate::store_test_suite!(suite, RemoteFixture::new(), [store, batch, scan, persist, model]);
+
+    #[test]
+    fn pipeline() -> Result<()> {
+        let store = RemoteStore::connect(serve(MemStore::open())?)?;
+        let mut pipeline = store.pipeline();
+        for i in 0..1000 {
+            pipeline.set(format!("key{:04}", i), vec![b'v'; 1000]);
+        }
+        pipeline.get("key0042").set("", "value").contains("missing").scan(b"key0998".to_vec()..);
+        assert_eq!(pipeline.len(), 1004);
+
+        let mut responses = pipeline.execute()?;
+        let tail: Vec<_> = responses.split_off(1000);
+        assert!(responses.into_iter().all(|response| response.unwrap() == Response::Done));
+        assert_eq!(tail[0].as_ref().unwrap(), &Response::Value(Some(vec![b'v'; 1000])));
+        assert!(matches!(tail[1], Err(KvsError::EmptyKey)));
+        assert_eq!(tail[2].as_ref().unwrap(), &Response::Bool(false));
+        let keys = match &tail[3] {
+            Ok(Response::Pairs(pairs)) => pairs.iter().map(|(key, _)| key.clone()).collect(),
+            _ => Vec::new(),
+        };
+        assert_eq!(keys, vec![b"key0998".to_vec(), b"key0999".to_vec()]);
+        Ok(())
+    }
+
+    #[test]
+    fn scan_limit() -> Result<()> {
+        let mut store = RemoteStore::connect(serve(MemStore::open())?)?;
+        for i in 0..10 {
+            store.set(format!("key{}", i), "value")?;
+        }
+        let keys: Vec<_> =
+            store.scan_limit(b"key3".to_vec().., 2)?.into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec![b"key3".to_vec(), b"key4".to_vec()]);
+        assert!(store.scan_limit(.., 0)?.is_empty());
+        assert_eq!(store.scan_limit(.., 100)?.len(), 10);
+        Ok(())
+    }
+
+    #[test]
+    fn transaction() -> Result<()> {
+        let mut store = RemoteStore::connect(serve(MemStore::open())?)?;
+        store.set("balance", "10")?;
+
+        let mut txn = store.transaction();
+        txn.check("balance", "10").check_absent("lock").set("balance", "5").set("log", "-5");
+        assert!(txn.commit()?);
+        assert_eq!(store.get("balance")?, Some(b"5".to_vec()));
+        assert_eq!(store.get("log")?, Some(b"-5".to_vec()));
+
+        let mut txn = store.transaction();
+        txn.check("balance", "10").remove("balance");
+        assert!(!txn.commit()?);
+        assert_eq!(store.get("balance")?, Some(b"5".to_vec()));
+
+        // the last write of a key wins
+        let mut txn = store.transaction();
+        txn.set("balance", "0").remove("balance").remove("log").set("log", "-10");
+        assert!(txn.commit()?);
+        assert_eq!(store.get("balance")?, None);
+        assert_eq!(store.get("log")?, Some(b"-10".to_vec()));
+
+        let mut txn = store.transaction();
+        txn.set("other", "value").set("", "value");
+        assert!(matches!(txn.commit(), Err(KvsError::EmptyKey)));
+        assert_eq!(store.get("other")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn connection_pool() -> Result<()> {
+        let options = RemoteStoreOptions { max_connections: 2, ..Default::default() };
+        let store = RemoteStore::connect_with_options(serve(MemStore::open())?, options)?;
+        let handles: Vec<_> = (0..8)
+            .map(|t| {
+                let mut store = store.clone();
+                thread::spawn(move || -> Result<()> {
+                    for i in 0..50 {
+                        store.set(format!("{}-{}", t, i), "value")?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+        assert_eq!(store.scan(..)?.len(), 400);
+        assert!(store.pool.state.lock().open <= 2);
+        Ok(())
+    }
+
+    #[test]
+    fn server_gone() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        drop(listener);
+        assert!(matches!(RemoteStore::connect(addr), Err(KvsError::IOError(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,301 @@
+//! A dump format to move the pairs of any store into any other.
+//!
+//! A dump is a stream of length-prefixed pairs between a header and a trailer, all integers
+//! little-endian:
+//!
+//! ```text
+//! dump    := header record* trailer
+//! header  := magic:[u8; 8] version:u32 engine_len:u32 engine:[u8]
+//! record  := key_len:u32 key:[u8] value_len:u32 value:[u8]        (key_len > 0)
+//! trailer := 0:u32 pairs:u64 checksum:u64
+//! ```
+//!
+//! The engine is the name of the store the dump was taken from, for information only. Keys
+//! are never empty, so a zero key length marks the trailer. The checksum is the seahash of
+//! every byte before it.
+//!
+//! ```
+//! use ritekv::dump::{export, import};
+//! use ritekv::{MemStore, Store};
+//! # fn main() -> ritekv::Result<()> {
+//! let mut from = MemStore::open();
+//! from.set("beep", "boop")?;
+//!
+//! let mut dump = Vec::new();
+//! export(&from, &mut dump)?;
+//! let mut to = MemStore::open();
+//! import(&mut to, dump.as_slice())?;
+//! assert_eq!(to.get("beep")?, Some(b"boop".to_vec()));
+//! # Ok(()) }
+//! ```
+
+use crate::encoding::read_bytes;
+use crate::result::{KvsError, Result};
+use crate::storage::{BatchStore, ScanStore};
+
+use seahash::SeaHasher;
+
+use std::convert::TryFrom;
+use std::hash::Hasher;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::ops::Bound;
+
+const MAGIC: &[u8; 8] = b"ritekvD\0";
+/// The version of the dump format written by [`export`].
+pub const VERSION: u32 = 1;
+// the number of pairs exported with each scan, and imported with each `set_batch`
+const BATCH_LEN: usize = 1024;
+
+/// What [`import`] found in a dump.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DumpInfo {
+    /// The name of the store the dump was taken from.
+    pub engine: String,
+    /// The number of pairs in the dump.
+    pub pairs: u64,
+}
+
+/// Writes every pair of a store to `writer`, in key order.
+///
+/// Pairs are read in batches, each with a scan of its own, so that the memory it takes does
+/// not grow with the store; writes made meanwhile may or may not be in the dump. Export a
+/// [checkpoint](crate::CheckpointStore) for a dump of a single state of the store.
+///
+/// Returns the number of pairs written.
+///
+/// # Errors
+///
+/// It propagates errors of the store and of the writer.
+pub fn export<S: ScanStore>(store: &S, writer: impl Write) -> Result<u64> {
+    let mut writer = Checksummed::new(BufWriter::new(writer));
+    let engine = store.to_string();
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    write_chunk(&mut writer, engine.as_bytes())?;
+
+    let (mut pairs, mut start) = (0u64, Bound::Unbounded);
+    loop {
+        let batch = store.scan_limit((start, Bound::Unbounded), BATCH_LEN)?;
+        for (key, value) in &batch {
+            write_chunk(&mut writer, key)?;
+            write_chunk(&mut writer, value)?;
+        }
+        pairs += batch.len() as u64;
+        match batch.last() {
+            Some((key, _)) if batch.len() == BATCH_LEN => start = Bound::Excluded(key.clone()),
+            _ => break,
+        }
+    }
+
+    writer.write_all(&0u32.to_le_bytes())?;
+    writer.write_all(&pairs.to_le_bytes())?;
+    let checksum = writer.hasher.finish();
+    let mut writer = writer.inner;
+    writer.write_all(&checksum.to_le_bytes())?;
+    writer.flush()?;
+    Ok(pairs)
+}
+
+/// Reads a dump from `reader` into a store, replacing the values of keys it already has.
+///
+/// Pairs are written to the store as they are read, in batches, so a dump that turns out to
+/// be corrupt may leave part of it in the store.
+///
+/// # Errors
+///
+/// It fails with `KvsError::InvalidData` if the dump is not one, is of an unknown version, is
+/// cut short or does not match its checksum, and propagates errors of the store and of the
+/// reader.
+pub fn import<S: BatchStore>(store: &mut S, reader: impl Read) -> Result<DumpInfo> {
+    let mut reader = Checksummed::new(BufReader::new(reader));
+    let mut magic = [0; 8];
+    read_exact(&mut reader, &mut magic)?;
+    if &magic != MAGIC {
+        return Err(KvsError::InvalidData("not a dump".to_string()));
+    }
+    let version = read_u32(&mut reader)?;
+    if version != VERSION {
+        return Err(KvsError::InvalidData(format!("unsupported dump version {}", version)));
+    }
+    let engine = read_chunk(&mut reader)?;
+    let engine = String::from_utf8(engine)
+        .map_err(|_| KvsError::InvalidData("engine name is not valid UTF-8".to_string()))?;
+
+    let (mut keys, mut values) = (Vec::new(), Vec::new());
+    let mut read = 0;
+    loop {
+        let key = read_chunk(&mut reader)?;
+        if key.is_empty() {
+            break;
+        }
+        keys.push(key);
+        values.push(read_chunk(&mut reader)?);
+        read += 1;
+        if keys.len() == BATCH_LEN {
+            store.set_batch(&keys, &values)?;
+            keys.clear();
+            values.clear();
+        }
+    }
+    if !keys.is_empty() {
+        store.set_batch(&keys, &values)?;
+    }
+
+    let mut pairs = [0; 8];
+    read_exact(&mut reader, &mut pairs)?;
+    let pairs = u64::from_le_bytes(pairs);
+    let expected = reader.hasher.finish();
+    let mut checksum = [0; 8];
+    read_exact(&mut reader.inner, &mut checksum)?;
+    if pairs != read || u64::from_le_bytes(checksum) != expected {
+        return Err(KvsError::InvalidData("dump does not match its checksum".to_string()));
+    }
+    Ok(DumpInfo { engine, pairs })
+}
+
+/// A reader or writer hashing the bytes going through it.
+struct Checksummed<T> {
+    inner: T,
+    hasher: SeaHasher,
+}
+
+impl<T> Checksummed<T> {
+    fn new(inner: T) -> Self {
+        Checksummed { inner, hasher: SeaHasher::new() }
+    }
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.hasher.write(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for Checksummed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.hasher.write(&buf[..len]);
+        Ok(len)
+    }
+}
+
+/// Writes a length-prefixed chunk of bytes.
+fn write_chunk(writer: &mut impl Write, chunk: &[u8]) -> Result<()> {
+    let len = u32::try_from(chunk.len())
+        .map_err(|_| KvsError::InvalidData("value is too large to dump".to_string()))?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(chunk)?;
+    Ok(())
+}
+
+/// Reads a length-prefixed chunk of bytes.
+fn read_chunk(reader: &mut impl Read) -> Result<Vec<u8>> {
+    let len = read_u32(reader)?;
+    read_bytes(reader, u64::from(len))?.ok_or_else(truncated)
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32> {
+    let mut buf = [0; 4];
+    read_exact(reader, &mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_exact(reader: &mut impl Read, buf: &mut [u8]) -> Result<()> {
+    reader.read_exact(buf).map_err(|err| match err.kind() {
+        io::ErrorKind::UnexpectedEof => truncated(),
+        _ => err.into(),
+    })
+}
+
+fn truncated() -> KvsError {
+    KvsError::InvalidData("dump is cut short".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{DiskStore, MemStore, SledStore, Store};
+
+    use tempfile::TempDir;
+
+    fn dump(store: &impl ScanStore) -> Result<Vec<u8>> {
+        let mut dump = Vec::new();
+        export(store, &mut dump)?;
+        Ok(dump)
+    }
+
+    #[test]
+    fn across_engines() -> Result<()> {
+        let (sled_dir, disk_dir) = (TempDir::new()?, TempDir::new()?);
+        let mut sled = SledStore::open(sled::open(sled_dir.path())?);
+        for i in 0..3000 {
+            sled.set(format!("key{:04}", i), format!("value{}", i))?;
+        }
+
+        let mut disk = DiskStore::open(disk_dir.path())?;
+        disk.set("key0000", "replaced")?;
+        disk.set("other", "kept")?;
+        let info = import(&mut disk, dump(&sled)?.as_slice())?;
+        assert_eq!(info, DumpInfo { engine: "sledstore".to_string(), pairs: 3000 });
+        assert_eq!(disk.get("key0000")?, Some(b"value0".to_vec()));
+        assert_eq!(disk.get("key2999")?, Some(b"value2999".to_vec()));
+        assert_eq!(disk.get("other")?, Some(b"kept".to_vec()));
+
+        let mut mem = MemStore::open();
+        import(&mut mem, dump(&disk)?.as_slice())?;
+        assert_eq!(mem.scan(..)?, disk.scan(..)?);
+        Ok(())
+    }
+
+    #[test]
+    fn binary_pairs() -> Result<()> {
+        let mut from = MemStore::open();
+        from.set([0, 255], Vec::<u8>::new())?;
+        from.set([1], vec![0; 100_000])?;
+        let mut to = MemStore::open();
+        assert_eq!(import(&mut to, dump(&from)?.as_slice())?.pairs, 2);
+        assert_eq!(to.scan(..)?, from.scan(..)?);
+        Ok(())
+    }
+
+    #[test]
+    fn whole_batches() -> Result<()> {
+        let mut from = MemStore::open();
+        for i in 0..2 * BATCH_LEN {
+            from.set(format!("key{:05}", i), format!("value{}", i))?;
+        }
+        let mut to = MemStore::open();
+        assert_eq!(import(&mut to, dump(&from)?.as_slice())?.pairs, 2 * BATCH_LEN as u64);
+        assert_eq!(to.scan(..)?, from.scan(..)?);
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_dumps() -> Result<()> {
+        let mut store = MemStore::open();
+        store.set("key", "value")?;
+        let dump = dump(&store)?;
+        let invalid = |dump: &[u8]| {
+            matches!(import(&mut MemStore::open(), dump), Err(KvsError::InvalidData(_)))
+        };
+
+        for len in 0..dump.len() {
+            assert!(invalid(&dump[..len]), "dump cut at {}", len);
+        }
+        let mut flipped = dump.clone();
+        let value = flipped.len() - 20 - 1;
+        flipped[value] ^= 1;
+        assert!(invalid(&flipped));
+        let mut version = dump.clone();
+        version[8] = 2;
+        assert!(invalid(&version));
+        assert!(invalid(b"{\"Set\":{}}"));
+        Ok(())
+    }
+}
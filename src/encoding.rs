@@ -0,0 +1,43 @@
+//! Helpers shared by the formats the crate reads and writes: the native protocol, dumps, the
+//! write-ahead log and tables, and the requests the server parses.
+
+use std::convert::TryFrom;
+use std::io::{self, Read};
+
+/// Appends `bytes` after their length, as a little-endian `u32`.
+///
+/// A length that does not fit is written as `u32::MAX`, so callers reject such bytes first,
+/// or bound the whole frame or record they write to a `u32` as well, which rejects them.
+pub(crate) fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    let len = u32::try_from(bytes.len()).unwrap_or(u32::MAX);
+    buf.extend_from_slice(&len.to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+/// Reads `len` bytes, or returns `None` if the stream ends before them.
+///
+/// The length was read from the stream too, so it is not trusted enough to allocate it
+/// upfront: the bytes are only allocated as they arrive.
+pub(crate) fn read_bytes(reader: &mut impl Read, len: u64) -> io::Result<Option<Vec<u8>>> {
+    let mut bytes = Vec::new();
+    let read = reader.take(len).read_to_end(&mut bytes)?;
+    Ok(Some(bytes).filter(|_| read as u64 == len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes() -> io::Result<()> {
+        let mut buf = Vec::new();
+        put_bytes(&mut buf, b"abc");
+        assert_eq!(buf, b"\x03\0\0\0abc");
+
+        assert_eq!(read_bytes(&mut &b"abcd"[..], 3)?, Some(b"abc".to_vec()));
+        assert_eq!(read_bytes(&mut &b"ab"[..], 3)?, None);
+        // a huge length allocates no more than the stream holds
+        assert_eq!(read_bytes(&mut &b"ab"[..], u64::MAX)?, None);
+        Ok(())
+    }
+}
@@ -14,7 +14,21 @@
 //! # }
 //! ```
 
+pub mod client;
+pub mod dump;
+mod encoding;
 pub mod result;
+pub mod server;
 pub mod storage;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod wire;
 
-pub use storage::{BatchStore, DiskStore, MemStore, SledStore, Store};
+pub use result::{KvsError, Result};
+pub use storage::{
+    AsyncBatchStore, AsyncStore, BatchStore, BloomStats, BoundedMemStore, BoundedMemStoreOptions,
+    CacheStats, Capacity, CheckpointStore, Compression, DiskStore, DiskStoreOptions, DiskValue,
+    EncryptionKey, EvictionListener, EvictionPolicy, EvictionReason, LsmStore, LsmStoreOptions,
+    MemStore, MemStoreOptions, MemoryUsage, MemoryUsageStore, OrderedMemStore, PooledStore,
+    ScanStore, ShardedMemStore, SledStore, Store, SyncMode, ThreadPool, ValueCache,
+};
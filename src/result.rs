@@ -11,6 +11,8 @@ pub enum KvsError {
     IOError(#[from] std::io::Error),
     #[error("Internal Error: {0}")]
     Internal(String),
+    #[error("Encryption Error: {0}")]
+    Encryption(String),
     #[error("serde_json error: {0}")]
     Serde(#[from] serde_json::Error),
     #[error("Sled Error: {0}")]
@@ -0,0 +1,837 @@
+//! A server of any store, to Redis clients, to [`RemoteStore`](crate::client::RemoteStore)
+//! and over HTTP.
+//!
+//! Over the Redis protocol, it knows `GET`, `SET` (with `EX`, `PX`, `NX` and `XX`), `DEL`,
+//! `EXISTS`, `MGET`, `MSET`, `SCAN` (with `MATCH` and `COUNT`), `PING`, `ECHO`, `HELLO`,
+//! `COMMAND` and `QUIT`. Each connection gets a thread. Reads run side by side, while writes
+//! run one at a time, so that `NX` and `XX` see no write in between their check and their
+//! write. The [native protocol](Server::serve_native) covers every operation of the store traits, and
+//! transactions, and the [HTTP gateway](Server::serve_http) serves a JSON API. Clients of all
+//! three share the store, and see the same keys expire.
+//!
+//! Expiries are kept in memory only, so a server of a store that persists its data should
+//! turn them off with [`ServerOptions`], rather than have its keys outlive their time to live
+//! after a restart.
+//!
+//! ```no_run
+//! use ritekv::server::Server;
+//! use ritekv::MemStore;
+//! use std::net::TcpListener;
+//! # fn main() -> ritekv::Result<()> {
+//! let server = Server::new(MemStore::open());
+//! server.serve(TcpListener::bind("127.0.0.1:6379")?)?;
+//! # Ok(()) }
+//! ```
+
+#[cfg(feature = "http")]
+mod http;
+mod native;
+mod resp;
+
+use self::resp::{read_command, Protocol, Reply};
+use crate::result::{KvsError, Result};
+use crate::storage::{BatchStore, ScanStore, Store};
+
+use parking_lot::{Mutex, RwLock, RwLockWriteGuard};
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+use std::ops::Bound;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// how long a scan may be left unfinished before its cursor is forgotten
+const CURSOR_TTL: Duration = Duration::from_secs(600);
+const DEFAULT_SCAN_COUNT: usize = 10;
+// the most expired keys a write removes besides the ones reads came across
+const PURGE_SAMPLE: usize = 20;
+
+/// Serves a store to Redis clients, shared by all its clones.
+pub struct Server<S> {
+    state: Arc<RwLock<State<S>>>,
+    cursors: Arc<Mutex<Cursors>>,
+}
+
+/// How a [`Server`] behaves.
+#[derive(Clone, Copy, Debug)]
+pub struct ServerOptions {
+    /// Whether `SET` takes `EX` and `PX`. Expiries are kept in memory only, so with a store
+    /// that persists its data, the keys would live on as plain keys after a restart. When
+    /// off, such a `SET` is refused. On by default.
+    pub expiries: bool,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions { expiries: true }
+    }
+}
+
+struct State<S> {
+    store: S,
+    options: ServerOptions,
+    // when keys set with `EX` or `PX` expire
+    expiries: Expiries,
+    // the expired keys reads came across, for the next write to remove from the store
+    due: Mutex<HashSet<Vec<u8>>>,
+}
+
+/// When keys expire, by key and in the order they expire.
+#[derive(Default)]
+struct Expiries {
+    by_key: HashMap<Vec<u8>, Instant>,
+    by_time: BTreeSet<(Instant, Vec<u8>)>,
+}
+
+impl Expiries {
+    fn get(&self, key: &[u8]) -> Option<Instant> {
+        self.by_key.get(key).copied()
+    }
+
+    fn insert(&mut self, key: Vec<u8>, at: Instant) {
+        if let Some(old) = self.by_key.insert(key.clone(), at) {
+            self.by_time.remove(&(old, key.clone()));
+        }
+        self.by_time.insert((at, key));
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(at) = self.by_key.remove(key) {
+            self.by_time.remove(&(at, key.to_vec()));
+        }
+    }
+
+    /// The first `n` keys that have expired by `now`, soonest first.
+    fn due(&self, now: Instant, n: usize) -> Vec<Vec<u8>> {
+        self.by_time
+            .iter()
+            .take_while(|(at, _)| *at <= now)
+            .take(n)
+            .map(|(_, key)| key.clone())
+            .collect()
+    }
+}
+
+/// The key each unfinished scan goes on after, and when the cursor was handed out, by
+/// cursor. Cursors are numbered in the order they are handed out, so the oldest come first.
+struct Cursors {
+    keys: BTreeMap<u64, (Vec<u8>, Instant)>,
+    next: u64,
+}
+
+impl Cursors {
+    /// Hands out a cursor going on after `key`, forgetting the ones older than `CURSOR_TTL`.
+    fn insert(&mut self, key: Vec<u8>) -> u64 {
+        let now = Instant::now();
+        while let Some(entry) = self.keys.first_entry() {
+            if now.duration_since(entry.get().1) < CURSOR_TTL {
+                break;
+            }
+            entry.remove();
+        }
+        let cursor = self.next;
+        self.next += 1;
+        self.keys.insert(cursor, (key, now));
+        cursor
+    }
+
+    /// The key a cursor goes on after. A cursor can be used again, to read the same page.
+    fn get(&self, cursor: u64) -> Option<Vec<u8>> {
+        let (key, at) = self.keys.get(&cursor)?;
+        Some(key.clone()).filter(|_| at.elapsed() < CURSOR_TTL)
+    }
+}
+
+impl<S> Clone for Server<S> {
+    fn clone(&self) -> Self {
+        Server { state: Arc::clone(&self.state), cursors: Arc::clone(&self.cursors) }
+    }
+}
+
+impl<S: Store + BatchStore + ScanStore + 'static> Server<S> {
+    /// Creates a server of the given store, with the default options.
+    pub fn new(store: S) -> Self {
+        Server::with_options(store, ServerOptions::default())
+    }
+
+    /// Creates a server of the given store.
+    pub fn with_options(store: S, options: ServerOptions) -> Self {
+        let state = State {
+            store,
+            options,
+            expiries: Expiries::default(),
+            due: Mutex::new(HashSet::new()),
+        };
+        let cursors = Cursors { keys: BTreeMap::new(), next: 1 };
+        Server { state: Arc::new(RwLock::new(state)), cursors: Arc::new(Mutex::new(cursors)) }
+    }
+
+    /// Locks the state to write, after removing the keys reads found expired.
+    fn write(&self) -> RwLockWriteGuard<'_, State<S>> {
+        let mut state = self.state.write();
+        state.purge();
+        state
+    }
+
+    /// Serves the Redis clients connecting to `listener`, each on its own thread, until
+    /// accepting a connection fails.
+    pub fn serve(&self, listener: TcpListener) -> Result<()> {
+        self.accept(listener, Server::handle)
+    }
+
+    fn accept(
+        &self,
+        listener: TcpListener,
+        handle: fn(&Self, TcpStream) -> Result<()>,
+    ) -> Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let server = self.clone();
+            thread::spawn(move || {
+                // a client going away in the middle of a command is no concern of the server
+                let _ = handle(&server, stream);
+            });
+        }
+        Ok(())
+    }
+
+    /// Answers the commands of a client, until it quits or sends something else.
+    fn handle(&self, stream: TcpStream) -> Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        let mut protocol = Protocol::Resp2;
+        loop {
+            let args = match read_command(&mut reader) {
+                Ok(Some(args)) => args,
+                Ok(None) => return Ok(()),
+                Err(KvsError::InvalidData(message)) => {
+                    Reply::error(format!("ERR {}", message)).write_to(&mut writer, protocol)?;
+                    writer.flush()?;
+                    return Ok(());
+                }
+                Err(err) => return Err(err),
+            };
+            let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+            let reply = match name.as_str() {
+                "HELLO" => self.hello(&args[1..], &mut protocol),
+                "QUIT" => {
+                    Reply::ok().write_to(&mut writer, protocol)?;
+                    writer.flush()?;
+                    return Ok(());
+                }
+                _ => self.execute(&name, &args[1..]),
+            };
+            reply.write_to(&mut writer, protocol)?;
+            // pipelined commands get their replies in one write
+            if reader.buffer().is_empty() {
+                writer.flush()?;
+            }
+        }
+    }
+
+    /// Switches the protocol of a connection, with `HELLO [protover]`.
+    fn hello(&self, args: &[Vec<u8>], protocol: &mut Protocol) -> Reply {
+        if let Some(version) = args.first() {
+            *protocol = match &version[..] {
+                b"2" => Protocol::Resp2,
+                b"3" => Protocol::Resp3,
+                _ => return Reply::error("NOPROTO unsupported protocol version"),
+            };
+        }
+        let field = |name: &str| Reply::Bulk(name.as_bytes().to_vec());
+        let version = match protocol {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        };
+        Reply::Map(vec![
+            (field("server"), field("ritekv")),
+            (field("version"), field(env!("CARGO_PKG_VERSION"))),
+            (field("proto"), Reply::Integer(version)),
+            (field("mode"), field("standalone")),
+            (field("role"), field("master")),
+            (field("modules"), Reply::Array(Vec::new())),
+        ])
+    }
+
+    /// Runs a command, turning its errors into error replies.
+    fn execute(&self, name: &str, args: &[Vec<u8>]) -> Reply {
+        let result = match name {
+            "PING" => match args {
+                [] => Ok(Reply::Simple("PONG".to_string())),
+                [message] => Ok(Reply::Bulk(message.clone())),
+                _ => Err(wrong_args(name)),
+            },
+            "ECHO" => match args {
+                [message] => Ok(Reply::Bulk(message.clone())),
+                _ => Err(wrong_args(name)),
+            },
+            "GET" => match args {
+                [key] => self
+                    .state
+                    .read()
+                    .get(key)
+                    .map(|value| value.map_or(Reply::Null, Reply::Bulk))
+                    .map_err(Reply::from),
+                _ => Err(wrong_args(name)),
+            },
+            "SET" => self.write().set(args),
+            "DEL" | "EXISTS" if args.is_empty() => Err(wrong_args(name)),
+            "DEL" => self.write().del(args),
+            "EXISTS" => self.state.read().exists(args),
+            "MGET" if args.is_empty() => Err(wrong_args(name)),
+            "MGET" => self.state.read().mget(args),
+            "MSET" if args.is_empty() || !args.len().is_multiple_of(2) => Err(wrong_args(name)),
+            "MSET" => self.write().mset(args),
+            "SCAN" => self.scan(args),
+            // clients ask for the command table on connecting, and do without it
+            "COMMAND" => Ok(Reply::Array(Vec::new())),
+            // the name goes in a one-line reply, so it cannot end the line early
+            _ => {
+                let name = name.replace(['\r', '\n'], " ");
+                Err(Reply::error(format!("ERR unknown command '{}'", name)))
+            }
+        };
+        result.unwrap_or_else(|reply| reply)
+    }
+
+    /// `SCAN cursor [MATCH pattern] [COUNT count]`
+    ///
+    /// A cursor stands for the last key a scan returned, so that every key present for the
+    /// whole scan is returned exactly once, in key order. Each call reads no more than a page
+    /// of the store, and holds no lock in between pages. Cursors are shared by all clients,
+    /// and are forgotten ten minutes after they are handed out.
+    fn scan(&self, args: &[Vec<u8>]) -> CommandResult {
+        let cursor = args.first().ok_or_else(|| wrong_args("SCAN"))?;
+        let cursor = parse_int(cursor).filter(|&n| n >= 0).ok_or_else(invalid_cursor)? as u64;
+        let (mut pattern, mut count) = (None, DEFAULT_SCAN_COUNT);
+        let mut options = args[1..].iter();
+        while let Some(option) = options.next() {
+            let value = options.next().ok_or_else(syntax_error)?;
+            match option.to_ascii_uppercase().as_slice() {
+                b"MATCH" => pattern = Some(value),
+                b"COUNT" => {
+                    count = parse_int(value).filter(|&n| n > 0).ok_or_else(syntax_error)? as usize
+                }
+                _ => return Err(syntax_error()),
+            }
+        }
+
+        let start = match cursor {
+            0 => Bound::Unbounded,
+            _ => Bound::Excluded(self.cursors.lock().get(cursor).ok_or_else(invalid_cursor)?),
+        };
+        // one pair more than the page tells whether there is another page
+        let (pairs, live) = {
+            let state = self.state.read();
+            let pairs =
+                state.store.scan_limit((start, Bound::Unbounded), count.saturating_add(1))?;
+            let live: Vec<bool> = pairs.iter().map(|(key, _)| !state.expired(key)).collect();
+            (pairs, live)
+        };
+        let more = pairs.len() > count;
+        let mut keys = Vec::new();
+        let mut last = None;
+        for ((key, _), live) in pairs.into_iter().zip(live).take(count) {
+            if live && pattern.is_none_or(|pattern| glob_match(pattern, &key)) {
+                keys.push(Reply::Bulk(key.clone()));
+            }
+            last = Some(key);
+        }
+        let next = match last {
+            Some(last) if more => self.cursors.lock().insert(last),
+            _ => 0,
+        };
+        Ok(Reply::Array(vec![Reply::Bulk(next.to_string().into_bytes()), Reply::Array(keys)]))
+    }
+}
+
+/// The result of a command: its reply, or an error reply.
+type CommandResult = std::result::Result<Reply, Reply>;
+
+impl<S: Store + BatchStore + ScanStore> State<S> {
+    /// Whether a key has expired, in which case it is left for the next write to remove.
+    fn expired(&self, key: &[u8]) -> bool {
+        let expired = self.expiries.get(key).is_some_and(|at| at <= Instant::now());
+        if expired {
+            self.due.lock().insert(key.to_vec());
+        }
+        expired
+    }
+
+    /// Removes the keys reads found expired, but those set again since, and up to
+    /// `PURGE_SAMPLE` more expired keys, the ones expired the longest first, so that keys no
+    /// one reads do not stay in the store. A key failing to be removed stays in the
+    /// expiries, and reads as absent all the same.
+    fn purge(&mut self) {
+        let now = Instant::now();
+        let mut due = std::mem::take(self.due.get_mut());
+        due.extend(self.expiries.due(now, PURGE_SAMPLE));
+        for key in due {
+            if self.expiries.get(&key).is_some_and(|at| at <= now)
+                && self.store.remove(&key).is_ok()
+            {
+                self.expiries.remove(&key);
+            }
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.expired(key) {
+            true => Ok(None),
+            false => self.store.get(key),
+        }
+    }
+
+    /// Whether a key is present; through `get`, as `Store::contains` needs the write lock.
+    fn contains(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    fn get_batch(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>> {
+        let mut values = self.store.get_batch(keys)?;
+        for (key, value) in keys.iter().zip(&mut values) {
+            if self.expired(key) {
+                *value = None;
+            }
+        }
+        Ok(values)
+    }
+
+    /// Sets a key, without a time to live.
+    fn put(&mut self, key: &[u8], value: impl AsRef<[u8]>) -> Result<()> {
+        self.store.set(key, value)?;
+        self.expiries.remove(key);
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.store.remove(key)?;
+        self.expiries.remove(key);
+        Ok(())
+    }
+
+    /// Scans the first `limit` pairs in a range, but the expired ones, scanning on past them
+    /// so that fewer pairs only come back at the end of the range.
+    fn scan_range(
+        &self,
+        mut start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut pairs = Vec::new();
+        loop {
+            let wanted = limit - pairs.len();
+            let page = self.store.scan_limit((start, end.clone()), wanted)?;
+            let more = page.len() == wanted;
+            start = match page.last() {
+                Some((key, _)) => Bound::Excluded(key.clone()),
+                None => Bound::Unbounded,
+            };
+            pairs.extend(page.into_iter().filter(|(key, _)| !self.expired(key)));
+            if !more || pairs.len() == limit {
+                return Ok(pairs);
+            }
+        }
+    }
+
+    /// `SET key value [NX | XX] [EX seconds | PX milliseconds]`
+    fn set(&mut self, args: &[Vec<u8>]) -> CommandResult {
+        let (key, value) = match args {
+            [key, value, ..] => (key, value),
+            _ => return Err(wrong_args("SET")),
+        };
+        let (mut nx, mut xx, mut expires) = (false, false, None);
+        let mut options = args[2..].iter();
+        while let Some(option) = options.next() {
+            match option.to_ascii_uppercase().as_slice() {
+                b"NX" => nx = true,
+                b"XX" => xx = true,
+                unit @ (b"EX" | b"PX") if expires.is_none() => {
+                    let n = options.next().and_then(|n| parse_int(n)).filter(|&n| n > 0);
+                    let ms = n.and_then(|n| match unit {
+                        b"EX" => n.checked_mul(1000),
+                        _ => Some(n),
+                    });
+                    // like in Redis, the expiry has to fit a Unix time in milliseconds
+                    let ms = ms.filter(|&ms| unix_millis().checked_add(ms).is_some());
+                    let at = ms.and_then(|ms| {
+                        Instant::now().checked_add(Duration::from_millis(ms as u64))
+                    });
+                    let invalid = || Reply::error("ERR invalid expire time in 'set' command");
+                    expires = Some(at.ok_or_else(invalid)?);
+                }
+                _ => return Err(syntax_error()),
+            }
+        }
+        if nx && xx {
+            return Err(syntax_error());
+        }
+        if expires.is_some() && !self.options.expiries {
+            return Err(Reply::error(
+                "ERR expiries are turned off, as they would not survive a restart of the store",
+            ));
+        }
+        if nx || xx {
+            let exists = self.get(key)?.is_some();
+            if exists == nx {
+                return Ok(Reply::Null);
+            }
+        }
+        self.store.set(key, value)?;
+        match expires {
+            Some(at) => self.expiries.insert(key.clone(), at),
+            None => self.expiries.remove(key),
+        }
+        Ok(Reply::ok())
+    }
+
+    fn del(&mut self, keys: &[Vec<u8>]) -> CommandResult {
+        let mut removed = 0;
+        for key in keys {
+            if self.get(key)?.is_some() {
+                self.store.remove(key)?;
+                self.expiries.remove(key);
+                removed += 1;
+            }
+        }
+        Ok(Reply::Integer(removed))
+    }
+
+    fn exists(&self, keys: &[Vec<u8>]) -> CommandResult {
+        let mut found = 0;
+        for key in keys {
+            if self.contains(key)? {
+                found += 1;
+            }
+        }
+        Ok(Reply::Integer(found))
+    }
+
+    fn mget(&self, keys: &[Vec<u8>]) -> CommandResult {
+        let values = self.get_batch(keys)?;
+        Ok(Reply::Array(
+            values.into_iter().map(|value| value.map_or(Reply::Null, Reply::Bulk)).collect(),
+        ))
+    }
+
+    fn mset(&mut self, args: &[Vec<u8>]) -> CommandResult {
+        let keys: Vec<Vec<u8>> = args.iter().step_by(2).cloned().collect();
+        let values: Vec<Vec<u8>> = args.iter().skip(1).step_by(2).cloned().collect();
+        self.store.set_batch(&keys, &values)?;
+        for key in &keys {
+            self.expiries.remove(key);
+        }
+        Ok(Reply::ok())
+    }
+}
+
+/// Matches a key against a glob-style pattern, as Redis does: `*` for any bytes, `?` for any
+/// byte, `[abc]`, `[^abc]` and `[a-z]` for classes of bytes, and `\` to escape.
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => key.is_empty(),
+        Some((b'*', rest)) => (0..=key.len()).any(|skip| glob_match(rest, &key[skip..])),
+        Some((&first, rest)) => {
+            let (byte, key_rest) = match key.split_first() {
+                Some((&byte, key_rest)) => (byte, key_rest),
+                None => return false,
+            };
+            let (matched, rest) = match first {
+                b'?' => (true, rest),
+                b'[' => {
+                    let end = match rest.iter().skip(1).position(|&b| b == b']') {
+                        Some(end) => end + 1,
+                        None => return false,
+                    };
+                    let (class, rest) = (&rest[..end], &rest[end + 1..]);
+                    let (negated, class) = match class.split_first() {
+                        Some((b'^', class)) => (true, class),
+                        _ => (false, class),
+                    };
+                    (class_contains(class, byte) != negated, rest)
+                }
+                b'\\' if !rest.is_empty() => (rest[0] == byte, &rest[1..]),
+                _ => (first == byte, rest),
+            };
+            matched && glob_match(rest, key_rest)
+        }
+    }
+}
+
+fn class_contains(class: &[u8], byte: u8) -> bool {
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == b'-' {
+            let (low, high) = (class[i].min(class[i + 2]), class[i].max(class[i + 2]));
+            if (low..=high).contains(&byte) {
+                return true;
+            }
+            i += 3;
+        } else {
+            if class[i] == byte {
+                return true;
+            }
+            i += 1;
+        }
+    }
+    false
+}
+
+fn unix_millis() -> i64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    now.as_millis() as i64
+}
+
+fn parse_int(digits: &[u8]) -> Option<i64> {
+    std::str::from_utf8(digits).ok()?.parse().ok()
+}
+
+fn wrong_args(name: &str) -> Reply {
+    Reply::error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        name.to_ascii_lowercase()
+    ))
+}
+
+fn syntax_error() -> Reply {
+    Reply::error("ERR syntax error")
+}
+
+fn invalid_cursor() -> Reply {
+    Reply::error("ERR invalid cursor")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{DiskStore, MemStore};
+
+    use std::io::{BufRead, Read};
+    use tempfile::TempDir;
+
+    /// A client speaking raw RESP to a server on localhost.
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+    }
+
+    impl Client {
+        fn connect<S: Store + BatchStore + ScanStore + 'static>(store: S) -> Result<Client> {
+            let listener = TcpListener::bind("127.0.0.1:0")?;
+            let addr = listener.local_addr()?;
+            let server = Server::new(store);
+            thread::spawn(move || server.serve(listener));
+            let writer = TcpStream::connect(addr)?;
+            Ok(Client { reader: BufReader::new(writer.try_clone()?), writer })
+        }
+
+        /// Sends a command, and returns its raw reply.
+        fn call(&mut self, args: &[&str]) -> Result<String> {
+            let mut command = format!("*{}\r\n", args.len());
+            for arg in args {
+                command += &format!("${}\r\n{}\r\n", arg.len(), arg);
+            }
+            self.writer.write_all(command.as_bytes())?;
+            self.read_reply()
+        }
+
+        fn read_reply(&mut self) -> Result<String> {
+            let mut line = String::new();
+            self.reader.read_line(&mut line)?;
+            let count = line[1..line.len() - 2].parse::<i64>().unwrap_or(0);
+            match line.as_bytes()[0] {
+                b'$' if count >= 0 => {
+                    let mut data = vec![0; count as usize + 2];
+                    self.reader.read_exact(&mut data)?;
+                    line += &String::from_utf8_lossy(&data);
+                }
+                b'*' | b'%' => {
+                    let items = if line.starts_with('%') { 2 * count } else { count };
+                    for _ in 0..items {
+                        line += &self.read_reply()?;
+                    }
+                }
+                _ => {}
+            }
+            Ok(line)
+        }
+    }
+
+    #[test]
+    fn commands() -> Result<()> {
+        let mut client = Client::connect(MemStore::open())?;
+        assert_eq!(client.call(&["PING"])?, "+PONG\r\n");
+        assert_eq!(client.call(&["SET", "key", "value"])?, "+OK\r\n");
+        assert_eq!(client.call(&["get", "key"])?, "$5\r\nvalue\r\n");
+        assert_eq!(client.call(&["GET", "missing"])?, "$-1\r\n");
+        assert_eq!(client.call(&["SET", "key", "other", "NX"])?, "$-1\r\n");
+        assert_eq!(client.call(&["SET", "new", "other", "XX"])?, "$-1\r\n");
+        assert_eq!(client.call(&["SET", "new", "value", "NX"])?, "+OK\r\n");
+        assert_eq!(client.call(&["EXISTS", "key", "new", "missing", "key"])?, ":3\r\n");
+        assert_eq!(client.call(&["MSET", "a", "1", "b", "2"])?, "+OK\r\n");
+        assert_eq!(
+            client.call(&["MGET", "a", "missing", "b"])?,
+            "*3\r\n$1\r\n1\r\n$-1\r\n$1\r\n2\r\n"
+        );
+        assert_eq!(client.call(&["DEL", "a", "b", "missing"])?, ":2\r\n");
+        assert_eq!(client.call(&["EXISTS", "a"])?, ":0\r\n");
+
+        assert_eq!(
+            client.call(&["SET", "key"])?,
+            "-ERR wrong number of arguments for 'set' command\r\n"
+        );
+        assert_eq!(client.call(&["SET", "key", "value", "NX", "XX"])?, "-ERR syntax error\r\n");
+        assert_eq!(client.call(&["NOPE"])?, "-ERR unknown command 'NOPE'\r\n");
+        assert_eq!(client.call(&["NO\r\n+OK"])?, "-ERR unknown command 'NO  +OK'\r\n");
+        assert!(client.call(&["SET", "", "value"])?.starts_with("-ERR"));
+
+        // inline commands, and RESP3 once asked for
+        client.writer.write_all(b"PING hello\r\n")?;
+        assert_eq!(client.read_reply()?, "$5\r\nhello\r\n");
+        assert!(client
+            .call(&["HELLO", "3"])?
+            .starts_with("%6\r\n$6\r\nserver\r\n$6\r\nritekv\r\n"));
+        assert_eq!(client.call(&["GET", "missing"])?, "_\r\n");
+        assert_eq!(client.call(&["HELLO", "4"])?, "-NOPROTO unsupported protocol version\r\n");
+        // an empty array is skipped, and the connection goes on
+        client.writer.write_all(b"*0\r\n")?;
+        assert_eq!(client.call(&["PING"])?, "+PONG\r\n");
+        assert_eq!(client.call(&["QUIT"])?, "+OK\r\n");
+        Ok(())
+    }
+
+    #[test]
+    fn expiry() -> Result<()> {
+        let mut client = Client::connect(MemStore::open())?;
+        assert_eq!(client.call(&["SET", "short", "value", "PX", "1"])?, "+OK\r\n");
+        assert_eq!(client.call(&["SET", "long", "value", "EX", "3600"])?, "+OK\r\n");
+        assert_eq!(
+            client.call(&["SET", "key", "value", "EX", "0"])?,
+            "-ERR invalid expire time in 'set' command\r\n"
+        );
+        for huge in &[&["EX", "9223372036854775807"][..], &["PX", "9223372036854775807"]] {
+            let reply = client.call(&[&["SET", "key", "value"][..], huge].concat())?;
+            assert_eq!(reply, "-ERR invalid expire time in 'set' command\r\n");
+        }
+        assert_eq!(client.call(&["GET", "key"])?, "$-1\r\n");
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(client.call(&["GET", "short"])?, "$-1\r\n");
+        assert_eq!(client.call(&["SET", "short", "again", "NX"])?, "+OK\r\n");
+        assert_eq!(client.call(&["EXISTS", "short", "long"])?, ":2\r\n");
+
+        // setting a key again without a time to live keeps it
+        assert_eq!(client.call(&["SET", "gone", "value", "PX", "1"])?, "+OK\r\n");
+        assert_eq!(client.call(&["SET", "gone", "kept"])?, "+OK\r\n");
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(client.call(&["GET", "gone"])?, "$4\r\nkept\r\n");
+        Ok(())
+    }
+
+    #[test]
+    fn expiries_turned_off() -> Result<()> {
+        let server = Server::with_options(MemStore::open(), ServerOptions { expiries: false });
+        let set = |args: &[&str]| {
+            let args: Vec<Vec<u8>> = args.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+            server.execute("SET", &args)
+        };
+        assert!(matches!(set(&["key", "value", "EX", "10"]), Reply::Error(message)
+            if message.contains("expiries are turned off")));
+        assert!(matches!(set(&["key", "value"]), Reply::Simple(_)));
+        assert_eq!(server.state.read().store.get(b"key")?, Some(b"value".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn expired_keys_are_removed_by_writes() -> Result<()> {
+        let server = Server::new(MemStore::open());
+        let args = |args: &[&str]| -> Vec<Vec<u8>> {
+            args.iter().map(|arg| arg.as_bytes().to_vec()).collect()
+        };
+        server.execute("SET", &args(&["short", "value", "PX", "1"]));
+        thread::sleep(Duration::from_millis(10));
+        // a read finds it expired, but leaves it to the next write
+        assert!(matches!(server.execute("GET", &args(&["short"])), Reply::Null));
+        assert!(server.state.read().store.get(b"short")?.is_some());
+        server.execute("SET", &args(&["other", "value"]));
+        assert_eq!(server.state.read().store.get(b"short")?, None);
+        assert_eq!(server.state.read().expiries.get(b"short"), None);
+
+        // and a write removes expired keys no read came across
+        server.execute("SET", &args(&["unread", "value", "PX", "1"]));
+        server.execute("SET", &args(&["later", "value", "EX", "3600"]));
+        thread::sleep(Duration::from_millis(10));
+        server.execute("DEL", &args(&["other"]));
+        let state = server.state.read();
+        assert_eq!(state.store.get(b"unread")?, None);
+        assert_eq!(state.expiries.get(b"unread"), None);
+        assert!(state.store.get(b"later")?.is_some());
+        assert!(state.expiries.by_time.len() == 1 && state.expiries.by_key.len() == 1);
+        Ok(())
+    }
+
+    #[test]
+    fn scan_range_skips_expired_keys() -> Result<()> {
+        let server = Server::new(MemStore::open());
+        for key in &["a", "b", "c"] {
+            server.execute(
+                "SET",
+                &[key.as_bytes().to_vec(), b"v".to_vec(), b"PX".to_vec(), b"1".to_vec()],
+            );
+        }
+        for key in &["d", "e", "f"] {
+            server.execute("SET", &[key.as_bytes().to_vec(), b"v".to_vec()]);
+        }
+        thread::sleep(Duration::from_millis(10));
+        let keys = |limit| -> Result<Vec<Vec<u8>>> {
+            let pairs =
+                server.state.read().scan_range(Bound::Unbounded, Bound::Unbounded, limit)?;
+            Ok(pairs.into_iter().map(|(key, _)| key).collect())
+        };
+        // a page is filled from past the expired keys, and only short at the end of the range
+        assert_eq!(keys(2)?, vec![b"d".to_vec(), b"e".to_vec()]);
+        assert_eq!(keys(10)?, vec![b"d".to_vec(), b"e".to_vec(), b"f".to_vec()]);
+        assert!(keys(0)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn scan() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut client = Client::connect(DiskStore::open(dir.path())?)?;
+        for i in 0..25 {
+            client.call(&["SET", &format!("key{:02}", i), "value"])?;
+        }
+        client.call(&["SET", "other", "value"])?;
+
+        let mut cursor = "0".to_string();
+        let mut keys = Vec::new();
+        loop {
+            let reply = client.call(&["SCAN", &cursor, "MATCH", "key*", "COUNT", "10"])?;
+            let lines: Vec<&str> = reply.split("\r\n").collect();
+            // a cursor is not used up, so a page can be read again
+            let again = client.call(&["SCAN", &cursor, "MATCH", "key*", "COUNT", "10"])?;
+            assert_eq!(again.split("\r\n").skip(3).collect::<Vec<_>>(), lines[3..]);
+            cursor = lines[2].to_string();
+            keys.extend(lines[4..].iter().skip(1).step_by(2).map(|key| key.to_string()));
+            if cursor == "0" {
+                break;
+            }
+        }
+        assert_eq!(keys, (0..25).map(|i| format!("key{:02}", i)).collect::<Vec<_>>());
+        assert_eq!(client.call(&["SCAN", "12345"])?, "-ERR invalid cursor\r\n");
+        Ok(())
+    }
+
+    #[test]
+    fn glob() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"key*", b"key42"));
+        assert!(glob_match(b"k?y[0-9][^a]", b"key4b"));
+        assert!(!glob_match(b"k?y[0-9][^a]", b"key4a"));
+        assert!(glob_match(b"a\\*", b"a*"));
+        assert!(!glob_match(b"a\\*", b"ab"));
+        assert!(glob_match(b"*[xy]", b"abcy"));
+    }
+}
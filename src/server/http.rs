@@ -0,0 +1,678 @@
+//! The server side of the HTTP gateway, a JSON API over HTTP/1.1.
+
+use super::{Server, State};
+use crate::encoding::read_bytes;
+use crate::result::{KvsError, Result};
+use crate::storage::{prefix_successor, BatchStore, ScanStore, Store};
+
+use base64::alphabet::URL_SAFE;
+use base64::engine::general_purpose::{GeneralPurpose, GeneralPurposeConfig};
+use base64::engine::DecodePaddingMode;
+use base64::Engine;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::ops::Bound;
+
+const BASE64: GeneralPurpose = GeneralPurpose::new(
+    &URL_SAFE,
+    GeneralPurposeConfig::new()
+        .with_encode_padding(false)
+        .with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+// the limits of a request, so that a client cannot make the server allocate without bound
+const MAX_HEAD_LEN: usize = 64 * 1024;
+const MAX_BODY_LEN: usize = 512 * 1024 * 1024;
+const DEFAULT_SCAN_LIMIT: usize = 1000;
+
+impl<S: Store + BatchStore + ScanStore + 'static> Server<S> {
+    /// Serves the HTTP clients connecting to `listener`, each on its own thread, until
+    /// accepting a connection fails.
+    ///
+    /// - `GET /kv/{key}` answers `200 {"key", "value"}` with an `ETag`, or `404`.
+    /// - `PUT /kv/{key}` sets the key to the `value` of a `{"value"}` body, and answers `201`
+    ///   if it is new, `204` if not.
+    /// - `DELETE /kv/{key}` removes the key, and answers `204`, or `404` if it is absent.
+    /// - `POST /batch` runs the `{"ops": [{"op", "key", "value"}]}` of its body in order, with
+    ///   no other request in between, where `op` is `get`, `set` or `remove` and only `set`
+    ///   has a `value`. It answers `200 {"results": [...]}`, with a `{"value"}` for each `get`
+    ///   and `{}` for the others.
+    /// - `GET /scan?prefix=&after=&limit=` answers `200 {"pairs": [{"key", "value"}], "next"}`,
+    ///   all parameters optional.
+    ///
+    /// Keys and values are URL-safe base64, in paths, query parameters and bodies alike, padded
+    /// or not, so binary keys need no further escaping. The `ETag` of a value is its SHA-256: a
+    /// `PUT` or `DELETE` with `If-Match` only applies if the key holds a value of one of the
+    /// given tags (`*` for any), which makes a compare-and-swap, and a `PUT` with `If-None-Match:
+    /// *` only creates a key. Failed preconditions answer `412`. A scan reads at most `limit`
+    /// pairs, 1000 by default, and returns them but the expired ones; `next` is the `after` of
+    /// the following page, or `null` once there is none.
+    ///
+    /// Errors are JSON too, `{"error", "message"}`, where `error` is the `KvsError` variant, or
+    /// `NotFound`, `PreconditionFailed`, `MethodNotAllowed` and such for errors of HTTP itself.
+    pub fn serve_http(&self, listener: TcpListener) -> Result<()> {
+        self.accept(listener, Server::handle_http)
+    }
+
+    /// Answers the requests of a client, until it closes the connection or asks to.
+    fn handle_http(&self, stream: TcpStream) -> Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        loop {
+            let (response, keep_alive) = match read_request(&mut reader) {
+                Ok(Some(request)) => {
+                    let keep_alive = request.keep_alive();
+                    (self.route(&request), keep_alive)
+                }
+                Ok(None) => return Ok(()),
+                Err(KvsError::InvalidData(message)) => {
+                    (HttpResponse::error(400, "BadRequest", &message), false)
+                }
+                Err(err) => return Err(err),
+            };
+            response.write_to(&mut writer, keep_alive)?;
+            writer.flush()?;
+            if !keep_alive {
+                return Ok(());
+            }
+        }
+    }
+
+    fn route(&self, request: &HttpRequest) -> HttpResponse {
+        let segments: Vec<&str> = request.path.trim_start_matches('/').split('/').collect();
+        let result = match (request.method.as_str(), segments.as_slice()) {
+            (method, ["kv", key]) => match decode(key) {
+                Ok(key) => match method {
+                    "GET" => self.state.read().http_get(request, key),
+                    "PUT" => self.write().http_put(request, key),
+                    "DELETE" => self.write().http_delete(request, key),
+                    _ => return method_not_allowed("GET, PUT, DELETE"),
+                },
+                Err(err) => Err(err),
+            },
+            ("POST", ["batch"]) => self.write().http_batch(request),
+            (_, ["batch"]) => return method_not_allowed("POST"),
+            ("GET", ["scan"]) => self.state.read().http_scan(request),
+            (_, ["scan"]) => return method_not_allowed("GET"),
+            _ => return HttpResponse::error(404, "NotFound", "no such route"),
+        };
+        result.unwrap_or_else(|err| HttpResponse::from(&err))
+    }
+}
+
+#[derive(Deserialize)]
+struct PutBody {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct BatchBody {
+    ops: Vec<BatchOp>,
+}
+
+/// An op of a batch, with its keys and values in base64, or decoded.
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum BatchOp<T = String> {
+    Get { key: T },
+    Set { key: T, value: T },
+    Remove { key: T },
+}
+
+impl BatchOp {
+    fn decode(&self) -> Result<BatchOp<Vec<u8>>> {
+        let op = match self {
+            BatchOp::Get { key } => BatchOp::Get { key: decode(key)? },
+            BatchOp::Set { key, value } => {
+                BatchOp::Set { key: decode(key)?, value: decode(value)? }
+            }
+            BatchOp::Remove { key } => BatchOp::Remove { key: decode(key)? },
+        };
+        match &op {
+            BatchOp::Get { key } | BatchOp::Set { key, .. } | BatchOp::Remove { key }
+                if key.is_empty() =>
+            {
+                Err(KvsError::EmptyKey)
+            }
+            _ => Ok(op),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Pair {
+    key: String,
+    value: String,
+}
+
+impl<S: Store + BatchStore + ScanStore> State<S> {
+    fn http_get(&self, request: &HttpRequest, key: Vec<u8>) -> Result<HttpResponse> {
+        let value = match self.get(&key)? {
+            Some(value) => value,
+            None => return Ok(not_found()),
+        };
+        let tag = etag(&value);
+        let response = match request.header("if-none-match") {
+            Some(tags) if tags_match(tags, &tag) => HttpResponse::empty(304),
+            _ => HttpResponse::json(200, &json!({ "key": encode(&key), "value": encode(&value) })),
+        };
+        Ok(response.with_header("ETag", tag))
+    }
+
+    fn http_put(&mut self, request: &HttpRequest, key: Vec<u8>) -> Result<HttpResponse> {
+        let body: PutBody = parse_body(request)?;
+        let value = decode(&body.value)?;
+        let current = self.get(&key)?;
+        if let Some(failed) = preconditions(request, current.as_deref()) {
+            return Ok(failed);
+        }
+        self.put(&key, &value)?;
+        let status = if current.is_some() { 204 } else { 201 };
+        Ok(HttpResponse::empty(status).with_header("ETag", etag(&value)))
+    }
+
+    fn http_delete(&mut self, request: &HttpRequest, key: Vec<u8>) -> Result<HttpResponse> {
+        let current = self.get(&key)?;
+        if let Some(failed) = preconditions(request, current.as_deref()) {
+            return Ok(failed);
+        }
+        if current.is_none() {
+            return Ok(not_found());
+        }
+        self.delete(&key)?;
+        Ok(HttpResponse::empty(204))
+    }
+
+    /// Runs the ops in order, with no other request in between; keys and values are all
+    /// decoded first, so that a bad one fails the batch before any op runs.
+    fn http_batch(&mut self, request: &HttpRequest) -> Result<HttpResponse> {
+        let body: BatchBody = parse_body(request)?;
+        let ops = body.ops.iter().map(BatchOp::decode).collect::<Result<Vec<_>>>()?;
+        let mut results = Vec::with_capacity(ops.len());
+        for op in ops {
+            let result = match op {
+                BatchOp::Get { key } => {
+                    json!({ "value": self.get(&key)?.map(|value| encode(&value)) })
+                }
+                BatchOp::Set { key, value } => {
+                    self.put(&key, value)?;
+                    json!({})
+                }
+                BatchOp::Remove { key } => {
+                    self.delete(&key)?;
+                    json!({})
+                }
+            };
+            results.push(result);
+        }
+        Ok(HttpResponse::json(200, &json!({ "results": results })))
+    }
+
+    fn http_scan(&self, request: &HttpRequest) -> Result<HttpResponse> {
+        let prefix = request.query("prefix").map(decode).transpose()?.unwrap_or_default();
+        let after = request.query("after").map(decode).transpose()?;
+        let limit = match request.query("limit") {
+            Some(limit) => limit.parse().ok().filter(|&limit| limit > 0).ok_or_else(|| {
+                KvsError::InvalidData("limit is not a positive integer".to_string())
+            })?,
+            None => DEFAULT_SCAN_LIMIT,
+        };
+        let start = match after {
+            Some(after) if after >= prefix => Bound::Excluded(after),
+            _ => Bound::Included(prefix.clone()),
+        };
+        let end = prefix_successor(&prefix).map_or(Bound::Unbounded, Bound::Excluded);
+        // one pair more than the page tells whether there is another page
+        let mut pairs = self.store.scan_limit((start, end), limit.saturating_add(1))?;
+        let next = match pairs.len() > limit {
+            true => {
+                pairs.truncate(limit);
+                pairs.last().map(|(key, _)| encode(key))
+            }
+            false => None,
+        };
+        let pairs: Vec<Pair> = pairs
+            .iter()
+            .filter(|(key, _)| !self.expired(key))
+            .map(|(key, value)| Pair { key: encode(key), value: encode(value) })
+            .collect();
+        Ok(HttpResponse::json(200, &json!({ "pairs": pairs, "next": next })))
+    }
+}
+
+/// Answers `412` unless the `If-Match` and `If-None-Match` headers hold for the current value.
+fn preconditions(request: &HttpRequest, current: Option<&[u8]>) -> Option<HttpResponse> {
+    let tag = current.map(etag);
+    let holds = match (request.header("if-match"), &tag) {
+        (Some(_), None) => false,
+        (Some(tags), Some(tag)) => tags_match(tags, tag),
+        (None, _) => true,
+    } && match (request.header("if-none-match"), &tag) {
+        (Some(tags), Some(tag)) => !tags_match(tags, tag),
+        _ => true,
+    };
+    match holds {
+        true => None,
+        false => Some(HttpResponse::error(412, "PreconditionFailed", "the value has changed")),
+    }
+}
+
+/// The entity tag of a value, quoted: its SHA-256, so that no client can make up another
+/// value of the same tag to get past an `If-Match`.
+fn etag(value: &[u8]) -> String {
+    let digest = digest::digest(&digest::SHA256, value);
+    let hex: String = digest.as_ref().iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("\"{}\"", hex)
+}
+
+/// Whether a list of entity tags, as in `If-Match`, has `tag` or is `*`.
+fn tags_match(tags: &str, tag: &str) -> bool {
+    tags.trim() == "*" || tags.split(',').any(|candidate| candidate.trim() == tag)
+}
+
+fn encode(bytes: &[u8]) -> String {
+    BASE64.encode(bytes)
+}
+
+fn decode(text: &str) -> Result<Vec<u8>> {
+    BASE64
+        .decode(text)
+        .map_err(|_| KvsError::InvalidData(format!("'{}' is not URL-safe base64", text)))
+}
+
+fn parse_body<T: serde::de::DeserializeOwned>(request: &HttpRequest) -> Result<T> {
+    serde_json::from_slice(&request.body)
+        .map_err(|err| KvsError::InvalidData(format!("invalid body: {}", err)))
+}
+
+fn not_found() -> HttpResponse {
+    HttpResponse::error(404, "NotFound", "no such key")
+}
+
+fn method_not_allowed(allowed: &str) -> HttpResponse {
+    HttpResponse::error(405, "MethodNotAllowed", "method not allowed")
+        .with_header("Allow", allowed.to_string())
+}
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    version: String,
+    // names in lowercase
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpRequest {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == name).map(|(_, value)| value.as_str())
+    }
+
+    fn query(&self, name: &str) -> Option<&str> {
+        self.query.iter().find(|(n, _)| n == name).map(|(_, value)| value.as_str())
+    }
+
+    fn keep_alive(&self) -> bool {
+        match self.header("connection").map(str::to_ascii_lowercase) {
+            Some(connection) if connection == "close" => false,
+            Some(connection) if connection == "keep-alive" => true,
+            _ => self.version == "HTTP/1.1",
+        }
+    }
+}
+
+/// Reads the next request, or `None` once the client is gone.
+///
+/// # Errors
+///
+/// It fails with `KvsError::InvalidData` on anything that is not a request it can handle,
+/// after which the stream cannot be read any further.
+fn read_request(reader: &mut impl BufRead) -> Result<Option<HttpRequest>> {
+    let mut head = Vec::new();
+    loop {
+        let len = head.len();
+        reader.take((MAX_HEAD_LEN - len) as u64).read_until(b'\n', &mut head)?;
+        match &head[len..] {
+            [] if len == 0 => return Ok(None),
+            // the line ends the head
+            b"\r\n" | b"\n" if len > 0 => break,
+            // empty lines before a request are skipped
+            b"\r\n" | b"\n" => head.clear(),
+            line if line.ends_with(b"\n") => {}
+            _ => return Err(bad_request("request head is too long or cut short")),
+        }
+    }
+    let head = String::from_utf8(head).map_err(|_| bad_request("request head is not UTF-8"))?;
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (method, target, version) =
+        match (request_line.next(), request_line.next(), request_line.next()) {
+            (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/1.") => {
+                (method, target, version)
+            }
+            _ => return Err(bad_request("invalid request line")),
+        };
+    let mut headers = Vec::new();
+    for line in lines.filter(|line| !line.is_empty()) {
+        let (name, value) = line.split_once(':').ok_or_else(|| bad_request("invalid header"))?;
+        headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+    }
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let query = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Ok((percent_decode(name)?, percent_decode(value)?))
+        })
+        .collect::<Result<_>>()?;
+    let mut request = HttpRequest {
+        method: method.to_string(),
+        path: percent_decode(path)?,
+        query,
+        version: version.to_string(),
+        headers,
+        body: Vec::new(),
+    };
+
+    if request.header("transfer-encoding").is_some() {
+        return Err(bad_request("chunked bodies are not supported, send a Content-Length"));
+    }
+    let len = match request.header("content-length") {
+        Some(len) => len.parse().map_err(|_| bad_request("invalid Content-Length"))?,
+        None => 0,
+    };
+    if len > MAX_BODY_LEN {
+        return Err(bad_request("body is too large"));
+    }
+    request.body =
+        read_bytes(reader, len as u64)?.ok_or_else(|| bad_request("body is cut short"))?;
+    Ok(Some(request))
+}
+
+fn percent_decode(text: &str) -> Result<String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        match byte {
+            b'%' => {
+                let hex = tail.get(..2).and_then(|hex| std::str::from_utf8(hex).ok());
+                let byte = hex.and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                bytes.push(byte.ok_or_else(|| bad_request("invalid percent-encoding"))?);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| bad_request("URL is not UTF-8"))
+}
+
+fn bad_request(message: &str) -> KvsError {
+    KvsError::InvalidData(message.to_string())
+}
+
+struct HttpResponse {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn empty(status: u16) -> HttpResponse {
+        HttpResponse { status, headers: Vec::new(), body: Vec::new() }
+    }
+
+    fn json(status: u16, body: &Value) -> HttpResponse {
+        let body = serde_json::to_vec(body).expect("JSON values serialize");
+        HttpResponse {
+            status,
+            headers: vec![("Content-Type", "application/json".to_string())],
+            body,
+        }
+    }
+
+    fn error(status: u16, error: &str, message: &str) -> HttpResponse {
+        HttpResponse::json(status, &json!({ "error": error, "message": message }))
+    }
+
+    fn with_header(mut self, name: &'static str, value: String) -> HttpResponse {
+        self.headers.push((name, value));
+        self
+    }
+
+    fn write_to(&self, writer: &mut impl Write, keep_alive: bool) -> Result<()> {
+        write!(writer, "HTTP/1.1 {} {}\r\n", self.status, reason(self.status))?;
+        for (name, value) in &self.headers {
+            write!(writer, "{}: {}\r\n", name, value)?;
+        }
+        // neither may have a body, nor say how long it is
+        if self.status != 204 && self.status != 304 {
+            write!(writer, "Content-Length: {}\r\n", self.body.len())?;
+        }
+        if !keep_alive {
+            writer.write_all(b"Connection: close\r\n")?;
+        }
+        writer.write_all(b"\r\n")?;
+        writer.write_all(&self.body)?;
+        Ok(())
+    }
+}
+
+impl From<&KvsError> for HttpResponse {
+    fn from(err: &KvsError) -> HttpResponse {
+        let (status, error) = match err {
+            KvsError::EmptyKey => (400, "EmptyKey"),
+            KvsError::InvalidData(_) => (400, "InvalidData"),
+            KvsError::IOError(_) => (500, "IOError"),
+            KvsError::Internal(_) => (500, "Internal"),
+            KvsError::Encryption(_) => (500, "Encryption"),
+            KvsError::Serde(_) => (500, "Serde"),
+            KvsError::Sled(_) => (500, "Sled"),
+        };
+        HttpResponse::error(status, error, &err.to_string())
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        412 => "Precondition Failed",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemStore;
+
+    use std::thread;
+
+    /// A client sending raw HTTP to a server on localhost, over one connection.
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+    }
+
+    /// A response, as the client read it.
+    struct Reply {
+        status: u16,
+        headers: Vec<(String, String)>,
+        body: Value,
+    }
+
+    impl Reply {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers.iter().find(|(n, _)| n == name).map(|(_, value)| value.as_str())
+        }
+    }
+
+    impl Client {
+        fn connect() -> Result<Client> {
+            let listener = TcpListener::bind("127.0.0.1:0")?;
+            let addr = listener.local_addr()?;
+            let server = Server::new(MemStore::open());
+            thread::spawn(move || server.serve_http(listener));
+            let writer = TcpStream::connect(addr)?;
+            Ok(Client { reader: BufReader::new(writer.try_clone()?), writer })
+        }
+
+        fn send(
+            &mut self,
+            method: &str,
+            target: &str,
+            headers: &[&str],
+            body: &str,
+        ) -> Result<Reply> {
+            let mut request = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n", method, target);
+            for header in headers {
+                request += &format!("{}\r\n", header);
+            }
+            request += &format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+            self.writer.write_all(request.as_bytes())?;
+            self.read_reply()
+        }
+
+        fn read_reply(&mut self) -> Result<Reply> {
+            let mut line = String::new();
+            self.reader.read_line(&mut line)?;
+            let status = line.split(' ').nth(1).unwrap().parse().unwrap();
+            let mut headers = Vec::new();
+            loop {
+                line.clear();
+                self.reader.read_line(&mut line)?;
+                match line.trim_end().split_once(": ") {
+                    Some((name, value)) => {
+                        headers.push((name.to_ascii_lowercase(), value.to_string()))
+                    }
+                    None => break,
+                }
+            }
+            let len = headers.iter().find(|(name, _)| name == "content-length");
+            let mut body = vec![0; len.map_or(0, |(_, len)| len.parse().unwrap())];
+            self.reader.read_exact(&mut body)?;
+            let body = if body.is_empty() { Value::Null } else { serde_json::from_slice(&body)? };
+            Ok(Reply { status, headers, body })
+        }
+    }
+
+    fn put_body(value: &[u8]) -> String {
+        json!({ "value": encode(value) }).to_string()
+    }
+
+    #[test]
+    fn keys() -> Result<()> {
+        let mut client = Client::connect()?;
+        let key = encode(&[0, 0xff, b'/']);
+        let path = format!("/kv/{}", key);
+        assert_eq!(client.send("GET", &path, &[], "")?.status, 404);
+
+        let reply = client.send("PUT", &path, &[], &put_body(b"value"))?;
+        assert_eq!(reply.status, 201);
+        let tag = reply.header("etag").unwrap().to_string();
+        assert_eq!(tag, "\"cd42404d52ad55ccfa9aca4adc828aa5800ad9d385a0671fbcbf724118320619\"");
+        assert_eq!(client.send("PUT", &path, &[], &put_body(b"value"))?.status, 204);
+
+        let reply = client.send("GET", &path, &[], "")?;
+        assert_eq!(reply.status, 200);
+        assert_eq!(reply.body, json!({ "key": key, "value": encode(b"value") }));
+        assert_eq!(reply.header("etag"), Some(tag.as_str()));
+        let if_none_match = format!("If-None-Match: {}", tag);
+        assert_eq!(client.send("GET", &path, &[&if_none_match], "")?.status, 304);
+
+        assert_eq!(client.send("DELETE", &path, &[], "")?.status, 204);
+        assert_eq!(client.send("DELETE", &path, &[], "")?.status, 404);
+        assert_eq!(client.send("POST", &path, &[], "")?.status, 405);
+        assert_eq!(client.send("GET", "/nope", &[], "")?.status, 404);
+        Ok(())
+    }
+
+    #[test]
+    fn compare_and_swap() -> Result<()> {
+        let mut client = Client::connect()?;
+        let create_only = "If-None-Match: *";
+        assert_eq!(client.send("PUT", "/kv/a2V5", &[create_only], &put_body(b"1"))?.status, 201);
+        assert_eq!(client.send("PUT", "/kv/a2V5", &[create_only], &put_body(b"2"))?.status, 412);
+
+        let if_match = format!("If-Match: {}", etag(b"1"));
+        assert_eq!(client.send("PUT", "/kv/a2V5", &[&if_match], &put_body(b"2"))?.status, 204);
+        // the tag is stale now
+        let reply = client.send("PUT", "/kv/a2V5", &[&if_match], &put_body(b"3"))?;
+        assert_eq!(reply.status, 412);
+        assert_eq!(reply.body["error"], "PreconditionFailed");
+        assert_eq!(client.send("DELETE", "/kv/a2V5", &[&if_match], "")?.status, 412);
+        assert_eq!(client.send("GET", "/kv/a2V5", &[], "")?.body["value"], encode(b"2"));
+        assert_eq!(client.send("DELETE", "/kv/a2V5", &["If-Match: *"], "")?.status, 204);
+        assert_eq!(client.send("PUT", "/kv/a2V5", &["If-Match: *"], &put_body(b"4"))?.status, 412);
+        Ok(())
+    }
+
+    #[test]
+    fn batch_and_scan() -> Result<()> {
+        let mut client = Client::connect()?;
+        let mut ops: Vec<Value> = (0..5)
+            .map(|i| json!({ "op": "set", "key": encode(format!("key{}", i).as_bytes()), "value": encode(b"v") }))
+            .collect();
+        ops.push(json!({ "op": "set", "key": encode(b"other"), "value": encode(b"v") }));
+        ops.push(json!({ "op": "remove", "key": encode(b"key4") }));
+        ops.push(json!({ "op": "get", "key": encode(b"key0") }));
+        ops.push(json!({ "op": "get", "key": encode(b"key4") }));
+        let reply = client.send("POST", "/batch", &[], &json!({ "ops": ops }).to_string())?;
+        assert_eq!(reply.status, 200);
+        let results = reply.body["results"].as_array().unwrap();
+        assert_eq!(results.len(), 9);
+        assert_eq!(results[7], json!({ "value": encode(b"v") }));
+        assert_eq!(results[8], json!({ "value": null }));
+
+        let mut keys = Vec::new();
+        let mut target = format!("/scan?prefix={}&limit=3", encode(b"key"));
+        loop {
+            let reply = client.send("GET", &target, &[], "")?;
+            for pair in reply.body["pairs"].as_array().unwrap() {
+                keys.push(decode(pair["key"].as_str().unwrap())?);
+            }
+            match reply.body["next"].as_str() {
+                Some(next) => {
+                    target = format!("/scan?prefix={}&limit=3&after={}", encode(b"key"), next)
+                }
+                None => break,
+            }
+        }
+        assert_eq!(keys, (0..4).map(|i| format!("key{}", i).into_bytes()).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn errors() -> Result<()> {
+        let mut client = Client::connect()?;
+        let reply = client.send("PUT", "/kv/", &[], &put_body(b"value"))?;
+        assert_eq!((reply.status, &reply.body["error"]), (400, &json!("EmptyKey")));
+        let reply = client.send("PUT", "/kv/a2V5", &[], "not json")?;
+        assert_eq!((reply.status, &reply.body["error"]), (400, &json!("InvalidData")));
+        let reply = client.send("GET", "/kv/!!", &[], "")?;
+        assert_eq!((reply.status, &reply.body["error"]), (400, &json!("InvalidData")));
+        let ops = json!({ "ops": [{ "op": "set", "key": encode(b"k"), "value": encode(b"v") }, { "op": "remove", "key": "" }] });
+        let reply = client.send("POST", "/batch", &[], &ops.to_string())?;
+        assert_eq!(reply.body["error"], "EmptyKey");
+        assert_eq!(client.send("GET", "/kv/aw", &[], "")?.status, 404);
+
+        client.writer.write_all(b"BROKEN\r\n\r\n")?;
+        let reply = client.read_reply()?;
+        assert_eq!((reply.status, reply.header("connection")), (400, Some("close")));
+        Ok(())
+    }
+}
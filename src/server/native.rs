@@ -0,0 +1,136 @@
+//! The server side of the native protocol, see [`crate::wire`].
+
+use super::{Server, State};
+use crate::result::{KvsError, Result};
+use crate::storage::{BatchStore, ScanStore, Store};
+use crate::wire::{self, Request, Response, TxnWrite};
+
+use std::collections::BTreeMap;
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+
+impl<S: Store + BatchStore + ScanStore + 'static> Server<S> {
+    /// Serves the [`RemoteStore`](crate::client::RemoteStore) clients connecting to
+    /// `listener`, each on its own thread, until accepting a connection fails.
+    pub fn serve_native(&self, listener: TcpListener) -> Result<()> {
+        self.accept(listener, Server::handle_native)
+    }
+
+    /// Answers the requests of a client, until it goes away or sends a broken frame.
+    fn handle_native(&self, stream: TcpStream) -> Result<()> {
+        stream.set_nodelay(true)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        while let Some(body) = wire::read_frame(&mut reader)? {
+            let request = Request::decode(&body);
+            let broken = request.is_err();
+            let response = request.and_then(|request| self.apply(request));
+            // a response too large to send is answered with the error instead
+            let frame = wire::response_frame(&response)
+                .or_else(|err| wire::response_frame(&Err(err.into())))?;
+            writer.write_all(&frame)?;
+            if broken {
+                writer.flush()?;
+                return Ok(());
+            }
+            // pipelined requests get their responses in one write
+            if reader.buffer().is_empty() {
+                writer.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs a request, reads under the shared lock and writes under the exclusive one.
+    fn apply(&self, request: Request) -> Result<Response> {
+        Ok(match request {
+            Request::Ping => Response::Done,
+            Request::Get(key) => Response::Value(self.state.read().get(&key)?),
+            Request::Set(key, value) => {
+                self.write().put(&key, value)?;
+                Response::Done
+            }
+            Request::Remove(key) => {
+                self.write().delete(&key)?;
+                Response::Done
+            }
+            Request::Contains(key) => Response::Bool(self.state.read().contains(&key)?),
+            Request::GetBatch(keys) => Response::Values(self.state.read().get_batch(&keys)?),
+            Request::SetBatch(keys, values) => {
+                let mut state = self.write();
+                state.store.set_batch(&keys, values)?;
+                for key in &keys {
+                    state.expiries.remove(key);
+                }
+                Response::Done
+            }
+            Request::RemoveBatch(keys) => {
+                let mut state = self.write();
+                state.store.remove_batch(&keys)?;
+                for key in &keys {
+                    state.expiries.remove(key);
+                }
+                Response::Done
+            }
+            Request::Scan(start, end, limit) => {
+                Response::Pairs(self.state.read().scan_range(start, end, limit)?)
+            }
+            Request::Transaction(checks, writes) => {
+                Response::Bool(self.write().transact(checks, writes)?)
+            }
+        })
+    }
+}
+
+impl<S: Store + BatchStore + ScanStore> State<S> {
+    /// Applies `writes` if every check holds, and returns whether it did.
+    ///
+    /// No other request runs in between the checks and the writes. The last write of each key
+    /// wins, and they are applied as a batch of sets, then a batch of removes, so that each
+    /// is as atomic as the batches of the store; only an error of the store in between the
+    /// two leaves the sets without the removes. Empty keys fail the whole transaction upfront.
+    fn transact(
+        &mut self,
+        checks: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        writes: Vec<TxnWrite>,
+    ) -> Result<bool> {
+        let written = writes.iter().map(|write| match write {
+            TxnWrite::Set(key, _) | TxnWrite::Remove(key) => key,
+        });
+        if checks.iter().map(|(key, _)| key).chain(written).any(|key| key.is_empty()) {
+            return Err(KvsError::EmptyKey);
+        }
+        for (key, expected) in checks {
+            if self.get(&key)? != expected {
+                return Ok(false);
+            }
+        }
+        let mut last = BTreeMap::new();
+        for write in writes {
+            match write {
+                TxnWrite::Set(key, value) => last.insert(key, Some(value)),
+                TxnWrite::Remove(key) => last.insert(key, None),
+            };
+        }
+        let (mut set_keys, mut values, mut removed) = (Vec::new(), Vec::new(), Vec::new());
+        for (key, value) in last {
+            match value {
+                Some(value) => {
+                    set_keys.push(key);
+                    values.push(value);
+                }
+                None => removed.push(key),
+            }
+        }
+        if !set_keys.is_empty() {
+            self.store.set_batch(&set_keys, values)?;
+        }
+        if !removed.is_empty() {
+            self.store.remove_batch(&removed)?;
+        }
+        for key in set_keys.iter().chain(&removed) {
+            self.expiries.remove(key);
+        }
+        Ok(true)
+    }
+}
@@ -0,0 +1,210 @@
+//! The Redis serialization protocol, RESP2 and RESP3, as far as a server needs it: commands
+//! come in as arrays of bulk strings, or inline as a line of words, and replies go out in
+//! the version a client asked for with `HELLO`.
+
+use crate::encoding::read_bytes;
+use crate::result::{KvsError, Result};
+
+use std::io::{BufRead, Read, Write};
+
+// the limits of Redis, so that a bad length cannot make the server allocate without bound
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+const MAX_ARGS: usize = 1024 * 1024;
+const MAX_INLINE_LEN: usize = 64 * 1024;
+
+/// The version of the protocol replies are written in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Protocol {
+    Resp2,
+    Resp3,
+}
+
+/// A reply to a command.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Null,
+    Array(Vec<Reply>),
+    /// A map in RESP3, and a flat array of keys and values in RESP2.
+    Map(Vec<(Reply, Reply)>),
+}
+
+impl From<KvsError> for Reply {
+    fn from(err: KvsError) -> Reply {
+        Reply::error(format!("ERR {}", err))
+    }
+}
+
+impl Reply {
+    pub(crate) fn ok() -> Reply {
+        Reply::Simple("OK".to_string())
+    }
+
+    pub(crate) fn error(message: impl Into<String>) -> Reply {
+        Reply::Error(message.into())
+    }
+
+    /// Writes the reply in the given version of the protocol.
+    pub(crate) fn write_to(&self, writer: &mut impl Write, protocol: Protocol) -> Result<()> {
+        match self {
+            Reply::Simple(line) => write!(writer, "+{}\r\n", line)?,
+            Reply::Error(message) => write!(writer, "-{}\r\n", message)?,
+            Reply::Integer(n) => write!(writer, ":{}\r\n", n)?,
+            Reply::Bulk(data) => {
+                write!(writer, "${}\r\n", data.len())?;
+                writer.write_all(data)?;
+                writer.write_all(b"\r\n")?;
+            }
+            Reply::Null => match protocol {
+                Protocol::Resp2 => writer.write_all(b"$-1\r\n")?,
+                Protocol::Resp3 => writer.write_all(b"_\r\n")?,
+            },
+            Reply::Array(items) => {
+                write!(writer, "*{}\r\n", items.len())?;
+                for item in items {
+                    item.write_to(writer, protocol)?;
+                }
+            }
+            Reply::Map(pairs) => {
+                match protocol {
+                    Protocol::Resp2 => write!(writer, "*{}\r\n", 2 * pairs.len())?,
+                    Protocol::Resp3 => write!(writer, "%{}\r\n", pairs.len())?,
+                }
+                for (key, value) in pairs {
+                    key.write_to(writer, protocol)?;
+                    value.write_to(writer, protocol)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads the next command, as its words, or `None` once the client is gone.
+///
+/// # Errors
+///
+/// It fails with `KvsError::InvalidData` on anything that is not a command, after which the
+/// stream cannot be read any further.
+pub(crate) fn read_command(reader: &mut impl BufRead) -> Result<Option<Vec<Vec<u8>>>> {
+    loop {
+        let line = match read_line(reader)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        if line.first() != Some(&b'*') {
+            let words: Vec<Vec<u8>> = line
+                .split(u8::is_ascii_whitespace)
+                .filter(|w| !w.is_empty())
+                .map(Vec::from)
+                .collect();
+            // an empty line is no command, and is skipped like Redis does
+            if !words.is_empty() {
+                return Ok(Some(words));
+            }
+            continue;
+        }
+        let count = parse_len(&line[1..], MAX_ARGS)?;
+        // an empty array is no command either, and is skipped like Redis does
+        if count == 0 {
+            continue;
+        }
+        let mut words = Vec::with_capacity(count.min(1024));
+        for _ in 0..count {
+            let line = read_line(reader)?.ok_or_else(truncated)?;
+            if line.first() != Some(&b'$') {
+                return Err(protocol_error("expected a bulk string"));
+            }
+            let len = parse_len(&line[1..], MAX_BULK_LEN)?;
+            let word = read_bytes(reader, len as u64)?.ok_or_else(truncated)?;
+            let mut end = [0; 2];
+            reader.read_exact(&mut end).map_err(|_| truncated())?;
+            if &end != b"\r\n" {
+                return Err(protocol_error("bulk string is not terminated"));
+            }
+            words.push(word);
+        }
+        return Ok(Some(words));
+    }
+}
+
+/// Reads a line, without its line ending.
+fn read_line(reader: &mut impl BufRead) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    reader.take(MAX_INLINE_LEN as u64 + 2).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(protocol_error("line is too long"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8], max: usize) -> Result<usize> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse().ok())
+        .filter(|&len| len <= max)
+        .ok_or_else(|| protocol_error("invalid length"))
+}
+
+fn protocol_error(message: &str) -> KvsError {
+    KvsError::InvalidData(format!("Protocol error: {}", message))
+}
+
+fn truncated() -> KvsError {
+    protocol_error("command is cut short")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(mut input: &[u8]) -> Result<Vec<Vec<Vec<u8>>>> {
+        let mut commands = Vec::new();
+        while let Some(command) = read_command(&mut input)? {
+            commands.push(command);
+        }
+        Ok(commands)
+    }
+
+    fn written(reply: Reply, protocol: Protocol) -> Vec<u8> {
+        let mut buf = Vec::new();
+        reply.write_to(&mut buf, protocol).unwrap();
+        buf
+    }
+
+    #[test]
+    fn commands() -> Result<()> {
+        let input = b"*2\r\n$3\r\nGET\r\n$4\r\nk\r\ny\r\n\r\nPING  hello\r\n*0\r\n*1\r\n$0\r\n\r\n";
+        let words = |words: &[&[u8]]| words.iter().map(|w| w.to_vec()).collect::<Vec<_>>();
+        assert_eq!(
+            read_all(input)?,
+            vec![words(&[b"GET", b"k\r\ny"]), words(&[b"PING", b"hello"]), words(&[b""])]
+        );
+
+        for bad in [&b"*1\r\n$3\r\nGE"[..], b"*1\r\n:3\r\n", b"*x\r\n", b"*1\r\n$3\r\nGETxx"] {
+            assert!(matches!(read_all(bad), Err(KvsError::InvalidData(_))), "{:?}", bad);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn replies() {
+        let reply = Reply::Array(vec![Reply::Bulk(b"v".to_vec()), Reply::Null, Reply::Integer(3)]);
+        assert_eq!(written(reply.clone(), Protocol::Resp2), b"*3\r\n$1\r\nv\r\n$-1\r\n:3\r\n");
+        assert_eq!(written(reply, Protocol::Resp3), b"*3\r\n$1\r\nv\r\n_\r\n:3\r\n");
+
+        let map = Reply::Map(vec![(Reply::Simple("a".to_string()), Reply::Integer(1))]);
+        assert_eq!(written(map.clone(), Protocol::Resp2), b"*2\r\n+a\r\n:1\r\n");
+        assert_eq!(written(map, Protocol::Resp3), b"%1\r\n+a\r\n:1\r\n");
+        assert_eq!(written(Reply::error("ERR no"), Protocol::Resp2), b"-ERR no\r\n");
+    }
+}
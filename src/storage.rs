@@ -1,14 +1,39 @@
+mod bloom;
+mod cache;
+mod compress;
 mod disk;
+mod lsm;
 mod memory;
+mod pool;
+mod pooled;
+#[cfg(test)]
+mod sim;
 mod sled;
+mod usage;
+mod vfs;
+mod wal;
 
 pub use self::sled::SledStore;
-pub use disk::DiskStore;
-pub use memory::MemStore;
-
-use crate::result::Result;
+pub use bloom::BloomStats;
+pub use cache::{CacheStats, ValueCache};
+pub use compress::Compression;
+pub use disk::{DiskStore, DiskStoreOptions, DiskValue, EncryptionKey, SyncMode};
+pub use lsm::{LsmStore, LsmStoreOptions};
+pub use memory::{
+    BoundedMemStore, BoundedMemStoreOptions, Capacity, EvictionListener, EvictionPolicy,
+    EvictionReason, MemStore, MemStoreOptions, OrderedMemStore, ShardedMemStore,
+};
+pub use pool::ThreadPool;
+pub use pooled::PooledStore;
+pub use usage::MemoryUsage;
+
+use crate::result::{KvsError, Result};
 
 use std::fmt::Display;
+use std::fs;
+use std::future::{self, Future};
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
 
 /// A key/value store trait for basic ops.
 pub trait Store: Display + Send + Sync {
@@ -41,102 +66,181 @@ pub trait BatchStore: Display + Send + Sync {
     fn remove_batch(&mut self, keys: impl AsRef<[Vec<u8>]>) -> Result<()>;
 }
 
-#[cfg(test)]
-trait TestSuite<S: Store> {
-    fn setup() -> Result<S>;
-
-    fn test() -> Result<()> {
-        Self::test_remove()?;
-        Self::test_get()?;
-        Self::test_set()?;
-        Self::test_contains()?;
-        Ok(())
+/// A key/value store trait for ordered scans.
+pub trait ScanStore: Display + Send + Sync {
+    /// Gets all key/value pairs whose keys fall within `range`, in ascending key order.
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    /// Gets the first `limit` key/value pairs whose keys fall within `range`, in ascending
+    /// key order.
+    ///
+    /// By default it scans the whole range; stores keeping their keys in order stop early.
+    fn scan_limit(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut pairs = self.scan(range)?;
+        pairs.truncate(limit);
+        Ok(pairs)
     }
 
-    fn test_get() -> Result<()> {
-        let mut s = Self::setup()?;
-        s.set(b"a", vec![0x01])?;
-        assert_eq!(Some(vec![0x01]), s.get(b"a")?);
-        assert_eq!(None, s.get(b"b")?);
-        Ok(())
+    /// Gets all key/value pairs whose keys start with `prefix`, in ascending key order.
+    fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let prefix = prefix.as_ref().to_owned();
+        let end = match prefix_successor(&prefix) {
+            Some(end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        };
+        self.scan((Bound::Included(prefix), end))
     }
+}
 
-    fn test_remove() -> Result<()> {
-        let mut s = Self::setup()?;
-        s.set(b"a", vec![0x01])?;
-        assert_eq!(Some(vec![0x01]), s.get(b"a")?);
-        s.remove(b"a")?;
-        assert_eq!(None, s.get(b"a")?);
-        s.remove(b"b")?;
-        Ok(())
-    }
+/// An async key/value store trait for basic ops, mirroring `Store`.
+///
+/// An operation starts when it is called, not when its future is first polled, and runs to
+/// the end even if the future is dropped. Any blocking `Store` can be made one with
+/// [`PooledStore`].
+pub trait AsyncStore: Display + Send + Sync {
+    /// Gets a value for a key, if it exists.
+    fn get(&self, key: impl AsRef<[u8]>) -> impl Future<Output = Result<Option<Vec<u8>>>> + Send;
 
-    fn test_set() -> Result<()> {
-        let mut s = Self::setup()?;
-        s.set(b"a", vec![0x01])?;
-        assert_eq!(Some(vec![0x01]), s.get(b"a")?);
-        s.set(b"a", vec![0x02])?;
-        assert_eq!(Some(vec![0x02]), s.get(b"a")?);
-        Ok(())
-    }
+    /// Sets a value for a key, replacing the existing value if any.
+    fn set(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+    ) -> impl Future<Output = Result<()>> + Send;
 
-    fn test_contains() -> Result<()> {
-        let mut s = Self::setup()?;
-        s.set(b"a", vec![0x01])?;
-        assert_eq!(true, s.contains(b"a")?);
-        assert_eq!(false, s.contains(b"b")?);
-        Ok(())
-    }
+    /// Removes a key, or does nothing if it does not exist.
+    fn remove(&mut self, key: impl AsRef<[u8]>) -> impl Future<Output = Result<()>> + Send;
+
+    /// Returns `true` if the store contains a value for the specified key.
+    fn contains(&mut self, key: impl AsRef<[u8]>) -> impl Future<Output = Result<bool>> + Send;
 }
 
-#[cfg(test)]
-trait TestBatchSuite<B: Store + BatchStore> {
-    fn setup() -> Result<B>;
-
-    fn test() -> Result<()> {
-        Self::test_get_batch()?;
-        Self::test_set_batch()?;
-        Self::test_remove_batch()?;
-        Ok(())
-    }
+/// An async key/value store trait for batch ops, mirroring `BatchStore`.
+pub trait AsyncBatchStore: Display + Send + Sync {
+    /// Gets values for keys, if them exist.
+    fn get_batch(
+        &self,
+        keys: impl AsRef<[Vec<u8>]>,
+    ) -> impl Future<Output = Result<Vec<Option<Vec<u8>>>>> + Send;
 
-    fn test_get_batch() -> Result<()> {
-        let mut s = Self::setup()?;
-        let data1 = b"test1".to_vec();
-        let data2 = b"test2".to_vec();
-        s.set(data1.clone(), data1.clone()).unwrap();
-        s.set(data2.clone(), data2.clone()).unwrap();
-        assert_eq!(s.get(data1.clone())?, Some(data1.clone()));
-        assert_eq!(s.get(data2.clone())?, Some(data2.clone()));
-        assert_eq!(
-            s.get_batch(vec![data1.clone(), data2.clone()])?,
-            vec![Some(data1), Some(data2)]
-        );
-        Ok(())
-    }
+    /// Sets values for keys, replacing the existing values if any.
+    fn set_batch(
+        &mut self,
+        keys: impl AsRef<[Vec<u8>]>,
+        values: impl AsRef<[Vec<u8>]>,
+    ) -> impl Future<Output = Result<()>> + Send;
 
-    fn test_set_batch() -> Result<()> {
-        let mut s = Self::setup()?;
-        let data1 = b"test1".to_vec();
-        let data2 = b"test2".to_vec();
-        s.set_batch(vec![data1.clone(), data2.clone()], vec![data1.clone(), data2.clone()])
-            .unwrap();
-        assert_eq!(s.get(data1.clone())?, Some(data1.clone()));
-        assert_eq!(s.get(data2.clone())?, Some(data2.clone()));
-        Ok(())
+    /// Removes keys, or does nothing if them do not exist.
+    fn remove_batch(
+        &mut self,
+        keys: impl AsRef<[Vec<u8>]>,
+    ) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// Implements the async traits for an in-memory store, whose operations never wait on I/O,
+/// by running them right away and returning a ready future.
+macro_rules! impl_async_in_memory {
+    ($store:ty) => {
+        impl AsyncStore for $store {
+            fn get(
+                &self,
+                key: impl AsRef<[u8]>,
+            ) -> impl Future<Output = Result<Option<Vec<u8>>>> + Send {
+                future::ready(Store::get(self, key))
+            }
+
+            fn set(
+                &mut self,
+                key: impl AsRef<[u8]>,
+                value: impl AsRef<[u8]>,
+            ) -> impl Future<Output = Result<()>> + Send {
+                future::ready(Store::set(self, key, value))
+            }
+
+            fn remove(&mut self, key: impl AsRef<[u8]>) -> impl Future<Output = Result<()>> + Send {
+                future::ready(Store::remove(self, key))
+            }
+
+            fn contains(
+                &mut self,
+                key: impl AsRef<[u8]>,
+            ) -> impl Future<Output = Result<bool>> + Send {
+                future::ready(Store::contains(self, key))
+            }
+        }
+
+        impl AsyncBatchStore for $store {
+            fn get_batch(
+                &self,
+                keys: impl AsRef<[Vec<u8>]>,
+            ) -> impl Future<Output = Result<Vec<Option<Vec<u8>>>>> + Send {
+                future::ready(BatchStore::get_batch(self, keys))
+            }
+
+            fn set_batch(
+                &mut self,
+                keys: impl AsRef<[Vec<u8>]>,
+                values: impl AsRef<[Vec<u8>]>,
+            ) -> impl Future<Output = Result<()>> + Send {
+                future::ready(BatchStore::set_batch(self, keys, values))
+            }
+
+            fn remove_batch(
+                &mut self,
+                keys: impl AsRef<[Vec<u8>]>,
+            ) -> impl Future<Output = Result<()>> + Send {
+                future::ready(BatchStore::remove_batch(self, keys))
+            }
+        }
+    };
+}
+
+// `MemStore` is left out, since a persistent one writes to its log
+impl_async_in_memory!(BoundedMemStore);
+impl_async_in_memory!(OrderedMemStore);
+impl_async_in_memory!(ShardedMemStore);
+
+/// A key/value store trait for online backups.
+pub trait CheckpointStore: Display + Send + Sync {
+    /// Writes a consistent copy of the store to the directory `dest`, which must be empty or
+    /// not exist yet, while other threads keep reading and writing.
+    ///
+    /// The copy holds every write that finished before the call, and no write that started
+    /// after it; it opens like any store of the same kind.
+    fn checkpoint(&self, dest: impl AsRef<Path>) -> Result<()>;
+}
+
+/// A key/value store trait for reporting memory usage.
+pub trait MemoryUsageStore: Display + Send + Sync {
+    /// Estimates the heap memory the store holds.
+    ///
+    /// This walks the whole index, so it takes time in proportion to the number of keys, and
+    /// is meant to be called now and then, e.g. to alert before running out of memory.
+    fn memory_usage(&self) -> MemoryUsage;
+}
+
+/// Returns the smallest key greater than every key starting with `prefix`,
+/// or `None` if there is no such key (e.g. the prefix is empty or all `0xff`).
+pub(crate) fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_owned();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
     }
+    None
+}
 
-    fn test_remove_batch() -> Result<()> {
-        let mut s = Self::setup()?;
-        let data1 = b"test1".to_vec();
-        let data2 = b"test2".to_vec();
-        s.set(data1.clone(), data1.clone()).unwrap();
-        s.set(data2.clone(), data2.clone()).unwrap();
-        assert_eq!(s.get(data1.clone())?, Some(data1.clone()));
-        assert_eq!(s.get(data2.clone())?, Some(data2.clone()));
-        s.remove_batch(&[data1.clone(), data2.clone()]).unwrap();
-        assert_eq!(s.get(data1)?, None);
-        assert_eq!(s.get(data2)?, None);
-        Ok(())
+/// Creates the directory of a checkpoint, which must be empty if it exists already.
+fn create_checkpoint_dir(dest: &Path) -> Result<()> {
+    fs::create_dir_all(dest)?;
+    if fs::read_dir(dest)?.next().is_some() {
+        return Err(KvsError::InvalidData(format!("{} is not empty", dest.display())));
     }
+    Ok(())
 }
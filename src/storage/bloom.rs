@@ -0,0 +1,170 @@
+//! Bloom filters, which tell that a key is missing from a sorted file without reading it.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A set of keys that may answer "maybe" for a key not in it, but never "no" for one in it.
+#[derive(Debug)]
+pub(crate) struct BloomFilter {
+    bits: Vec<u8>,
+    // the number of bits set per key
+    hashes: u8,
+}
+
+impl BloomFilter {
+    /// Builds a filter with about `bits_per_key` bits for each of the keys, given by their
+    /// `hash`. With 10 bits per key, about 1% of missing keys get through.
+    ///
+    /// A filter with no bits per key is empty and lets every key through.
+    pub(crate) fn build(keys: &[u64], bits_per_key: usize) -> Self {
+        if bits_per_key == 0 {
+            return BloomFilter { bits: Vec::new(), hashes: 0 };
+        }
+        // ln(2) bits per key is the optimal number of hashes
+        let hashes = (bits_per_key * 69 / 100).clamp(1, 30) as u8;
+        let len = (keys.len() * bits_per_key).max(64).div_ceil(8);
+        let mut filter = BloomFilter { bits: vec![0; len], hashes };
+        for &key in keys {
+            for bit in filter.bit_positions(key) {
+                filter.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        filter
+    }
+
+    /// Returns `false` if the key is certainly not in the filter.
+    pub(crate) fn may_contain(&self, key: &[u8]) -> bool {
+        self.bits.is_empty()
+            || self.bit_positions(hash(key)).all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    /// The bits to set for a key, derived from two halves of its hash.
+    fn bit_positions(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let len = self.bits.len() as u64 * 8;
+        let (h1, h2) = (hash & 0xffff_ffff, hash >> 32);
+        (0..u64::from(self.hashes))
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+
+    /// The number of bytes of the filter in memory.
+    pub(crate) fn size(&self) -> usize {
+        self.bits.capacity()
+    }
+
+    /// Appends the filter to `buf`.
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.bits);
+        if !self.bits.is_empty() {
+            buf.push(self.hashes);
+        }
+    }
+
+    /// Reads a filter written by `encode`.
+    pub(crate) fn decode(buf: &[u8]) -> Self {
+        match buf.split_last() {
+            Some((&hashes, bits)) if !bits.is_empty() => {
+                BloomFilter { bits: bits.to_owned(), hashes }
+            }
+            _ => BloomFilter { bits: Vec::new(), hashes: 0 },
+        }
+    }
+}
+
+/// The hash a filter is built from.
+pub(crate) fn hash(key: &[u8]) -> u64 {
+    seahash::hash(key)
+}
+
+/// How well bloom filters saved reads of missing keys.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BloomStats {
+    /// Lookups a filter answered without a read.
+    pub negatives: u64,
+    /// Lookups a filter let through, of a key that was not there.
+    pub false_positives: u64,
+    /// Lookups a filter let through, of a key that was there.
+    pub true_positives: u64,
+}
+
+impl BloomStats {
+    /// The share of lookups of missing keys that a filter let through.
+    pub fn false_positive_rate(&self) -> f64 {
+        let missing = self.negatives + self.false_positives;
+        if missing == 0 {
+            return 0.0;
+        }
+        self.false_positives as f64 / missing as f64
+    }
+}
+
+/// Counts the outcomes of filter lookups, from any thread.
+#[derive(Debug, Default)]
+pub(crate) struct BloomCounters {
+    negatives: AtomicU64,
+    false_positives: AtomicU64,
+    true_positives: AtomicU64,
+}
+
+impl BloomCounters {
+    pub(crate) fn negative(&self) {
+        self.negatives.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a lookup the filter let through, and whether the key was found.
+    pub(crate) fn positive(&self, found: bool) {
+        let counter = if found { &self.true_positives } else { &self.false_positives };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self) -> BloomStats {
+        BloomStats {
+            negatives: self.negatives.load(Ordering::Relaxed),
+            false_positives: self.false_positives.load(Ordering::Relaxed),
+            true_positives: self.true_positives.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(range: std::ops::Range<u32>) -> Vec<Vec<u8>> {
+        range.map(|i| format!("key{}", i).into_bytes()).collect()
+    }
+
+    fn build(keys: &[Vec<u8>], bits_per_key: usize) -> BloomFilter {
+        let hashes: Vec<u64> = keys.iter().map(|key| hash(key)).collect();
+        BloomFilter::build(&hashes, bits_per_key)
+    }
+
+    #[test]
+    fn no_false_negatives() {
+        let keys = keys(0..10_000);
+        let filter = build(&keys, 10);
+        assert!(keys.iter().all(|key| filter.may_contain(key)));
+    }
+
+    #[test]
+    fn false_positive_rate() {
+        let filter = build(&keys(0..10_000), 10);
+        let false_positives =
+            keys(10_000..20_000).iter().filter(|key| filter.may_contain(key)).count();
+        assert!(false_positives < 200, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn encode_decode() {
+        let keys = keys(0..100);
+        let filter = build(&keys, 10);
+        let mut buf = Vec::new();
+        filter.encode(&mut buf);
+        let decoded = BloomFilter::decode(&buf);
+        assert_eq!((decoded.bits, decoded.hashes), (filter.bits, filter.hashes));
+
+        let empty = build(&keys, 0);
+        buf.clear();
+        empty.encode(&mut buf);
+        assert!(buf.is_empty());
+        assert!(BloomFilter::decode(&buf).may_contain(b"anything"));
+    }
+}
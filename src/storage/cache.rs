@@ -0,0 +1,303 @@
+//! A cache of values read from disk, bounded by a budget of bytes.
+
+use parking_lot::Mutex;
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+const SHARDS: usize = 16;
+
+/// A value in the cache, by the store it belongs to, its generation and its position.
+pub(crate) type CacheKey = (u64, u64, u64);
+
+/// A least-recently-used cache of values, shared by all its clones.
+///
+/// The cache is split into shards, each with its own lock, so that concurrent readers rarely
+/// wait on each other. Values are admitted against the budget of the whole cache, evicting the
+/// least recently used values of their own shard first, then those of the others, so any value
+/// up to the budget is cached. Concurrent inserts may go over the budget for as long as they
+/// take to make room. A single cache can be handed to several stores, which then share its
+/// budget.
+#[derive(Clone)]
+pub struct ValueCache {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    shards: Vec<Mutex<Shard>>,
+    capacity: usize,
+    // the bytes of values in all the shards
+    size: AtomicUsize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    // the id of the next store to use the cache
+    next_owner: AtomicU64,
+}
+
+#[derive(Default)]
+struct Shard {
+    size: usize,
+    // map a key to its value and its last use
+    entries: HashMap<CacheKey, (Arc<[u8]>, u64)>,
+    // keys by last use, least recent first
+    lru: BTreeMap<u64, CacheKey>,
+    tick: u64,
+}
+
+/// Counters of a `ValueCache`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Lookups that found their value.
+    pub hits: u64,
+    /// Lookups that had to read their value from disk.
+    pub misses: u64,
+    /// The number of values in the cache.
+    pub entries: u64,
+    /// The number of bytes of values in the cache.
+    pub size: u64,
+}
+
+impl ValueCache {
+    /// Creates a cache holding up to `capacity` bytes of values.
+    pub fn new(capacity: usize) -> Self {
+        ValueCache::with_shards(capacity, SHARDS)
+    }
+
+    fn with_shards(capacity: usize, shards: usize) -> Self {
+        let shards = (0..shards).map(|_| Mutex::new(Shard::default())).collect();
+        ValueCache {
+            inner: Arc::new(Inner {
+                shards,
+                capacity,
+                size: AtomicUsize::new(0),
+                hits: AtomicU64::new(0),
+                misses: AtomicU64::new(0),
+                next_owner: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Returns the counters of the cache.
+    pub fn stats(&self) -> CacheStats {
+        let mut stats = CacheStats {
+            hits: self.inner.hits.load(Ordering::Relaxed),
+            misses: self.inner.misses.load(Ordering::Relaxed),
+            ..CacheStats::default()
+        };
+        for shard in &self.inner.shards {
+            let shard = shard.lock();
+            stats.entries += shard.entries.len() as u64;
+            stats.size += shard.size as u64;
+        }
+        stats
+    }
+
+    /// Returns a new id for a store to key its values with.
+    pub(crate) fn register(&self) -> u64 {
+        self.inner.next_owner.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub(crate) fn get(&self, key: &CacheKey) -> Option<Arc<[u8]>> {
+        let value = self.shard(key).lock().get(key);
+        let counter = if value.is_some() { &self.inner.hits } else { &self.inner.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    /// Adds a value, evicting the least recently used ones to make room for it.
+    pub(crate) fn insert(&self, key: CacheKey, value: Arc<[u8]>) {
+        let len = value.len();
+        if len > self.inner.capacity {
+            return;
+        }
+        let own = self.shard(&key);
+        {
+            let mut shard = own.lock();
+            self.freed(shard.remove(&key));
+            self.make_room(&mut shard, len);
+            shard.insert(key, value);
+            self.inner.size.fetch_add(len, Ordering::Relaxed);
+        }
+        // the shard of the value ran out of others to evict, so the rest of the room is made in
+        // the other shards, locked one at a time
+        for shard in self.inner.shards.iter().filter(|&shard| !std::ptr::eq(shard, own)) {
+            if self.inner.size.load(Ordering::Relaxed) <= self.inner.capacity {
+                break;
+            }
+            self.make_room(&mut shard.lock(), 0);
+        }
+    }
+
+    /// Evicts values of a shard until `len` more bytes fit the budget, or it has none left.
+    fn make_room(&self, shard: &mut Shard, len: usize) {
+        while self.inner.size.load(Ordering::Relaxed) + len > self.inner.capacity {
+            match shard.evict() {
+                Some(evicted) => self.freed(evicted),
+                None => return,
+            }
+        }
+    }
+
+    fn freed(&self, len: usize) {
+        self.inner.size.fetch_sub(len, Ordering::Relaxed);
+    }
+
+    /// Removes the values of a generation of a store.
+    pub(crate) fn remove_gen(&self, owner: u64, gen: u64) {
+        for shard in &self.inner.shards {
+            let removed = shard
+                .lock()
+                .retain(|&(key_owner, key_gen, _)| (key_owner, key_gen) != (owner, gen));
+            self.freed(removed);
+        }
+    }
+
+    /// The number of bytes of values of a store in the cache.
+    pub(crate) fn owner_size(&self, owner: u64) -> u64 {
+        let mut size = 0;
+        for shard in &self.inner.shards {
+            let shard = shard.lock();
+            for ((key_owner, _, _), (value, _)) in &shard.entries {
+                if *key_owner == owner {
+                    size += value.len() as u64;
+                }
+            }
+        }
+        size
+    }
+
+    fn shard(&self, key: &CacheKey) -> &Mutex<Shard> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.inner.shards[hasher.finish() as usize % self.inner.shards.len()]
+    }
+}
+
+impl std::fmt::Debug for ValueCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ValueCache").field(&self.stats()).finish()
+    }
+}
+
+impl Shard {
+    fn get(&mut self, key: &CacheKey) -> Option<Arc<[u8]>> {
+        let tick = self.next_tick();
+        let (value, last_use) = self.entries.get_mut(key)?;
+        self.lru.remove(last_use);
+        self.lru.insert(tick, *key);
+        *last_use = tick;
+        Some(Arc::clone(value))
+    }
+
+    /// Adds a value whose key is not in the shard, room for it being made by the caller.
+    fn insert(&mut self, key: CacheKey, value: Arc<[u8]>) {
+        let tick = self.next_tick();
+        self.size += value.len();
+        self.lru.insert(tick, key);
+        self.entries.insert(key, (value, tick));
+    }
+
+    /// Evicts the least recently used value, returning its size, or `None` if there is none.
+    fn evict(&mut self) -> Option<usize> {
+        let (_, oldest) = self.lru.pop_first()?;
+        let (evicted, _) = self.entries.remove(&oldest).expect("Cannot find cache entry");
+        self.size -= evicted.len();
+        Some(evicted.len())
+    }
+
+    /// Removes a value, returning its size, or zero if it is not in the shard.
+    fn remove(&mut self, key: &CacheKey) -> usize {
+        match self.entries.remove(key) {
+            Some((value, last_use)) => {
+                self.lru.remove(&last_use);
+                self.size -= value.len();
+                value.len()
+            }
+            None => 0,
+        }
+    }
+
+    /// Removes the values whose keys `keep` rejects, returning their size.
+    fn retain(&mut self, mut keep: impl FnMut(&CacheKey) -> bool) -> usize {
+        let removed: Vec<CacheKey> =
+            self.entries.keys().filter(|key| !keep(key)).cloned().collect();
+        removed.iter().map(|key| self.remove(key)).sum()
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(len: usize) -> Arc<[u8]> {
+        vec![0; len].into()
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = ValueCache::with_shards(300, 1);
+        cache.insert((0, 1, 0), value(100));
+        cache.insert((0, 1, 1), value(100));
+        cache.insert((0, 1, 2), value(100));
+        assert!(cache.get(&(0, 1, 0)).is_some());
+        cache.insert((0, 1, 3), value(100));
+        assert!(cache.get(&(0, 1, 1)).is_none());
+        assert!(cache.get(&(0, 1, 0)).is_some());
+        assert_eq!(cache.stats().size, 300);
+
+        // too large to cache at all
+        cache.insert((0, 1, 4), value(301));
+        assert!(cache.get(&(0, 1, 4)).is_none());
+        assert_eq!(cache.stats().entries, 3);
+    }
+
+    #[test]
+    fn values_larger_than_a_shard() {
+        let cache = ValueCache::new(16 * 1024);
+        for pos in 0..1000 {
+            cache.insert((0, 1, pos), value(100));
+        }
+        // more than the 1 KiB share of a shard, which takes evicting values of the others
+        cache.insert((0, 2, 0), value(12 * 1024));
+        assert!(cache.get(&(0, 2, 0)).is_some());
+        cache.insert((0, 2, 1), value(16 * 1024));
+        assert!(cache.get(&(0, 2, 1)).is_some());
+        assert_eq!(cache.stats().size, 16 * 1024);
+        assert_eq!(cache.stats().entries, 1);
+    }
+
+    #[test]
+    fn stays_within_budget() {
+        let cache = ValueCache::new(16 * 1024);
+        for pos in 0..1000 {
+            cache.insert((0, 1, pos), value(100));
+        }
+        let stats = cache.stats();
+        assert!(stats.size <= 16 * 1024, "{:?}", stats);
+        assert!(stats.entries > 100, "{:?}", stats);
+    }
+
+    #[test]
+    fn remove_gen() {
+        let cache = ValueCache::new(1024 * 1024);
+        let (a, b) = (cache.register(), cache.register());
+        for pos in 0..100 {
+            cache.insert((a, 1, pos), value(10));
+            cache.insert((a, 2, pos), value(10));
+            cache.insert((b, 1, pos), value(10));
+        }
+        cache.remove_gen(a, 1);
+        assert!(cache.get(&(a, 1, 0)).is_none());
+        assert!(cache.get(&(a, 2, 0)).is_some());
+        assert!(cache.get(&(b, 1, 0)).is_some());
+        assert_eq!(cache.stats(), CacheStats { hits: 2, misses: 1, entries: 200, size: 2000 });
+    }
+}
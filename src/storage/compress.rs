@@ -0,0 +1,295 @@
+//! Compression of the values written by a store.
+//!
+//! Compressed values are written in base64, as the log of a `DiskStore` is JSON. Without the
+//! `compression` feature, values are never compressed, and reading a compressed one fails.
+
+use crate::result::{KvsError, Result};
+
+#[cfg(feature = "compression")]
+use base64::engine::general_purpose::STANDARD as BASE64;
+#[cfg(feature = "compression")]
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "compression")]
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
+
+#[cfg(feature = "compression")]
+use std::collections::HashMap;
+#[cfg(feature = "compression")]
+use std::io::Read;
+#[cfg(feature = "compression")]
+use std::sync::Arc;
+
+/// How a store compresses the values it writes.
+///
+/// Every compressed value records its codec, so a store can change its compression between
+/// opens and still read the values written before. The codecs need the `compression`
+/// feature, on by default.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// Values are written as they are.
+    #[default]
+    None,
+    /// LZ4, which is fast but compresses less.
+    #[cfg(feature = "compression")]
+    Lz4,
+    /// Zstandard at the given level, from 1 to 22, which is slower but compresses more.
+    #[cfg(feature = "compression")]
+    Zstd(i32),
+    /// Zstandard with a dictionary trained on sample values, see
+    /// [`Compression::zstd_dictionary`].
+    ///
+    /// Small values have too little in them to compress well on their own; a dictionary of
+    /// what they have in common, such as the field names of JSON documents, makes up for it.
+    #[cfg(feature = "compression")]
+    ZstdDictionary {
+        /// The compression level, from 1 to 22.
+        level: i32,
+        /// The trained dictionary.
+        dictionary: Arc<[u8]>,
+    },
+}
+
+impl Compression {
+    /// The dictionary values are compressed with, if any.
+    pub(crate) fn dictionary(&self) -> Option<&[u8]> {
+        #[cfg(feature = "compression")]
+        if let Compression::ZstdDictionary { dictionary, .. } = self {
+            return Some(dictionary);
+        }
+        None
+    }
+
+    /// Trains a Zstandard dictionary of up to `max_size` bytes on sample values.
+    ///
+    /// # Errors
+    ///
+    /// It fails if there are too few samples to learn from, a hundred or so at least.
+    #[cfg(feature = "compression")]
+    pub fn zstd_dictionary(
+        level: i32,
+        samples: &[impl AsRef<[u8]>],
+        max_size: usize,
+    ) -> Result<Compression> {
+        let dictionary = zstd::dict::from_samples(samples, max_size)?;
+        Ok(Compression::ZstdDictionary { level, dictionary: dictionary.into() })
+    }
+}
+
+/// The codec a value was compressed with, recorded next to it.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Codec {
+    Lz4,
+    Zstd,
+    // with the dictionary of the given id
+    ZstdDictionary(u64),
+}
+
+/// Compresses values with the compression of a store.
+#[cfg(feature = "compression")]
+pub(crate) struct Compressor {
+    compression: Compression,
+    // values smaller than this are left as they are
+    threshold: usize,
+    dictionary: Option<(u64, EncoderDictionary<'static>)>,
+}
+
+#[cfg(feature = "compression")]
+impl Compressor {
+    pub(crate) fn new(compression: Compression, threshold: usize) -> Self {
+        let dictionary = match &compression {
+            Compression::ZstdDictionary { level, dictionary } => {
+                Some((dictionary_id(dictionary), EncoderDictionary::copy(dictionary, *level)))
+            }
+            _ => None,
+        };
+        Compressor { compression, threshold, dictionary }
+    }
+
+    /// Compresses a value into base64, or returns `None` if it is below the threshold or
+    /// would not shrink.
+    ///
+    /// The log is JSON, so a compressed value shrinks only if its base64, and its codec next
+    /// to it, are shorter than the value.
+    pub(crate) fn compress(&self, value: &[u8]) -> Result<Option<(Codec, String)>> {
+        if value.len() < self.threshold {
+            return Ok(None);
+        }
+        let compressed = match (&self.compression, &self.dictionary) {
+            (Compression::None, _) => return Ok(None),
+            (Compression::Lz4, _) => (Codec::Lz4, lz4_flex::compress_prepend_size(value)),
+            (Compression::Zstd(level), _) => (Codec::Zstd, zstd::bulk::compress(value, *level)?),
+            (Compression::ZstdDictionary { .. }, Some((id, dictionary))) => {
+                let mut compressor = zstd::bulk::Compressor::with_prepared_dictionary(dictionary)?;
+                (Codec::ZstdDictionary(*id), compressor.compress(value)?)
+            }
+            (Compression::ZstdDictionary { .. }, None) => unreachable!("dictionary is prepared"),
+        };
+        let (codec, text) = (compressed.0, BASE64.encode(compressed.1));
+        if logged_len(codec, &text) >= value.len() {
+            return Ok(None);
+        }
+        Ok(Some((codec, text)))
+    }
+}
+
+/// The bytes a compressed value takes in a command of the log: its base64, and a `"codec"`
+/// field that an uncompressed value does not have.
+#[cfg(feature = "compression")]
+fn logged_len(codec: Codec, text: &str) -> usize {
+    let codec = serde_json::to_string(&codec).map_or(0, |codec| codec.len());
+    text.len() + r#","codec":"#.len() + codec
+}
+
+/// Decompresses values, with any of the dictionaries they may have been compressed with.
+#[cfg(feature = "compression")]
+#[derive(Default)]
+pub(crate) struct Decompressor {
+    dictionaries: HashMap<u64, DecoderDictionary<'static>>,
+}
+
+#[cfg(feature = "compression")]
+impl Decompressor {
+    pub(crate) fn add_dictionary(&mut self, dictionary: &[u8]) {
+        self.dictionaries.insert(dictionary_id(dictionary), DecoderDictionary::copy(dictionary));
+    }
+
+    /// Decompresses a value written in base64 by `Compressor::compress`.
+    pub(crate) fn decompress(&self, codec: Codec, text: &str) -> Result<Vec<u8>> {
+        let data = BASE64
+            .decode(text)
+            .map_err(|_| KvsError::InvalidData("compressed value is not base64".to_string()))?;
+        let data = &data[..];
+        match codec {
+            Codec::Lz4 => lz4_flex::decompress_size_prepended(data)
+                .map_err(|err| KvsError::InvalidData(format!("corrupt value: {}", err))),
+            Codec::Zstd => Ok(zstd::stream::decode_all(data)?),
+            Codec::ZstdDictionary(id) => {
+                let dictionary = self.dictionaries.get(&id).ok_or_else(|| {
+                    KvsError::InvalidData(format!("missing compression dictionary {:016x}", id))
+                })?;
+                let mut value = Vec::new();
+                zstd::stream::Decoder::with_prepared_dictionary(data, dictionary)?
+                    .read_to_end(&mut value)?;
+                Ok(value)
+            }
+        }
+    }
+}
+
+/// Leaves values as they are, without the `compression` feature.
+#[cfg(not(feature = "compression"))]
+pub(crate) struct Compressor;
+
+#[cfg(not(feature = "compression"))]
+impl Compressor {
+    pub(crate) fn new(_compression: Compression, _threshold: usize) -> Self {
+        Compressor
+    }
+
+    pub(crate) fn compress(&self, _value: &[u8]) -> Result<Option<(Codec, String)>> {
+        Ok(None)
+    }
+}
+
+/// Fails on every compressed value, without the `compression` feature.
+#[cfg(not(feature = "compression"))]
+#[derive(Default)]
+pub(crate) struct Decompressor {}
+
+#[cfg(not(feature = "compression"))]
+impl Decompressor {
+    pub(crate) fn add_dictionary(&mut self, _dictionary: &[u8]) {}
+
+    pub(crate) fn decompress(&self, codec: Codec, _text: &str) -> Result<Vec<u8>> {
+        Err(KvsError::InvalidData(format!(
+            "value is compressed with {:?}, but the `compression` feature is off",
+            codec
+        )))
+    }
+}
+
+/// The id a value records to name the dictionary it was compressed with.
+pub(crate) fn dictionary_id(dictionary: &[u8]) -> u64 {
+    seahash::hash(dictionary)
+}
+
+#[cfg(all(test, feature = "compression"))]
+mod tests {
+    use super::*;
+
+    fn document(i: usize) -> Vec<u8> {
+        format!(
+            r#"{{"id":{},"name":"user {}","email":"user{}@example.com","active":true}}"#,
+            i, i, i
+        )
+        .into_bytes()
+    }
+
+    fn round_trip(compression: Compression) -> Result<()> {
+        let compressor = Compressor::new(compression.clone(), 16);
+        let mut decompressor = Decompressor::default();
+        if let Compression::ZstdDictionary { dictionary, .. } = &compression {
+            decompressor.add_dictionary(dictionary);
+        }
+        let value = document(7).repeat(10);
+        let (codec, compressed) = compressor.compress(&value)?.expect("value is compressed");
+        assert!(compressed.len() < value.len() / 2);
+        assert_eq!(decompressor.decompress(codec, &compressed)?, value);
+        // too small to bother
+        assert!(compressor.compress(b"small")?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn codecs() -> Result<()> {
+        round_trip(Compression::Lz4)?;
+        round_trip(Compression::Zstd(3))?;
+        let samples: Vec<_> = (0..1000).map(document).collect();
+        round_trip(Compression::zstd_dictionary(3, &samples, 4096)?)
+    }
+
+    /// A value of a couple of documents, still small, but worth compressing with a dictionary
+    /// once in base64.
+    fn small_value(i: usize) -> Vec<u8> {
+        [document(i), document(i + 1)].concat()
+    }
+
+    #[test]
+    fn base64_growth() -> Result<()> {
+        // shrinks a little when compressed, but not once in base64
+        let mut value: Vec<u8> =
+            (0..180u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8).collect();
+        value.extend_from_slice(&[0; 40]);
+        let compressor = Compressor::new(Compression::Lz4, 0);
+        assert!(lz4_flex::compress_prepend_size(&value).len() < value.len());
+        assert!(compressor.compress(&value)?.is_none());
+        assert_eq!(logged_len(Codec::Lz4, "AAAA"), 4 + r#","codec":"Lz4""#.len());
+        Ok(())
+    }
+
+    #[test]
+    fn dictionary_compresses_small_values() -> Result<()> {
+        let samples: Vec<_> = (0..1000).map(document).collect();
+        let plain = Compressor::new(Compression::Zstd(3), 0);
+        let trained = Compressor::new(Compression::zstd_dictionary(3, &samples, 4096)?, 0);
+        let value = small_value(123_456);
+        let plain_len = plain.compress(&value)?.map_or(value.len(), |(_, data)| data.len());
+        let (_, trained) = trained.compress(&value)?.expect("value is compressed");
+        // the two documents have a lot in common for plain Zstandard too
+        assert!(trained.len() < plain_len * 2 / 3, "{} vs {}", trained.len(), plain_len);
+        Ok(())
+    }
+
+    #[test]
+    fn missing_dictionary() -> Result<()> {
+        let samples: Vec<_> = (0..1000).map(document).collect();
+        let compressor = Compressor::new(Compression::zstd_dictionary(3, &samples, 4096)?, 0);
+        let (codec, data) = compressor.compress(&small_value(1))?.expect("value is compressed");
+        assert!(matches!(
+            Decompressor::default().decompress(codec, &data),
+            Err(KvsError::InvalidData(_))
+        ));
+        Ok(())
+    }
+}
@@ -1,30 +1,198 @@
+#[cfg(test)]
+mod crash_tests;
+#[cfg(feature = "encryption")]
+mod crypt;
+#[cfg(not(feature = "encryption"))]
+#[path = "disk/plain.rs"]
+mod crypt;
+
+pub use self::crypt::EncryptionKey;
+
+use self::crypt::{Keyring, HEADER_LEN};
+use super::cache::ValueCache;
+use super::compress::{dictionary_id, Codec, Compression, Compressor, Decompressor};
+use super::pool::{Completer, Task, ThreadPool};
+use super::usage::{btree_bytes, hash_table_bytes};
+use super::vfs::{Mapping, RealFs, Vfs, VfsFile};
 use crate::result::{KvsError, Result};
+use crate::storage::{
+    BatchStore, CheckpointStore, MemoryUsage, MemoryUsageStore, ScanStore, Store,
+};
 
+use parking_lot::{Condvar, Mutex, MutexGuard, RwLock};
 use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
+use serde_json::de::IoRead;
+use serde_json::{Deserializer, StreamDeserializer};
 
+use std::any::Any;
+use std::borrow::Cow;
 use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsStr;
-use std::fs::{self, File, OpenOptions};
+use std::fmt::Display;
+use std::future::Future;
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::ops::Range;
+use std::ops::{Bound, Deref, Range, RangeBounds};
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
-use std::fmt::Display;
+use std::sync::Arc;
+
+const MANIFEST: &str = "MANIFEST";
+const MANIFEST_TMP: &str = "MANIFEST.tmp";
+
+/// Options for opening a `DiskStore`.
+#[derive(Clone, Debug)]
+pub struct DiskStoreOptions {
+    /// The size in bytes after which the current log file is sealed, and writes move on to a
+    /// new one. Defaults to 64 MiB.
+    pub max_segment_size: u64,
+    /// The fraction of stale bytes above which a log file gets rewritten by compaction.
+    /// Defaults to 0.5.
+    pub compaction_ratio: f64,
+    /// The number of stale bytes, in log files above `compaction_ratio`, that triggers a
+    /// compaction. Defaults to 1 MiB.
+    pub compaction_threshold: u64,
+    /// Whether to read sealed log files, which are never written again, through memory maps
+    /// rather than read calls. Defaults to false.
+    pub mmap: bool,
+    /// When writes are flushed to durable storage. Defaults to `SyncMode::Never`.
+    pub sync: SyncMode,
+    /// A cache for the values read from the log, which may be shared with other stores.
+    /// Defaults to none.
+    pub cache: Option<ValueCache>,
+    /// How values are compressed in the log. Compressed values are written in base64, since
+    /// the log is JSON. Defaults to `Compression::None`.
+    pub compression: Compression,
+    /// The size in bytes below which values are written uncompressed. Defaults to 128.
+    pub compression_threshold: usize,
+    /// The key new log files are encrypted with. Defaults to none, for plain log files.
+    ///
+    /// Compaction rewrites older log files with the current key, so a key is rotated by
+    /// opening the store with a new one, the old one in `previous_keys`, and compacting. The
+    /// manifest is not encrypted; compression dictionaries are, and move to the current key
+    /// when the store is opened.
+    pub encryption_key: Option<EncryptionKey>,
+    /// Keys that older log files may still be encrypted with. Defaults to none.
+    pub previous_keys: Vec<EncryptionKey>,
+    /// Whether opening fails on a log file or a dictionary that is not encrypted, which
+    /// takes `encryption_key`. Defaults to false.
+    ///
+    /// Otherwise a plain file is read as it is, which lets anyone able to replace one slip in
+    /// unauthenticated commands. A store written without a key is encrypted by opening it with
+    /// one, but not this, and compacting it; from then on it should be opened with this set.
+    pub require_encryption: bool,
+}
+
+/// When a `DiskStore` flushes writes to durable storage.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncMode {
+    /// Writes are handed to the operating system: they survive the process crashing, but not
+    /// a power loss until [`DiskStore::sync`] is called.
+    Never,
+    /// Every write is flushed to durable storage before it returns.
+    ///
+    /// Writes from concurrent threads are committed together, with a single flush.
+    Always,
+}
 
-const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+impl Default for DiskStoreOptions {
+    fn default() -> Self {
+        DiskStoreOptions {
+            max_segment_size: 64 * 1024 * 1024,
+            compaction_ratio: 0.5,
+            compaction_threshold: 1024 * 1024,
+            mmap: false,
+            sync: SyncMode::Never,
+            cache: None,
+            compression: Compression::None,
+            compression_threshold: 128,
+            encryption_key: None,
+            previous_keys: Vec::new(),
+            require_encryption: false,
+        }
+    }
+}
 
+/// The `DiskStore` stores key/value pairs in a log-structured set of files.
+///
+/// Commands are appended to the current log file until it reaches
+/// [`max_segment_size`](DiskStoreOptions::max_segment_size), then a new one is started.
+/// Compaction rewrites only the log files holding mostly stale commands.
+///
+/// A `DiskStore` can be cloned cheaply, and all clones share the same store: any number of
+/// threads read in parallel with positional reads, while writes go through one at a time.
+///
+/// Keys and values must be valid UTF-8, since every command is written to the log as JSON.
+#[derive(Clone)]
 pub struct DiskStore {
+    shared: Arc<Shared>,
+    log: Arc<Mutex<Log>>,
+    queue: Arc<CommitQueue>,
+}
+
+/// The state of a `DiskStore` that readers need.
+struct Shared {
     // directory for the log and other data
     path: PathBuf,
-    // map generation number to the file reader
-    readers: HashMap<u64, BufReaderWithPos<File>>,
+    // file system the log lives on
+    vfs: Box<dyn Vfs>,
+    // map generation number to the log file
+    files: RwLock<HashMap<u64, LogFile>>,
+    index: RwLock<BTreeMap<String, CommandPos>>,
+    // whether sealed log files get memory mapped
+    mmap: bool,
+    // the value cache, and the id this store keys its values with
+    cache: Option<(ValueCache, u64)>,
+    compressor: Compressor,
+    // knows every dictionary in the directory
+    decompressor: Decompressor,
+    keyring: Keyring,
+}
+
+/// Writes waiting to be committed to the log, see `DiskStore::commit`.
+#[derive(Default)]
+struct CommitQueue {
+    state: Mutex<QueueState>,
+    // notified whenever a group of writes is committed
+    committed: Condvar,
+}
+
+#[derive(Default)]
+struct QueueState {
+    next_ticket: u64,
+    // commands of each write, by ticket, in arrival order
+    pending: Vec<(u64, Vec<Command>)>,
+    // results of committed writes, until their writers pick them up
+    results: HashMap<u64, Result<()>>,
+    // where to hand the results of async writes, which do not pick them up
+    completers: HashMap<u64, Completer<Result<()>>>,
+    // whether a writer is committing a group
+    leader: bool,
+}
+
+/// A log file open for reading.
+#[derive(Clone)]
+struct LogFile {
+    file: Arc<dyn VfsFile>,
+    // the whole content of the file, once it is sealed and mapped
+    map: Option<Arc<Mapping>>,
+    // the key the file is encrypted with
+    key: Option<EncryptionKey>,
+}
+
+/// The writing end of a `DiskStore`.
+struct Log {
+    shared: Arc<Shared>,
     // writer of the current log
-    writer: BufWriterWithPos<File>,
+    writer: BufWriterWithPos<Box<dyn VfsFile>>,
     current_gen: u64,
-    index: BTreeMap<String, CommandPos>,
+    // map generation number to the sizes of its log
+    segments: BTreeMap<u64, Segment>,
     // the number of bytes representing "stale" commands that could be
     // deleted during a compaction
     uncompacted: u64,
+    // why the last sealing or compaction after a write failed, until it is taken
+    maintenance_error: Option<KvsError>,
+    options: DiskStoreOptions,
 }
 
 impl Display for DiskStore {
@@ -42,211 +210,1066 @@ impl DiskStore {
     ///
     /// It propagates I/O or deserialization errors during the log replay.
     pub fn open(path: impl Into<PathBuf>) -> Result<DiskStore> {
+        DiskStore::open_with_options(path, DiskStoreOptions::default())
+    }
+
+    /// Opens a `DiskStore` with the given path and options.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during the log replay, and fails with
+    /// `KvsError::Encryption` on a file it cannot decrypt, or a plain one when
+    /// [`require_encryption`](DiskStoreOptions::require_encryption) is set. Without the
+    /// `encryption` feature, it fails the same way on a key, or on an encrypted file.
+    pub fn open_with_options(
+        path: impl Into<PathBuf>,
+        options: DiskStoreOptions,
+    ) -> Result<DiskStore> {
+        DiskStore::open_with_vfs(path, Box::new(RealFs), options)
+    }
+
+    /// Opens a `DiskStore` with the given path on the given file system.
+    pub(crate) fn open_with_vfs(
+        path: impl Into<PathBuf>,
+        vfs: Box<dyn Vfs>,
+        options: DiskStoreOptions,
+    ) -> Result<DiskStore> {
         let path = path.into();
-        fs::create_dir_all(&path)?;
+        vfs.create_dir_all(&path)?;
 
-        let mut readers = HashMap::new();
+        let mut files = HashMap::new();
         let mut index = BTreeMap::new();
+        let mut segments = BTreeMap::new();
+
+        let gen_list = match read_manifest(&*vfs, &path)? {
+            Some(manifest) => {
+                // generations left behind by a compaction or an open that did not finish
+                for gen in sorted_gen_list(&*vfs, &path)? {
+                    if !manifest.gens.contains(&gen) {
+                        vfs.remove_file(&log_path(&path, gen))?;
+                    }
+                }
+                manifest.gens
+            }
+            // a store written before the manifest existed
+            None => sorted_gen_list(&*vfs, &path)?,
+        };
 
-        let gen_list = sorted_gen_list(&path)?;
-        let mut uncompacted = 0;
+        if cfg!(not(feature = "encryption"))
+            && (options.encryption_key.is_some() || options.require_encryption)
+        {
+            return Err(KvsError::Encryption("the `encryption` feature is off".to_string()));
+        }
+        if options.require_encryption && options.encryption_key.is_none() {
+            return Err(KvsError::Encryption(
+                "encryption is required, but no key was given".to_string(),
+            ));
+        }
+        let keyring = Keyring::new(
+            options.encryption_key.clone(),
+            &options.previous_keys,
+            options.require_encryption,
+        );
+        let mut dictionaries = read_dictionaries(&*vfs, &path, &keyring)?;
+        if let Some(dictionary) = options.compression.dictionary() {
+            if !dictionaries.iter().any(|(known, _)| **known == *dictionary) {
+                write_dictionary(&*vfs, &path, dictionary, &keyring)?;
+                dictionaries.push((dictionary.to_vec(), keyring.current().map(EncryptionKey::id)));
+            }
+        }
+        let mut decompressor = Decompressor::default();
+        for (dictionary, key_id) in dictionaries {
+            // rewritten with the current key, so that older keys are only needed by logs
+            if key_id != keyring.current().map(EncryptionKey::id) {
+                write_dictionary(&*vfs, &path, &dictionary, &keyring)?;
+            }
+            decompressor.add_dictionary(&dictionary);
+        }
 
         for &gen in &gen_list {
-            let mut reader = BufReaderWithPos::new(File::open(log_path(&path, gen))?)?;
-            uncompacted += load(gen, &mut reader, &mut index)?;
-            readers.insert(gen, reader);
+            let mut reader = BufReaderWithPos::new(vfs.open_read(&log_path(&path, gen))?)?;
+            let key = load(gen, &mut reader, &keyring, &mut index, &mut segments)?;
+            files.insert(gen, LogFile { file: Arc::from(reader.into_inner()), map: None, key });
         }
 
+        let shared = Arc::new(Shared {
+            path,
+            vfs,
+            files: RwLock::new(files),
+            index: RwLock::new(index),
+            mmap: options.mmap,
+            cache: options.cache.as_ref().map(|cache| (cache.clone(), cache.register())),
+            compressor: Compressor::new(options.compression.clone(), options.compression_threshold),
+            decompressor,
+            keyring,
+        });
+        for &gen in &gen_list {
+            shared.map_sealed(gen);
+        }
         let current_gen = gen_list.last().unwrap_or(&0) + 1;
-        let writer = new_log_file(&path, current_gen, &mut readers)?;
+        let writer = shared.new_log_file(current_gen)?;
+        segments.insert(current_gen, Segment::default());
+        write_manifest(&*shared.vfs, &shared.path, segments.keys().cloned().collect())?;
+        let uncompacted = segments.values().map(Segment::uncompacted).sum();
 
-        Ok(DiskStore { path, readers, writer, current_gen, index, uncompacted })
+        let log = Log {
+            shared: Arc::clone(&shared),
+            writer,
+            current_gen,
+            segments,
+            uncompacted,
+            maintenance_error: None,
+            options,
+        };
+        Ok(DiskStore { shared, log: Arc::new(Mutex::new(log)), queue: Arc::default() })
     }
 
     /// Clears stale entries in the log.
-    pub fn compact(&mut self) -> Result<()> {
-        // increase current gen by 2. current_gen + 1 is for the compaction file
-        let compaction_gen = self.current_gen + 1;
-        self.current_gen += 2;
-        self.writer = self.new_log_file(self.current_gen)?;
-
-        let mut compaction_writer = self.new_log_file(compaction_gen)?;
-
-        let mut new_pos = 0; // pos in the new log file
-        for cmd_pos in &mut self.index.values_mut() {
-            let reader = self.readers.get_mut(&cmd_pos.gen).expect("Cannot find log reader");
-            if reader.pos != cmd_pos.pos {
-                reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+    ///
+    /// Every log file is rewritten, so that only the live commands are left.
+    pub fn compact(&self) -> Result<()> {
+        self.log.lock().compact()
+    }
+
+    /// Flushes the current log to durable storage.
+    ///
+    /// Every command is handed to the operating system as soon as it is written, so it
+    /// survives the process crashing; `sync` makes it survive a power loss as well.
+    pub fn sync(&self) -> Result<()> {
+        self.log.lock().writer.sync()?;
+        Ok(())
+    }
+
+    /// Returns why sealing the current log file or compacting the log after a write last
+    /// failed, if it did since the last call.
+    ///
+    /// Writes do not fail for it, since their commands are in the log by then; the
+    /// maintenance is tried again after the next write.
+    pub fn take_maintenance_error(&self) -> Option<KvsError> {
+        self.log.lock().maintenance_error.take()
+    }
+
+    /// Appends commands to the log, and applies them to the index.
+    ///
+    /// Writers queue their commands; the first one to find no group in progress becomes the
+    /// leader, and commits every queued command at once with a single write and, with
+    /// `SyncMode::Always`, a single flush. The other writers wait for their result.
+    fn commit(&self, cmds: Vec<Command>) -> Result<()> {
+        let mut state = self.queue.state.lock();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.pending.push((ticket, cmds));
+        loop {
+            if let Some(result) = state.results.remove(&ticket) {
+                return result;
             }
+            if !state.leader {
+                break;
+            }
+            self.queue.committed.wait(&mut state);
+        }
 
-            let mut entry_reader = reader.take(cmd_pos.len);
-            let len = io::copy(&mut entry_reader, &mut compaction_writer)?;
-            *cmd_pos = (compaction_gen, new_pos..new_pos + len).into();
-            new_pos += len;
+        state.leader = true;
+        let result = self.commit_group(&mut state, Some(ticket));
+        // async writers cannot lead, so a thread takes over if any is left waiting
+        if state.pending.iter().any(|(other, _)| state.completers.contains_key(other)) {
+            self.spawn_leader();
+        } else {
+            state.leader = false;
         }
-        compaction_writer.flush()?;
+        self.queue.committed.notify_all();
+        result.expect("the group holds the leader's commands")
+    }
 
-        // remove stale log files
-        let stale_gens: Vec<_> =
-            self.readers.keys().filter(|&&gen| gen < compaction_gen).cloned().collect();
-        for stale_gen in stale_gens {
-            self.readers.remove(&stale_gen);
-            fs::remove_file(log_path(&self.path, stale_gen))?;
+    /// Queues commands like `commit`, returning a future of their result instead of waiting
+    /// for it, or fails right away if the commands could not be built.
+    ///
+    /// If no group is in progress, a thread is started to lead, and keeps committing groups
+    /// until the queue is empty.
+    fn commit_async(&self, cmds: Result<Vec<Command>>) -> Task<Result<()>> {
+        let cmds = match cmds {
+            Ok(cmds) => cmds,
+            Err(err) => return Task::ready(Err(err)),
+        };
+        let (task, completer) = Task::new();
+        let mut state = self.queue.state.lock();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.pending.push((ticket, cmds));
+        state.completers.insert(ticket, completer);
+        if !state.leader {
+            state.leader = true;
+            self.spawn_leader();
         }
+        task
+    }
 
-        self.uncompacted = 0;
+    /// Starts a thread to `lead`.
+    ///
+    /// It is not a thread of a pool: blocking writers may fill the pool, waiting for the
+    /// leader, which would then never get to run.
+    fn spawn_leader(&self) {
+        let store = self.clone();
+        std::thread::Builder::new()
+            .name("ritekv-commit".to_string())
+            .spawn(move || store.lead())
+            .expect("failed to spawn a commit thread");
+    }
 
-        Ok(())
+    /// Commits groups of queued commands until there are none left, on behalf of writers that
+    /// cannot lead.
+    fn lead(&self) {
+        let mut state = self.queue.state.lock();
+        while !state.pending.is_empty() {
+            self.commit_group(&mut state, None);
+            self.queue.committed.notify_all();
+        }
+        state.leader = false;
+        self.queue.committed.notify_all();
     }
 
-    /// Create a new log file with given generation number and add the reader to the readers map.
+    /// Commits every queued command, with the queue unlocked meanwhile, and hands out the
+    /// result to the writers of the group, but for `own`, whose result is returned.
     ///
-    /// Returns the writer to the log.
-    fn new_log_file(&mut self, gen: u64) -> Result<BufWriterWithPos<File>> {
-        new_log_file(&self.path, gen, &mut self.readers)
+    /// A panic while writing fails the whole group, rather than leaving its writers waiting
+    /// on a leader that is gone.
+    fn commit_group(
+        &self,
+        state: &mut MutexGuard<QueueState>,
+        own: Option<u64>,
+    ) -> Option<Result<()>> {
+        let group = std::mem::take(&mut state.pending);
+        let result = MutexGuard::unlocked(state, || {
+            let cmds = group.iter().flat_map(|(_, cmds)| cmds).cloned().collect();
+            panic::catch_unwind(AssertUnwindSafe(|| self.log.lock().write(cmds)))
+                .unwrap_or_else(|panic| Err(KvsError::Internal(panic_message(&*panic))))
+        });
+        for &(other, _) in &group {
+            if Some(other) == own {
+                continue;
+            }
+            let other_result = result.as_ref().map_err(copy_error).map(|_| ());
+            match state.completers.remove(&other) {
+                Some(completer) => completer.complete(Ok(other_result)),
+                None => {
+                    state.results.insert(other, other_result);
+                }
+            }
+        }
+        own.map(|_| result)
+    }
+
+    /// Gets the value of a given key, without copying it out of a memory-mapped log file.
+    ///
+    /// Returns `None` if the given key does not exist. The value borrows from the mapping
+    /// only with [`mmap`](DiskStoreOptions::mmap) enabled, and if the value needed no escaping
+    /// in the log; otherwise it is read into memory like with `get`.
+    pub fn get_value(&self, key: impl AsRef<[u8]>) -> Result<Option<DiskValue>> {
+        let key = utf8_key(key)?;
+        self.shared.get(&key)
     }
 }
 
-impl DiskStore {
-    /// Sets the value of a string key to a string.
+/// A value read from a `DiskStore`.
+///
+/// It may borrow from a memory-mapped log file, which is kept mapped for as long as the
+/// value lives, even if a compaction removes the file in the meantime.
+#[derive(Clone)]
+pub struct DiskValue(ValueRepr);
+
+#[derive(Clone)]
+enum ValueRepr {
+    Owned(Vec<u8>),
+    Mapped(Arc<Mapping>, Range<usize>),
+    Cached(Arc<[u8]>),
+}
+
+impl Deref for DiskValue {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.0 {
+            ValueRepr::Owned(value) => value,
+            ValueRepr::Mapped(map, range) => &(**map).as_ref()[range.clone()],
+            ValueRepr::Cached(value) => value,
+        }
+    }
+}
+
+impl AsRef<[u8]> for DiskValue {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl std::fmt::Debug for DiskValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("DiskValue").field(&&**self).finish()
+    }
+}
+
+impl From<DiskValue> for Vec<u8> {
+    fn from(value: DiskValue) -> Self {
+        match value.0 {
+            ValueRepr::Owned(value) => value,
+            ValueRepr::Mapped(..) | ValueRepr::Cached(..) => value.to_vec(),
+        }
+    }
+}
+
+impl Shared {
+    /// Reads the value of a key from the cache, or else from the log.
+    fn get(&self, key: &str) -> Result<Option<DiskValue>> {
+        let (file, cmd_pos) = {
+            let index = self.index.read();
+            match index.get(key) {
+                Some(cmd_pos) => (self.file(cmd_pos.gen), cmd_pos.clone()),
+                None => return Ok(None),
+            }
+        };
+        let (cache, owner) = match &self.cache {
+            Some(cache) => cache,
+            None => return self.read_value(&file, &cmd_pos).map(Some),
+        };
+        let cache_key = (*owner, cmd_pos.gen, cmd_pos.pos);
+        if let Some(value) = cache.get(&cache_key) {
+            return Ok(Some(DiskValue(ValueRepr::Cached(value))));
+        }
+        // a compaction may have removed the generation in the meantime, leaving the value
+        // in the cache until it gets evicted
+        let value: Arc<[u8]> = Arc::from(&*self.read_value(&file, &cmd_pos)?);
+        cache.insert(cache_key, Arc::clone(&value));
+        Ok(Some(DiskValue(ValueRepr::Cached(value))))
+    }
+
+    /// Reads the value of the "set" command at the given position of a log file.
+    fn read_value(&self, file: &LogFile, cmd_pos: &CommandPos) -> Result<DiskValue> {
+        let start = cmd_pos.pos as usize;
+        let buf = match &file.map {
+            Some(map) => Cow::Borrowed(&(**map).as_ref()[start..start + cmd_pos.len as usize]),
+            None => {
+                let mut buf = vec![0; cmd_pos.len as usize];
+                file.file.read_exact_at(&mut buf, cmd_pos.pos)?;
+                Cow::Owned(buf)
+            }
+        };
+        let map = match (&file.map, &file.key) {
+            (_, Some(key)) => {
+                return self.owned_value(serde_json::from_slice(&crypt::open(
+                    key,
+                    &buf,
+                    cmd_pos.gen,
+                    cmd_pos.pos,
+                )?)?)
+            }
+            (Some(map), None) => map,
+            (None, None) => return self.owned_value(serde_json::from_slice(&buf)?),
+        };
+        match serde_json::from_slice(&buf)? {
+            CommandRef::Set { value, codec: Some(codec) } => self.decompress(codec, &value),
+            CommandRef::Set { value: Cow::Borrowed(value), codec: None } => {
+                let start = start + (value.as_ptr() as usize - buf.as_ptr() as usize);
+                Ok(DiskValue(ValueRepr::Mapped(Arc::clone(map), start..start + value.len())))
+            }
+            CommandRef::Set { value: Cow::Owned(value), codec: None } => {
+                Ok(DiskValue(ValueRepr::Owned(value.into_bytes())))
+            }
+            CommandRef::Remove {} => unexpected_command(),
+        }
+    }
+
+    /// Returns the value of a "set" command read into memory.
+    fn owned_value(&self, cmd: Command) -> Result<DiskValue> {
+        match cmd {
+            Command::Set { value, codec: None, .. } => {
+                Ok(DiskValue(ValueRepr::Owned(value.into_bytes())))
+            }
+            Command::Set { value, codec: Some(codec), .. } => self.decompress(codec, &value),
+            Command::Remove { .. } => unexpected_command(),
+        }
+    }
+
+    fn decompress(&self, codec: Codec, value: &str) -> Result<DiskValue> {
+        Ok(DiskValue(ValueRepr::Owned(self.decompressor.decompress(codec, value)?)))
+    }
+
+    /// Serializes a command for the log, sealed with the current key if there is one, to
+    /// the given position of the log file of generation `gen`.
+    fn encode(&self, cmd: &Command, gen: u64, pos: u64) -> Result<Vec<u8>> {
+        let json = serde_json::to_vec(cmd)?;
+        match self.keyring.current() {
+            Some(key) => self.keyring.seal(key, &json, gen, pos),
+            None => Ok(json),
+        }
+    }
+
+    /// Returns the "set" command of a value, compressed if it is worth it.
+    fn set_command(&self, key: String, value: String) -> Result<Command> {
+        Ok(match self.compressor.compress(value.as_bytes())? {
+            Some((codec, value)) => Command::Set { key, codec: Some(codec), value },
+            None => Command::set(key, value),
+        })
+    }
+
+    /// Returns the log file of a generation.
     ///
-    /// If the key already exists, the previous value will be overwritten.
+    /// It must be called with the index locked, so that a compaction cannot remove the file
+    /// in between. The file stays readable for as long as the returned handle is held.
+    fn file(&self, gen: u64) -> LogFile {
+        self.files.read().get(&gen).expect("Cannot find log file").clone()
+    }
+
+    /// Maps a sealed log file into memory, if enabled.
+    fn map_sealed(&self, gen: u64) {
+        if !self.mmap {
+            return;
+        }
+        let file = Arc::clone(&self.files.read().get(&gen).expect("Cannot find log file").file);
+        // reads fall back to read calls if the file cannot be mapped
+        if let Ok(map) = file.map() {
+            if let Some(log_file) = self.files.write().get_mut(&gen) {
+                log_file.map = Some(map);
+            }
+        }
+    }
+
+    /// Create a new log file with given generation number and add it to the files map.
     ///
-    /// # Errors
+    /// Returns the writer to the log.
+    fn new_log_file(&self, gen: u64) -> Result<BufWriterWithPos<Box<dyn VfsFile>>> {
+        let path = log_path(&self.path, gen);
+        let mut file = self.vfs.open_append(&path)?;
+        // left over by a compaction that failed, and never listed in the manifest
+        file.set_len(0)?;
+        file.seek(SeekFrom::End(0))?;
+        let mut writer = BufWriterWithPos::new(file)?;
+        let key = self.keyring.current().cloned();
+        if let Some(key) = &key {
+            writer.append(&self.keyring.header(key)?)?;
+            // the header is durable before the manifest lists the file
+            writer.sync()?;
+        }
+        let file = Arc::from(self.vfs.open_read(&path)?);
+        self.files.write().insert(gen, LogFile { file, map: None, key });
+        Ok(writer)
+    }
+}
+
+impl Log {
+    /// Appends commands to the current log as a single write, and applies them to the index.
     ///
-    /// It propagates I/O or serialization errors during writing the log.
-    #[inline]
-    pub fn set(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
-        let key = String::from_utf8(key.as_ref().to_owned()).unwrap();
-        let value = String::from_utf8(value.as_ref().to_owned()).unwrap();
-        let cmd = Command::set(key, value);
+    /// "remove" commands of keys that do not exist are left out.
+    fn write(&mut self, cmds: Vec<Command>) -> Result<()> {
         let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
-        if let Command::Set { key, .. } = cmd {
-            if let Some(old_cmd) =
-                self.index.insert(key, (self.current_gen, pos..self.writer.pos).into())
-            {
+        let mut buf = Vec::new();
+        let mut written = Vec::with_capacity(cmds.len());
+        {
+            let index = self.shared.index.read();
+            // whether keys exist after the commands so far
+            let mut exists = HashMap::new();
+            for cmd in cmds {
+                let key = match &cmd {
+                    Command::Set { key, .. } => key,
+                    Command::Remove { key } => key,
+                };
+                let existed = exists.get(key).cloned().unwrap_or_else(|| index.contains_key(key));
+                if let Command::Remove { .. } = cmd {
+                    if !existed {
+                        continue;
+                    }
+                }
+                exists.insert(key.clone(), matches!(cmd, Command::Set { .. }));
+                let start = buf.len() as u64;
+                buf.extend_from_slice(&self.shared.encode(&cmd, self.current_gen, pos + start)?);
+                written.push((cmd, start..buf.len() as u64));
+            }
+        }
+        if written.is_empty() {
+            return Ok(());
+        }
+
+        self.writer.append(&buf)?;
+        // the commands are in the log even if the flush fails, so they are applied anyway
+        let synced = match self.options.sync {
+            SyncMode::Never => Ok(()),
+            SyncMode::Always => self.writer.sync(),
+        };
+
+        let mut index = self.shared.index.write();
+        for (cmd, range) in written {
+            let len = range.end - range.start;
+            let cmd_pos = CommandPos::from((self.current_gen, pos + range.start..pos + range.end));
+            let segment = self.segments.get_mut(&self.current_gen).expect("Cannot find segment");
+            segment.len += len;
+            let old_cmd = match cmd {
+                Command::Set { key, .. } => index.insert(key, cmd_pos),
+                Command::Remove { key } => {
+                    // the "remove" command itself can be deleted in a compaction,
+                    // just like `load` counts it on replay
+                    segment.removes += len;
+                    self.uncompacted += len;
+                    index.remove(&key)
+                }
+            };
+            if let Some(old_cmd) = old_cmd {
+                mark_stale(&mut self.segments, &old_cmd);
                 self.uncompacted += old_cmd.len;
             }
         }
+        drop(index);
+
+        synced?;
+        if let Err(err) = self.maintain() {
+            self.maintenance_error = Some(err);
+        }
+        Ok(())
+    }
+
+    /// Rewrites every log file, so that only the live commands are left.
+    fn compact(&mut self) -> Result<()> {
+        let gens: Vec<u64> = self.segments.keys().cloned().collect();
+        self.compact_gens(&gens)
+    }
 
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?;
+    /// Seals the current log once it is full, and compacts the log files holding mostly
+    /// stale commands once they add up to `compaction_threshold`.
+    fn maintain(&mut self) -> Result<()> {
+        if self.writer.pos >= self.options.max_segment_size {
+            self.seal()?;
         }
+        if self.uncompacted <= self.options.compaction_threshold {
+            return Ok(());
+        }
+        let ratio = self.options.compaction_ratio;
+        let (gens, stale) = self
+            .segments
+            .iter()
+            .filter(|(_, segment)| segment.stale_ratio() > ratio)
+            .fold((Vec::new(), 0), |(mut gens, stale), (&gen, segment)| {
+                gens.push(gen);
+                (gens, stale + segment.stale)
+            });
+        if stale > self.options.compaction_threshold {
+            self.compact_gens(&gens)?;
+        }
+        Ok(())
+    }
+
+    /// Seals the current log, and moves writes on to a new generation.
+    fn seal(&mut self) -> Result<()> {
+        let new_gen = self.current_gen + 1;
+        let writer = self.shared.new_log_file(new_gen)?;
+        let mut gens: Vec<u64> = self.segments.keys().cloned().collect();
+        gens.push(new_gen);
+        write_manifest(&*self.shared.vfs, &self.shared.path, gens)?;
+
+        let sealed_gen = self.current_gen;
+        self.writer = writer;
+        self.current_gen = new_gen;
+        self.segments.insert(new_gen, Segment::default());
+        self.shared.map_sealed(sealed_gen);
         Ok(())
     }
 
-    /// Gets the string value of a given string key.
+    /// Copies the live commands of the given generations, in ascending order, into new
+    /// generations and removes the old ones.
     ///
-    /// Returns `None` if the given key does not exist.
-    #[inline]
-    pub fn get(&mut self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
-        let key = String::from_utf8(key.as_ref().to_owned()).unwrap();
-        if let Some(cmd_pos) = self.index.get(&key) {
-            let reader = self.readers.get_mut(&cmd_pos.gen).expect("Cannot find log reader");
-            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-            let cmd_reader = reader.take(cmd_pos.len);
-            if let Command::Set { value, .. } = serde_json::from_reader(cmd_reader)? {
-                Ok(Some(value.into_bytes()))
-            } else {
-                Err(KvsError::InvalidData("unexpected command type".to_string()))
+    /// The copies are numbered after the current log, which gets sealed, so that replaying
+    /// the log still ends up with the same values. The old generations are replaced once the
+    /// manifest says so; until then, an error or a crash leaves the store as it was.
+    fn compact_gens(&mut self, gens: &[u64]) -> Result<()> {
+        // a "remove" command has to be kept as long as an older log, not being compacted,
+        // may still hold a value for the key
+        let oldest_kept = self.segments.keys().find(|gen| !gens.contains(gen)).cloned();
+
+        let mut out_gen = self.current_gen;
+        let mut out: Option<BufWriterWithPos<Box<dyn VfsFile>>> = None;
+        let mut out_segments = BTreeMap::new();
+        let mut new_positions = Vec::new();
+        // writes go through `Log`, so the index does not change until the commit below
+        let index = self.shared.index.read();
+        for &gen in gens {
+            let keep_removes = oldest_kept.is_some_and(|kept| kept < gen);
+            let path = log_path(&self.shared.path, gen);
+            let mut reader = BufReaderWithPos::new(self.shared.vfs.open_read(&path)?)?;
+            for cmd in CommandStream::new(&mut reader, &self.shared.keyring, gen)? {
+                let (range, cmd) = cmd?;
+                let cmd_pos = range.start;
+                let live = match &cmd {
+                    Command::Set { key, .. } => {
+                        index.get(key).is_some_and(|live| live.gen == gen && live.pos == cmd_pos)
+                    }
+                    Command::Remove { key } => keep_removes && !index.contains_key(key),
+                };
+                if !live {
+                    continue;
+                }
+
+                let writer = match out {
+                    Some(ref mut writer) if writer.pos < self.options.max_segment_size => writer,
+                    _ => {
+                        if let Some(mut full) = out.take() {
+                            full.sync()?;
+                        }
+                        out_gen += 1;
+                        out_segments.insert(out_gen, Segment::default());
+                        out.insert(self.shared.new_log_file(out_gen)?)
+                    }
+                };
+                let new_pos = writer.pos;
+                let buf = self.shared.encode(&cmd, out_gen, new_pos)?;
+                writer.write_all(&buf)?;
+                let segment = out_segments.get_mut(&out_gen).expect("Cannot find segment");
+                segment.len += buf.len() as u64;
+                match cmd {
+                    Command::Set { key, .. } => {
+                        new_positions.push((key, CommandPos::from((out_gen, new_pos..writer.pos))))
+                    }
+                    Command::Remove { .. } => segment.removes += buf.len() as u64,
+                }
             }
-        } else {
-            Ok(None)
         }
+        drop(index);
+        if let Some(mut writer) = out {
+            writer.sync()?;
+        }
+
+        let new_gen = out_gen + 1;
+        let writer = self.shared.new_log_file(new_gen)?;
+        let mut live_gens: Vec<u64> =
+            self.segments.keys().filter(|gen| !gens.contains(gen)).cloned().collect();
+        live_gens.extend(out_segments.keys());
+        live_gens.push(new_gen);
+        write_manifest(&*self.shared.vfs, &self.shared.path, live_gens)?;
+
+        // the compaction is committed, switch over to the new generations
+        let mut index = self.shared.index.write();
+        for (key, cmd_pos) in new_positions {
+            *index.get_mut(&key).expect("key not found") = cmd_pos;
+        }
+        drop(index);
+        let mut sealed_gens: Vec<u64> = out_segments.keys().cloned().collect();
+        if !gens.contains(&self.current_gen) {
+            sealed_gens.push(self.current_gen);
+        }
+        for gen in gens {
+            self.segments.remove(gen);
+        }
+        self.segments.append(&mut out_segments);
+        self.segments.insert(new_gen, Segment::default());
+        self.writer = writer;
+        self.current_gen = new_gen;
+        self.uncompacted = self.segments.values().map(Segment::uncompacted).sum();
+        for gen in sealed_gens {
+            self.shared.map_sealed(gen);
+        }
+
+        // remove stale log files; readers still holding one can finish with it
+        for &gen in gens {
+            self.shared.files.write().remove(&gen);
+            if let Some((cache, owner)) = &self.shared.cache {
+                cache.remove_gen(*owner, gen);
+            }
+            self.shared.vfs.remove_file(&log_path(&self.shared.path, gen))?;
+        }
+
+        Ok(())
     }
+}
 
-    /// Removes a given key.
+impl Store for DiskStore {
+    /// Gets the value of a given key.
     ///
-    /// # Error
+    /// Returns `None` if the given key does not exist.
+    #[inline]
+    fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        let key = utf8_key(key)?;
+        Ok(self.shared.get(&key)?.map(Vec::from))
+    }
+
+    /// Sets the value of a key.
     ///
-    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    /// If the key already exists, the previous value will be overwritten.
+    ///
+    /// # Errors
     ///
     /// It propagates I/O or serialization errors during writing the log.
     #[inline]
-    pub fn remove(&mut self, key: impl AsRef<[u8]>) -> Result<()> {
-        let key = String::from_utf8(key.as_ref().to_owned()).unwrap();
-        if self.index.contains_key(&key) {
-            let cmd = Command::remove(key);
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
-            if let Command::Remove { key } = cmd {
-                let old_cmd = self.index.remove(&key).expect("key not found");
-                self.uncompacted += old_cmd.len;
-            }
-            Ok(())
-        } else {
-            Err(KvsError::InvalidData("key not found".to_string()))
+    fn set(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
+        let key = utf8_key(key)?;
+        let value = utf8_value(value)?;
+        self.commit(vec![self.shared.set_command(key, value)?])
+    }
+
+    /// Removes a given key, or does nothing if it does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    #[inline]
+    fn remove(&mut self, key: impl AsRef<[u8]>) -> Result<()> {
+        let key = utf8_key(key)?;
+        if !self.shared.index.read().contains_key(&key) {
+            return Ok(());
         }
+        self.commit(vec![Command::remove(key)])
     }
 
     #[inline]
-    pub fn contains(&mut self, key: impl AsRef<[u8]>) -> Result<bool> {
-        let key = String::from_utf8(key.as_ref().to_owned()).unwrap();
-        Ok(self.index.contains_key(&key))
+    fn contains(&mut self, key: impl AsRef<[u8]>) -> Result<bool> {
+        let key = utf8_key(key)?;
+        Ok(self.shared.index.read().contains_key(&key))
     }
+}
 
+impl BatchStore for DiskStore {
     #[inline]
-    pub fn get_batch(&mut self, keys: impl AsRef<[Vec<u8>]>) -> Result<Vec<Option<Vec<u8>>>> {
-            let keys = keys.as_ref().to_owned();
-        let values = keys.into_iter().map(|key| self.get(&key).ok()?.map(|v| v)).collect();
+    fn get_batch(&self, keys: impl AsRef<[Vec<u8>]>) -> Result<Vec<Option<Vec<u8>>>> {
+        let keys = keys.as_ref().to_owned();
+        let values = keys.into_iter().map(|key| self.get(&key).ok()?).collect();
         Ok(values)
     }
 
     #[inline]
-    pub fn set_batch(
+    fn set_batch(
         &mut self,
         keys: impl AsRef<[Vec<u8>]>,
         values: impl AsRef<[Vec<u8>]>,
     ) -> Result<()> {
-            let keys = keys.as_ref().to_owned();
-            let values = values.as_ref().to_owned();
+        let keys = keys.as_ref();
+        let values = values.as_ref();
         if keys.len() != values.len() {
             return Err(KvsError::InvalidData(
                 "The number of keys does not match the number of values".to_string(),
             ));
         }
-        for i in 0..keys.len() {
-            let key = keys[i].to_vec();
-            let value = values[i].to_vec();
-
-            self.set(key, value);
+        let mut cmds = Vec::with_capacity(keys.len());
+        for (key, value) in keys.iter().zip(values) {
+            cmds.push(self.shared.set_command(utf8_key(key)?, utf8_value(value)?)?);
         }
-        Ok(())
+        self.commit(cmds)
     }
 
     #[inline]
-    pub fn remove_batch(&mut self, keys: impl AsRef<[Vec<u8>]>) -> Result<()> {
-            let keys = keys.as_ref().to_owned();
-        for key in keys {
-            self.remove(&key);
+    fn remove_batch(&mut self, keys: impl AsRef<[Vec<u8>]>) -> Result<()> {
+        let mut cmds = Vec::with_capacity(keys.as_ref().len());
+        for key in keys.as_ref() {
+            cmds.push(Command::remove(utf8_key(key)?));
         }
-        Ok(())
+        self.commit(cmds)
+    }
+}
+
+impl ScanStore for DiskStore {
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan_limit(range, usize::MAX)
+    }
+
+    /// Gets the first `limit` pairs within `range`, reading only their values.
+    fn scan_limit(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        // locate every value at once, so that the scan sees a single state of the store
+        let located: Vec<_> = {
+            let index = self.shared.index.read();
+            // keys are UTF-8, so a start that is not cannot be in the index, and is passed
+            // over by filtering instead
+            let start = match range.start_bound() {
+                Bound::Included(key) => std::str::from_utf8(key).map(Bound::Included),
+                Bound::Excluded(key) => std::str::from_utf8(key).map(Bound::Excluded),
+                Bound::Unbounded => Ok(Bound::Unbounded),
+            };
+            let entries: Box<dyn Iterator<Item = (&String, &CommandPos)>> = match start {
+                Ok(start) => Box::new(index.range::<str, _>((start, Bound::Unbounded))),
+                Err(_) => Box::new(index.iter()),
+            };
+            entries
+                .skip_while(|(key, _)| !range.contains(&key.as_bytes().to_vec()))
+                .take_while(|(key, _)| range.contains(&key.as_bytes().to_vec()))
+                .take(limit)
+                .map(|(key, cmd_pos)| (key.clone(), self.shared.file(cmd_pos.gen), cmd_pos.clone()))
+                .collect()
+        };
+        let mut pairs = Vec::with_capacity(located.len());
+        for (key, file, cmd_pos) in located {
+            pairs.push((key.into_bytes(), self.shared.read_value(&file, &cmd_pos)?.into()));
+        }
+        Ok(pairs)
+    }
+}
+
+// the async traits are not imported, so that calls of `Store` methods stay unambiguous
+impl super::AsyncStore for DiskStore {
+    /// Gets the value of a given key on the shared pool, see [`ThreadPool::shared`].
+    fn get(&self, key: impl AsRef<[u8]>) -> impl Future<Output = Result<Option<Vec<u8>>>> + Send {
+        let (store, key) = (self.clone(), key.as_ref().to_owned());
+        ThreadPool::shared().run(move || Store::get(&store, key))
+    }
+
+    /// Sets the value of a key, committed along with the other writes queued meanwhile, by
+    /// async and blocking writers alike.
+    fn set(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+    ) -> impl Future<Output = Result<()>> + Send {
+        let cmd = utf8_key(key).and_then(|key| self.shared.set_command(key, utf8_value(value)?));
+        self.commit_async(cmd.map(|cmd| vec![cmd]))
+    }
+
+    /// Removes a key, queued like `set`. Whether the key exists is only known once the
+    /// writes queued before have committed, so removing an absent key is left to the log.
+    fn remove(&mut self, key: impl AsRef<[u8]>) -> impl Future<Output = Result<()>> + Send {
+        self.commit_async(utf8_key(key).map(|key| vec![Command::remove(key)]))
+    }
+
+    /// Returns whether the index has the key, which never waits on I/O.
+    fn contains(&mut self, key: impl AsRef<[u8]>) -> impl Future<Output = Result<bool>> + Send {
+        Task::ready(Store::contains(self, key))
+    }
+}
+
+impl super::AsyncBatchStore for DiskStore {
+    fn get_batch(
+        &self,
+        keys: impl AsRef<[Vec<u8>]>,
+    ) -> impl Future<Output = Result<Vec<Option<Vec<u8>>>>> + Send {
+        let (store, keys) = (self.clone(), keys.as_ref().to_owned());
+        ThreadPool::shared().run(move || BatchStore::get_batch(&store, keys))
+    }
+
+    fn set_batch(
+        &mut self,
+        keys: impl AsRef<[Vec<u8>]>,
+        values: impl AsRef<[Vec<u8>]>,
+    ) -> impl Future<Output = Result<()>> + Send {
+        let (keys, values) = (keys.as_ref(), values.as_ref());
+        if keys.len() != values.len() {
+            return Task::ready(Err(KvsError::InvalidData(
+                "The number of keys does not match the number of values".to_string(),
+            )));
+        }
+        let cmds = keys
+            .iter()
+            .zip(values)
+            .map(|(key, value)| self.shared.set_command(utf8_key(key)?, utf8_value(value)?))
+            .collect();
+        self.commit_async(cmds)
+    }
+
+    fn remove_batch(
+        &mut self,
+        keys: impl AsRef<[Vec<u8>]>,
+    ) -> impl Future<Output = Result<()>> + Send {
+        let cmds = keys.as_ref().iter().map(|key| Ok(Command::remove(utf8_key(key)?))).collect();
+        self.commit_async(cmds)
+    }
+}
+
+impl CheckpointStore for DiskStore {
+    /// Writes a copy of the store to `dest`, without holding up writes for long.
+    ///
+    /// Sealed log files never change, so they are hard-linked into `dest`, or copied if the
+    /// file system cannot link them. The current log file is copied up to the end of the last
+    /// committed write. The manifest is written last, so a checkpoint cut short by a crash
+    /// does not open as a store.
+    fn checkpoint(&self, dest: impl AsRef<Path>) -> Result<()> {
+        let dest = dest.as_ref();
+        let vfs = &*self.shared.vfs;
+        vfs.create_dir_all(dest)?;
+        if !vfs.list_files(dest)?.is_empty() {
+            return Err(KvsError::InvalidData(format!("{} is not empty", dest.display())));
+        }
+
+        // the log lock keeps writes and compactions out while the generations are listed
+        let (gens, copies) = {
+            let mut log = self.log.lock();
+            log.writer.flush()?;
+            let gens: Vec<u64> = log.segments.keys().cloned().collect();
+            let mut copies = Vec::new();
+            for &gen in &gens {
+                let to = log_path(dest, gen);
+                if gen == log.current_gen {
+                    copies.push((self.shared.file(gen), to, Some(log.writer.pos)));
+                } else if vfs.hard_link(&log_path(&self.shared.path, gen), &to).is_err() {
+                    copies.push((self.shared.file(gen), to, None));
+                }
+            }
+            (gens, copies)
+        };
+
+        for (file, to, len) in copies {
+            copy_file(&*file.file, vfs.create(&to)?, len)?;
+        }
+        let keyring = &self.shared.keyring;
+        for (dictionary, _) in read_dictionaries(vfs, &self.shared.path, keyring)? {
+            write_dictionary(vfs, dest, &dictionary, keyring)?;
+        }
+        write_manifest(vfs, dest, gens)
+    }
+}
+
+impl MemoryUsageStore for DiskStore {
+    /// Counts the index of keys to log positions, the values of this store in the cache, and
+    /// the write buffer of the current log file.
+    ///
+    /// Memory-mapped log files are not counted, since the kernel pages them in and out as it
+    /// sees fit.
+    fn memory_usage(&self) -> MemoryUsage {
+        let mut usage = {
+            let index = self.shared.index.read();
+            let mut usage = MemoryUsage {
+                entries: index.len() as u64,
+                capacity: index.len() as u64,
+                index: btree_bytes::<String, CommandPos>(index.len()),
+                ..MemoryUsage::default()
+            };
+            for key in index.keys() {
+                usage.index += key.capacity() as u64;
+            }
+            usage
+        };
+        usage.index += hash_table_bytes::<u64, LogFile>(self.shared.files.read().capacity());
+        if let Some((cache, owner)) = &self.shared.cache {
+            usage.values = cache.owner_size(*owner);
+        }
+        let log = self.log.lock();
+        usage.index += btree_bytes::<u64, Segment>(log.segments.len());
+        usage.buffers = log.writer.writer.capacity() as u64;
+        usage
+    }
+}
+
+/// Checks that a key is non-empty UTF-8, which is what the JSON log can hold.
+fn utf8_key(key: impl AsRef<[u8]>) -> Result<String> {
+    let key = key.as_ref();
+    if key.is_empty() {
+        return Err(KvsError::EmptyKey);
+    }
+    String::from_utf8(key.to_owned())
+        .map_err(|_| KvsError::InvalidData("key is not valid UTF-8".to_string()))
+}
+
+/// Checks that a value is UTF-8, which is what the JSON log can hold.
+fn utf8_value(value: impl AsRef<[u8]>) -> Result<String> {
+    String::from_utf8(value.as_ref().to_owned())
+        .map_err(|_| KvsError::InvalidData("value is not valid UTF-8".to_string()))
+}
+
+/// Copies an error for the other writers of a group that failed to commit.
+fn copy_error(err: &KvsError) -> KvsError {
+    match err {
+        KvsError::IOError(err) => KvsError::IOError(io::Error::new(err.kind(), err.to_string())),
+        KvsError::InvalidData(msg) => KvsError::InvalidData(msg.clone()),
+        KvsError::Encryption(msg) => KvsError::Encryption(msg.clone()),
+        err => KvsError::Internal(err.to_string()),
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    let message = panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown cause");
+    format!("a write panicked: {}", message)
+}
+
+fn unexpected_command<T>() -> Result<T> {
+    Err(KvsError::InvalidData("unexpected command type".to_string()))
+}
+
+/// Copies the first `len` bytes of a file, or all of it, to a new file, and syncs it.
+fn copy_file(from: &dyn VfsFile, mut to: Box<dyn VfsFile>, len: Option<u64>) -> Result<()> {
+    let len = len.unwrap_or(u64::MAX);
+    let mut buf = vec![0; 64 * 1024];
+    let mut pos = 0;
+    while pos < len {
+        let max = (buf.len() as u64).min(len - pos) as usize;
+        let read = from.read_at(&mut buf[..max], pos)?;
+        if read == 0 {
+            break;
+        }
+        to.write_all(&buf[..read])?;
+        pos += read as u64;
     }
+    to.sync_all()?;
+    Ok(())
+}
+
+/// Reads the manifest in the given directory, if there is one.
+fn read_manifest(vfs: &dyn Vfs, dir: &Path) -> Result<Option<Manifest>> {
+    let mut file = match vfs.open_read(&dir.join(MANIFEST)) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+    Ok(Some(serde_json::from_slice(&buf)?))
 }
 
-/// Create a new log file with given generation number and add the reader to the readers map.
+/// Replaces the manifest in the given directory.
 ///
-/// Returns the writer to the log.
-fn new_log_file(
-    path: &Path,
-    gen: u64,
-    readers: &mut HashMap<u64, BufReaderWithPos<File>>,
-) -> Result<BufWriterWithPos<File>> {
-    let path = log_path(&path, gen);
-    let writer = BufWriterWithPos::new(
-        OpenOptions::new().create(true).write(true).append(true).open(&path)?,
-    )?;
-    readers.insert(gen, BufReaderWithPos::new(File::open(&path)?)?);
-    Ok(writer)
+/// The new manifest is written to a temporary file first and renamed over the old one, so
+/// that a crash leaves either of them in place, never a mix.
+fn write_manifest(vfs: &dyn Vfs, dir: &Path, gens: Vec<u64>) -> Result<()> {
+    let tmp_path = dir.join(MANIFEST_TMP);
+    let mut file = vfs.create(&tmp_path)?;
+    file.write_all(&serde_json::to_vec(&Manifest { gens })?)?;
+    file.sync_all()?;
+    vfs.rename(&tmp_path, &dir.join(MANIFEST))?;
+    vfs.sync_dir(dir)?;
+    Ok(())
+}
+
+/// Writes a compression dictionary to the given directory, encrypted with the current key if
+/// there is one.
+///
+/// Dictionaries are never removed, since values compressed with them may be anywhere in the
+/// log.
+fn write_dictionary(vfs: &dyn Vfs, dir: &Path, dictionary: &[u8], keyring: &Keyring) -> Result<()> {
+    let path = dict_path(dir, dictionary_id(dictionary));
+    let tmp_path = path.with_extension("tmp");
+    let mut file = vfs.create(&tmp_path)?;
+    file.write_all(&keyring.seal_file(dictionary)?)?;
+    file.sync_all()?;
+    vfs.rename(&tmp_path, &path)?;
+    vfs.sync_dir(dir)?;
+    Ok(())
+}
+
+/// Reads the compression dictionaries in the given directory, along with the id of the key
+/// each is encrypted with, if any.
+fn read_dictionaries(
+    vfs: &dyn Vfs,
+    dir: &Path,
+    keyring: &Keyring,
+) -> Result<Vec<(Vec<u8>, Option<u32>)>> {
+    let mut dictionaries = Vec::new();
+    for path in vfs.list_files(dir)? {
+        if path.extension() == Some("dict".as_ref()) {
+            let mut file = Vec::new();
+            vfs.open_read(&path)?.read_to_end(&mut file)?;
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            dictionaries.push(keyring.open_file(&file, &name)?);
+        }
+    }
+    Ok(dictionaries)
+}
+
+fn dict_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:016x}.dict", id))
 }
 
 /// Returns sorted generation numbers in the given directory
-fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
-    let mut gen_list: Vec<u64> = fs::read_dir(&path)?
-        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
-        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
+fn sorted_gen_list(vfs: &dyn Vfs, path: &Path) -> Result<Vec<u64>> {
+    let mut gen_list: Vec<u64> = vfs
+        .list_files(path)?
+        .into_iter()
+        .filter(|path| path.extension() == Some("log".as_ref()))
         .flat_map(|path| {
             path.file_name()
                 .and_then(OsStr::to_str)
@@ -261,52 +1284,212 @@ fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
 
 /// Load the whole log file and store value locations in the index map.
 ///
-/// Returns how many bytes can be saved after a compaction.
+/// A command cut short at the end of the file, left by a write that failed or was
+/// interrupted by a crash, is skipped.
+///
+/// Adds the sizes of the log to `segments`, marking the commands it replaces as stale.
+/// Returns the key the file is encrypted with, if any.
 fn load(
     gen: u64,
-    reader: &mut BufReaderWithPos<File>,
+    reader: &mut BufReaderWithPos<Box<dyn VfsFile>>,
+    keyring: &Keyring,
     index: &mut BTreeMap<String, CommandPos>,
-) -> Result<u64> {
-    // To make sure we read from the beginning of the file
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
-    let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
-    let mut uncompacted = 0; // number of bytes that can be saved after a compaction
-    while let Some(cmd) = stream.next() {
-        let new_pos = stream.byte_offset() as u64;
-        match cmd? {
+    segments: &mut BTreeMap<u64, Segment>,
+) -> Result<Option<EncryptionKey>> {
+    let mut stream = CommandStream::new(reader, keyring, gen)?;
+    let len = stream.len;
+    segments.insert(gen, Segment { len: len - stream.pos, ..Segment::default() });
+    for cmd in &mut stream {
+        let (range, cmd) = cmd?;
+        match cmd {
             Command::Set { key, .. } => {
-                if let Some(old_cmd) = index.insert(key, (gen, pos..new_pos).into()) {
-                    uncompacted += old_cmd.len;
+                if let Some(old_cmd) = index.insert(key, (gen, range).into()) {
+                    mark_stale(segments, &old_cmd);
                 }
             }
             Command::Remove { key } => {
                 if let Some(old_cmd) = index.remove(&key) {
-                    uncompacted += old_cmd.len;
+                    mark_stale(segments, &old_cmd);
                 }
-                // the "remove" command itself can be deleted in the next compaction
-                // so we add its length to `uncompacted`
-                uncompacted += new_pos - pos;
+                // the "remove" command itself can be deleted in a compaction
+                segments.get_mut(&gen).expect("Cannot find segment").removes +=
+                    range.end - range.start;
             }
         }
-        pos = new_pos;
     }
-    Ok(uncompacted)
+    // a torn command is garbage, to be dropped by the next compaction
+    segments.get_mut(&gen).expect("Cannot find segment").stale += len - stream.pos;
+    Ok(stream.key)
+}
+
+/// Reads the commands of a log file in order, with their positions, decrypting them if the
+/// file is encrypted.
+///
+/// It stops at a command cut short at the end of the file; `pos` is then where the intact
+/// commands end. An encrypted record whose length runs past the end of the file is only cut
+/// short if no intact record follows it; otherwise its length is corrupt, and it fails.
+struct CommandStream<'a> {
+    source: CommandSource<'a>,
+    key: Option<EncryptionKey>,
+    gen: u64,
+    // the length of the file
+    len: u64,
+    // where the next command starts
+    pos: u64,
+}
+
+enum CommandSource<'a> {
+    Plain(StreamDeserializer<'a, IoRead<&'a mut BufReaderWithPos<Box<dyn VfsFile>>>, Command>),
+    Encrypted(&'a mut BufReaderWithPos<Box<dyn VfsFile>>),
+}
+
+impl<'a> CommandStream<'a> {
+    /// Starts reading a log file, after its header if it is encrypted.
+    fn new(
+        reader: &'a mut BufReaderWithPos<Box<dyn VfsFile>>,
+        keyring: &Keyring,
+        gen: u64,
+    ) -> Result<Self> {
+        let len = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(0))?;
+        let mut header = vec![0; len.min(HEADER_LEN) as usize];
+        reader.read_exact(&mut header)?;
+        let key = keyring.file_key(&header, &format!("{}.log", gen))?;
+        let (source, pos) = match key {
+            Some(_) => (CommandSource::Encrypted(reader), HEADER_LEN),
+            None => {
+                reader.seek(SeekFrom::Start(0))?;
+                (CommandSource::Plain(Deserializer::from_reader(reader).into_iter()), 0)
+            }
+        };
+        Ok(CommandStream { source, key, gen, len, pos })
+    }
+
+    /// Reads the next command, returning where it ends.
+    fn read_next(&mut self) -> Result<Option<(u64, Command)>> {
+        let reader = match &mut self.source {
+            CommandSource::Plain(stream) => {
+                return match stream.next() {
+                    Some(Ok(cmd)) => Ok(Some((stream.byte_offset() as u64, cmd))),
+                    Some(Err(err)) if err.is_eof() => Ok(None),
+                    Some(Err(err)) => Err(err.into()),
+                    None => Ok(None),
+                }
+            }
+            CommandSource::Encrypted(reader) => reader,
+        };
+        if self.len - self.pos < 4 {
+            return Ok(None);
+        }
+        let mut len = [0; 4];
+        reader.read_exact(&mut len)?;
+        let record_len = crypt::record_len(len);
+        let key = self.key.as_ref().expect("encrypted file has a key");
+        if self.len - self.pos < record_len {
+            let mut rest = Vec::new();
+            reader.read_to_end(&mut rest)?;
+            if crypt::has_record(key, &rest, self.gen, self.pos + 4) {
+                return Err(KvsError::Encryption(format!(
+                    "record at {} of {}.log has a corrupt length",
+                    self.pos, self.gen
+                )));
+            }
+            return Ok(None);
+        }
+        let mut record = vec![0; record_len as usize];
+        record[..4].copy_from_slice(&len);
+        reader.read_exact(&mut record[4..])?;
+        let cmd = serde_json::from_slice(&crypt::open(key, &record, self.gen, self.pos)?)?;
+        Ok(Some((self.pos + record_len, cmd)))
+    }
+}
+
+impl Iterator for CommandStream<'_> {
+    type Item = Result<(Range<u64>, Command)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.read_next() {
+            Ok(Some((end, cmd))) => {
+                let start = std::mem::replace(&mut self.pos, end);
+                Some(Ok((start..end, cmd)))
+            }
+            Ok(None) => None,
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+fn mark_stale(segments: &mut BTreeMap<u64, Segment>, cmd_pos: &CommandPos) {
+    segments.get_mut(&cmd_pos.gen).expect("Cannot find segment").stale += cmd_pos.len;
 }
 
 fn log_path(dir: &Path, gen: u64) -> PathBuf {
     dir.join(format!("{}.log", gen))
 }
 
-/// Struct representing a command
+/// The sizes of the log of one generation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Segment {
+    // length of the log file
+    len: u64,
+    // bytes of "set" commands replaced or removed since, or torn
+    stale: u64,
+    // bytes of "remove" commands
+    removes: u64,
+}
+
+impl Segment {
+    /// The number of bytes that could be saved by compacting every log.
+    fn uncompacted(&self) -> u64 {
+        self.stale + self.removes
+    }
+
+    /// The fraction of the log taken up by replaced or removed values.
+    fn stale_ratio(&self) -> f64 {
+        if self.len == 0 {
+            0.0
+        } else {
+            self.stale as f64 / self.len as f64
+        }
+    }
+}
+
+/// The generations making up the store, oldest first.
 #[derive(Serialize, Deserialize, Debug)]
+struct Manifest {
+    gens: Vec<u64>,
+}
+
+/// Struct representing a command
+#[derive(Serialize, Deserialize, Debug, Clone)]
 enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+    Set {
+        key: String,
+        // set if the value is compressed, and then written in base64
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        codec: Option<Codec>,
+        value: String,
+    },
+    Remove {
+        key: String,
+    },
+}
+
+/// The value of a `Command`, borrowed from the log where it needed no unescaping.
+#[derive(Deserialize)]
+enum CommandRef<'a> {
+    Set {
+        #[serde(default)]
+        codec: Option<Codec>,
+        #[serde(borrow)]
+        value: Cow<'a, str>,
+    },
+    Remove {},
 }
 
 impl Command {
     fn set(key: String, value: String) -> Command {
-        Command::Set { key, value }
+        Command::Set { key, codec: None, value }
     }
 
     fn remove(key: String) -> Command {
@@ -315,6 +1498,7 @@ impl Command {
 }
 
 /// Represents the position and length of a json-serialized command in the log
+#[derive(Clone)]
 struct CommandPos {
     gen: u64,
     pos: u64,
@@ -334,9 +1518,13 @@ struct BufReaderWithPos<R: Read + Seek> {
 
 impl<R: Read + Seek> BufReaderWithPos<R> {
     fn new(mut inner: R) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+        let pos = inner.stream_position()?;
         Ok(BufReaderWithPos { reader: BufReader::new(inner), pos })
     }
+
+    fn into_inner(self) -> R {
+        self.reader.into_inner()
+    }
 }
 
 impl<R: Read + Seek> Read for BufReaderWithPos<R> {
@@ -361,7 +1549,7 @@ struct BufWriterWithPos<W: Write + Seek> {
 
 impl<W: Write + Seek> BufWriterWithPos<W> {
     fn new(mut inner: W) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+        let pos = inner.stream_position()?;
         Ok(BufWriterWithPos { writer: BufWriter::new(inner), pos })
     }
 }
@@ -378,9 +1566,855 @@ impl<W: Write + Seek> Write for BufWriterWithPos<W> {
     }
 }
 
+impl BufWriterWithPos<Box<dyn VfsFile>> {
+    /// Writes a whole command to the file, bypassing the buffer.
+    ///
+    /// If the write fails, the file is truncated back to where the command started, so that
+    /// later commands are not appended after a partial one.
+    fn append(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.writer.flush()?;
+        let file = self.writer.get_mut();
+        if let Err(err) = file.write_all(buf) {
+            file.set_len(self.pos)?;
+            return Err(err);
+        }
+        self.pos += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Flushes the buffer and the file to durable storage.
+    fn sync(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()
+    }
+}
+
 impl<W: Write + Seek> Seek for BufWriterWithPos<W> {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
         self.pos = self.writer.seek(pos)?;
         Ok(self.pos)
     }
 }
+
+#[cfg(test)]
+struct DiskFixture(tempfile::TempDir, DiskStoreOptions);
+
+#[cfg(test)]
+impl DiskFixture {
+    fn new(options: DiskStoreOptions) -> Self {
+        let temp_dir = tempfile::TempDir::new().expect("unable to create temporary directory");
+        DiskFixture(temp_dir, options)
+    }
+
+    /// Small log files compacted often, so that the tests go through many of them.
+    fn small_segments() -> Self {
+        DiskFixture::new(DiskStoreOptions {
+            max_segment_size: 4 * 1024,
+            compaction_threshold: 16 * 1024,
+            ..DiskStoreOptions::default()
+        })
+    }
+
+    fn mmap() -> Self {
+        DiskFixture::new(DiskStoreOptions { mmap: true, ..DiskFixture::small_segments().1 })
+    }
+
+    fn durable() -> Self {
+        DiskFixture::new(DiskStoreOptions { sync: SyncMode::Always, ..DiskStoreOptions::default() })
+    }
+
+    /// A cache small enough to evict, over log files small enough to get compacted.
+    fn cached() -> Self {
+        let cache = Some(ValueCache::new(64 * 1024));
+        DiskFixture::new(DiskStoreOptions { cache, ..DiskFixture::small_segments().1 })
+    }
+
+    /// Compresses all but the smallest values, in memory-mapped log files.
+    #[cfg(feature = "compression")]
+    fn compressed() -> Self {
+        DiskFixture::new(DiskStoreOptions {
+            compression: Compression::Lz4,
+            compression_threshold: 16,
+            ..DiskFixture::mmap().1
+        })
+    }
+
+    /// Encrypts memory-mapped log files, small enough to get compacted.
+    #[cfg(feature = "encryption")]
+    fn encrypted() -> Self {
+        DiskFixture::new(DiskStoreOptions {
+            encryption_key: Some(EncryptionKey::new(1, [42; 32])),
+            ..DiskFixture::mmap().1
+        })
+    }
+}
+
+#[cfg(test)]
+impl crate::testing::Fixture for DiskFixture {
+    type Store = DiskStore;
+
+    const PERSISTENT: bool = true;
+
+    fn open(&mut self) -> Result<DiskStore> {
+        DiskStore::open_with_options(self.0.path(), self.1.clone())
+    }
+
+    fn compact(store: &mut DiskStore) -> Result<()> {
+        store.compact()
+    }
+}
+
+#[cfg(test)]
+crate::store_test_suite!(
+    suite,
+    DiskFixture::new(DiskStoreOptions::default()),
+    [store, batch, scan, persist, model]
+);
+
+#[cfg(test)]
+crate::store_test_suite!(
+    small_segments,
+    DiskFixture::small_segments(),
+    [store, batch, scan, persist, model]
+);
+
+#[cfg(test)]
+crate::store_test_suite!(mmap, DiskFixture::mmap(), [store, batch, scan, persist, model]);
+
+#[cfg(test)]
+crate::store_test_suite!(durable, DiskFixture::durable(), [store, batch, scan, persist]);
+
+#[cfg(test)]
+crate::store_test_suite!(cached, DiskFixture::cached(), [store, batch, scan, persist, model]);
+
+#[cfg(all(test, feature = "compression"))]
+crate::store_test_suite!(
+    compressed,
+    DiskFixture::compressed(),
+    [store, batch, scan, persist, model]
+);
+
+#[cfg(all(test, feature = "encryption"))]
+crate::store_test_suite!(encrypted, DiskFixture::encrypted(), [store, batch, scan, persist, model]);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{ops, Op};
+    use proptest::prelude::*;
+    use proptest::test_runner::TestCaseError;
+    use tempfile::TempDir;
+
+    /// Bytes in the log files that are not referenced by the index.
+    fn stale_bytes(store: &DiskStore) -> Result<u64> {
+        let path = &store.shared.path;
+        let mut total = 0;
+        for gen in sorted_gen_list(&RealFs, path)? {
+            total += std::fs::metadata(log_path(path, gen))?.len();
+        }
+        let live: u64 = store.shared.index.read().values().map(|cmd_pos| cmd_pos.len).sum();
+        Ok(total - live)
+    }
+
+    /// Checks the sizes kept for every log file against the files themselves.
+    fn check_segments(store: &DiskStore) -> std::result::Result<(), TestCaseError> {
+        let log = store.log.lock();
+        let path = &store.shared.path;
+        let gens: Vec<u64> = log.segments.keys().cloned().collect();
+        prop_assert_eq!(&gens, &sorted_gen_list(&RealFs, path).unwrap());
+        prop_assert_eq!(gens.last(), Some(&log.current_gen));
+        let mut live = BTreeMap::new();
+        for cmd_pos in store.shared.index.read().values() {
+            *live.entry(cmd_pos.gen).or_insert(0) += cmd_pos.len;
+        }
+        for (gen, segment) in &log.segments {
+            let len = std::fs::metadata(log_path(path, *gen)).unwrap().len();
+            prop_assert_eq!(segment.len, len, "length of generation {}", gen);
+            prop_assert_eq!(
+                segment.stale + segment.removes + live.get(gen).unwrap_or(&0),
+                len,
+                "stale bytes of generation {}",
+                gen
+            );
+        }
+        Ok(())
+    }
+
+    fn options() -> DiskStoreOptions {
+        DiskStoreOptions {
+            max_segment_size: 64 * 1024,
+            compaction_threshold: 256 * 1024,
+            ..DiskStoreOptions::default()
+        }
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn uncompacted_matches_stale_bytes(ops in ops()) {
+            let temp_dir = TempDir::new().unwrap();
+            let mut store = DiskStore::open_with_options(temp_dir.path(), options()).unwrap();
+            for op in ops {
+                match op {
+                    Op::Set(key, value) => store.set(&key, &value).unwrap(),
+                    Op::Remove(key) => store.remove(&key).unwrap(),
+                    Op::SetBatch(pairs) => {
+                        let (keys, values): (Vec<_>, Vec<_>) = pairs
+                            .into_iter()
+                            .map(|(key, value)| (key.into_bytes(), value.into_bytes()))
+                            .unzip();
+                        store.set_batch(keys, values).unwrap();
+                    }
+                    Op::RemoveBatch(keys) => {
+                        let keys: Vec<_> = keys.into_iter().map(String::into_bytes).collect();
+                        store.remove_batch(keys).unwrap();
+                    }
+                    Op::Compact => {
+                        store.compact().unwrap();
+                        prop_assert_eq!(store.log.lock().uncompacted, 0);
+                    }
+                    Op::Reopen => {
+                        let uncompacted = store.log.lock().uncompacted;
+                        let mut segments = store.log.lock().segments.clone();
+                        drop(store);
+                        store = DiskStore::open_with_options(temp_dir.path(), options()).unwrap();
+                        let log = store.log.lock();
+                        prop_assert_eq!(log.uncompacted, uncompacted, "changed by reopen");
+                        segments.insert(log.current_gen, Segment::default());
+                        prop_assert_eq!(&log.segments, &segments, "changed by reopen");
+                    }
+                }
+                prop_assert_eq!(store.log.lock().uncompacted, stale_bytes(&store).unwrap());
+                check_segments(&store)?;
+            }
+        }
+    }
+
+    #[test]
+    fn open_without_manifest() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let mut store = DiskStore::open(temp_dir.path())?;
+        store.set("a", "1")?;
+        store.compact()?;
+        store.set("b", "2")?;
+        drop(store);
+
+        // stores written before the manifest existed replay every generation
+        std::fs::remove_file(temp_dir.path().join(MANIFEST))?;
+        let store = DiskStore::open(temp_dir.path())?;
+        assert_eq!(store.get("a")?, Some(b"1".to_vec()));
+        assert_eq!(store.get("b")?, Some(b"2".to_vec()));
+        assert!(temp_dir.path().join(MANIFEST).exists());
+        Ok(())
+    }
+
+    #[cfg(not(feature = "compression"))]
+    #[test]
+    fn compression_feature_off() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let value = r#"{"Set":{"key":"a","codec":"Lz4","value":"AAAA"}}"#;
+        std::fs::write(log_path(temp_dir.path(), 1), value)?;
+        let store = DiskStore::open(temp_dir.path())?;
+        assert!(matches!(store.get("a"), Err(KvsError::InvalidData(_))));
+        Ok(())
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    #[test]
+    fn encryption_feature_off() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let key = Some(EncryptionKey::new(1, [1; 32]));
+        let options = DiskStoreOptions { encryption_key: key, ..DiskStoreOptions::default() };
+        assert!(matches!(
+            DiskStore::open_with_options(temp_dir.path(), options),
+            Err(KvsError::Encryption(_))
+        ));
+
+        std::fs::write(log_path(temp_dir.path(), 1), [&b"ritekv\0E"[..], &[0; 32]].concat())?;
+        assert!(matches!(DiskStore::open(temp_dir.path()), Err(KvsError::Encryption(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn compacts_only_stale_segments() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let options = DiskStoreOptions {
+            max_segment_size: 1024,
+            compaction_threshold: 4 * 1024,
+            ..DiskStoreOptions::default()
+        };
+        let mut store = DiskStore::open_with_options(temp_dir.path(), options.clone())?;
+        for i in 0..20 {
+            store.set(format!("cold{}", i), "c".repeat(100))?;
+        }
+        store.remove("cold0")?;
+        let gens =
+            |store: &DiskStore| -> Vec<u64> { store.log.lock().segments.keys().cloned().collect() };
+        let cold_gens: Vec<u64> = gens(&store).into_iter().take(2).collect();
+        for i in 0..1000 {
+            store.set("hot", format!("value{}", i))?;
+        }
+
+        // the logs holding the cold keys are left alone, while the overwritten values are dropped
+        assert_eq!(gens(&store)[..2], cold_gens[..]);
+        assert!(gens(&store).len() < 20, "{} log files", gens(&store).len());
+        drop(store);
+
+        let store = DiskStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.get("cold0")?, None);
+        assert_eq!(store.get("cold19")?, Some("c".repeat(100).into_bytes()));
+        assert_eq!(store.get("hot")?, Some(b"value999".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_readers_and_writer() -> Result<()> {
+        const READERS: usize = 4;
+        const KEYS: usize = 50;
+        const ROUNDS: usize = 40;
+
+        let temp_dir = TempDir::new()?;
+        let options = DiskStoreOptions {
+            max_segment_size: 4 * 1024,
+            compaction_threshold: 16 * 1024,
+            ..DiskStoreOptions::default()
+        };
+        let mut store = DiskStore::open_with_options(temp_dir.path(), options)?;
+        for i in 0..KEYS {
+            store.set(format!("key{}", i), format!("key{}-0", i))?;
+        }
+
+        let readers: Vec<_> = (0..READERS)
+            .map(|_| {
+                let store = store.clone();
+                std::thread::spawn(move || -> Result<()> {
+                    for _ in 0..ROUNDS {
+                        for i in 0..KEYS {
+                            let key = format!("key{}", i);
+                            let value = store.get(&key)?.expect("key not found");
+                            let value = String::from_utf8(value).expect("value is not UTF-8");
+                            assert!(value.starts_with(&format!("{}-", key)), "{}", value);
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+
+        // the readers keep going while values are replaced and compacted away
+        for round in 1..=ROUNDS {
+            for i in 0..KEYS {
+                store.set(format!("key{}", i), format!("key{}-{}", i, round))?;
+            }
+            if round % 10 == 0 {
+                store.compact()?;
+            }
+        }
+        for reader in readers {
+            reader.join().expect("reader thread panicked")?;
+        }
+
+        for i in 0..KEYS {
+            assert_eq!(
+                store.get(format!("key{}", i))?,
+                Some(format!("key{}-{}", i, ROUNDS).into_bytes())
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn mapped_values_outlive_compaction() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let options = DiskStoreOptions { mmap: true, ..DiskStoreOptions::default() };
+        let mut store = DiskStore::open_with_options(temp_dir.path(), options.clone())?;
+        store.set("plain", "value")?;
+        store.set("escaped", "\"quoted\"")?;
+        drop(store);
+
+        let store = DiskStore::open_with_options(temp_dir.path(), options)?;
+        let plain = store.get_value("plain")?.expect("key not found");
+        let escaped = store.get_value("escaped")?.expect("key not found");
+        assert!(matches!(plain.0, ValueRepr::Mapped(..)));
+        assert!(matches!(escaped.0, ValueRepr::Owned(..)));
+
+        // the log file is removed, but the value stays readable
+        store.compact()?;
+        assert_eq!(sorted_gen_list(&RealFs, temp_dir.path())?.len(), 2);
+        assert_eq!(&*plain, b"value");
+        assert_eq!(&*escaped, b"\"quoted\"");
+        assert_eq!(store.get("plain")?, Some(b"value".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn async_writes() -> Result<()> {
+        use crate::storage::pool::block_on;
+        use crate::storage::{AsyncBatchStore, AsyncStore};
+
+        let temp_dir = TempDir::new()?;
+        let options = DiskStoreOptions { sync: SyncMode::Always, ..DiskStoreOptions::default() };
+        let store = DiskStore::open_with_options(temp_dir.path(), options.clone())?;
+        let writer = {
+            let mut store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for i in 0..100 {
+                    Store::set(&mut store, format!("sync{}", i), "value")?;
+                }
+                Ok(())
+            })
+        };
+        // every async write is queued before any is awaited, so they commit in groups
+        let mut clones = vec![store.clone(); 100];
+        let tasks: Vec<_> = clones
+            .iter_mut()
+            .enumerate()
+            .map(|(i, store)| AsyncStore::set(store, format!("async{}", i), "value"))
+            .collect();
+        for task in tasks {
+            block_on(task)?;
+        }
+        writer.join().expect("writer thread panicked")?;
+
+        let mut store = store;
+        block_on(AsyncStore::remove(&mut store, "async0"))?;
+        // a remove queued right behind the set of its key removes it
+        let (mut setter, mut remover) = (store.clone(), store.clone());
+        let set = AsyncStore::set(&mut setter, "queued", "value");
+        let remove = AsyncStore::remove(&mut remover, "queued");
+        block_on(set)?;
+        block_on(remove)?;
+        assert_eq!(Store::get(&store, "queued")?, None);
+        let keys = vec![b"async1".to_vec(), b"async2".to_vec()];
+        block_on(AsyncBatchStore::remove_batch(&mut store, &keys))?;
+        assert!(matches!(
+            block_on(AsyncStore::set(&mut store, "key", [0xff])),
+            Err(KvsError::InvalidData(_))
+        ));
+        drop(store);
+
+        let store = DiskStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(block_on(AsyncStore::get(&store, "async0"))?, None);
+        assert_eq!(block_on(AsyncStore::get(&store, "queued"))?, None);
+        for i in 3..100 {
+            assert_eq!(
+                block_on(AsyncStore::get(&store, format!("async{}", i)))?,
+                Some(b"value".to_vec())
+            );
+            assert_eq!(Store::get(&store, format!("sync{}", i))?, Some(b"value".to_vec()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn async_writes_with_a_busy_pool() -> Result<()> {
+        use crate::storage::pool::block_on;
+        use crate::storage::AsyncStore;
+        use std::sync::Barrier;
+
+        let temp_dir = TempDir::new()?;
+        let mut store = DiskStore::open(temp_dir.path())?;
+        // every thread of the shared pool writes, but only after an async write queued its
+        // leader, so the writers wait on a leader that no thread of the pool is free to run
+        let pool = ThreadPool::shared();
+        let (started, release) = (
+            Arc::new(Barrier::new(pool.threads() + 1)),
+            Arc::new(Barrier::new(pool.threads() + 1)),
+        );
+        let tasks: Vec<_> = (0..pool.threads())
+            .map(|i| {
+                let (started, release, mut store) =
+                    (started.clone(), release.clone(), store.clone());
+                pool.run(move || {
+                    started.wait();
+                    release.wait();
+                    Store::set(&mut store, format!("pool{}", i), "value")
+                })
+            })
+            .collect();
+        started.wait();
+        let task = AsyncStore::set(&mut store, "async", "value");
+        release.wait();
+        for task in tasks {
+            block_on(task)?;
+        }
+        block_on(task)?;
+        assert_eq!(Store::get(&store, "async")?, Some(b"value".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn panicking_write() -> Result<()> {
+        use crate::storage::pool::block_on;
+        use crate::storage::AsyncStore;
+
+        let temp_dir = TempDir::new()?;
+        let mut store = DiskStore::open(temp_dir.path())?;
+        Store::set(&mut store, "key", "value")?;
+        // without its segment, applying a write to the index panics
+        let segments = std::mem::take(&mut store.log.lock().segments);
+        assert!(matches!(Store::set(&mut store, "key", "panics"), Err(KvsError::Internal(_))));
+
+        // the leader is gone, but the next writers are not left waiting for it
+        assert!(matches!(Store::set(&mut store, "key", "panics"), Err(KvsError::Internal(_))));
+        let task = AsyncStore::set(&mut store, "key", "panics");
+        assert!(matches!(block_on(task), Err(KvsError::Internal(_))));
+
+        store.log.lock().segments = segments;
+        Store::set(&mut store, "key", "value")?;
+        assert_eq!(Store::get(&store, "key")?, Some(b"value".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn shared_cache() -> Result<()> {
+        let (dir_a, dir_b) = (TempDir::new()?, TempDir::new()?);
+        let cache = ValueCache::new(1024 * 1024);
+        let options =
+            DiskStoreOptions { cache: Some(cache.clone()), ..DiskStoreOptions::default() };
+        let mut a = DiskStore::open_with_options(dir_a.path(), options.clone())?;
+        let mut b = DiskStore::open_with_options(dir_b.path(), options)?;
+        a.set("key", "a")?;
+        b.set("key", "b")?;
+
+        // the stores share the cache, but not their values
+        for _ in 0..3 {
+            assert_eq!(a.get("key")?, Some(b"a".to_vec()));
+            assert_eq!(b.get("key")?, Some(b"b".to_vec()));
+        }
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (4, 2, 2));
+
+        // compaction moves the value, and drops the cached copy of the old one
+        a.compact()?;
+        assert_eq!(cache.stats().entries, 1);
+        assert_eq!(a.get("key")?, Some(b"a".to_vec()));
+        assert_eq!(cache.stats().misses, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn memory_usage() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let cache = Some(ValueCache::new(1024 * 1024));
+        let mut store = DiskStore::open_with_options(
+            temp_dir.path(),
+            DiskStoreOptions { cache, ..DiskStoreOptions::default() },
+        )?;
+        for i in 0..100 {
+            store.set(format!("key{:02}", i), "v".repeat(100))?;
+        }
+        for i in 0..10 {
+            store.get(format!("key{:02}", i))?;
+        }
+        let usage = store.memory_usage();
+        assert_eq!((usage.entries, usage.values), (100, 10 * 100));
+        assert!(usage.index > 5 * 100);
+        assert!(usage.buffers > 0);
+        Ok(())
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn mixed_compression() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let document = |i: usize| format!(r#"{{"id":{},"name":"user {}","tags":["a","b"]}}"#, i, i);
+        let samples: Vec<_> = (0..500).map(document).collect();
+        let compressions = [
+            Compression::None,
+            Compression::Lz4,
+            Compression::Zstd(3),
+            Compression::zstd_dictionary(3, &samples, 4096)?,
+        ];
+        for (i, compression) in compressions.iter().enumerate() {
+            let options = DiskStoreOptions {
+                compression: compression.clone(),
+                compression_threshold: 0,
+                ..DiskStoreOptions::default()
+            };
+            let mut store = DiskStore::open_with_options(temp_dir.path(), options)?;
+            store.set(format!("key{}", i), document(i).repeat(4))?;
+        }
+
+        // every value stays readable, whatever the store compresses new ones with
+        let store = DiskStore::open(temp_dir.path())?;
+        for i in 0..compressions.len() {
+            assert_eq!(store.get(format!("key{}", i))?, Some(document(i).repeat(4).into_bytes()));
+        }
+        store.compact()?;
+        drop(store);
+        let store = DiskStore::open(temp_dir.path())?;
+        for i in 0..compressions.len() {
+            assert_eq!(store.get(format!("key{}", i))?, Some(document(i).repeat(4).into_bytes()));
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compression_shrinks_log() -> Result<()> {
+        let log_size = |compression| -> Result<u64> {
+            let temp_dir = TempDir::new()?;
+            let options = DiskStoreOptions { compression, ..DiskStoreOptions::default() };
+            let mut store = DiskStore::open_with_options(temp_dir.path(), options)?;
+            for i in 0..100 {
+                let value = format!(r#"{{"id":{},"payload":"{}"}}"#, i, "abcd".repeat(100));
+                store.set(format!("key{}", i), value)?;
+            }
+            Ok(std::fs::metadata(log_path(temp_dir.path(), 1))?.len())
+        };
+        let raw = log_size(Compression::None)?;
+        assert!(log_size(Compression::Lz4)? * 3 < raw);
+        assert!(log_size(Compression::Zstd(3))? * 3 < raw);
+        Ok(())
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn encrypted_at_rest() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let key = EncryptionKey::new(1, [1; 32]);
+        let options = DiskStoreOptions { encryption_key: Some(key), ..DiskStoreOptions::default() };
+        let mut store = DiskStore::open_with_options(temp_dir.path(), options)?;
+        store.set("name", "Alice Example")?;
+        drop(store);
+
+        let log = std::fs::read(log_path(temp_dir.path(), 1))?;
+        assert!(!log.windows(5).any(|window| window == b"Alice"));
+        assert!(!log.windows(4).any(|window| window == b"name"));
+
+        // no key, or the wrong one
+        let err = DiskStore::open(temp_dir.path()).err().expect("key is required");
+        assert!(matches!(err, KvsError::Encryption(_)), "{}", err);
+        let wrong = EncryptionKey::new(1, [2; 32]);
+        let options =
+            DiskStoreOptions { encryption_key: Some(wrong), ..DiskStoreOptions::default() };
+        let err = DiskStore::open_with_options(temp_dir.path(), options).err().expect("wrong key");
+        assert!(matches!(err, KvsError::Encryption(_)), "{}", err);
+        Ok(())
+    }
+
+    #[cfg(all(feature = "compression", feature = "encryption"))]
+    #[test]
+    fn encrypted_dictionary() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let document = |i: usize| format!(r#"{{"id":{},"name":"user {}"}}"#, i, i);
+        let samples: Vec<_> = (0..500).map(document).collect();
+        let key = EncryptionKey::new(1, [1; 32]);
+        let options = DiskStoreOptions {
+            compression: Compression::zstd_dictionary(3, &samples, 4096)?,
+            compression_threshold: 0,
+            encryption_key: Some(key.clone()),
+            ..DiskStoreOptions::default()
+        };
+        let mut store = DiskStore::open_with_options(temp_dir.path(), options)?;
+        store.set("key", document(1))?;
+        drop(store);
+
+        // the dictionary is learned from the values, so it is as secret as they are
+        let dicts: Vec<_> = RealFs
+            .list_files(temp_dir.path())?
+            .into_iter()
+            .filter(|path| path.extension() == Some("dict".as_ref()))
+            .collect();
+        assert_eq!(dicts.len(), 1);
+        assert!(!std::fs::read(&dicts[0])?.windows(4).any(|window| window == b"user"));
+
+        let err = DiskStore::open(temp_dir.path()).err().expect("key is required");
+        assert!(matches!(err, KvsError::Encryption(_)), "{}", err);
+        let options = DiskStoreOptions { encryption_key: Some(key), ..DiskStoreOptions::default() };
+        let store = DiskStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.get("key")?, Some(document(1).into_bytes()));
+        Ok(())
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn moved_records() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let key = EncryptionKey::new(1, [1; 32]);
+        let options = DiskStoreOptions { encryption_key: Some(key), ..DiskStoreOptions::default() };
+        let mut store = DiskStore::open_with_options(temp_dir.path(), options.clone())?;
+        store.set("name", "Alice Example")?;
+        store.set("name", "Bob")?;
+        drop(store);
+
+        // replaying the first record at the end of the log would bring the old value back
+        let path = log_path(temp_dir.path(), 1);
+        let mut log = std::fs::read(&path)?;
+        let start = HEADER_LEN as usize;
+        let mut len = [0; 4];
+        len.copy_from_slice(&log[start..start + 4]);
+        let first = log[start..start + crypt::record_len(len) as usize].to_vec();
+        log.extend_from_slice(&first);
+        std::fs::write(&path, log)?;
+        let err = DiskStore::open_with_options(temp_dir.path(), options).err().expect("moved");
+        assert!(matches!(err, KvsError::Encryption(_)), "{}", err);
+        Ok(())
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn corrupt_record_length() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let key = EncryptionKey::new(1, [1; 32]);
+        let options = DiskStoreOptions { encryption_key: Some(key), ..DiskStoreOptions::default() };
+        let mut store = DiskStore::open_with_options(temp_dir.path(), options.clone())?;
+        store.set("a", "1")?;
+        store.set("b", "2")?;
+        drop(store);
+        let path = log_path(temp_dir.path(), 1);
+        let log = std::fs::read(&path)?;
+
+        // a length running past the end of the file, with an intact record after it
+        let mut corrupt = log.clone();
+        corrupt[HEADER_LEN as usize + 3] = 0xff;
+        std::fs::write(&path, corrupt)?;
+        let err = DiskStore::open_with_options(temp_dir.path(), options.clone()).err();
+        assert!(matches!(err, Some(KvsError::Encryption(_))), "{:?}", err);
+
+        // the last record cut short by a crash
+        std::fs::write(&path, &log[..log.len() - 1])?;
+        let store = DiskStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.get("a")?, Some(b"1".to_vec()));
+        assert_eq!(store.get("b")?, None);
+        Ok(())
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn rotate_key() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let (old, new) = (EncryptionKey::new(1, [1; 32]), EncryptionKey::new(2, [2; 32]));
+        let options =
+            DiskStoreOptions { encryption_key: Some(old.clone()), ..DiskStoreOptions::default() };
+        let mut store = DiskStore::open_with_options(temp_dir.path(), options)?;
+        store.set("key1", "value1")?;
+        drop(store);
+
+        let options = DiskStoreOptions {
+            encryption_key: Some(new.clone()),
+            previous_keys: vec![old],
+            ..DiskStoreOptions::default()
+        };
+        let mut store = DiskStore::open_with_options(temp_dir.path(), options)?;
+        store.set("key2", "value2")?;
+        store.compact()?;
+        drop(store);
+
+        // everything is rewritten with the new key, the old one is no longer needed
+        let options = DiskStoreOptions { encryption_key: Some(new), ..DiskStoreOptions::default() };
+        let store = DiskStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.get("key1")?, Some(b"value1".to_vec()));
+        assert_eq!(store.get("key2")?, Some(b"value2".to_vec()));
+        Ok(())
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn require_encryption() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let mut store = DiskStore::open(temp_dir.path())?;
+        store.set("key", "value")?;
+        drop(store);
+
+        let key = EncryptionKey::new(1, [1; 32]);
+        let required = DiskStoreOptions {
+            encryption_key: Some(key.clone()),
+            require_encryption: true,
+            ..DiskStoreOptions::default()
+        };
+        let err = DiskStore::open_with_options(temp_dir.path(), required.clone()).err();
+        assert!(matches!(err, Some(KvsError::Encryption(_))), "{:?}", err);
+        let err = DiskStore::open_with_options(
+            temp_dir.path(),
+            DiskStoreOptions { require_encryption: true, ..DiskStoreOptions::default() },
+        )
+        .err();
+        assert!(matches!(err, Some(KvsError::Encryption(_))), "{:?}", err);
+
+        // migrated by a compaction with the key, but without requiring it
+        let options = DiskStoreOptions { encryption_key: Some(key), ..DiskStoreOptions::default() };
+        DiskStore::open_with_options(temp_dir.path(), options)?.compact()?;
+        let store = DiskStore::open_with_options(temp_dir.path(), required)?;
+        assert_eq!(store.get("key")?, Some(b"value".to_vec()));
+        Ok(())
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn checkpoint_during_writes() -> Result<()> {
+        let (temp_dir, dest) = (TempDir::new()?, TempDir::new()?);
+        let options = DiskStoreOptions {
+            encryption_key: Some(EncryptionKey::new(1, [1; 32])),
+            ..DiskFixture::small_segments().1
+        };
+        let store = DiskStore::open_with_options(temp_dir.path(), options.clone())?;
+        let writer = {
+            let mut store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for i in 0..2000 {
+                    // "last" is overwritten all along, so that compactions run as well
+                    let keys = [format!("key{}", i).into_bytes(), b"last".to_vec()];
+                    store.set_batch(
+                        keys,
+                        [format!("value{}", i).into_bytes(), i.to_string().into_bytes()],
+                    )?;
+                }
+                Ok(())
+            })
+        };
+        while store.get("key500")?.is_none() {
+            std::thread::yield_now();
+        }
+        store.checkpoint(dest.path())?;
+        writer.join().expect("writer panicked")?;
+
+        // the writes up to some point, and none after it
+        let copy = DiskStore::open_with_options(dest.path(), options)?;
+        let mut count = 0;
+        while copy.get(format!("key{}", count))?.is_some() {
+            count += 1;
+        }
+        assert!(count > 500, "{} keys", count);
+        for i in count..2000 {
+            assert_eq!(copy.get(format!("key{}", i))?, None);
+        }
+        assert_eq!(copy.get("last")?, Some((count - 1).to_string().into_bytes()));
+        assert_eq!(
+            copy.get(format!("key{}", count - 1))?,
+            Some(format!("value{}", count - 1).into_bytes())
+        );
+
+        // the destination must be empty
+        let err = store.checkpoint(dest.path()).expect_err("destination is not empty");
+        assert!(matches!(err, KvsError::InvalidData(_)), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn reopen_after_compaction() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let mut store = DiskStore::open(temp_dir.path())?;
+        for i in 0..100 {
+            store.set(format!("key{}", i % 10), format!("value{}", i))?;
+        }
+        store.remove("key0")?;
+        store.compact()?;
+        store.set("key1", "after compaction")?;
+        drop(store);
+
+        let store = DiskStore::open(temp_dir.path())?;
+        assert_eq!(sorted_gen_list(&RealFs, temp_dir.path())?.len(), 3);
+        assert_eq!(store.get("key0")?, None);
+        assert_eq!(store.get("key1")?, Some(b"after compaction".to_vec()));
+        for i in 2..10 {
+            assert_eq!(
+                store.get(format!("key{}", i))?,
+                Some(format!("value{}", 90 + i).into_bytes())
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,346 @@
+//! Tests of how `DiskStore` copes with failing I/O and crashes, run on a simulated file system.
+
+#[cfg(feature = "encryption")]
+use super::EncryptionKey;
+use super::{log_path, DiskStore, DiskStoreOptions, SyncMode};
+use crate::result::Result;
+use crate::storage::sim::{Fault, FsOp, SimFs};
+use crate::storage::Store;
+
+use std::path::Path;
+
+const DIR: &str = "/db";
+
+fn open(fs: &SimFs) -> Result<DiskStore> {
+    DiskStore::open_with_vfs(DIR, Box::new(fs.clone()), DiskStoreOptions::default())
+}
+
+fn assert_contents(store: &DiskStore, expected: &[(&str, Option<&str>)]) -> Result<()> {
+    for &(key, value) in expected {
+        assert_eq!(store.get(key)?, value.map(|v| v.as_bytes().to_vec()), "key {}", key);
+    }
+    Ok(())
+}
+
+#[test]
+fn no_space_on_write() -> Result<()> {
+    let fs = SimFs::new();
+    let mut store = open(&fs)?;
+    store.set("a", "1")?;
+    store.set("b", "2")?;
+
+    fs.inject(FsOp::Write, 0, Fault::NoSpace);
+    assert!(store.set("c", "3").is_err());
+    assert!(!store.contains("c")?);
+
+    // the store keeps working once there is space again
+    store.set("d", "4")?;
+    assert_contents(&store, &[("a", Some("1")), ("b", Some("2")), ("c", None), ("d", Some("4"))])?;
+    drop(store);
+
+    let store = open(&fs)?;
+    assert_contents(&store, &[("a", Some("1")), ("b", Some("2")), ("c", None), ("d", Some("4"))])
+}
+
+#[test]
+fn short_write_is_rolled_back() -> Result<()> {
+    let fs = SimFs::new();
+    let mut store = open(&fs)?;
+    store.set("a", "1")?;
+    let log = log_path(Path::new(DIR), store.log.lock().current_gen);
+    let len = fs.read(&log).unwrap().len();
+
+    fs.inject(FsOp::Write, 0, Fault::ShortWrite);
+    assert!(store.remove("a").is_err());
+    assert_eq!(fs.read(&log).unwrap().len(), len, "partial command left in the log");
+    assert_contents(&store, &[("a", Some("1"))])?;
+
+    store.set("b", "2")?;
+    drop(store);
+
+    let store = open(&fs)?;
+    assert_contents(&store, &[("a", Some("1")), ("b", Some("2"))])
+}
+
+#[test]
+fn crash_during_write() -> Result<()> {
+    let fs = SimFs::new();
+    let mut store = open(&fs)?;
+    store.set("a", "1")?;
+    store.set("b", "2")?;
+
+    fs.inject(FsOp::Write, 0, Fault::TornWrite);
+    assert!(store.set("a", "3").is_err());
+    drop(store);
+    fs.recover();
+
+    // the torn command is skipped, and the store can be written to again
+    let mut store = open(&fs)?;
+    assert_contents(&store, &[("a", Some("1")), ("b", Some("2"))])?;
+    store.set("c", "3")?;
+    drop(store);
+
+    let store = open(&fs)?;
+    assert_contents(&store, &[("a", Some("1")), ("b", Some("2")), ("c", Some("3"))])
+}
+
+#[test]
+fn failed_sync() -> Result<()> {
+    let fs = SimFs::new();
+    let mut store = open(&fs)?;
+    store.set("a", "1")?;
+
+    fs.inject(FsOp::Sync, 0, Fault::Error);
+    assert!(store.sync().is_err());
+    store.sync()?;
+    fs.power_loss();
+    drop(store);
+
+    let store = open(&fs)?;
+    assert_contents(&store, &[("a", Some("1"))])
+}
+
+#[test]
+fn durable_writes_survive_power_loss() -> Result<()> {
+    let fs = SimFs::new();
+    let options = DiskStoreOptions { sync: SyncMode::Always, ..DiskStoreOptions::default() };
+    let mut store = DiskStore::open_with_vfs(DIR, Box::new(fs.clone()), options.clone())?;
+    let threads: Vec<_> = (0..4)
+        .map(|t| {
+            let mut store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for i in 0..20 {
+                    store.set(format!("key{}-{}", t, i), "1")?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().expect("writer thread panicked")?;
+    }
+
+    // a write failing to sync reports it, to every writer of its group
+    fs.inject(FsOp::Sync, 0, Fault::Error);
+    assert!(store.set("unsynced", "1").is_err());
+    store.remove("key0-0")?;
+    fs.power_loss();
+    drop(store);
+
+    let store = DiskStore::open_with_vfs(DIR, Box::new(fs.clone()), options)?;
+    assert_contents(&store, &[("key0-0", None), ("key0-1", Some("1")), ("key3-19", Some("1"))])
+}
+
+#[test]
+fn power_loss_keeps_synced_commands() -> Result<()> {
+    let fs = SimFs::new();
+    let mut store = open(&fs)?;
+    store.set("a", "1")?;
+    store.set("b", "2")?;
+    store.sync()?;
+    store.set("a", "3")?;
+    store.remove("b")?;
+    store.set("c", "4")?;
+    fs.power_loss();
+    drop(store);
+
+    let store = open(&fs)?;
+    assert_contents(&store, &[("a", Some("1")), ("b", Some("2")), ("c", None)])
+}
+
+#[test]
+fn crash_before_removing_stale_generations() -> Result<()> {
+    let fs = SimFs::new();
+    let mut store = open(&fs)?;
+    for i in 0..50 {
+        store.set(format!("key{}", i % 5), format!("value{}", i))?;
+    }
+    store.remove("key0")?;
+
+    // the compaction file is written, but the process dies before the first stale
+    // generation is removed
+    fs.inject(FsOp::Remove, 0, Fault::Crash);
+    assert!(store.compact().is_err());
+    drop(store);
+    fs.recover();
+
+    let store = open(&fs)?;
+    assert_contents(
+        &store,
+        &[
+            ("key0", None),
+            ("key1", Some("value46")),
+            ("key2", Some("value47")),
+            ("key3", Some("value48")),
+            ("key4", Some("value49")),
+        ],
+    )
+}
+
+#[test]
+fn crash_between_stale_generation_removals() -> Result<()> {
+    let fs = SimFs::new();
+    let mut store = open(&fs)?;
+    for i in 0..10 {
+        store.set(format!("key{}", i % 3), format!("value{}", i))?;
+        drop(store);
+        // every reopen starts a new generation
+        store = open(&fs)?;
+    }
+
+    fs.inject(FsOp::Remove, 4, Fault::Crash);
+    assert!(store.compact().is_err());
+    drop(store);
+    fs.recover();
+
+    let store = open(&fs)?;
+    assert_contents(
+        &store,
+        &[("key0", Some("value9")), ("key1", Some("value7")), ("key2", Some("value8"))],
+    )
+}
+
+#[test]
+fn power_loss_after_compaction() -> Result<()> {
+    let fs = SimFs::new();
+    let mut store = open(&fs)?;
+    for i in 0..50 {
+        store.set(format!("key{}", i % 5), format!("value{}", i))?;
+    }
+
+    // nothing was synced, but the compaction syncs what it copies before the stale
+    // generations go away
+    fs.inject(FsOp::Remove, 0, Fault::Crash);
+    assert!(store.compact().is_err());
+    drop(store);
+    fs.power_loss();
+
+    let store = open(&fs)?;
+    assert_contents(
+        &store,
+        &[
+            ("key0", Some("value45")),
+            ("key1", Some("value46")),
+            ("key2", Some("value47")),
+            ("key3", Some("value48")),
+            ("key4", Some("value49")),
+        ],
+    )
+}
+
+#[test]
+fn crash_before_manifest_update() -> Result<()> {
+    let fs = SimFs::new();
+    let mut store = open(&fs)?;
+    for i in 0..20 {
+        store.set(format!("key{}", i % 4), format!("value{}", i))?;
+    }
+    store.remove("key0")?;
+    drop(store);
+    let store = open(&fs)?;
+    let files_before = fs.files();
+
+    fs.inject(FsOp::Rename, 0, Fault::Crash);
+    assert!(store.compact().is_err());
+    drop(store);
+    fs.recover();
+
+    // the half-done compaction is thrown away
+    let store = open(&fs)?;
+    let gen = store.log.lock().current_gen;
+    let mut files = fs.files();
+    files.retain(|file| file != &log_path(Path::new(DIR), gen));
+    assert_eq!(files, files_before);
+    assert_contents(
+        &store,
+        &[
+            ("key0", None),
+            ("key1", Some("value17")),
+            ("key2", Some("value18")),
+            ("key3", Some("value19")),
+        ],
+    )
+}
+
+#[test]
+fn failed_compaction_is_retried() -> Result<()> {
+    let fs = SimFs::new();
+    let mut store = open(&fs)?;
+    for i in 0..20 {
+        store.set(format!("key{}", i % 4), format!("value{}", i))?;
+    }
+
+    fs.inject(FsOp::Rename, 0, Fault::Error);
+    assert!(store.compact().is_err());
+    assert_contents(&store, &[("key0", Some("value16")), ("key3", Some("value19"))])?;
+
+    // the store keeps writing to its old log, and the next compaction starts over
+    store.set("key0", "after")?;
+    store.compact()?;
+    store.set("key1", "compacted")?;
+    assert_contents(&store, &[("key0", Some("after")), ("key1", Some("compacted"))])?;
+    drop(store);
+
+    let store = open(&fs)?;
+    assert_contents(
+        &store,
+        &[
+            ("key0", Some("after")),
+            ("key1", Some("compacted")),
+            ("key2", Some("value18")),
+            ("key3", Some("value19")),
+        ],
+    )
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn crash_during_encrypted_write() -> Result<()> {
+    let fs = SimFs::new();
+    let options = || DiskStoreOptions {
+        encryption_key: Some(EncryptionKey::new(1, [9; 32])),
+        ..DiskStoreOptions::default()
+    };
+    let open = || DiskStore::open_with_vfs(DIR, Box::new(fs.clone()), options());
+    let mut store = open()?;
+    store.set("a", "1")?;
+
+    fs.inject(FsOp::Write, 0, Fault::TornWrite);
+    assert!(store.set("b", "2").is_err());
+    drop(store);
+    fs.recover();
+
+    // the torn record is skipped like a torn command
+    let mut store = open()?;
+    assert_contents(&store, &[("a", Some("1")), ("b", None)])?;
+    store.set("c", "3")?;
+    store.compact()?;
+    drop(store);
+
+    let store = open()?;
+    assert_contents(&store, &[("a", Some("1")), ("b", None), ("c", Some("3"))])
+}
+
+#[test]
+fn failed_compaction_does_not_fail_writes() -> Result<()> {
+    let fs = SimFs::new();
+    let options = DiskStoreOptions { compaction_threshold: 100, ..DiskStoreOptions::default() };
+    let mut store = DiskStore::open_with_vfs(DIR, Box::new(fs.clone()), options.clone())?;
+
+    // the compaction a write starts fails to write its manifest, but the write is in the log
+    fs.inject(FsOp::Rename, 0, Fault::Error);
+    let mut i = 0;
+    while store.take_maintenance_error().is_none() {
+        store.set("key", format!("value{}", i))?;
+        i += 1;
+    }
+    assert_contents(&store, &[("key", Some(&format!("value{}", i - 1)))])?;
+    assert!(store.take_maintenance_error().is_none(), "the error is taken");
+
+    // and the next write compacts
+    store.set("key", "last")?;
+    assert!(store.take_maintenance_error().is_none());
+    drop(store);
+    let store = DiskStore::open_with_vfs(DIR, Box::new(fs.clone()), options)?;
+    assert_contents(&store, &[("key", Some("last"))])
+}
@@ -0,0 +1,295 @@
+//! Encryption of log files at rest.
+//!
+//! An encrypted log file starts with a header naming the key it is encrypted with, then every
+//! command is sealed on its own with ChaCha20-Poly1305 and a random nonce:
+//!
+//! ```text
+//! header := magic:[u8; 8] key_id:u32 nonce:[u8; 12] tag:[u8; 16]
+//! record := len:u32 nonce:[u8; 12] ciphertext tag:[u8; 16]     (len counts what follows it)
+//! ```
+//!
+//! The tag of the header seals no data, it only checks the key. The tag of a record also
+//! covers the generation of its file and its position in it, so that a record copied
+//! elsewhere does not open. Plain log files hold JSON, which never starts with the magic.
+//!
+//! Other files, such as compression dictionaries, are encrypted whole, as a header and a
+//! single record, sealed as if it was in a log file of generation 0, which no log file has.
+
+use crate::result::{KvsError, Result};
+
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+const MAGIC: &[u8; 8] = b"ritekv\0E";
+const TAG_LEN: usize = 16;
+pub(super) const HEADER_LEN: u64 = (MAGIC.len() + 4 + NONCE_LEN + TAG_LEN) as u64;
+
+/// A key to encrypt log files with.
+///
+/// Its id is written in the header of every file encrypted with it, to tell which key the
+/// file needs. The id should change whenever the key does.
+#[derive(Clone)]
+pub struct EncryptionKey {
+    id: u32,
+    key: LessSafeKey,
+}
+
+impl EncryptionKey {
+    /// Creates a key from 32 bytes, which should come from a secure random source.
+    pub fn new(id: u32, key: [u8; 32]) -> Self {
+        let key = UnboundKey::new(&CHACHA20_POLY1305, &key).expect("key has the right length");
+        EncryptionKey { id, key: LessSafeKey::new(key) }
+    }
+
+    /// The id of the key.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+// never prints the key itself
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptionKey").field("id", &self.id).finish_non_exhaustive()
+    }
+}
+
+/// The keys of a store: the one new files are encrypted with, and the ones older files may
+/// still need.
+pub(super) struct Keyring {
+    current: Option<EncryptionKey>,
+    keys: HashMap<u32, EncryptionKey>,
+    // whether plain files are rejected
+    required: bool,
+    rng: SystemRandom,
+}
+
+impl Keyring {
+    pub(super) fn new(
+        current: Option<EncryptionKey>,
+        previous: &[EncryptionKey],
+        required: bool,
+    ) -> Self {
+        let keys = previous.iter().chain(&current).map(|key| (key.id, key.clone())).collect();
+        Keyring { current, keys, required, rng: SystemRandom::new() }
+    }
+
+    /// The key new files are encrypted with, if any.
+    pub(super) fn current(&self) -> Option<&EncryptionKey> {
+        self.current.as_ref()
+    }
+
+    /// Returns the header of a new file encrypted with `key`.
+    pub(super) fn header(&self, key: &EncryptionKey) -> Result<Vec<u8>> {
+        let nonce = self.nonce()?;
+        let mut header = Vec::with_capacity(HEADER_LEN as usize);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&key.id.to_le_bytes());
+        header.extend_from_slice(&nonce);
+        let tag = key
+            .key
+            .seal_in_place_separate_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut [])
+            .map_err(|_| KvsError::Encryption("cannot seal file header".to_string()))?;
+        header.extend_from_slice(tag.as_ref());
+        Ok(header)
+    }
+
+    /// Returns the key a file is encrypted with, given its first bytes, or `None` if it is
+    /// a plain file, which fails if encryption is required. An empty file holds nothing to
+    /// decrypt, and passes either way.
+    pub(super) fn file_key(&self, start: &[u8], file: &str) -> Result<Option<EncryptionKey>> {
+        if !start.starts_with(MAGIC) {
+            if self.required && !start.is_empty() {
+                return Err(KvsError::Encryption(format!("{} is not encrypted", file)));
+            }
+            return Ok(None);
+        }
+        if start.len() < HEADER_LEN as usize {
+            return Err(KvsError::Encryption(format!("{} has a truncated header", file)));
+        }
+        let mut id = [0; 4];
+        id.copy_from_slice(&start[MAGIC.len()..MAGIC.len() + 4]);
+        let id = u32::from_le_bytes(id);
+        let key = self.keys.get(&id).ok_or_else(|| {
+            KvsError::Encryption(format!(
+                "{} is encrypted with key {}, which was not given",
+                file, id
+            ))
+        })?;
+        let (nonce, tag) = start[MAGIC.len() + 4..HEADER_LEN as usize].split_at(NONCE_LEN);
+        let mut tag = tag.to_vec();
+        key.key
+            .open_in_place(nonce_from(nonce), Aad::empty(), &mut tag)
+            .map_err(|_| KvsError::Encryption(format!("wrong key {} for {}", id, file)))?;
+        Ok(Some(key.clone()))
+    }
+
+    /// Seals a command into a record, to be written at `pos` of the log file of `gen`.
+    pub(super) fn seal(
+        &self,
+        key: &EncryptionKey,
+        plaintext: &[u8],
+        gen: u64,
+        pos: u64,
+    ) -> Result<Vec<u8>> {
+        let nonce = self.nonce()?;
+        let len = NONCE_LEN + plaintext.len() + TAG_LEN;
+        let mut record = Vec::with_capacity(4 + len);
+        record.extend_from_slice(&(len as u32).to_le_bytes());
+        record.extend_from_slice(&nonce);
+        let mut sealed = plaintext.to_vec();
+        key.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(record_aad(gen, pos)),
+                &mut sealed,
+            )
+            .map_err(|_| KvsError::Encryption("cannot seal command".to_string()))?;
+        record.extend_from_slice(&sealed);
+        Ok(record)
+    }
+
+    /// Returns the content of a whole file, encrypted with the current key if there is one.
+    pub(super) fn seal_file(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let key = match self.current() {
+            Some(key) => key,
+            None => return Ok(data.to_vec()),
+        };
+        let mut file = self.header(key)?;
+        file.extend_from_slice(&self.seal(key, data, 0, HEADER_LEN)?);
+        Ok(file)
+    }
+
+    /// Returns the data of a file written by `seal_file`, and the id of the key it was
+    /// encrypted with, if any.
+    pub(super) fn open_file(&self, file: &[u8], name: &str) -> Result<(Vec<u8>, Option<u32>)> {
+        match self.file_key(file, name)? {
+            Some(key) => {
+                Ok((open(&key, &file[HEADER_LEN as usize..], 0, HEADER_LEN)?, Some(key.id)))
+            }
+            None => Ok((file.to_vec(), None)),
+        }
+    }
+
+    fn nonce(&self) -> Result<[u8; NONCE_LEN]> {
+        let mut nonce = [0; NONCE_LEN];
+        self.rng
+            .fill(&mut nonce)
+            .map_err(|_| KvsError::Encryption("cannot generate a nonce".to_string()))?;
+        Ok(nonce)
+    }
+}
+
+/// Returns the length of the record starting with the given 4 bytes.
+pub(super) fn record_len(len: [u8; 4]) -> u64 {
+    4 + u64::from(u32::from_le_bytes(len))
+}
+
+/// Opens a whole record read at `pos` of the log file of `gen`, returning the command in it.
+pub(super) fn open(key: &EncryptionKey, record: &[u8], gen: u64, pos: u64) -> Result<Vec<u8>> {
+    let corrupt = || KvsError::Encryption("cannot decrypt command, it is corrupt".to_string());
+    if record.len() < 4 + NONCE_LEN + TAG_LEN {
+        return Err(corrupt());
+    }
+    let (nonce, sealed) = record[4..].split_at(NONCE_LEN);
+    let mut sealed = sealed.to_vec();
+    let len = key
+        .key
+        .open_in_place(nonce_from(nonce), Aad::from(record_aad(gen, pos)), &mut sealed)
+        .map_err(|_| corrupt())?
+        .len();
+    sealed.truncate(len);
+    Ok(sealed)
+}
+
+/// Whether a whole record that opens starts anywhere in `bytes`, read from `pos` of the log
+/// file of `gen` to its end.
+///
+/// A record opens only where it was sealed for, so finding one shows there is more to the
+/// file than a record cut short by a crash.
+pub(super) fn has_record(key: &EncryptionKey, bytes: &[u8], gen: u64, pos: u64) -> bool {
+    (0..bytes.len()).any(|offset| {
+        let candidate = &bytes[offset..];
+        let len = match candidate.get(..4) {
+            Some(len) => record_len(len.try_into().expect("4 bytes")) as usize,
+            None => return false,
+        };
+        len <= candidate.len() && open(key, &candidate[..len], gen, pos + offset as u64).is_ok()
+    })
+}
+
+fn record_aad(gen: u64, pos: u64) -> [u8; 16] {
+    let mut aad = [0; 16];
+    aad[..8].copy_from_slice(&gen.to_le_bytes());
+    aad[8..].copy_from_slice(&pos.to_le_bytes());
+    aad
+}
+
+fn nonce_from(bytes: &[u8]) -> Nonce {
+    Nonce::try_assume_unique_for_key(bytes).expect("nonce has the right length")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seal_and_open() -> Result<()> {
+        let key = EncryptionKey::new(1, [7; 32]);
+        let keyring = Keyring::new(Some(key.clone()), &[], false);
+        let header = keyring.header(&key)?;
+        assert_eq!(header.len() as u64, HEADER_LEN);
+        assert_eq!(keyring.file_key(&header, "1.log")?.map(|key| key.id), Some(1));
+        assert!(keyring.file_key(b"{\"Set\"", "1.log")?.is_none());
+
+        let record = keyring.seal(&key, b"secret", 1, HEADER_LEN)?;
+        let mut len = [0; 4];
+        len.copy_from_slice(&record[..4]);
+        assert_eq!(record_len(len), record.len() as u64);
+        assert!(!record.windows(6).any(|window| window == b"secret"));
+        assert_eq!(open(&key, &record, 1, HEADER_LEN)?, b"secret");
+
+        let mut tampered = record.clone();
+        *tampered.last_mut().expect("record is not empty") ^= 1;
+        assert!(matches!(open(&key, &tampered, 1, HEADER_LEN), Err(KvsError::Encryption(_))));
+
+        // a record does not open anywhere else than where it was sealed for
+        for (gen, pos) in [(2, HEADER_LEN), (1, HEADER_LEN + record.len() as u64)] {
+            assert!(matches!(open(&key, &record, gen, pos), Err(KvsError::Encryption(_))));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn whole_files() -> Result<()> {
+        let key = EncryptionKey::new(1, [7; 32]);
+        let keyring = Keyring::new(Some(key), &[], false);
+        let file = keyring.seal_file(b"secret")?;
+        assert!(!file.windows(6).any(|window| window == b"secret"));
+        assert_eq!(keyring.open_file(&file, "a.dict")?, (b"secret".to_vec(), Some(1)));
+
+        let plain = Keyring::new(None, &[], false);
+        assert_eq!(plain.seal_file(b"open")?, b"open");
+        assert_eq!(keyring.open_file(b"open", "a.dict")?, (b"open".to_vec(), None));
+        assert!(matches!(plain.open_file(&file, "a.dict"), Err(KvsError::Encryption(_))));
+
+        let required = Keyring::new(Some(EncryptionKey::new(1, [7; 32])), &[], true);
+        assert_eq!(required.open_file(&file, "a.dict")?.0, b"secret");
+        assert!(matches!(required.open_file(b"open", "a.dict"), Err(KvsError::Encryption(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn wrong_key() -> Result<()> {
+        let key = EncryptionKey::new(1, [7; 32]);
+        let header = Keyring::new(Some(key), &[], false).header(&EncryptionKey::new(1, [7; 32]))?;
+        let other = Keyring::new(Some(EncryptionKey::new(1, [8; 32])), &[], false);
+        assert!(matches!(other.file_key(&header, "1.log"), Err(KvsError::Encryption(_))));
+        let none = Keyring::new(None, &[], false);
+        assert!(matches!(none.file_key(&header, "1.log"), Err(KvsError::Encryption(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,93 @@
+//! Stands in for the encryption of log files without the `encryption` feature: every file is
+//! written plain, and opening an encrypted one fails.
+
+use crate::result::{KvsError, Result};
+
+// the start of an encrypted file, as in `crypt`
+const MAGIC: &[u8; 8] = b"ritekv\0E";
+pub(super) const HEADER_LEN: u64 = 40;
+
+/// A key to encrypt log files with, which cannot be used without the `encryption` feature.
+#[derive(Clone, Debug)]
+pub struct EncryptionKey {
+    id: u32,
+}
+
+impl EncryptionKey {
+    /// Creates a key from 32 bytes, which should come from a secure random source.
+    pub fn new(id: u32, _key: [u8; 32]) -> Self {
+        EncryptionKey { id }
+    }
+
+    /// The id of the key.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+/// A keyring without keys.
+pub(super) struct Keyring;
+
+impl Keyring {
+    pub(super) fn new(
+        _current: Option<EncryptionKey>,
+        _previous: &[EncryptionKey],
+        _required: bool,
+    ) -> Self {
+        Keyring
+    }
+
+    pub(super) fn current(&self) -> Option<&EncryptionKey> {
+        None
+    }
+
+    pub(super) fn header(&self, _key: &EncryptionKey) -> Result<Vec<u8>> {
+        Err(feature_off())
+    }
+
+    /// Returns `None` for a plain file, and fails on an encrypted one.
+    pub(super) fn file_key(&self, start: &[u8], file: &str) -> Result<Option<EncryptionKey>> {
+        match start.starts_with(MAGIC) {
+            true => Err(KvsError::Encryption(format!(
+                "{} is encrypted, but the `encryption` feature is off",
+                file
+            ))),
+            false => Ok(None),
+        }
+    }
+
+    pub(super) fn seal(
+        &self,
+        _key: &EncryptionKey,
+        _plaintext: &[u8],
+        _gen: u64,
+        _pos: u64,
+    ) -> Result<Vec<u8>> {
+        Err(feature_off())
+    }
+
+    pub(super) fn seal_file(&self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(data.to_vec())
+    }
+
+    pub(super) fn open_file(&self, file: &[u8], name: &str) -> Result<(Vec<u8>, Option<u32>)> {
+        self.file_key(file, name)?;
+        Ok((file.to_vec(), None))
+    }
+}
+
+pub(super) fn record_len(len: [u8; 4]) -> u64 {
+    4 + u64::from(u32::from_le_bytes(len))
+}
+
+pub(super) fn open(_key: &EncryptionKey, _record: &[u8], _gen: u64, _pos: u64) -> Result<Vec<u8>> {
+    Err(feature_off())
+}
+
+pub(super) fn has_record(_key: &EncryptionKey, _bytes: &[u8], _gen: u64, _pos: u64) -> bool {
+    false
+}
+
+fn feature_off() -> KvsError {
+    KvsError::Encryption("the `encryption` feature is off".to_string())
+}
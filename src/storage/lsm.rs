@@ -0,0 +1,869 @@
+mod merge;
+mod table;
+
+use self::merge::{MergeIter, Source};
+use self::table::{Table, TableBuilder, TableIter};
+use super::bloom::{BloomCounters, BloomStats};
+use super::usage::btree_bytes;
+use super::vfs::{RealFs, Vfs};
+use super::wal::{Entry, Wal};
+use crate::result::{KvsError, Result};
+use crate::storage::{BatchStore, MemoryUsage, MemoryUsageStore, ScanStore, Store, SyncMode};
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fmt::Display;
+use std::io::{self, Read, Write};
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const MANIFEST: &str = "MANIFEST";
+const MANIFEST_TMP: &str = "MANIFEST.tmp";
+
+/// Options for opening an `LsmStore`.
+#[derive(Clone, Debug)]
+pub struct LsmStoreOptions {
+    /// The number of bytes of keys and values buffered in memory before they are flushed to a
+    /// table. Defaults to 4 MiB.
+    pub memtable_size: usize,
+    /// The size in bytes of the blocks tables are read in. Defaults to 4 KiB.
+    pub block_size: usize,
+    /// The size in bytes after which compaction starts a new table. Defaults to 2 MiB.
+    pub table_size: u64,
+    /// The number of flushed tables that triggers a compaction. Defaults to 4.
+    pub level0_tables: usize,
+    /// The number of bits per key of the bloom filter of every table, which saves reads of
+    /// missing keys. Defaults to 10, which lets about 1% of them through; 0 disables filters.
+    pub bloom_bits_per_key: usize,
+    /// When writes are flushed to durable storage. Defaults to `SyncMode::Never`.
+    pub sync: SyncMode,
+}
+
+impl Default for LsmStoreOptions {
+    fn default() -> Self {
+        LsmStoreOptions {
+            memtable_size: 4 * 1024 * 1024,
+            block_size: 4 * 1024,
+            table_size: 2 * 1024 * 1024,
+            level0_tables: 4,
+            bloom_bits_per_key: 10,
+            sync: SyncMode::Never,
+        }
+    }
+}
+
+/// The `LsmStore` stores key/value pairs in a log-structured merge tree.
+///
+/// Writes go to a write-ahead log and to a sorted in-memory table, the memtable, which is
+/// flushed to an immutable sorted table on disk once full. Flushed tables pile up in level 0,
+/// where their key ranges overlap; once there are enough of them, compaction merges them into
+/// level 1, whose tables hold disjoint key ranges. Only the block index of every table is
+/// kept in memory, so the keys do not need to fit in RAM.
+pub struct LsmStore {
+    // directory for the tables and other data
+    path: PathBuf,
+    // file system the store lives on
+    vfs: Box<dyn Vfs>,
+    options: LsmStoreOptions,
+    wal: Wal,
+    wal_id: u64,
+    memtable: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    // the number of bytes of keys and values in the memtable
+    memtable_size: usize,
+    // flushed tables, oldest first
+    level0: Vec<(u64, Arc<Table>)>,
+    // compacted tables, in key order
+    level1: Vec<(u64, Arc<Table>)>,
+    // the next id for a table or a log
+    next_id: u64,
+    bloom: BloomCounters,
+    // why the last sync of the log, or flush of the memtable, after a write failed, until it
+    // is taken
+    maintenance_error: Option<KvsError>,
+}
+
+impl Display for LsmStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "lsmstore")
+    }
+}
+
+impl LsmStore {
+    /// Opens an `LsmStore` with the given path.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors, or `InvalidData` for a damaged table.
+    pub fn open(path: impl Into<PathBuf>) -> Result<LsmStore> {
+        LsmStore::open_with_options(path, LsmStoreOptions::default())
+    }
+
+    /// Opens an `LsmStore` with the given path and options.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors, or `InvalidData` for a damaged table.
+    pub fn open_with_options(
+        path: impl Into<PathBuf>,
+        options: LsmStoreOptions,
+    ) -> Result<LsmStore> {
+        LsmStore::open_with_vfs(path, Box::new(RealFs), options)
+    }
+
+    /// Opens an `LsmStore` with the given path on the given file system.
+    pub(crate) fn open_with_vfs(
+        path: impl Into<PathBuf>,
+        vfs: Box<dyn Vfs>,
+        options: LsmStoreOptions,
+    ) -> Result<LsmStore> {
+        let path = path.into();
+        vfs.create_dir_all(&path)?;
+        let manifest = match read_manifest(&*vfs, &path)? {
+            Some(manifest) => manifest,
+            None => {
+                let manifest = Manifest { next_id: 2, wal: 1, level0: vec![], level1: vec![] };
+                write_manifest(&*vfs, &path, &manifest)?;
+                manifest
+            }
+        };
+
+        // tables and logs left behind by a flush or a compaction that did not finish
+        for file in vfs.list_files(&path)? {
+            if let Some(id) = file_id(&file) {
+                let live = manifest.wal == id
+                    || manifest.level0.contains(&id)
+                    || manifest.level1.contains(&id);
+                if !live {
+                    vfs.remove_file(&file)?;
+                }
+            }
+        }
+
+        let open_tables = |ids: &[u64]| -> Result<Vec<(u64, Arc<Table>)>> {
+            ids.iter()
+                .map(|&id| Ok((id, Arc::new(Table::open(vfs.open_read(&table_path(&path, id))?)?))))
+                .collect()
+        };
+        let level0 = open_tables(&manifest.level0)?;
+        let level1 = open_tables(&manifest.level1)?;
+
+        let (wal, entries) = Wal::open(&*vfs, &wal_path(&path, manifest.wal))?;
+        let mut store = LsmStore {
+            path,
+            vfs,
+            options,
+            wal,
+            wal_id: manifest.wal,
+            memtable: BTreeMap::new(),
+            memtable_size: 0,
+            level0,
+            level1,
+            next_id: manifest.next_id,
+            bloom: BloomCounters::default(),
+            maintenance_error: None,
+        };
+        for (key, value) in entries {
+            store.insert(key, value);
+        }
+        Ok(store)
+    }
+
+    /// Flushes the memtable, and merges every level 0 table into level 1.
+    ///
+    /// Replaced values and tombstones are dropped along the way.
+    pub fn compact(&mut self) -> Result<()> {
+        self.flush()?;
+        self.compact_levels()
+    }
+
+    /// Returns how often the bloom filters of the tables saved a read since the store was
+    /// opened.
+    pub fn bloom_stats(&self) -> BloomStats {
+        self.bloom.stats()
+    }
+
+    /// Returns why syncing the log, or flushing the memtable and compacting the tables, after
+    /// a write last failed, if it did since the last call.
+    ///
+    /// Writes do not fail for it, since their entries are in the log by then; both are tried
+    /// again after the next write.
+    pub fn take_maintenance_error(&mut self) -> Option<KvsError> {
+        self.maintenance_error.take()
+    }
+
+    /// Looks a key up, from the newest data to the oldest, returning `Some(None)` for a
+    /// removed key.
+    fn lookup(&self, key: &[u8]) -> Result<Option<Option<Vec<u8>>>> {
+        if let Some(value) = self.memtable.get(key) {
+            return Ok(Some(value.clone()));
+        }
+        for (_, table) in self.level0.iter().rev() {
+            if let Some(value) = self.table_get(table, key)? {
+                return Ok(Some(value));
+            }
+        }
+        match self.level1.partition_point(|(_, table)| table.first_key() <= Some(key)) {
+            0 => Ok(None),
+            i => self.table_get(&self.level1[i - 1].1, key),
+        }
+    }
+
+    /// Looks a key up in a table, unless its bloom filter rules the key out.
+    fn table_get(&self, table: &Table, key: &[u8]) -> Result<Option<Option<Vec<u8>>>> {
+        if !table.may_contain(key) {
+            self.bloom.negative();
+            return Ok(None);
+        }
+        let value = table.get(key)?;
+        self.bloom.positive(value.is_some());
+        Ok(value)
+    }
+
+    /// Logs entries with a single write, and applies them to the memtable.
+    ///
+    /// The entries are in the log once it is written, so the write is made even if syncing
+    /// the log or flushing the memtable fails after it; that is kept for
+    /// `take_maintenance_error` instead.
+    fn write(&mut self, entries: &[(&[u8], Option<&[u8]>)]) -> Result<()> {
+        self.wal.append(entries)?;
+        for &(key, value) in entries {
+            self.insert(key.to_owned(), value.map(<[u8]>::to_owned));
+        }
+        if let Err(err) = self.maintain() {
+            self.maintenance_error = Some(err);
+        }
+        Ok(())
+    }
+
+    /// Syncs the log if the options ask for it, and flushes the memtable once it is full.
+    fn maintain(&mut self) -> Result<()> {
+        if self.options.sync == SyncMode::Always {
+            self.wal.sync()?;
+        }
+        if self.memtable_size >= self.options.memtable_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, key: Vec<u8>, value: Option<Vec<u8>>) {
+        self.memtable_size += key.len() + value.as_ref().map_or(0, Vec::len);
+        let key_len = key.len();
+        if let Some(old) = self.memtable.insert(key, value) {
+            self.memtable_size -= key_len + old.map_or(0, |old| old.len());
+        }
+    }
+
+    /// Writes the memtable to a new level 0 table, and starts a new write-ahead log.
+    fn flush(&mut self) -> Result<()> {
+        if self.memtable.is_empty() {
+            return Ok(());
+        }
+        let id = self.new_id();
+        let path = table_path(&self.path, id);
+        let mut builder = TableBuilder::new(
+            self.vfs.create(&path)?,
+            self.options.block_size,
+            self.options.bloom_bits_per_key,
+        );
+        for (key, value) in &self.memtable {
+            builder.add(key, value.as_deref())?;
+        }
+        builder.finish()?;
+        let table = Arc::new(Table::open(self.vfs.open_read(&path)?)?);
+
+        let wal_id = self.new_id();
+        let (wal, _) = Wal::open(&*self.vfs, &wal_path(&self.path, wal_id))?;
+        let mut level0 = ids(&self.level0);
+        level0.push(id);
+        self.write_manifest(level0, ids(&self.level1), wal_id)?;
+
+        // the flush is committed, switch over to the new table and log
+        let old_wal = std::mem::replace(&mut self.wal_id, wal_id);
+        self.wal = wal;
+        self.level0.push((id, table));
+        self.memtable.clear();
+        self.memtable_size = 0;
+        self.vfs.remove_file(&wal_path(&self.path, old_wal))?;
+
+        if self.level0.len() >= self.options.level0_tables {
+            self.compact_levels()?;
+        }
+        Ok(())
+    }
+
+    /// Merges the level 0 tables, and the level 1 tables their keys overlap, into new level 1
+    /// tables; the other level 1 tables are left as they are.
+    ///
+    /// Level 1 is the last level, so tombstones have nothing left to hide and are dropped.
+    fn compact_levels(&mut self) -> Result<()> {
+        // level 1 tables do not overlap each other, so the ones to merge are a single run
+        let (first, end) = match key_range(&self.level0) {
+            Some((min, max)) => {
+                let level1 = &self.level1;
+                let first = level1.partition_point(|(_, table)| table.last_key() < Some(&*min));
+                let end = level1.partition_point(|(_, table)| table.first_key() <= Some(&*max));
+                (first, end.max(first))
+            }
+            None => (0, 0),
+        };
+
+        let mut sources: Vec<Source> = Vec::new();
+        for (_, table) in self.level0.iter().rev() {
+            sources.push(Box::new(TableIter::new(Arc::clone(table), Bound::Unbounded)?));
+        }
+        sources.push(level_iter(self.level1[first..end].to_vec(), Bound::Unbounded));
+
+        let mut outputs = Vec::new();
+        let mut builder: Option<(u64, TableBuilder)> = None;
+        for entry in MergeIter::new(sources) {
+            let (key, value) = match entry? {
+                (key, Some(value)) => (key, value),
+                (_, None) => continue,
+            };
+            let (_, table) = match builder {
+                Some(ref mut builder) => builder,
+                None => {
+                    let id = self.new_id();
+                    let file = self.vfs.create(&table_path(&self.path, id))?;
+                    builder.insert((
+                        id,
+                        TableBuilder::new(
+                            file,
+                            self.options.block_size,
+                            self.options.bloom_bits_per_key,
+                        ),
+                    ))
+                }
+            };
+            table.add(&key, Some(&value))?;
+            if table.len() >= self.options.table_size {
+                let (id, table) = builder.take().expect("builder is set");
+                table.finish()?;
+                outputs.push(id);
+            }
+        }
+        if let Some((id, table)) = builder {
+            table.finish()?;
+            outputs.push(id);
+        }
+        let mut level1 = self.level1[..first].to_vec();
+        for id in outputs {
+            let table = Table::open(self.vfs.open_read(&table_path(&self.path, id))?)?;
+            level1.push((id, Arc::new(table)));
+        }
+        level1.extend_from_slice(&self.level1[end..]);
+        self.write_manifest(vec![], ids(&level1), self.wal_id)?;
+
+        // the compaction is committed, remove the old tables
+        let merged: Vec<_> = self.level1.drain(first..end).collect();
+        self.level1 = level1;
+        let old_tables: Vec<u64> = self.level0.drain(..).chain(merged).map(|(id, _)| id).collect();
+        for id in old_tables {
+            self.vfs.remove_file(&table_path(&self.path, id))?;
+        }
+        Ok(())
+    }
+
+    fn new_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    fn write_manifest(&self, level0: Vec<u64>, level1: Vec<u64>, wal: u64) -> Result<()> {
+        let manifest = Manifest { next_id: self.next_id, wal, level0, level1 };
+        write_manifest(&*self.vfs, &self.path, &manifest)
+    }
+}
+
+impl Store for LsmStore {
+    fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        let key = check_key(key.as_ref())?;
+        Ok(self.lookup(key)?.flatten())
+    }
+
+    fn set(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
+        let key = check_key(key.as_ref())?;
+        self.write(&[(key, Some(value.as_ref()))])
+    }
+
+    fn remove(&mut self, key: impl AsRef<[u8]>) -> Result<()> {
+        let key = check_key(key.as_ref())?;
+        self.write(&[(key, None)])
+    }
+
+    fn contains(&mut self, key: impl AsRef<[u8]>) -> Result<bool> {
+        let key = check_key(key.as_ref())?;
+        Ok(self.lookup(key)?.flatten().is_some())
+    }
+}
+
+impl BatchStore for LsmStore {
+    fn get_batch(&self, keys: impl AsRef<[Vec<u8>]>) -> Result<Vec<Option<Vec<u8>>>> {
+        keys.as_ref().iter().map(|key| self.get(key)).collect()
+    }
+
+    fn set_batch(
+        &mut self,
+        keys: impl AsRef<[Vec<u8>]>,
+        values: impl AsRef<[Vec<u8>]>,
+    ) -> Result<()> {
+        let (keys, values) = (keys.as_ref(), values.as_ref());
+        if keys.len() != values.len() {
+            return Err(KvsError::InvalidData(
+                "The number of keys does not match the number of values".to_string(),
+            ));
+        }
+        let mut entries = Vec::with_capacity(keys.len());
+        for (key, value) in keys.iter().zip(values) {
+            entries.push((check_key(key)?, Some(value.as_slice())));
+        }
+        self.write(&entries)
+    }
+
+    fn remove_batch(&mut self, keys: impl AsRef<[Vec<u8>]>) -> Result<()> {
+        let mut entries = Vec::with_capacity(keys.as_ref().len());
+        for key in keys.as_ref() {
+            entries.push((check_key(key)?, None));
+        }
+        self.write(&entries)
+    }
+}
+
+impl ScanStore for LsmStore {
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan_limit(range, usize::MAX)
+    }
+
+    /// Gets the first `limit` pairs within `range`, reading the tables only as far as it
+    /// takes to find them.
+    fn scan_limit(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let start = match range.start_bound() {
+            Bound::Included(key) => Bound::Included(key.as_slice()),
+            Bound::Excluded(key) => Bound::Excluded(key.as_slice()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => Bound::Included(key.as_slice()),
+            Bound::Excluded(key) => Bound::Excluded(key.as_slice()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        if is_empty(start, end) {
+            return Ok(Vec::new());
+        }
+        // the memtable is read as the merge goes, copying only the entries it gets to
+        let memtable = self
+            .memtable
+            .range::<[u8], _>((start, end))
+            .map(|(key, value)| Ok((key.clone(), value.clone())));
+        let mut sources: Vec<Source> = vec![Box::new(memtable)];
+        for (_, table) in self.level0.iter().rev() {
+            sources.push(Box::new(TableIter::new(Arc::clone(table), start)?));
+        }
+        // skip the level 1 tables entirely before the start
+        let first = match start {
+            Bound::Included(key) | Bound::Excluded(key) => self
+                .level1
+                .partition_point(|(_, table)| table.first_key() <= Some(key))
+                .saturating_sub(1),
+            Bound::Unbounded => 0,
+        };
+        let start = start.map(<[u8]>::to_owned);
+        sources.push(level_iter(self.level1[first..].to_vec(), start.as_ref().map(Vec::as_slice)));
+
+        let mut pairs = Vec::new();
+        for entry in MergeIter::new(sources) {
+            if pairs.len() >= limit {
+                break;
+            }
+            let (key, value) = entry?;
+            if !range.contains(&key) {
+                match range.end_bound() {
+                    Bound::Included(end) if key > *end => break,
+                    Bound::Excluded(end) if key >= *end => break,
+                    _ => continue,
+                }
+            }
+            if let Some(value) = value {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+}
+
+impl MemoryUsageStore for LsmStore {
+    /// Counts the block indexes and bloom filters of the tables as the index, and the
+    /// memtable as a buffer. Values are only read from the tables, not cached.
+    fn memory_usage(&self) -> MemoryUsage {
+        let mut usage = MemoryUsage::default();
+        for (_, table) in self.level0.iter().chain(&self.level1) {
+            usage += table.memory_usage();
+        }
+        usage.buffers = btree_bytes::<Vec<u8>, Option<Vec<u8>>>(self.memtable.len());
+        for (key, value) in &self.memtable {
+            usage.buffers += (key.capacity() + value.as_ref().map_or(0, Vec::capacity)) as u64;
+        }
+        usage
+    }
+}
+
+/// Iterates over level 1, reading its tables one after the other.
+fn level_iter(tables: Vec<(u64, Arc<Table>)>, start: Bound<&[u8]>) -> Source<'static> {
+    let start = start.map(<[u8]>::to_owned);
+    Box::new(tables.into_iter().flat_map(move |(_, table)| {
+        let iter: Source = match TableIter::new(table, start.as_ref().map(Vec::as_slice)) {
+            Ok(iter) => Box::new(iter),
+            Err(err) => Box::new(std::iter::once(Err(err))),
+        };
+        iter
+    }))
+}
+
+/// Whether no key falls between the bounds, which `BTreeMap::range` panics on if the start is
+/// past the end.
+fn is_empty(start: Bound<&[u8]>, end: Bound<&[u8]>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
+
+fn check_key(key: &[u8]) -> Result<&[u8]> {
+    if key.is_empty() {
+        return Err(KvsError::EmptyKey);
+    }
+    Ok(key)
+}
+
+/// Returns the smallest and the largest key of the given tables, unless they are all empty.
+fn key_range(tables: &[(u64, Arc<Table>)]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let first = tables.iter().filter_map(|(_, table)| table.first_key()).min()?;
+    let last = tables.iter().filter_map(|(_, table)| table.last_key()).max()?;
+    Some((first.to_vec(), last.to_vec()))
+}
+
+fn ids(tables: &[(u64, Arc<Table>)]) -> Vec<u64> {
+    tables.iter().map(|(id, _)| *id).collect()
+}
+
+fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.sst", id))
+}
+
+fn wal_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.wal", id))
+}
+
+/// Returns the id of a table or log file.
+fn file_id(path: &Path) -> Option<u64> {
+    match path.extension().and_then(OsStr::to_str) {
+        Some("sst") | Some("wal") => path.file_stem()?.to_str()?.parse().ok(),
+        _ => None,
+    }
+}
+
+/// The tables and the log making up the store.
+#[derive(Serialize, Deserialize, Debug)]
+struct Manifest {
+    next_id: u64,
+    wal: u64,
+    level0: Vec<u64>,
+    level1: Vec<u64>,
+}
+
+/// Reads the manifest in the given directory, if there is one.
+fn read_manifest(vfs: &dyn Vfs, dir: &Path) -> Result<Option<Manifest>> {
+    let mut file = match vfs.open_read(&dir.join(MANIFEST)) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+    Ok(Some(serde_json::from_slice(&buf)?))
+}
+
+/// Replaces the manifest in the given directory, through a temporary file renamed over it.
+fn write_manifest(vfs: &dyn Vfs, dir: &Path, manifest: &Manifest) -> Result<()> {
+    let tmp_path = dir.join(MANIFEST_TMP);
+    let mut file = vfs.create(&tmp_path)?;
+    file.write_all(&serde_json::to_vec(manifest)?)?;
+    file.sync_all()?;
+    vfs.rename(&tmp_path, &dir.join(MANIFEST))?;
+    vfs.sync_dir(dir)?;
+    Ok(())
+}
+
+#[cfg(test)]
+struct LsmFixture(tempfile::TempDir, LsmStoreOptions);
+
+#[cfg(test)]
+impl LsmFixture {
+    fn new(options: LsmStoreOptions) -> Self {
+        let temp_dir = tempfile::TempDir::new().expect("unable to create temporary directory");
+        LsmFixture(temp_dir, options)
+    }
+
+    /// A tiny memtable and tables, so that the tests go through many flushes and compactions.
+    fn small() -> Self {
+        LsmFixture::new(LsmStoreOptions {
+            memtable_size: 4 * 1024,
+            block_size: 256,
+            table_size: 2 * 1024,
+            level0_tables: 2,
+            ..LsmStoreOptions::default()
+        })
+    }
+}
+
+#[cfg(test)]
+impl crate::testing::Fixture for LsmFixture {
+    type Store = LsmStore;
+
+    const PERSISTENT: bool = true;
+
+    fn open(&mut self) -> Result<LsmStore> {
+        LsmStore::open_with_options(self.0.path(), self.1.clone())
+    }
+
+    fn compact(store: &mut LsmStore) -> Result<()> {
+        store.compact()
+    }
+}
+
+#[cfg(test)]
+crate::store_test_suite!(
+    suite,
+    LsmFixture::new(LsmStoreOptions::default()),
+    [store, batch, scan, persist, model]
+);
+
+#[cfg(test)]
+crate::store_test_suite!(small, LsmFixture::small(), [store, batch, scan, persist, model]);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::sim::{Fault, FsOp, SimFs};
+
+    const DIR: &str = "/db";
+
+    fn open(fs: &SimFs, sync: SyncMode) -> Result<LsmStore> {
+        let options = LsmStoreOptions { memtable_size: 512, sync, ..LsmStoreOptions::default() };
+        LsmStore::open_with_vfs(DIR, Box::new(fs.clone()), options)
+    }
+
+    #[test]
+    fn memory_usage() -> Result<()> {
+        let fs = SimFs::new();
+        let mut store = open(&fs, SyncMode::Never)?;
+        store.set("a", "1")?;
+        let usage = store.memory_usage();
+        assert_eq!((usage.entries, usage.index), (0, 0));
+        assert!(usage.buffers > 2);
+
+        for i in 0..200 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        store.compact()?;
+        let usage = store.memory_usage();
+        assert!(usage.entries > 1);
+        assert!(usage.index > 200 * 10 / 8, "the filter has 10 bits per key");
+        assert_eq!(usage.buffers, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn torn_log_record_is_dropped() -> Result<()> {
+        let fs = SimFs::new();
+        let mut store = open(&fs, SyncMode::Never)?;
+        store.set("a", "1")?;
+        fs.inject(FsOp::Write, 0, Fault::TornWrite);
+        assert!(store.set("b", "2").is_err());
+        fs.recover();
+        drop(store);
+
+        let mut store = open(&fs, SyncMode::Never)?;
+        assert_eq!(store.get("a")?, Some(b"1".to_vec()));
+        assert_eq!(store.get("b")?, None);
+        // the log was truncated, so new records are not lost behind the torn one
+        store.set("c", "3")?;
+        drop(store);
+        let store = open(&fs, SyncMode::Never)?;
+        assert_eq!(store.get("c")?, Some(b"3".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn failed_flush_does_not_fail_writes() -> Result<()> {
+        let fs = SimFs::new();
+        let mut store = open(&fs, SyncMode::Always)?;
+        fs.inject(FsOp::Sync, 0, Fault::Error);
+        store.set("a", "1")?;
+        assert!(store.take_maintenance_error().is_some());
+        assert!(store.take_maintenance_error().is_none());
+
+        // the rename of the manifest committing the first flush
+        fs.inject(FsOp::Rename, 0, Fault::Error);
+        let mut i = 0;
+        while store.take_maintenance_error().is_none() {
+            store.set(format!("key{}", i), "value")?;
+            i += 1;
+        }
+        // the next write flushes the memtable after all
+        store.set("b", "2")?;
+        assert!(store.take_maintenance_error().is_none());
+        assert_eq!(store.memtable_size, 0);
+        drop(store);
+
+        let store = open(&fs, SyncMode::Always)?;
+        assert_eq!(store.get("a")?, Some(b"1".to_vec()));
+        assert_eq!(store.get("b")?, Some(b"2".to_vec()));
+        for j in 0..i {
+            assert_eq!(store.get(format!("key{}", j))?, Some(b"value".to_vec()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_log_record_fails_open() -> Result<()> {
+        let fs = SimFs::new();
+        let mut store = open(&fs, SyncMode::Never)?;
+        store.set("a", "1")?;
+        store.set("b", "2")?;
+        drop(store);
+        let wal = fs.files().into_iter().find(|path| path.extension() == Some("wal".as_ref()));
+        let wal = wal.expect("no log file");
+        let log = fs.read(&wal).unwrap();
+        let rewrite = |log: &[u8]| fs.create(&wal)?.write_all(log);
+
+        // the payload of the first record, with another record after it
+        let mut corrupt = log.clone();
+        corrupt[12] ^= 1;
+        rewrite(&corrupt)?;
+        assert!(matches!(open(&fs, SyncMode::Never), Err(KvsError::InvalidData(_))));
+
+        // the payload of the last record, which a crash may have left half written
+        let mut torn = log;
+        *torn.last_mut().unwrap() ^= 1;
+        rewrite(&torn)?;
+        let store = open(&fs, SyncMode::Never)?;
+        assert_eq!(store.get("a")?, Some(b"1".to_vec()));
+        assert_eq!(store.get("b")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn power_loss_keeps_durable_writes() -> Result<()> {
+        let fs = SimFs::new();
+        let mut store = open(&fs, SyncMode::Always)?;
+        // enough writes to flush and compact several times
+        for i in 0..200 {
+            store.set(format!("key{}", i % 50), format!("value{}", i))?;
+        }
+        store.remove("key0")?;
+        fs.power_loss();
+        drop(store);
+
+        let store = open(&fs, SyncMode::Always)?;
+        assert_eq!(store.get("key0")?, None);
+        for i in 1..50 {
+            assert_eq!(
+                store.get(format!("key{}", i))?,
+                Some(format!("value{}", 150 + i).into_bytes())
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn bloom_filters_skip_missing_keys() -> Result<()> {
+        let fs = SimFs::new();
+        let mut store = open(&fs, SyncMode::Never)?;
+        for i in 0..1000 {
+            store.set(format!("key{}", i), "value")?;
+        }
+        store.compact()?;
+        for i in 0..1000 {
+            assert!(store.contains(format!("key{}", i))?);
+            assert!(!store.contains(format!("missing{}", i))?);
+        }
+        let stats = store.bloom_stats();
+        assert_eq!(stats.true_positives, 1000);
+        assert_eq!(stats.negatives + stats.false_positives, 1000);
+        assert!(stats.false_positive_rate() < 0.05, "{:?}", stats);
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_leaves_other_tables() -> Result<()> {
+        let fs = SimFs::new();
+        let options = LsmStoreOptions { table_size: 1024, ..LsmStoreOptions::default() };
+        let mut store = LsmStore::open_with_vfs(DIR, Box::new(fs.clone()), options)?;
+        for i in 0..200 {
+            store.set(format!("key{:03}", i), "value")?;
+        }
+        store.compact()?;
+        let before = ids(&store.level1);
+        assert!(before.len() > 3, "{:?}", before);
+
+        // keys after every other key only add tables
+        store.set("last", "value")?;
+        store.compact()?;
+        assert_eq!(ids(&store.level1)[..before.len()], before[..]);
+
+        // a key in the middle of a table only replaces that table
+        let middle = &store.level1[before.len() / 2].1;
+        let key = middle.first_key().expect("table is not empty").to_vec();
+        store.set(&key, "new value")?;
+        store.compact()?;
+        let after = ids(&store.level1);
+        let kept = before.iter().filter(|id| after.contains(id)).count();
+        assert_eq!(kept, before.len() - 1);
+        assert_eq!(store.get(&key)?, Some(b"new value".to_vec()));
+        assert_eq!(store.get("key000")?, Some(b"value".to_vec()));
+        assert_eq!(store.get("last")?, Some(b"value".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn crash_during_compaction() -> Result<()> {
+        let fs = SimFs::new();
+        let mut store = open(&fs, SyncMode::Never)?;
+        for i in 0..100 {
+            store.set(format!("key{}", i % 20), format!("value{}", i))?;
+        }
+        // the next manifest update is the one of the compaction
+        store.flush()?;
+        fs.inject(FsOp::Rename, 0, Fault::Crash);
+        assert!(store.compact_levels().is_err());
+        fs.recover();
+        drop(store);
+
+        let store = open(&fs, SyncMode::Never)?;
+        for i in 0..20 {
+            assert_eq!(
+                store.get(format!("key{}", i))?,
+                Some(format!("value{}", 80 + i).into_bytes())
+            );
+        }
+        // the tables written by the compaction were not committed, and are gone
+        let tables =
+            fs.files().iter().filter(|file| file.extension() == Some("sst".as_ref())).count();
+        assert_eq!(tables, store.level0.len() + store.level1.len());
+        Ok(())
+    }
+}
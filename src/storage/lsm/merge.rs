@@ -0,0 +1,116 @@
+//! Merges sorted runs of entries, such as the memtable and tables, into one.
+
+use super::Entry;
+use crate::result::Result;
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+/// A sorted run of entries, which may borrow the memtable.
+pub(super) type Source<'a> = Box<dyn Iterator<Item = Result<Entry>> + 'a>;
+
+/// Iterates over the entries of several sources in key order.
+///
+/// Sources are given newest first: when several of them hold a key, only the entry of the
+/// newest one is returned, tombstones included.
+pub(super) struct MergeIter<'a> {
+    sources: Vec<Source<'a>>,
+    // the next entry of every source that is not exhausted
+    heap: BinaryHeap<HeapEntry>,
+    // an error to return before the next entry
+    error: Option<crate::KvsError>,
+}
+
+struct HeapEntry {
+    entry: Entry,
+    source: usize,
+}
+
+// ordered so that the max-heap pops the smallest key first, from the newest source
+impl Ord for HeapEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.entry.0.cmp(&self.entry.0).then_with(|| other.source.cmp(&self.source))
+    }
+}
+
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for HeapEntry {}
+
+impl<'a> MergeIter<'a> {
+    pub(super) fn new(sources: Vec<Source<'a>>) -> Self {
+        let mut iter = MergeIter { sources, heap: BinaryHeap::new(), error: None };
+        for source in 0..iter.sources.len() {
+            iter.advance(source);
+        }
+        iter
+    }
+
+    /// Pushes the next entry of a source onto the heap.
+    fn advance(&mut self, source: usize) {
+        match self.sources[source].next() {
+            Some(Ok(entry)) => self.heap.push(HeapEntry { entry, source }),
+            Some(Err(err)) => self.error = Some(err),
+            None => {}
+        }
+    }
+}
+
+impl Iterator for MergeIter<'_> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(err) = self.error.take() {
+            return Some(Err(err));
+        }
+        let HeapEntry { entry, source } = self.heap.pop()?;
+        self.advance(source);
+        // skip the older entries of the same key
+        while self.heap.peek().is_some_and(|next| next.entry.0 == entry.0) {
+            let older = self.heap.pop().expect("heap is not empty");
+            self.advance(older.source);
+        }
+        Some(Ok(entry))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(entries: &[(&str, Option<&str>)]) -> Source<'static> {
+        let entries: Vec<_> = entries
+            .iter()
+            .map(|(k, v)| Ok((k.as_bytes().to_vec(), v.map(|v| v.as_bytes().to_vec()))))
+            .collect();
+        Box::new(entries.into_iter())
+    }
+
+    #[test]
+    fn newest_source_wins() -> Result<()> {
+        let merged: Vec<_> = MergeIter::new(vec![
+            source(&[("b", None), ("d", Some("new"))]),
+            source(&[("a", Some("1")), ("b", Some("old")), ("d", Some("old"))]),
+            source(&[("c", Some("3")), ("d", Some("oldest"))]),
+        ])
+        .collect::<Result<_>>()?;
+        let expected = vec![
+            (b"a".to_vec(), Some(b"1".to_vec())),
+            (b"b".to_vec(), None),
+            (b"c".to_vec(), Some(b"3".to_vec())),
+            (b"d".to_vec(), Some(b"new".to_vec())),
+        ];
+        assert_eq!(merged, expected);
+        Ok(())
+    }
+}
@@ -0,0 +1,354 @@
+//! Immutable sorted tables (SSTables), the on-disk part of the `LsmStore`.
+//!
+//! A table is a sequence of data blocks, followed by an index block, a bloom filter of its
+//! keys and a footer:
+//!
+//! ```text
+//! block := entry* checksum:u64
+//! entry := key_len:u32 key tag:u8 [value_len:u32 value]     (tag 0 is a tombstone)
+//! index := count:u32 (key_len:u32 first_key offset:u64 len:u32)* checksum:u64
+//! filter := bits [hashes:u8] checksum:u64
+//! footer := index_offset:u64 index_len:u64 filter_offset:u64 filter_len:u64 magic:u64
+//! ```
+//!
+//! Integers are little-endian, and checksums are the `seahash` of the bytes before them. The
+//! index and the filter are kept in memory, so that a lookup reads at most a single block.
+
+use super::Entry;
+use crate::encoding::put_bytes;
+use crate::result::{KvsError, Result};
+use crate::storage::bloom::{self, BloomFilter};
+use crate::storage::vfs::VfsFile;
+use crate::storage::MemoryUsage;
+
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::mem::size_of;
+use std::ops::Bound;
+use std::sync::Arc;
+
+const MAGIC: u64 = 0x7269_7465_6b76_5354; // "ritekvST"
+const FOOTER_LEN: u64 = 40;
+
+/// The location of a data block, and the first key in it.
+struct BlockHandle {
+    first_key: Vec<u8>,
+    offset: u64,
+    len: u32,
+}
+
+/// Writes a new table, from entries added in ascending key order.
+pub(super) struct TableBuilder {
+    writer: BufWriter<Box<dyn VfsFile>>,
+    block_size: usize,
+    offset: u64,
+    block: Vec<u8>,
+    index: Vec<BlockHandle>,
+    first_key: Option<Vec<u8>>,
+    bits_per_key: usize,
+    // the hashes of the keys added, for the bloom filter
+    key_hashes: Vec<u64>,
+}
+
+impl TableBuilder {
+    pub(super) fn new(file: Box<dyn VfsFile>, block_size: usize, bits_per_key: usize) -> Self {
+        TableBuilder {
+            writer: BufWriter::new(file),
+            block_size,
+            offset: 0,
+            block: Vec::new(),
+            index: Vec::new(),
+            first_key: None,
+            bits_per_key,
+            key_hashes: Vec::new(),
+        }
+    }
+
+    /// Adds an entry, whose key must be greater than every key added before.
+    pub(super) fn add(&mut self, key: &[u8], value: Option<&[u8]>) -> Result<()> {
+        if self.first_key.is_none() {
+            self.first_key = Some(key.to_owned());
+        }
+        if self.bits_per_key > 0 {
+            self.key_hashes.push(bloom::hash(key));
+        }
+        put_bytes(&mut self.block, key);
+        match value {
+            Some(value) => {
+                self.block.push(1);
+                put_bytes(&mut self.block, value);
+            }
+            None => self.block.push(0),
+        }
+        if self.block.len() >= self.block_size {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    /// The number of bytes written so far.
+    pub(super) fn len(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        let first_key = match self.first_key.take() {
+            Some(first_key) => first_key,
+            None => return Ok(()),
+        };
+        let checksum = seahash::hash(&self.block);
+        self.block.extend_from_slice(&checksum.to_le_bytes());
+        self.writer.write_all(&self.block)?;
+        self.index.push(BlockHandle {
+            first_key,
+            offset: self.offset,
+            len: self.block.len() as u32,
+        });
+        self.offset += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Writes the index, the filter and the footer, and flushes the table to durable storage.
+    pub(super) fn finish(mut self) -> Result<()> {
+        self.finish_block()?;
+        let mut index = Vec::new();
+        index.extend_from_slice(&(self.index.len() as u32).to_le_bytes());
+        for handle in &self.index {
+            put_bytes(&mut index, &handle.first_key);
+            index.extend_from_slice(&handle.offset.to_le_bytes());
+            index.extend_from_slice(&handle.len.to_le_bytes());
+        }
+        let checksum = seahash::hash(&index);
+        index.extend_from_slice(&checksum.to_le_bytes());
+        self.writer.write_all(&index)?;
+
+        let mut filter = Vec::new();
+        BloomFilter::build(&self.key_hashes, self.bits_per_key).encode(&mut filter);
+        let checksum = seahash::hash(&filter);
+        filter.extend_from_slice(&checksum.to_le_bytes());
+        self.writer.write_all(&filter)?;
+
+        let filter_offset = self.offset + index.len() as u64;
+        self.writer.write_all(&self.offset.to_le_bytes())?;
+        self.writer.write_all(&(index.len() as u64).to_le_bytes())?;
+        self.writer.write_all(&filter_offset.to_le_bytes())?;
+        self.writer.write_all(&(filter.len() as u64).to_le_bytes())?;
+        self.writer.write_all(&MAGIC.to_le_bytes())?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        Ok(())
+    }
+}
+
+/// A table open for reads.
+pub(super) struct Table {
+    file: Box<dyn VfsFile>,
+    index: Vec<BlockHandle>,
+    filter: BloomFilter,
+    // read from the last block when the table is opened
+    last_key: Option<Vec<u8>>,
+}
+
+impl Table {
+    /// Opens a table, reading its index and filter.
+    pub(super) fn open(mut file: Box<dyn VfsFile>) -> Result<Table> {
+        let len = file.seek(SeekFrom::End(0))?;
+        if len < FOOTER_LEN {
+            return Err(corrupt("table is too short"));
+        }
+        let mut footer = [0; FOOTER_LEN as usize];
+        file.read_exact_at(&mut footer, len - FOOTER_LEN)?;
+        let mut footer = Reader(&footer);
+        let (index_offset, index_len) = (footer.u64()?, footer.u64()?);
+        let (filter_offset, filter_len) = (footer.u64()?, footer.u64()?);
+        if footer.u64()? != MAGIC
+            || index_offset + index_len > filter_offset
+            || filter_offset + filter_len > len - FOOTER_LEN
+        {
+            return Err(corrupt("bad table footer"));
+        }
+        let filter =
+            BloomFilter::decode(&read_checked(&*file, filter_offset, filter_len as usize)?);
+        let buf = read_checked(&*file, index_offset, index_len as usize)?;
+        let mut reader = Reader(&buf);
+        let count = reader.u32()?;
+        let mut index = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let first_key = reader.bytes()?.to_owned();
+            index.push(BlockHandle { first_key, offset: reader.u64()?, len: reader.u32()? });
+        }
+        let mut table = Table { file, index, filter, last_key: None };
+        if let Some(last) = table.index.len().checked_sub(1) {
+            table.last_key = table.read_block(last)?.pop().map(|(key, _)| key);
+        }
+        Ok(table)
+    }
+
+    /// The smallest key in the table, if it is not empty.
+    pub(super) fn first_key(&self) -> Option<&[u8]> {
+        self.index.first().map(|handle| &*handle.first_key)
+    }
+
+    /// The largest key in the table, if it is not empty.
+    pub(super) fn last_key(&self) -> Option<&[u8]> {
+        self.last_key.as_deref()
+    }
+
+    /// Estimates the memory held by the block index and the bloom filter, which stay in
+    /// memory while the table is open.
+    pub(super) fn memory_usage(&self) -> MemoryUsage {
+        let mut usage = MemoryUsage {
+            entries: self.index.len() as u64,
+            capacity: self.index.capacity() as u64,
+            index: (self.index.capacity() * size_of::<BlockHandle>() + self.filter.size()) as u64,
+            ..MemoryUsage::default()
+        };
+        for handle in &self.index {
+            usage.index += handle.first_key.capacity() as u64;
+        }
+        usage.index += self.last_key.as_ref().map_or(0, Vec::capacity) as u64;
+        usage
+    }
+
+    /// Returns `false` if the bloom filter rules the key out, so that `get` would not find it.
+    pub(super) fn may_contain(&self, key: &[u8]) -> bool {
+        self.filter.may_contain(key)
+    }
+
+    /// Looks a key up, returning `Some(None)` for a tombstone.
+    pub(super) fn get(&self, key: &[u8]) -> Result<Option<Option<Vec<u8>>>> {
+        let block = match self.block_for(key) {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+        let entries = self.read_block(block)?;
+        Ok(entries
+            .binary_search_by(|(entry_key, _)| entry_key.as_slice().cmp(key))
+            .ok()
+            .map(|i| entries[i].1.clone()))
+    }
+
+    /// The index of the only block that may hold `key`.
+    fn block_for(&self, key: &[u8]) -> Option<usize> {
+        self.index.partition_point(|handle| handle.first_key.as_slice() <= key).checked_sub(1)
+    }
+
+    fn read_block(&self, block: usize) -> Result<Vec<Entry>> {
+        let handle = &self.index[block];
+        let buf = read_checked(&*self.file, handle.offset, handle.len as usize)?;
+        let mut reader = Reader(&buf);
+        let mut entries = Vec::new();
+        while !reader.0.is_empty() {
+            let key = reader.bytes()?.to_owned();
+            let value = match reader.u8()? {
+                0 => None,
+                _ => Some(reader.bytes()?.to_owned()),
+            };
+            entries.push((key, value));
+        }
+        Ok(entries)
+    }
+}
+
+/// Iterates over the entries of a table in key order, from a lower bound.
+pub(super) struct TableIter {
+    table: Arc<Table>,
+    // index of the next block to read
+    next_block: usize,
+    entries: std::vec::IntoIter<Entry>,
+}
+
+impl TableIter {
+    pub(super) fn new(table: Arc<Table>, start: Bound<&[u8]>) -> Result<TableIter> {
+        let key = match start {
+            Bound::Included(key) | Bound::Excluded(key) => key,
+            Bound::Unbounded => {
+                return Ok(TableIter { table, next_block: 0, entries: Vec::new().into_iter() })
+            }
+        };
+        let block = table.block_for(key).unwrap_or(0);
+        let mut entries = match table.index.get(block) {
+            Some(_) => table.read_block(block)?,
+            None => Vec::new(),
+        };
+        entries.retain(|(entry_key, _)| match start {
+            Bound::Included(key) => entry_key.as_slice() >= key,
+            Bound::Excluded(key) => entry_key.as_slice() > key,
+            Bound::Unbounded => true,
+        });
+        Ok(TableIter { table, next_block: block + 1, entries: entries.into_iter() })
+    }
+}
+
+impl Iterator for TableIter {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Some(Ok(entry));
+            }
+            if self.next_block >= self.table.index.len() {
+                return None;
+            }
+            match self.table.read_block(self.next_block) {
+                Ok(entries) => self.entries = entries.into_iter(),
+                Err(err) => return Some(Err(err)),
+            }
+            self.next_block += 1;
+        }
+    }
+}
+
+/// Reads bytes ending with their checksum, and returns them without it.
+fn read_checked(file: &dyn VfsFile, offset: u64, len: usize) -> Result<Vec<u8>> {
+    if len < 8 {
+        return Err(corrupt("block is too short"));
+    }
+    let mut buf = vec![0; len];
+    file.read_exact_at(&mut buf, offset)?;
+    let checksum = buf.split_off(len - 8);
+    if seahash::hash(&buf).to_le_bytes()[..] != checksum[..] {
+        return Err(corrupt("checksum mismatch"));
+    }
+    Ok(buf)
+}
+
+fn corrupt(msg: &str) -> KvsError {
+    KvsError::InvalidData(format!("corrupt table: {}", msg))
+}
+
+/// Decodes the integers and byte strings of a table.
+pub(super) struct Reader<'a>(pub(super) &'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(corrupt("unexpected end of data"));
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    pub(super) fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(super) fn u32(&mut self) -> Result<u32> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    pub(super) fn u64(&mut self) -> Result<u64> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    pub(super) fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
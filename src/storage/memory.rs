@@ -1,35 +1,223 @@
+mod bounded;
+mod ordered;
+mod persist;
+mod sharded;
+
+pub use self::bounded::{
+    BoundedMemStore, BoundedMemStoreOptions, Capacity, EvictionListener, EvictionPolicy,
+    EvictionReason,
+};
+pub use self::ordered::OrderedMemStore;
+pub use self::persist::MemStoreOptions;
+pub use self::sharded::ShardedMemStore;
+
+use self::persist::{read_snapshot, write_snapshot, Persistence};
 use crate::result::{KvsError, Result};
-use crate::storage::{BatchStore, Store};
+#[cfg(feature = "amortized")]
+use crate::storage::usage::griddle_table_bytes;
+#[cfg(not(feature = "amortized"))]
+use crate::storage::usage::hash_table_bytes;
+use crate::storage::vfs::{RealFs, Vfs};
+use crate::storage::{
+    create_checkpoint_dir, BatchStore, CheckpointStore, MemoryUsage, MemoryUsageStore, ScanStore,
+    Store,
+};
 
 #[cfg(not(feature = "amortized"))]
 use std::collections::HashMap;
 use std::fmt::Display;
 use std::hash::BuildHasherDefault;
+use std::io;
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 #[cfg(feature = "amortized")]
 use griddle::HashMap;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock, RwLockWriteGuard};
 use seahash::SeaHasher;
 
 use serde::{Deserialize, Serialize};
 
-type SeaHashMap = HashMap<Vec<u8>, Vec<u8>, BuildHasherDefault<SeaHasher>>;
+// values are shared, so that `get_ref` hands them out without copying
+type SeaHashMap = HashMap<Vec<u8>, Arc<[u8]>, BuildHasherDefault<SeaHasher>>;
 
 /// The `MemStore` stores  key/value pairs.
 ///
-/// In-memory key-value store using `HashMap` implementation and not persisted to disk.
+/// In-memory key-value store using `HashMap` implementation, not persisted to disk unless
+/// opened with [`MemStore::open_persistent`].
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MemStore {
     #[serde(with = "arc_rwlock_serde")]
     storage: Arc<RwLock<SeaHashMap>>,
+    // the log and snapshots of a persistent store
+    #[serde(skip)]
+    persistence: Option<Mutex<Persistence>>,
+    // held while a snapshot is written, one at a time
+    #[serde(skip)]
+    snapshotting: Mutex<()>,
 }
 
 impl MemStore {
     /// Creates a new Memory key-value storage engine.
     #[inline]
     pub fn open() -> Self {
-        MemStore { storage: Arc::new(RwLock::new(SeaHashMap::default())) }
+        MemStore {
+            storage: Arc::new(RwLock::new(SeaHashMap::default())),
+            persistence: None,
+            snapshotting: Mutex::new(()),
+        }
+    }
+
+    /// Opens a `MemStore` that keeps its pairs in the given directory, creating it if it does
+    /// not exist.
+    ///
+    /// Every write is appended to a log before it is applied. Once the log grows past
+    /// [`snapshot_threshold`](MemStoreOptions::snapshot_threshold), the whole map is written
+    /// to a snapshot and the log starts over. Opening loads the latest snapshot, then replays
+    /// the log written since.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors while loading the snapshot and the log.
+    pub fn open_persistent(path: impl Into<PathBuf>) -> Result<Self> {
+        MemStore::open_persistent_with_options(path, MemStoreOptions::default())
+    }
+
+    /// Opens a persistent `MemStore` with the given options, see
+    /// [`MemStore::open_persistent`].
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors while loading the snapshot and the log.
+    pub fn open_persistent_with_options(
+        path: impl Into<PathBuf>,
+        options: MemStoreOptions,
+    ) -> Result<Self> {
+        MemStore::open_persistent_with_vfs(path, Box::new(RealFs), options)
+    }
+
+    /// Opens a persistent `MemStore` on the given file system.
+    pub(crate) fn open_persistent_with_vfs(
+        path: impl Into<PathBuf>,
+        vfs: Box<dyn Vfs>,
+        options: MemStoreOptions,
+    ) -> Result<Self> {
+        let (persistence, storage) = Persistence::open(vfs, path.into(), options)?;
+        Ok(MemStore {
+            storage: Arc::new(RwLock::new(storage)),
+            persistence: Some(Mutex::new(persistence)),
+            snapshotting: Mutex::new(()),
+        })
+    }
+
+    /// Opens a `MemStore` holding the pairs of a checkpoint written by
+    /// [`CheckpointStore::checkpoint`], in memory only.
+    ///
+    /// A checkpoint can be opened with [`MemStore::open_persistent`] as well.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors while reading the snapshot.
+    pub fn open_checkpoint(dir: impl AsRef<Path>) -> Result<Self> {
+        let (_, storage) = read_snapshot(&RealFs, dir.as_ref())?.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "no checkpoint in the directory")
+        })?;
+        Ok(MemStore {
+            storage: Arc::new(RwLock::new(storage)),
+            persistence: None,
+            snapshotting: Mutex::new(()),
+        })
+    }
+
+    /// Gets the value of a given key, without copying it.
+    ///
+    /// Returns `None` if the given key does not exist. The value is shared with the store,
+    /// and stays valid even if the key is set again or removed.
+    pub fn get_ref(&self, key: impl AsRef<[u8]>) -> Result<Option<Arc<[u8]>>> {
+        let key = key.as_ref();
+        if key.is_empty() {
+            return Err(KvsError::EmptyKey);
+        }
+        Ok(self.storage.read().get(key).cloned())
+    }
+
+    /// Writes a snapshot of a persistent store now, and starts its log over.
+    ///
+    /// Writers only wait for the map to be copied, not for the snapshot to be written. Does
+    /// nothing for a store that is not persistent.
+    pub fn snapshot(&self) -> Result<()> {
+        if let Some(persistence) = &self.persistence {
+            let _snapshotting = self.snapshotting.lock();
+            let (pending, map) = {
+                let storage = self.storage.read();
+                (persistence.lock().start_snapshot()?, storage.clone())
+            };
+            pending.write(&map)?;
+        }
+        Ok(())
+    }
+
+    /// Returns why writing a snapshot after a write first failed, if it did since the last
+    /// call.
+    ///
+    /// Writes do not fail for it, since they are in the log by then; the snapshot is tried
+    /// again after later writes, and the logs grow until one is written.
+    pub fn take_snapshot_error(&self) -> Option<KvsError> {
+        self.persistence.as_ref()?.lock().take_snapshot_error()
+    }
+
+    /// Flushes the log of a persistent store to durable storage.
+    ///
+    /// Does nothing for a store that is not persistent.
+    pub fn sync(&self) -> Result<()> {
+        match &self.persistence {
+            Some(persistence) => persistence.lock().sync(),
+            None => Ok(()),
+        }
+    }
+
+    /// Logs writes of a persistent store, before they are applied.
+    fn log(&self, entries: &[(&[u8], Option<&[u8]>)]) -> Result<()> {
+        match &self.persistence {
+            Some(persistence) => persistence.lock().append(entries),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes a snapshot of a persistent store whose log has grown past the threshold, once
+    /// writes are applied, from a copy of the map.
+    ///
+    /// `storage` is downgraded to a read lock for the copy, so readers go on while it is made,
+    /// but other writers wait for it, which takes as long as the map is large.
+    ///
+    /// The writes are logged already, so a snapshot that fails is no failure of theirs: the
+    /// logs still cover them, another snapshot is tried after later writes, and the first
+    /// failure is kept for [`take_snapshot_error`](MemStore::take_snapshot_error). One being
+    /// written already makes do for this one.
+    fn snapshot_if_needed(&self, storage: RwLockWriteGuard<SeaHashMap>) {
+        let persistence = match &self.persistence {
+            Some(persistence) => persistence,
+            None => return,
+        };
+        let _snapshotting = match self.snapshotting.try_lock() {
+            Some(snapshotting) => snapshotting,
+            None => return,
+        };
+        let pending = {
+            let mut persistence = persistence.lock();
+            if !persistence.needs_snapshot() {
+                return;
+            }
+            persistence.start_snapshot()
+        };
+        let written = pending.and_then(|pending| {
+            let map = SeaHashMap::clone(&RwLockWriteGuard::downgrade(storage));
+            pending.write(&map)
+        });
+        if let Err(err) = written {
+            persistence.lock().snapshot_failed(err);
+        }
     }
 }
 
@@ -54,7 +242,7 @@ impl Store for MemStore {
             return Err(KvsError::EmptyKey);
         }
         let storage = storage.read();
-        Ok(storage.get(&key).cloned())
+        Ok(storage.get(&key).map(|value| value.to_vec()))
     }
 
     #[inline]
@@ -64,9 +252,11 @@ impl Store for MemStore {
         if key.is_empty() {
             return Err(KvsError::EmptyKey);
         }
-        let value = value.as_ref().to_owned();
+        let value = Arc::from(value.as_ref());
         let mut storage = storage.write();
+        self.log(&[(&key, Some(&value))])?;
         storage.insert(key, value);
+        self.snapshot_if_needed(storage);
         Ok(())
     }
 
@@ -78,7 +268,9 @@ impl Store for MemStore {
             return Err(KvsError::EmptyKey);
         }
         let mut storage = storage.write();
+        self.log(&[(&key, None)])?;
         storage.remove(&key);
+        self.snapshot_if_needed(storage);
         Ok(())
     }
 
@@ -119,12 +311,16 @@ impl BatchStore for MemStore {
             ));
         }
         let mut storage = storage.write();
+        let entries: Vec<_> =
+            keys.iter().zip(&values).map(|(key, value)| (&key[..], Some(&value[..]))).collect();
+        self.log(&entries)?;
         for i in 0..keys.len() {
             let key = keys[i].to_vec();
-            let value = values[i].to_vec();
+            let value = Arc::from(&values[i][..]);
 
             storage.insert(key, value);
         }
+        self.snapshot_if_needed(storage);
         Ok(())
     }
 
@@ -133,39 +329,150 @@ impl BatchStore for MemStore {
         let storage = Arc::clone(&self.storage);
         let keys = keys.as_ref().to_owned();
         let mut storage = storage.write();
+        let entries: Vec<_> = keys.iter().map(|key| (&key[..], None)).collect();
+        self.log(&entries)?;
         for key in keys {
             storage.remove(&key);
         }
+        self.snapshot_if_needed(storage);
         Ok(())
     }
 }
 
-#[cfg(test)]
-impl super::TestSuite<MemStore> for MemStore {
-    fn setup() -> Result<Self> {
-        Ok(MemStore::open())
+impl ScanStore for MemStore {
+    /// Gets all key/value pairs within `range`.
+    ///
+    /// The hash map keeps no order, so this visits every entry and sorts the matches.
+    #[inline]
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan_limit(range, usize::MAX)
+    }
+
+    /// Gets the first `limit` pairs within `range`.
+    ///
+    /// This still visits every entry, but only copies the pairs it returns.
+    fn scan_limit(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let storage = self.storage.read();
+        let pairs = storage.iter().filter(|(key, _)| range.contains(*key)).collect();
+        let pairs = first_pairs(pairs, limit);
+        Ok(pairs.into_iter().map(|(key, value)| (key.clone(), value.to_vec())).collect())
     }
 }
 
-#[test]
-fn test_basic() -> Result<()> {
-    use super::TestSuite;
-    MemStore::test()
+impl CheckpointStore for MemStore {
+    /// Writes a snapshot of the pairs to `dest`, see [`MemStore::open_checkpoint`].
+    ///
+    /// Writers wait only while the map is copied, not while the copy is written out.
+    fn checkpoint(&self, dest: impl AsRef<Path>) -> Result<()> {
+        let dest = dest.as_ref();
+        create_checkpoint_dir(dest)?;
+        let storage = self.storage.read().clone();
+        write_snapshot(&RealFs, dest, 1, &storage)
+    }
 }
 
+impl MemoryUsageStore for MemStore {
+    fn memory_usage(&self) -> MemoryUsage {
+        map_usage(&self.storage.read())
+    }
+}
+
+/// Returns the key, or `KvsError::EmptyKey` if it is empty.
+/// Keeps the `limit` pairs with the lowest keys, sorted, without sorting the others.
+fn first_pairs<K: Ord, V>(mut pairs: Vec<(K, V)>, limit: usize) -> Vec<(K, V)> {
+    if pairs.len() > limit {
+        if limit == 0 {
+            return Vec::new();
+        }
+        pairs.select_nth_unstable_by(limit - 1, |a, b| a.0.cmp(&b.0));
+        pairs.truncate(limit);
+    }
+    pairs.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    pairs
+}
+
+pub(crate) fn non_empty(key: &[u8]) -> Result<&[u8]> {
+    if key.is_empty() {
+        return Err(KvsError::EmptyKey);
+    }
+    Ok(key)
+}
+
+/// Estimates the bytes of the table of a map, or of both tables while a `griddle` map grows.
+fn table_bytes<K, V, S>(map: &HashMap<K, V, S>) -> u64 {
+    #[cfg(feature = "amortized")]
+    let bytes = griddle_table_bytes::<K, V>(map.len(), map.capacity());
+    #[cfg(not(feature = "amortized"))]
+    let bytes = hash_table_bytes::<K, V>(map.capacity());
+    bytes
+}
+
+/// Estimates the memory held by a map, and the keys and values in it.
+fn map_usage(map: &SeaHashMap) -> MemoryUsage {
+    let mut usage = MemoryUsage {
+        entries: map.len() as u64,
+        capacity: map.capacity() as u64,
+        index: table_bytes(map),
+        ..MemoryUsage::default()
+    };
+    for (key, value) in map.iter() {
+        usage.index += key.capacity() as u64;
+        usage.values += value.len() as u64;
+    }
+    usage
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct MemFixture;
+
 #[cfg(test)]
-impl super::TestBatchSuite<MemStore> for MemStore {
-    fn setup() -> Result<Self> {
+impl crate::testing::Fixture for MemFixture {
+    type Store = MemStore;
+
+    fn open(&mut self) -> Result<MemStore> {
         Ok(MemStore::open())
     }
 }
 
-#[test]
-fn test_batch() -> Result<()> {
-    use super::TestBatchSuite;
-    MemStore::test()
+#[cfg(test)]
+crate::store_test_suite!(suite, MemFixture, [store, batch, scan, model]);
+
+/// A persistent store, with a log small enough to be snapshotted often.
+#[cfg(test)]
+struct PersistentFixture(tempfile::TempDir);
+
+#[cfg(test)]
+impl Default for PersistentFixture {
+    fn default() -> Self {
+        PersistentFixture(tempfile::TempDir::new().expect("unable to create temporary directory"))
+    }
+}
+
+#[cfg(test)]
+impl crate::testing::Fixture for PersistentFixture {
+    type Store = MemStore;
+
+    const PERSISTENT: bool = true;
+
+    fn open(&mut self) -> Result<MemStore> {
+        let options =
+            MemStoreOptions { snapshot_threshold: 4 * 1024, ..MemStoreOptions::default() };
+        MemStore::open_persistent_with_options(self.0.path(), options)
+    }
 }
 
+#[cfg(test)]
+crate::store_test_suite!(
+    persistent,
+    PersistentFixture::default(),
+    [store, batch, scan, persist, model]
+);
+
 #[test]
 fn test_empty_key_error() {
     let mut store = MemStore::open();
@@ -190,6 +497,58 @@ fn test_invalid_data_error() {
     }
 }
 
+#[test]
+fn test_checkpoint() -> Result<()> {
+    let dir = tempfile::TempDir::new()?;
+    let mut store = MemStore::open();
+    store.set(b"key1", b"value1")?;
+    store.set(vec![0, 255], vec![1, 2, 3])?;
+    store.checkpoint(dir.path())?;
+    store.set(b"key2", b"value2")?;
+
+    let restored = MemStore::open_checkpoint(dir.path())?;
+    assert_eq!(restored.get(b"key1")?, Some(b"value1".to_vec()));
+    assert_eq!(restored.get([0, 255])?, Some(vec![1, 2, 3]));
+    assert_eq!(restored.get(b"key2")?, None);
+    let persistent = MemStore::open_persistent(dir.path())?;
+    assert_eq!(persistent.get(b"key1")?, Some(b"value1".to_vec()));
+
+    // the destination must be empty
+    assert!(matches!(store.checkpoint(dir.path()), Err(KvsError::InvalidData(_))));
+    Ok(())
+}
+
+#[test]
+fn test_memory_usage() -> Result<()> {
+    let mut store = MemStore::open();
+    assert_eq!(store.memory_usage(), MemoryUsage::default());
+    for i in 0..1000 {
+        store.set(format!("key{:04}", i), vec![0; 100])?;
+    }
+    let usage = store.memory_usage();
+    assert_eq!((usage.entries, usage.values), (1000, 100 * 1000));
+    assert!(usage.capacity >= 1000);
+    let table = crate::storage::usage::hash_table_bytes::<Vec<u8>, Arc<[u8]>>(1000);
+    assert!(usage.index > 7 * 1000 + table);
+    assert_eq!(usage.buffers, 0);
+    Ok(())
+}
+
+#[test]
+fn test_get_ref() -> Result<()> {
+    let mut store = MemStore::open();
+    store.set(b"key", vec![7; 1024])?;
+    let value = store.get_ref(b"key")?.expect("value is set");
+    assert!(Arc::ptr_eq(&value, &store.get_ref(b"key")?.expect("value is set")));
+    // the value outlives the entry it was read from
+    store.set(b"key", b"other")?;
+    store.remove(b"key")?;
+    assert_eq!(&value[..], &[7; 1024][..]);
+    assert_eq!(store.get_ref(b"key")?, None);
+    assert!(matches!(store.get_ref(b""), Err(KvsError::EmptyKey)));
+    Ok(())
+}
+
 mod arc_rwlock_serde {
     use serde::de::Deserializer;
     use serde::ser::Serializer;
@@ -0,0 +1,666 @@
+use super::{first_pairs, non_empty, table_bytes, HashMap};
+use crate::result::{KvsError, Result};
+use crate::storage::usage::btree_bytes;
+use crate::storage::{BatchStore, MemoryUsage, MemoryUsageStore, ScanStore, Store};
+
+use parking_lot::Mutex;
+use seahash::SeaHasher;
+
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt::Display;
+use std::hash::{BuildHasher, BuildHasherDefault, Hasher};
+use std::mem::size_of;
+use std::ops::RangeBounds;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The bytes an entry takes besides its key and value: its slot in the map, and its slot in
+/// the eviction order. The spare capacity of the map and allocator overhead are not counted.
+const ENTRY_OVERHEAD: usize = size_of::<(Vec<u8>, Entry)>() + size_of::<((u64, u64), Vec<u8>)>();
+
+/// The most a `BoundedMemStore` holds, see [`BoundedMemStoreOptions`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Capacity {
+    /// A number of bytes of keys, values and per-entry overhead.
+    Bytes(usize),
+    /// A number of entries.
+    Entries(usize),
+}
+
+/// Which entries a `BoundedMemStore` evicts first once it is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// The least recently used.
+    Lru,
+    /// The least frequently used, the least recently used of them first.
+    Lfu,
+    /// Any entry, at random.
+    Random,
+    /// The ones expiring soonest, then the ones without a time to live, least recently used
+    /// first.
+    TtlFirst,
+}
+
+/// Why an entry was evicted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvictionReason {
+    /// To make room for another.
+    Capacity,
+    /// Its time to live ran out.
+    Expired,
+}
+
+/// Called with the key and value of every evicted entry.
+pub type EvictionListener = Arc<dyn Fn(&[u8], &[u8], EvictionReason) + Send + Sync>;
+
+/// Options for opening a `BoundedMemStore`.
+#[derive(Clone)]
+pub struct BoundedMemStoreOptions {
+    /// The most the store holds.
+    pub capacity: Capacity,
+    /// Which entries are evicted first. Defaults to `EvictionPolicy::Lru`.
+    pub policy: EvictionPolicy,
+    /// Called once an entry is evicted, outside of the lock of the store. Defaults to none.
+    pub listener: Option<EvictionListener>,
+}
+
+impl BoundedMemStoreOptions {
+    /// Options with the given capacity, and the defaults otherwise.
+    pub fn new(capacity: Capacity) -> Self {
+        BoundedMemStoreOptions { capacity, policy: EvictionPolicy::Lru, listener: None }
+    }
+}
+
+impl std::fmt::Debug for BoundedMemStoreOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BoundedMemStoreOptions")
+            .field("capacity", &self.capacity)
+            .field("policy", &self.policy)
+            .field("listener", &self.listener.is_some())
+            .finish()
+    }
+}
+
+/// An in-memory store that evicts entries to stay within a capacity, for use as a cache.
+///
+/// Entries can be given a time to live, after which they are gone. Apart from evictions and
+/// expiries, it behaves like any other `Store`. A `BoundedMemStore` can be cloned cheaply,
+/// and all clones share the same store.
+#[derive(Clone)]
+pub struct BoundedMemStore {
+    inner: Arc<Mutex<Inner>>,
+    listener: Option<EvictionListener>,
+}
+
+struct Inner {
+    entries: HashMap<Vec<u8>, Entry, BuildHasherDefault<SeaHasher>>,
+    // keys in eviction order, the first one goes first
+    order: BTreeMap<(u64, u64), Vec<u8>>,
+    capacity: Capacity,
+    policy: EvictionPolicy,
+    // accounted bytes of every entry
+    size: usize,
+    tick: u64,
+    // the time expiries are counted from
+    start: Instant,
+    rng: u64,
+}
+
+struct Entry {
+    value: Vec<u8>,
+    rank: (u64, u64),
+    hits: u64,
+    expires: Option<Instant>,
+}
+
+/// Evicted pairs, to be handed to the listener once the lock is released.
+type Evicted = Vec<(Vec<u8>, Vec<u8>, EvictionReason)>;
+
+impl BoundedMemStore {
+    /// Creates a new, empty store.
+    pub fn open(options: BoundedMemStoreOptions) -> Self {
+        let inner = Inner {
+            entries: HashMap::default(),
+            order: BTreeMap::new(),
+            capacity: options.capacity,
+            policy: options.policy,
+            size: 0,
+            tick: 0,
+            start: Instant::now(),
+            // xorshift needs a non-zero state
+            rng: RandomState::new().build_hasher().finish() | 1,
+        };
+        BoundedMemStore { inner: Arc::new(Mutex::new(inner)), listener: options.listener }
+    }
+
+    /// Sets the value of a key, which expires after `ttl`, or never if that is too far ahead
+    /// to tell the time of.
+    pub fn set_with_ttl(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+        ttl: Duration,
+    ) -> Result<()> {
+        let key = non_empty(key.as_ref())?;
+        let mut evicted = Vec::new();
+        let expires = Instant::now().checked_add(ttl);
+        self.inner.lock().insert(key, value.as_ref(), expires, &mut evicted);
+        self.notify(evicted);
+        Ok(())
+    }
+
+    /// Evicts every expired entry now, rather than as they are met.
+    pub fn purge_expired(&self) {
+        let mut evicted = Vec::new();
+        {
+            let mut inner = self.inner.lock();
+            let now = Instant::now();
+            let expired: Vec<Vec<u8>> = inner
+                .entries
+                .iter()
+                .filter(|(_, entry)| entry.expired(now))
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in expired {
+                inner.evict(&key, EvictionReason::Expired, &mut evicted);
+            }
+        }
+        self.notify(evicted);
+    }
+
+    /// The number of entries, including expired ones not evicted yet.
+    pub fn len(&self) -> usize {
+        self.inner.lock().entries.len()
+    }
+
+    /// Whether the store has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The bytes taken by the keys, values and per-entry overhead of every entry.
+    pub fn size(&self) -> usize {
+        self.inner.lock().size
+    }
+
+    fn notify(&self, evicted: Evicted) {
+        if let Some(listener) = &self.listener {
+            for (key, value, reason) in evicted {
+                listener(&key, &value, reason);
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for BoundedMemStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let inner = self.inner.lock();
+        f.debug_struct("BoundedMemStore")
+            .field("capacity", &inner.capacity)
+            .field("policy", &inner.policy)
+            .field("len", &inner.entries.len())
+            .field("size", &inner.size)
+            .finish()
+    }
+}
+
+impl Inner {
+    /// Returns the value of a key, counting it as used, and evicting it if it expired.
+    fn get(&mut self, key: &[u8], evicted: &mut Evicted) -> Option<Vec<u8>> {
+        let now = Instant::now();
+        let expired = self.entries.get(key)?.expired(now);
+        if expired {
+            self.evict(key, EvictionReason::Expired, evicted);
+            return None;
+        }
+        let tick = self.next_tick();
+        let entry = self.entries.get_mut(key).expect("Cannot find entry");
+        entry.hits += 1;
+        let old_rank = entry.rank;
+        let rank = match self.policy {
+            EvictionPolicy::Lru => (0, tick),
+            EvictionPolicy::Lfu => (entry.hits, tick),
+            EvictionPolicy::Random => old_rank,
+            EvictionPolicy::TtlFirst => (old_rank.0, tick),
+        };
+        entry.rank = rank;
+        let value = entry.value.clone();
+        if rank != old_rank {
+            let key = self.order.remove(&old_rank).expect("Cannot find entry rank");
+            self.order.insert(rank, key);
+        }
+        Some(value)
+    }
+
+    /// Adds or replaces an entry, then evicts others until the store is within capacity.
+    ///
+    /// An entry too large for the store is evicted right away, and so is the value it
+    /// replaces, which would be stale otherwise.
+    fn insert(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        expires: Option<Instant>,
+        evicted: &mut Evicted,
+    ) {
+        let cost = cost(key, value);
+        let fits = match self.capacity {
+            Capacity::Bytes(capacity) => cost <= capacity,
+            Capacity::Entries(capacity) => capacity > 0,
+        };
+        if !fits {
+            self.evict(key, EvictionReason::Capacity, evicted);
+            evicted.push((key.to_owned(), value.to_owned(), EvictionReason::Capacity));
+            return;
+        }
+        self.remove(key);
+        while self.over_capacity(cost) {
+            let (_, oldest) = self.order.iter().next().expect("store is not empty");
+            let oldest = oldest.clone();
+            self.evict(&oldest, EvictionReason::Capacity, evicted);
+        }
+
+        let tick = self.next_tick();
+        let rank = match self.policy {
+            EvictionPolicy::Lru => (0, tick),
+            EvictionPolicy::Lfu => (0, tick),
+            EvictionPolicy::Random => (self.next_random(), tick),
+            EvictionPolicy::TtlFirst => {
+                let expires = expires.map_or(u64::MAX, |expires| {
+                    let nanos = expires.saturating_duration_since(self.start).as_nanos();
+                    u64::try_from(nanos).unwrap_or(u64::MAX)
+                });
+                (expires, tick)
+            }
+        };
+        self.order.insert(rank, key.to_owned());
+        self.entries
+            .insert(key.to_owned(), Entry { value: value.to_owned(), rank, hits: 0, expires });
+        self.size += cost;
+    }
+
+    fn over_capacity(&self, incoming: usize) -> bool {
+        match self.capacity {
+            Capacity::Bytes(capacity) => self.size + incoming > capacity,
+            Capacity::Entries(capacity) => self.entries.len() + 1 > capacity,
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        let entry = self.entries.remove(key)?;
+        self.order.remove(&entry.rank);
+        self.size -= cost(key, &entry.value);
+        Some(entry.value)
+    }
+
+    fn evict(&mut self, key: &[u8], reason: EvictionReason, evicted: &mut Evicted) {
+        if let Some(value) = self.remove(key) {
+            evicted.push((key.to_owned(), value, reason));
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    // xorshift64
+    fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+}
+
+impl Entry {
+    fn expired(&self, now: Instant) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+fn cost(key: &[u8], value: &[u8]) -> usize {
+    // the key is held by the map and by the eviction order
+    2 * key.len() + value.len() + ENTRY_OVERHEAD
+}
+
+impl Display for BoundedMemStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "boundedmemstore")
+    }
+}
+
+impl Store for BoundedMemStore {
+    /// Gets the value of a key, counting it as used.
+    #[inline]
+    fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        let key = non_empty(key.as_ref())?;
+        let mut evicted = Vec::new();
+        let value = self.inner.lock().get(key, &mut evicted);
+        self.notify(evicted);
+        Ok(value)
+    }
+
+    /// Sets the value of a key, with no time to live, evicting others if the store is full.
+    #[inline]
+    fn set(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
+        let key = non_empty(key.as_ref())?;
+        let mut evicted = Vec::new();
+        self.inner.lock().insert(key, value.as_ref(), None, &mut evicted);
+        self.notify(evicted);
+        Ok(())
+    }
+
+    #[inline]
+    fn remove(&mut self, key: impl AsRef<[u8]>) -> Result<()> {
+        let key = non_empty(key.as_ref())?;
+        self.inner.lock().remove(key);
+        Ok(())
+    }
+
+    /// Returns `true` if the store has an unexpired value for the key, without counting it as
+    /// used.
+    #[inline]
+    fn contains(&mut self, key: impl AsRef<[u8]>) -> Result<bool> {
+        let key = non_empty(key.as_ref())?;
+        let now = Instant::now();
+        Ok(self.inner.lock().entries.get(key).is_some_and(|entry| !entry.expired(now)))
+    }
+}
+
+impl BatchStore for BoundedMemStore {
+    fn get_batch(&self, keys: impl AsRef<[Vec<u8>]>) -> Result<Vec<Option<Vec<u8>>>> {
+        let mut evicted = Vec::new();
+        let values = {
+            let mut inner = self.inner.lock();
+            keys.as_ref().iter().map(|key| inner.get(key, &mut evicted)).collect()
+        };
+        self.notify(evicted);
+        Ok(values)
+    }
+
+    fn set_batch(
+        &mut self,
+        keys: impl AsRef<[Vec<u8>]>,
+        values: impl AsRef<[Vec<u8>]>,
+    ) -> Result<()> {
+        let (keys, values) = (keys.as_ref(), values.as_ref());
+        if keys.len() != values.len() {
+            return Err(KvsError::InvalidData(
+                "The number of keys does not match the number of values".to_string(),
+            ));
+        }
+        let mut evicted = Vec::new();
+        {
+            let mut inner = self.inner.lock();
+            for (key, value) in keys.iter().zip(values) {
+                inner.insert(key, value, None, &mut evicted);
+            }
+        }
+        self.notify(evicted);
+        Ok(())
+    }
+
+    fn remove_batch(&mut self, keys: impl AsRef<[Vec<u8>]>) -> Result<()> {
+        let mut inner = self.inner.lock();
+        for key in keys.as_ref() {
+            inner.remove(key);
+        }
+        Ok(())
+    }
+}
+
+impl ScanStore for BoundedMemStore {
+    /// Gets all unexpired key/value pairs within `range`, without counting them as used.
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan_limit(range, usize::MAX)
+    }
+
+    /// Gets the first `limit` unexpired pairs within `range`, without counting them as used.
+    ///
+    /// This still visits every entry, but only copies the pairs it returns.
+    fn scan_limit(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let now = Instant::now();
+        let inner = self.inner.lock();
+        let pairs = inner
+            .entries
+            .iter()
+            .filter(|(key, entry)| range.contains(*key) && !entry.expired(now))
+            .collect();
+        let pairs = first_pairs(pairs, limit);
+        Ok(pairs.into_iter().map(|(key, entry)| (key.clone(), entry.value.clone())).collect())
+    }
+}
+
+impl MemoryUsageStore for BoundedMemStore {
+    fn memory_usage(&self) -> MemoryUsage {
+        let inner = self.inner.lock();
+        let capacity = inner.entries.capacity();
+        let mut usage = MemoryUsage {
+            entries: inner.entries.len() as u64,
+            capacity: capacity as u64,
+            index: table_bytes(&inner.entries)
+                + btree_bytes::<(u64, u64), Vec<u8>>(inner.order.len()),
+            ..MemoryUsage::default()
+        };
+        for (key, entry) in inner.entries.iter() {
+            // the key is held by the map and by the eviction order
+            usage.index += 2 * key.capacity() as u64;
+            usage.values += entry.value.capacity() as u64;
+        }
+        usage
+    }
+}
+
+#[cfg(test)]
+struct BoundedFixture(BoundedMemStoreOptions);
+
+#[cfg(test)]
+impl crate::testing::Fixture for BoundedFixture {
+    type Store = BoundedMemStore;
+
+    fn open(&mut self) -> Result<BoundedMemStore> {
+        Ok(BoundedMemStore::open(self.0.clone()))
+    }
+}
+
+// room enough that nothing gets evicted, so that it behaves like any store
+#[cfg(test)]
+crate::store_test_suite!(
+    suite,
+    BoundedFixture(BoundedMemStoreOptions::new(Capacity::Bytes(1 << 30))),
+    [store, batch, scan, model]
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(capacity: Capacity, policy: EvictionPolicy) -> BoundedMemStore {
+        BoundedMemStore::open(BoundedMemStoreOptions {
+            policy,
+            ..BoundedMemStoreOptions::new(capacity)
+        })
+    }
+
+    fn keys(store: &BoundedMemStore) -> Result<Vec<String>> {
+        let pairs = store.scan(..)?;
+        Ok(pairs.into_iter().map(|(key, _)| String::from_utf8(key).unwrap()).collect())
+    }
+
+    #[test]
+    fn lru() -> Result<()> {
+        let mut store = open(Capacity::Entries(3), EvictionPolicy::Lru);
+        store.set("a", "1")?;
+        store.set("b", "2")?;
+        store.set("c", "3")?;
+        store.get("a")?;
+        store.set("d", "4")?;
+        assert_eq!(keys(&store)?, ["a", "c", "d"]);
+        // replacing a value does not evict anything
+        store.set("c", "5")?;
+        assert_eq!(keys(&store)?, ["a", "c", "d"]);
+        let usage = store.memory_usage();
+        assert_eq!((usage.entries, usage.values), (3, 3));
+        Ok(())
+    }
+
+    #[test]
+    fn lfu() -> Result<()> {
+        let mut store = open(Capacity::Entries(3), EvictionPolicy::Lfu);
+        store.set("a", "1")?;
+        store.set("b", "2")?;
+        store.set("c", "3")?;
+        for _ in 0..3 {
+            store.get("a")?;
+            store.get("c")?;
+        }
+        store.get("b")?;
+        store.set("d", "4")?;
+        assert_eq!(keys(&store)?, ["a", "c", "d"]);
+        // "d" was never read
+        store.set("e", "5")?;
+        assert_eq!(keys(&store)?, ["a", "c", "e"]);
+        Ok(())
+    }
+
+    #[test]
+    fn random() -> Result<()> {
+        let mut store = open(Capacity::Entries(100), EvictionPolicy::Random);
+        for i in 0..1000 {
+            store.set(format!("key{}", i), "value")?;
+        }
+        assert_eq!(store.len(), 100);
+        // not only the most recent ones are left
+        let old = (0..900).filter(|i| store.contains(format!("key{}", i)).unwrap()).count();
+        assert!(old > 0);
+        Ok(())
+    }
+
+    #[test]
+    fn ttl() -> Result<()> {
+        let mut store = open(Capacity::Entries(3), EvictionPolicy::TtlFirst);
+        store.set("forever", "1")?;
+        store.set_with_ttl("long", "2", Duration::from_secs(3600))?;
+        store.set_with_ttl("short", "3", Duration::from_secs(60))?;
+        store.set("new", "4")?;
+        // the one expiring soonest went first, then the ones expiring at all
+        assert_eq!(keys(&store)?, ["forever", "long", "new"]);
+        store.set("newer", "5")?;
+        assert_eq!(keys(&store)?, ["forever", "new", "newer"]);
+
+        store.set_with_ttl("gone", "6", Duration::from_millis(0))?;
+        assert!(!store.contains("gone")?);
+        assert_eq!(store.get("gone")?, None);
+        assert_eq!(store.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn byte_capacity() -> Result<()> {
+        let capacity = 10 * cost(b"key0", &[0; 100]);
+        let mut store = open(Capacity::Bytes(capacity), EvictionPolicy::Lru);
+        for i in 0..100 {
+            store.set(format!("key{}", i % 10), vec![0; 100])?;
+        }
+        assert_eq!((store.len(), store.size()), (10, capacity));
+        // a longer key takes the room of two
+        store.set("key10", vec![0; 100])?;
+        assert_eq!(store.len(), 9);
+        assert!(store.size() <= capacity);
+        assert_eq!(store.get("key0")?, None);
+        assert_eq!(store.get("key1")?, None);
+
+        // too large to keep at all, and the old value is gone too
+        store.set("key3", vec![0; capacity])?;
+        assert_eq!(store.get("key3")?, None);
+        store.remove("key2")?;
+        let size = 6 * cost(b"key0", &[0; 100]) + cost(b"key10", &[0; 100]);
+        assert_eq!((store.len(), store.size()), (7, size));
+        Ok(())
+    }
+
+    #[test]
+    fn listener() -> Result<()> {
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let listener: EvictionListener = {
+            let evicted = Arc::clone(&evicted);
+            Arc::new(move |key: &[u8], value: &[u8], reason| {
+                evicted.lock().push((key.to_vec(), value.to_vec(), reason))
+            })
+        };
+        let options = BoundedMemStoreOptions {
+            listener: Some(listener),
+            ..BoundedMemStoreOptions::new(Capacity::Entries(2))
+        };
+        let mut store = BoundedMemStore::open(options);
+        store.set("a", "1")?;
+        store.set_with_ttl("b", "2", Duration::from_millis(0))?;
+        store.set("c", "3")?;
+        store.set("d", "4")?;
+        store.purge_expired();
+        assert_eq!(
+            *evicted.lock(),
+            vec![
+                (b"a".to_vec(), b"1".to_vec(), EvictionReason::Capacity),
+                (b"b".to_vec(), b"2".to_vec(), EvictionReason::Capacity),
+            ]
+        );
+        store.set_with_ttl("e", "5", Duration::from_millis(0))?;
+        store.purge_expired();
+        assert_eq!(
+            evicted.lock().last(),
+            Some(&(b"e".to_vec(), b"5".to_vec(), EvictionReason::Expired))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn oversized_replace() -> Result<()> {
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let listener: EvictionListener = {
+            let evicted = Arc::clone(&evicted);
+            Arc::new(move |key: &[u8], value: &[u8], reason| {
+                evicted.lock().push((key.to_vec(), value.to_vec(), reason))
+            })
+        };
+        let options = BoundedMemStoreOptions {
+            listener: Some(listener),
+            ..BoundedMemStoreOptions::new(Capacity::Bytes(1024))
+        };
+        let mut store = BoundedMemStore::open(options);
+        store.set("a", "1")?;
+        store.set("a", vec![0; 2048])?;
+        assert_eq!(store.get("a")?, None);
+        assert_eq!(
+            *evicted.lock(),
+            vec![
+                (b"a".to_vec(), b"1".to_vec(), EvictionReason::Capacity),
+                (b"a".to_vec(), vec![0; 2048], EvictionReason::Capacity),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn ttl_past_the_end_of_time() -> Result<()> {
+        let mut store = BoundedMemStore::open(BoundedMemStoreOptions::new(Capacity::Entries(2)));
+        store.set_with_ttl("a", "1", Duration::MAX)?;
+        store.purge_expired();
+        assert_eq!(store.get("a")?, Some(b"1".to_vec()));
+
+        // more nanoseconds than a `u64` holds still expire after the others
+        let mut store = open(Capacity::Entries(2), EvictionPolicy::TtlFirst);
+        store.set_with_ttl("far", "1", Duration::from_nanos(u64::MAX) + Duration::from_secs(1))?;
+        store.set_with_ttl("soon", "2", Duration::from_secs(60))?;
+        store.set("new", "3")?;
+        assert_eq!(keys(&store)?, ["far", "new"]);
+        Ok(())
+    }
+}
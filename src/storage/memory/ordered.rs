@@ -0,0 +1,229 @@
+use super::non_empty;
+use crate::result::{KvsError, Result};
+use crate::storage::usage::btree_bytes;
+use crate::storage::{BatchStore, MemoryUsage, MemoryUsageStore, ScanStore, Store};
+
+use parking_lot::RwLock;
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+
+/// An in-memory store keeping its keys in order, in a B-tree.
+///
+/// Point operations are slower than with the hash map of `MemStore`, but scans only visit
+/// the keys in their range, and come out sorted as they are, like with `SledStore`. An
+/// `OrderedMemStore` can be cloned cheaply, and all clones share the same store.
+#[derive(Clone, Debug, Default)]
+pub struct OrderedMemStore {
+    storage: Arc<RwLock<BTreeMap<Vec<u8>, Vec<u8>>>>,
+}
+
+impl OrderedMemStore {
+    /// Creates a new, empty store.
+    #[inline]
+    pub fn open() -> Self {
+        OrderedMemStore::default()
+    }
+
+    /// Returns the pair with the smallest key, if the store is not empty.
+    pub fn first(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        let storage = self.storage.read();
+        storage.iter().next().map(|(key, value)| (key.clone(), value.clone()))
+    }
+
+    /// Returns the pair with the largest key, if the store is not empty.
+    pub fn last(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        let storage = self.storage.read();
+        storage.iter().next_back().map(|(key, value)| (key.clone(), value.clone()))
+    }
+
+    /// Calls `f` on the pairs within `range`, in ascending key order, until it returns
+    /// `false`.
+    ///
+    /// Writers wait until the iteration is over, so `f` should be quick, and must not write
+    /// to the store.
+    pub fn for_each_in(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        mut f: impl FnMut(&[u8], &[u8]) -> bool,
+    ) {
+        let storage = self.storage.read();
+        for (key, value) in range_of(&storage, &range) {
+            if !f(key, value) {
+                break;
+            }
+        }
+    }
+}
+
+impl Display for OrderedMemStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "orderedmemstore")
+    }
+}
+
+impl Store for OrderedMemStore {
+    #[inline]
+    fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        let key = non_empty(key.as_ref())?;
+        Ok(self.storage.read().get(key).cloned())
+    }
+
+    #[inline]
+    fn set(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
+        let key = non_empty(key.as_ref())?;
+        self.storage.write().insert(key.to_owned(), value.as_ref().to_owned());
+        Ok(())
+    }
+
+    #[inline]
+    fn remove(&mut self, key: impl AsRef<[u8]>) -> Result<()> {
+        let key = non_empty(key.as_ref())?;
+        self.storage.write().remove(key);
+        Ok(())
+    }
+
+    #[inline]
+    fn contains(&mut self, key: impl AsRef<[u8]>) -> Result<bool> {
+        let key = non_empty(key.as_ref())?;
+        Ok(self.storage.read().contains_key(key))
+    }
+}
+
+impl BatchStore for OrderedMemStore {
+    fn get_batch(&self, keys: impl AsRef<[Vec<u8>]>) -> Result<Vec<Option<Vec<u8>>>> {
+        let storage = self.storage.read();
+        Ok(keys.as_ref().iter().map(|key| storage.get(key).cloned()).collect())
+    }
+
+    fn set_batch(
+        &mut self,
+        keys: impl AsRef<[Vec<u8>]>,
+        values: impl AsRef<[Vec<u8>]>,
+    ) -> Result<()> {
+        let (keys, values) = (keys.as_ref(), values.as_ref());
+        if keys.len() != values.len() {
+            return Err(KvsError::InvalidData(
+                "The number of keys does not match the number of values".to_string(),
+            ));
+        }
+        let mut storage = self.storage.write();
+        for (key, value) in keys.iter().zip(values) {
+            storage.insert(key.clone(), value.clone());
+        }
+        Ok(())
+    }
+
+    fn remove_batch(&mut self, keys: impl AsRef<[Vec<u8>]>) -> Result<()> {
+        let mut storage = self.storage.write();
+        for key in keys.as_ref() {
+            storage.remove(key);
+        }
+        Ok(())
+    }
+}
+
+impl ScanStore for OrderedMemStore {
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let storage = self.storage.read();
+        Ok(range_of(&storage, &range).map(|(key, value)| (key.clone(), value.clone())).collect())
+    }
+
+    fn scan_limit(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let storage = self.storage.read();
+        Ok(range_of(&storage, &range)
+            .take(limit)
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+}
+
+impl MemoryUsageStore for OrderedMemStore {
+    fn memory_usage(&self) -> MemoryUsage {
+        let storage = self.storage.read();
+        let mut usage = MemoryUsage {
+            entries: storage.len() as u64,
+            capacity: storage.len() as u64,
+            index: btree_bytes::<Vec<u8>, Vec<u8>>(storage.len()),
+            ..MemoryUsage::default()
+        };
+        for (key, value) in storage.iter() {
+            usage.index += key.capacity() as u64;
+            usage.values += value.capacity() as u64;
+        }
+        usage
+    }
+}
+
+/// Iterates over the pairs within `range`.
+///
+/// Unlike `BTreeMap::range`, it does not panic on a range that ends before it starts.
+fn range_of<'a>(
+    storage: &'a BTreeMap<Vec<u8>, Vec<u8>>,
+    range: &'a impl RangeBounds<Vec<u8>>,
+) -> impl Iterator<Item = (&'a Vec<u8>, &'a Vec<u8>)> {
+    let start = match range.start_bound() {
+        Bound::Included(key) => Bound::Included(key.as_slice()),
+        Bound::Excluded(key) => Bound::Excluded(key.as_slice()),
+        Bound::Unbounded => Bound::Unbounded,
+    };
+    storage
+        .range::<[u8], _>((start, Bound::Unbounded))
+        .take_while(move |(key, _)| range.contains(key))
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct OrderedFixture;
+
+#[cfg(test)]
+impl crate::testing::Fixture for OrderedFixture {
+    type Store = OrderedMemStore;
+
+    fn open(&mut self) -> Result<OrderedMemStore> {
+        Ok(OrderedMemStore::open())
+    }
+}
+
+#[cfg(test)]
+crate::store_test_suite!(suite, OrderedFixture, [store, batch, scan, model]);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ordered_iteration() -> Result<()> {
+        let mut store = OrderedMemStore::open();
+        assert_eq!(store.first(), None);
+        for i in (0..100).rev() {
+            store.set(format!("key{:02}", i), i.to_string())?;
+        }
+        assert_eq!(store.first(), Some((b"key00".to_vec(), b"0".to_vec())));
+        assert_eq!(store.last(), Some((b"key99".to_vec(), b"99".to_vec())));
+
+        let mut keys = Vec::new();
+        store.for_each_in(b"key10".to_vec().., |key, _| {
+            keys.push(key.to_owned());
+            keys.len() < 3
+        });
+        assert_eq!(keys, vec![b"key10".to_vec(), b"key11".to_vec(), b"key12".to_vec()]);
+
+        // a range ending before it starts is empty
+        assert!(store.scan(b"key50".to_vec()..b"key40".to_vec())?.is_empty());
+        assert!(store
+            .scan((Bound::Excluded(b"key50".to_vec()), Bound::Excluded(b"key50".to_vec())))?
+            .is_empty());
+
+        let usage = store.memory_usage();
+        assert_eq!((usage.entries, usage.capacity), (100, 100));
+        assert!(usage.index > 5 * 100);
+        Ok(())
+    }
+}
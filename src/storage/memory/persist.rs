@@ -0,0 +1,370 @@
+//! Persistence of a `MemStore`: a write-ahead log of every write, and snapshots of the whole
+//! map that let the log start over, like the append-only file of Redis.
+//!
+//! The directory of a store holds its latest snapshot, `memstore.json`, and the logs of the
+//! writes made since, `{id}.wal`. A snapshot names the first log it does not cover; opening
+//! the store loads it, then replays that log and any later one, in order.
+
+use super::SeaHashMap;
+use crate::result::{KvsError, Result};
+use crate::storage::vfs::Vfs;
+use crate::storage::wal::{Entry, Wal};
+use crate::storage::SyncMode;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+use std::ffi::OsStr;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const SNAPSHOT: &str = "memstore.json";
+const SNAPSHOT_TMP: &str = "memstore.json.tmp";
+
+/// Options for opening a persistent `MemStore`.
+#[derive(Clone, Debug)]
+pub struct MemStoreOptions {
+    /// The size in bytes of the log after which a snapshot is taken, and the log starts over.
+    /// Defaults to 64 MiB.
+    pub snapshot_threshold: u64,
+    /// When writes are flushed to durable storage. Defaults to `SyncMode::Never`.
+    pub sync: SyncMode,
+}
+
+impl Default for MemStoreOptions {
+    fn default() -> Self {
+        MemStoreOptions { snapshot_threshold: 64 * 1024 * 1024, sync: SyncMode::Never }
+    }
+}
+
+/// The content of a snapshot file, with keys and values in base64.
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    // the first log written after the snapshot was taken
+    wal: u64,
+    pairs: Vec<(String, String)>,
+}
+
+/// The log and the snapshots of a persistent `MemStore`.
+pub(super) struct Persistence {
+    vfs: Arc<dyn Vfs>,
+    path: PathBuf,
+    wal: Wal,
+    wal_id: u64,
+    options: MemStoreOptions,
+    // why a snapshot taken after a write first failed, since it was last taken
+    snapshot_error: Option<KvsError>,
+}
+
+impl std::fmt::Debug for Persistence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Persistence")
+            .field("path", &self.path)
+            .field("wal_id", &self.wal_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Persistence {
+    /// Opens the directory of a store, creating it if it does not exist, and returns the map
+    /// it holds.
+    pub(super) fn open(
+        vfs: Box<dyn Vfs>,
+        path: PathBuf,
+        options: MemStoreOptions,
+    ) -> Result<(Persistence, SeaHashMap)> {
+        let vfs: Arc<dyn Vfs> = Arc::from(vfs);
+        vfs.create_dir_all(&path)?;
+        let (first, mut map) = read_snapshot(&*vfs, &path)?.unwrap_or_default();
+        let first = first.max(1);
+
+        let mut ids = Vec::new();
+        for id in wal_ids(&*vfs, &path)? {
+            if id < first {
+                // covered by the snapshot, left by one that did not finish
+                vfs.remove_file(&wal_path(&path, id))?;
+            } else {
+                ids.push(id);
+            }
+        }
+        let wal_id = ids.last().copied().unwrap_or(first);
+        for &id in &ids {
+            if id != wal_id {
+                let (_, entries) = Wal::open(&*vfs, &wal_path(&path, id))?;
+                apply(&mut map, entries);
+            }
+        }
+        let (wal, entries) = Wal::open(&*vfs, &wal_path(&path, wal_id))?;
+        apply(&mut map, entries);
+
+        Ok((Persistence { vfs, path, wal, wal_id, options, snapshot_error: None }, map))
+    }
+
+    /// Appends writes to the log, before they are applied to the map.
+    pub(super) fn append(&mut self, entries: &[(&[u8], Option<&[u8]>)]) -> Result<()> {
+        self.wal.append(entries)?;
+        if self.options.sync == SyncMode::Always {
+            self.wal.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the log to durable storage.
+    pub(super) fn sync(&self) -> Result<()> {
+        self.wal.sync()
+    }
+
+    /// Keeps why a snapshot taken after a write failed, unless an earlier failure is kept.
+    pub(super) fn snapshot_failed(&mut self, err: KvsError) {
+        self.snapshot_error.get_or_insert(err);
+    }
+
+    /// Takes the first failure kept by [`snapshot_failed`](Persistence::snapshot_failed).
+    pub(super) fn take_snapshot_error(&mut self) -> Option<KvsError> {
+        self.snapshot_error.take()
+    }
+
+    /// Whether the log has grown past the snapshot threshold.
+    pub(super) fn needs_snapshot(&self) -> bool {
+        self.wal.len() >= self.options.snapshot_threshold
+    }
+
+    /// Starts a new log, for a snapshot of the map as it is now, which must hold every write
+    /// logged so far, to be written with the returned [`PendingSnapshot`].
+    ///
+    /// The new log is started first, so that a crash at any point, or a snapshot that fails
+    /// to be written, leaves either the old snapshot and every log since, or the new snapshot
+    /// and the new log.
+    pub(super) fn start_snapshot(&mut self) -> Result<PendingSnapshot> {
+        let wal_id = self.wal_id + 1;
+        let (wal, _) = Wal::open(&*self.vfs, &wal_path(&self.path, wal_id))?;
+        self.wal_id = wal_id;
+        self.wal = wal;
+        Ok(PendingSnapshot { vfs: Arc::clone(&self.vfs), path: self.path.clone(), wal_id })
+    }
+}
+
+/// A snapshot whose log is started, to be written without holding the log, or the map.
+///
+/// Only one may be written at a time, lest an older one replace a newer one whose logs are
+/// gone.
+pub(super) struct PendingSnapshot {
+    vfs: Arc<dyn Vfs>,
+    path: PathBuf,
+    wal_id: u64,
+}
+
+impl PendingSnapshot {
+    /// Writes `map`, as it was when the snapshot was started, and removes the logs it covers.
+    pub(super) fn write(self, map: &SeaHashMap) -> Result<()> {
+        write_snapshot(&*self.vfs, &self.path, self.wal_id, map)?;
+        // older logs may be left by a snapshot that did not finish
+        for id in wal_ids(&*self.vfs, &self.path)? {
+            if id < self.wal_id {
+                self.vfs.remove_file(&wal_path(&self.path, id))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes a snapshot of `map` to the given directory, to be followed by the log `wal`.
+///
+/// It is written to a temporary file first and renamed over the old one, so that a crash
+/// leaves either of them in place, never a mix.
+pub(super) fn write_snapshot(vfs: &dyn Vfs, dir: &Path, wal: u64, map: &SeaHashMap) -> Result<()> {
+    let pairs = map.iter().map(|(key, value)| (BASE64.encode(key), BASE64.encode(value))).collect();
+    let tmp_path = dir.join(SNAPSHOT_TMP);
+    let mut file = vfs.create(&tmp_path)?;
+    file.write_all(&serde_json::to_vec(&Snapshot { wal, pairs })?)?;
+    file.sync_all()?;
+    vfs.rename(&tmp_path, &dir.join(SNAPSHOT))?;
+    vfs.sync_dir(dir)?;
+    Ok(())
+}
+
+/// Reads the snapshot in the given directory, if there is one, returning the log that
+/// follows it and the map.
+pub(super) fn read_snapshot(vfs: &dyn Vfs, dir: &Path) -> Result<Option<(u64, SeaHashMap)>> {
+    let mut file = match vfs.open_read(&dir.join(SNAPSHOT)) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+    let snapshot: Snapshot = serde_json::from_slice(&buf)?;
+    let decode = |data: &str| {
+        BASE64
+            .decode(data)
+            .map_err(|err| KvsError::InvalidData(format!("corrupt snapshot: {}", err)))
+    };
+    let mut map = SeaHashMap::default();
+    for (key, value) in snapshot.pairs {
+        map.insert(decode(&key)?, decode(&value)?.into());
+    }
+    Ok(Some((snapshot.wal, map)))
+}
+
+fn apply(map: &mut SeaHashMap, entries: Vec<Entry>) {
+    for (key, value) in entries {
+        match value {
+            Some(value) => map.insert(key, value.into()),
+            None => map.remove(&key),
+        };
+    }
+}
+
+fn wal_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.wal", id))
+}
+
+/// Returns the ids of the logs in the given directory, in order.
+fn wal_ids(vfs: &dyn Vfs, dir: &Path) -> Result<Vec<u64>> {
+    let mut ids: Vec<u64> = vfs
+        .list_files(dir)?
+        .into_iter()
+        .filter(|path| path.extension() == Some("wal".as_ref()))
+        .filter_map(|path| path.file_stem().and_then(OsStr::to_str)?.parse().ok())
+        .collect();
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::MemStore;
+    use super::*;
+    use crate::storage::sim::{Fault, FsOp, SimFs};
+    use crate::storage::Store;
+
+    fn open(fs: &SimFs, sync: SyncMode) -> Result<MemStore> {
+        let options = MemStoreOptions { snapshot_threshold: 4 * 1024, sync };
+        MemStore::open_persistent_with_vfs("/mem", Box::new(fs.clone()), options)
+    }
+
+    fn logs(fs: &SimFs) -> usize {
+        fs.files().iter().filter(|file| file.extension() == Some("wal".as_ref())).count()
+    }
+
+    #[test]
+    fn snapshots_bound_the_log() -> Result<()> {
+        let fs = SimFs::new();
+        let mut store = open(&fs, SyncMode::Never)?;
+        for i in 0..1000 {
+            store.set(format!("key{}", i % 50), format!("value{}", i))?;
+        }
+        store.remove("key0")?;
+        assert_eq!(logs(&fs), 1);
+        assert!(fs.read(Path::new("/mem/1.wal")).is_none(), "the first log was snapshotted");
+        drop(store);
+
+        let store = open(&fs, SyncMode::Never)?;
+        assert_eq!(store.get("key0")?, None);
+        for i in 1..50 {
+            assert_eq!(
+                store.get(format!("key{}", i))?,
+                Some(format!("value{}", 950 + i).into_bytes())
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn torn_log_record_is_dropped() -> Result<()> {
+        let fs = SimFs::new();
+        let mut store = open(&fs, SyncMode::Never)?;
+        store.set("a", "1")?;
+        fs.inject(FsOp::Write, 0, Fault::TornWrite);
+        assert!(store.set("b", "2").is_err());
+        // a write that could not be logged is not applied either
+        assert_eq!(store.get("b")?, None);
+        fs.recover();
+        drop(store);
+
+        let mut store = open(&fs, SyncMode::Never)?;
+        assert_eq!(store.get("a")?, Some(b"1".to_vec()));
+        assert_eq!(store.get("b")?, None);
+        store.set("c", "3")?;
+        drop(store);
+        let store = open(&fs, SyncMode::Never)?;
+        assert_eq!(store.get("c")?, Some(b"3".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn power_loss_keeps_durable_writes() -> Result<()> {
+        let fs = SimFs::new();
+        let mut store = open(&fs, SyncMode::Always)?;
+        for i in 0..500 {
+            store.set(format!("key{}", i % 50), format!("value{}", i))?;
+        }
+        fs.power_loss();
+        drop(store);
+
+        let store = open(&fs, SyncMode::Always)?;
+        for i in 0..50 {
+            assert_eq!(
+                store.get(format!("key{}", i))?,
+                Some(format!("value{}", 450 + i).into_bytes())
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn crash_during_snapshot() -> Result<()> {
+        let fs = SimFs::new();
+        let mut store = open(&fs, SyncMode::Never)?;
+        for i in 0..100 {
+            store.set(format!("key{}", i % 20), format!("value{}", i))?;
+        }
+        fs.inject(FsOp::Rename, 0, Fault::Crash);
+        assert!(store.snapshot().is_err());
+        fs.recover();
+        drop(store);
+
+        // the old snapshot and both logs
+        assert_eq!(logs(&fs), 2);
+        let store = open(&fs, SyncMode::Never)?;
+        for i in 0..20 {
+            assert_eq!(
+                store.get(format!("key{}", i))?,
+                Some(format!("value{}", 80 + i).into_bytes())
+            );
+        }
+        store.snapshot()?;
+        assert_eq!(logs(&fs), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn failed_snapshot_does_not_fail_writes() -> Result<()> {
+        let fs = SimFs::new();
+        let mut store = open(&fs, SyncMode::Never)?;
+        fs.inject(FsOp::Rename, 0, Fault::Error);
+        // up to the write that takes the snapshot, which starts a log but writes no snapshot
+        let mut i = 0;
+        while logs(&fs) == 1 {
+            store.set(format!("key{}", i % 20), format!("value{}", i))?;
+            i += 1;
+        }
+        assert!(fs.read(Path::new("/mem/memstore.json")).is_none());
+        assert!(matches!(store.take_snapshot_error(), Some(KvsError::IOError(_))));
+        assert!(store.take_snapshot_error().is_none());
+        store.set("after", "value")?;
+        drop(store);
+
+        let store = open(&fs, SyncMode::Never)?;
+        assert_eq!(store.get("after")?, Some(b"value".to_vec()));
+        for j in i - 20..i {
+            assert_eq!(
+                store.get(format!("key{}", j % 20))?,
+                Some(format!("value{}", j).into_bytes())
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,280 @@
+use super::{first_pairs, map_usage, non_empty, SeaHashMap};
+use crate::result::{KvsError, Result};
+use crate::storage::{BatchStore, MemoryUsage, MemoryUsageStore, ScanStore, Store};
+
+use parking_lot::RwLock;
+
+use std::fmt::Display;
+use std::ops::RangeBounds;
+use std::sync::Arc;
+
+const DEFAULT_SHARDS: usize = 16;
+
+/// An in-memory store split into shards by the hash of their keys, each behind its own lock.
+///
+/// Writers of keys in different shards do not wait on each other, unlike with `MemStore`,
+/// whose single lock serializes every write. A `ShardedMemStore` can be cloned cheaply, and
+/// all clones share the same store, so that each thread can write through its own.
+///
+/// A batch locks each of its shards once, in shard order; it is applied shard by shard, so
+/// readers may see part of it.
+#[derive(Clone, Debug)]
+pub struct ShardedMemStore {
+    shards: Arc<[RwLock<SeaHashMap>]>,
+}
+
+impl ShardedMemStore {
+    /// Creates a new store with 16 shards.
+    #[inline]
+    pub fn open() -> Self {
+        ShardedMemStore::open_with_shards(DEFAULT_SHARDS)
+    }
+
+    /// Creates a new store with the given number of shards, at least one.
+    pub fn open_with_shards(shards: usize) -> Self {
+        let shards = (0..shards.max(1)).map(|_| RwLock::new(SeaHashMap::default())).collect();
+        ShardedMemStore { shards }
+    }
+
+    /// Gets the value of a given key, without copying it, like [`MemStore::get_ref`].
+    ///
+    /// [`MemStore::get_ref`]: super::MemStore::get_ref
+    pub fn get_ref(&self, key: impl AsRef<[u8]>) -> Result<Option<Arc<[u8]>>> {
+        let key = non_empty(key.as_ref())?;
+        Ok(self.shard(key).read().get(key).cloned())
+    }
+
+    /// The number of shards.
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn shard(&self, key: &[u8]) -> &RwLock<SeaHashMap> {
+        &self.shards[self.shard_index(key)]
+    }
+
+    fn shard_index(&self, key: &[u8]) -> usize {
+        (seahash::hash(key) % self.shards.len() as u64) as usize
+    }
+
+    /// Groups the positions of keys by the shard they belong to.
+    fn group<'a>(&self, keys: &'a [Vec<u8>]) -> Vec<Vec<(usize, &'a [u8])>> {
+        let mut groups = vec![Vec::new(); self.shards.len()];
+        for (i, key) in keys.iter().enumerate() {
+            groups[self.shard_index(key)].push((i, &key[..]));
+        }
+        groups
+    }
+}
+
+impl Default for ShardedMemStore {
+    fn default() -> Self {
+        Self::open()
+    }
+}
+
+impl Display for ShardedMemStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "shardedmemstore")
+    }
+}
+
+impl Store for ShardedMemStore {
+    #[inline]
+    fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        let key = non_empty(key.as_ref())?;
+        Ok(self.shard(key).read().get(key).map(|value| value.to_vec()))
+    }
+
+    #[inline]
+    fn set(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
+        let key = non_empty(key.as_ref())?;
+        self.shard(key).write().insert(key.to_owned(), Arc::from(value.as_ref()));
+        Ok(())
+    }
+
+    #[inline]
+    fn remove(&mut self, key: impl AsRef<[u8]>) -> Result<()> {
+        let key = non_empty(key.as_ref())?;
+        self.shard(key).write().remove(key);
+        Ok(())
+    }
+
+    #[inline]
+    fn contains(&mut self, key: impl AsRef<[u8]>) -> Result<bool> {
+        let key = non_empty(key.as_ref())?;
+        Ok(self.shard(key).read().contains_key(key))
+    }
+}
+
+impl BatchStore for ShardedMemStore {
+    fn get_batch(&self, keys: impl AsRef<[Vec<u8>]>) -> Result<Vec<Option<Vec<u8>>>> {
+        let keys = keys.as_ref();
+        let mut values = vec![None; keys.len()];
+        for (shard, group) in self.shards.iter().zip(self.group(keys)) {
+            if group.is_empty() {
+                continue;
+            }
+            let shard = shard.read();
+            for (i, key) in group {
+                values[i] = shard.get(key).map(|value| value.to_vec());
+            }
+        }
+        Ok(values)
+    }
+
+    fn set_batch(
+        &mut self,
+        keys: impl AsRef<[Vec<u8>]>,
+        values: impl AsRef<[Vec<u8>]>,
+    ) -> Result<()> {
+        let (keys, values) = (keys.as_ref(), values.as_ref());
+        if keys.len() != values.len() {
+            return Err(KvsError::InvalidData(
+                "The number of keys does not match the number of values".to_string(),
+            ));
+        }
+        for (shard, group) in self.shards.iter().zip(self.group(keys)) {
+            if group.is_empty() {
+                continue;
+            }
+            let mut shard = shard.write();
+            for (i, key) in group {
+                shard.insert(key.to_owned(), Arc::from(&values[i][..]));
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_batch(&mut self, keys: impl AsRef<[Vec<u8>]>) -> Result<()> {
+        for (shard, group) in self.shards.iter().zip(self.group(keys.as_ref())) {
+            if group.is_empty() {
+                continue;
+            }
+            let mut shard = shard.write();
+            for (_, key) in group {
+                shard.remove(key);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ScanStore for ShardedMemStore {
+    /// Gets all key/value pairs within `range`.
+    ///
+    /// Like with `MemStore`, this visits every entry of every shard and sorts the matches.
+    /// Shards are read one after the other, so the scan may see part of a concurrent batch.
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan_limit(range, usize::MAX)
+    }
+
+    /// Gets the first `limit` pairs within `range`.
+    ///
+    /// This still visits every entry, but keeps at most `limit` pairs of each shard, and only
+    /// copies the values it returns.
+    fn scan_limit(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut pairs = Vec::new();
+        for shard in self.shards.iter() {
+            let shard = shard.read();
+            let matches = shard.iter().filter(|(key, _)| range.contains(*key)).collect();
+            pairs.extend(
+                first_pairs(matches, limit)
+                    .into_iter()
+                    .map(|(key, value)| (key.clone(), Arc::clone(value))),
+            );
+        }
+        let pairs = first_pairs(pairs, limit);
+        Ok(pairs.into_iter().map(|(key, value)| (key, value.to_vec())).collect())
+    }
+}
+
+impl MemoryUsageStore for ShardedMemStore {
+    /// Sums the usage of every shard, reading them one after the other.
+    fn memory_usage(&self) -> MemoryUsage {
+        let mut usage = MemoryUsage::default();
+        for shard in self.shards.iter() {
+            usage += map_usage(&shard.read());
+        }
+        usage
+    }
+}
+
+#[cfg(test)]
+struct ShardedFixture(usize);
+
+#[cfg(test)]
+impl crate::testing::Fixture for ShardedFixture {
+    type Store = ShardedMemStore;
+
+    fn open(&mut self) -> Result<ShardedMemStore> {
+        Ok(ShardedMemStore::open_with_shards(self.0))
+    }
+}
+
+#[cfg(test)]
+crate::store_test_suite!(suite, ShardedFixture(DEFAULT_SHARDS), [store, batch, scan, model]);
+
+#[cfg(test)]
+crate::store_test_suite!(single_shard, ShardedFixture(1), [store, batch, scan, model]);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn keys_spread_over_shards() -> Result<()> {
+        let mut store = ShardedMemStore::open();
+        let keys: Vec<Vec<u8>> = (0..1000).map(|i| format!("key{}", i).into_bytes()).collect();
+        store.set_batch(&keys, &keys)?;
+        for shard in store.shards.iter() {
+            let len = shard.read().len();
+            assert!(len > 1000 / DEFAULT_SHARDS / 2, "{} keys in a shard", len);
+        }
+        assert_eq!(store.get_batch(&keys)?, keys.iter().cloned().map(Some).collect::<Vec<_>>());
+        assert_eq!(store.get_ref(&keys[0])?.as_deref(), Some(&keys[0][..]));
+        let usage = store.memory_usage();
+        assert_eq!(usage.entries, 1000);
+        assert!(usage.capacity >= 1000);
+        Ok(())
+    }
+
+    #[test]
+    fn async_ops_are_ready() -> Result<()> {
+        use crate::storage::pool::block_on;
+        use crate::storage::AsyncStore;
+
+        let mut store = ShardedMemStore::open();
+        block_on(AsyncStore::set(&mut store, "key", "value"))?;
+        assert_eq!(block_on(AsyncStore::get(&store, "key"))?, Some(b"value".to_vec()));
+        assert!(block_on(AsyncStore::contains(&mut store, "key"))?);
+        Ok(())
+    }
+
+    #[test]
+    fn clones_share_the_store() -> Result<()> {
+        let store = ShardedMemStore::open();
+        let handles: Vec<_> = (0..8)
+            .map(|t| {
+                let mut store = store.clone();
+                thread::spawn(move || -> Result<()> {
+                    for i in 0..500 {
+                        store.set(format!("key-{}-{}", t, i), "value")?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().expect("writer thread panicked")?;
+        }
+        assert_eq!(store.scan(..)?.len(), 8 * 500);
+        Ok(())
+    }
+}
@@ -0,0 +1,224 @@
+//! A pool of threads to run blocking store operations on, away from the tasks of an async
+//! runtime.
+
+use parking_lot::Mutex;
+
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed set of threads running blocking jobs, shared by all its clones.
+///
+/// The threads exit once every clone is dropped and the queued jobs are done.
+#[derive(Clone)]
+pub struct ThreadPool {
+    sender: Arc<Mutex<Sender<Job>>>,
+    threads: usize,
+}
+
+impl ThreadPool {
+    /// Starts a pool of the given number of threads, at least one.
+    pub fn new(threads: usize) -> Self {
+        let threads = threads.max(1);
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for i in 0..threads {
+            let receiver = Arc::clone(&receiver);
+            thread::Builder::new()
+                .name(format!("ritekv-pool-{}", i))
+                .spawn(move || loop {
+                    let job = match receiver.lock().recv() {
+                        Ok(job) => job,
+                        Err(_) => return,
+                    };
+                    // a panicking job does not take its thread down; its `Task`, if it has one,
+                    // resumes the panic
+                    let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                })
+                .expect("failed to spawn a pool thread");
+        }
+        ThreadPool { sender: Arc::new(Mutex::new(sender)), threads }
+    }
+
+    /// The pool used unless another is given, with two threads per core, since its jobs
+    /// mostly wait on I/O.
+    pub fn shared() -> ThreadPool {
+        static SHARED: OnceLock<ThreadPool> = OnceLock::new();
+        SHARED
+            .get_or_init(|| {
+                let cores = thread::available_parallelism().map_or(1, |cores| cores.get());
+                ThreadPool::new(2 * cores)
+            })
+            .clone()
+    }
+
+    /// The number of threads.
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Queues `f` to run on the pool, returning a future of its result.
+    ///
+    /// `f` starts whether or not the future is polled, and runs to the end even if the future
+    /// is dropped. If `f` panics, the panic is resumed where the future is polled.
+    pub(crate) fn run<T: Send + 'static>(&self, f: impl FnOnce() -> T + Send + 'static) -> Task<T> {
+        let (task, completer) = Task::new();
+        self.spawn(move || completer.complete(panic::catch_unwind(AssertUnwindSafe(f))));
+        task
+    }
+
+    /// Queues `f` to run on the pool.
+    pub(crate) fn spawn(&self, f: impl FnOnce() + Send + 'static) {
+        // the threads only exit once the sender is gone, so this cannot fail
+        let _ = self.sender.lock().send(Box::new(f));
+    }
+}
+
+impl std::fmt::Debug for ThreadPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ThreadPool").field("threads", &self.threads).finish()
+    }
+}
+
+/// The result of a job, once it is done.
+pub(crate) struct Task<T> {
+    slot: Arc<Mutex<Slot<T>>>,
+}
+
+struct Slot<T> {
+    result: Option<thread::Result<T>>,
+    waker: Option<Waker>,
+}
+
+/// Hands the result of a job to its `Task`.
+///
+/// A completer dropped without completing fails its task, which then panics where it is
+/// polled instead of never finishing.
+pub(crate) struct Completer<T> {
+    slot: Arc<Mutex<Slot<T>>>,
+    completed: bool,
+}
+
+impl<T> Task<T> {
+    pub(crate) fn new() -> (Task<T>, Completer<T>) {
+        let slot = Arc::new(Mutex::new(Slot { result: None, waker: None }));
+        (Task { slot: Arc::clone(&slot) }, Completer { slot, completed: false })
+    }
+
+    /// A task that is done already.
+    pub(crate) fn ready(value: T) -> Task<T> {
+        Task { slot: Arc::new(Mutex::new(Slot { result: Some(Ok(value)), waker: None })) }
+    }
+}
+
+impl<T> Completer<T> {
+    pub(crate) fn complete(mut self, result: thread::Result<T>) {
+        self.completed = true;
+        self.fill(result);
+    }
+
+    fn fill(&self, result: thread::Result<T>) {
+        let waker = {
+            let mut slot = self.slot.lock();
+            slot.result = Some(result);
+            slot.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Drop for Completer<T> {
+    fn drop(&mut self) {
+        if !self.completed {
+            self.fill(Err(Box::new("the job was dropped before it completed")));
+        }
+    }
+}
+
+impl<T> Future for Task<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut slot = self.slot.lock();
+        match slot.result.take() {
+            Some(Ok(value)) => Poll::Ready(value),
+            Some(Err(panic)) => {
+                drop(slot);
+                panic::resume_unwind(panic)
+            }
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Runs a future to completion on the current thread.
+#[cfg(test)]
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    use std::task::Wake;
+
+    struct Unpark(thread::Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_jobs() {
+        let pool = ThreadPool::new(4);
+        let tasks: Vec<_> = (0..100).map(|i| pool.run(move || i * 2)).collect();
+        let results: Vec<_> = tasks.into_iter().map(block_on).collect();
+        assert_eq!(results, (0..100).map(|i| i * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn resumes_panics() {
+        let pool = ThreadPool::new(1);
+        let task = pool.run(|| panic!("in the pool"));
+        let panic = panic::catch_unwind(AssertUnwindSafe(|| block_on(task)))
+            .expect_err("the panic is resumed");
+        assert_eq!(panic.downcast_ref::<&str>(), Some(&"in the pool"));
+        // the thread survived it
+        assert_eq!(block_on(pool.run(|| 1)), 1);
+
+        // and survives a job without a task panicking too
+        pool.spawn(|| panic!("in the pool"));
+        assert_eq!(block_on(pool.run(|| 2)), 2);
+    }
+
+    #[test]
+    fn dropped_completers() {
+        let (task, completer) = Task::<()>::new();
+        drop(completer);
+        let panic =
+            panic::catch_unwind(AssertUnwindSafe(|| block_on(task))).expect_err("the task fails");
+        assert_eq!(panic.downcast_ref::<&str>(), Some(&"the job was dropped before it completed"));
+    }
+}
@@ -0,0 +1,233 @@
+use super::pool::{Task, ThreadPool};
+use crate::result::Result;
+use crate::storage::{AsyncBatchStore, AsyncStore, BatchStore, Store};
+
+use parking_lot::{Mutex, RwLock};
+
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+type Write<S> = Box<dyn FnOnce(&mut S) + Send>;
+
+/// Runs a blocking store on a [`ThreadPool`], so that async tasks never wait on its I/O.
+///
+/// Reads of the store run in parallel, writes one at a time, behind a read-write lock.
+/// Writes, and `contains`, which needs the write lock, run in the order they were issued
+/// by all clones, whether or not their futures are polled; a read may run before a write
+/// issued earlier. Stores that commit concurrent writes together, like `DiskStore`, are
+/// better used through their own `AsyncStore` implementation. A `PooledStore` can be cloned
+/// cheaply, and all clones share the same store.
+pub struct PooledStore<S> {
+    store: Arc<RwLock<S>>,
+    writes: Arc<Mutex<Writes<S>>>,
+    pool: ThreadPool,
+}
+
+/// The writes issued but not run yet, in order.
+struct Writes<S> {
+    queue: VecDeque<Write<S>>,
+    // whether a job of the pool is running the queue
+    running: bool,
+}
+
+impl<S> PooledStore<S> {
+    /// Runs `store` on the [shared pool](ThreadPool::shared).
+    pub fn new(store: S) -> Self {
+        PooledStore::with_pool(store, ThreadPool::shared())
+    }
+
+    /// Runs `store` on the given pool.
+    pub fn with_pool(store: S, pool: ThreadPool) -> Self {
+        let writes = Writes { queue: VecDeque::new(), running: false };
+        PooledStore {
+            store: Arc::new(RwLock::new(store)),
+            writes: Arc::new(Mutex::new(writes)),
+            pool,
+        }
+    }
+
+    /// The store, for blocking calls.
+    pub fn store(&self) -> &Arc<RwLock<S>> {
+        &self.store
+    }
+}
+
+impl<S: Send + Sync + 'static> PooledStore<S> {
+    /// Queues a write behind the ones issued before, returning a future of its result.
+    ///
+    /// A single job of the pool runs the queue at a time, so that writes keep their order
+    /// even though the pool has many threads.
+    fn write<T: Send + 'static>(&self, f: impl FnOnce(&mut S) -> T + Send + 'static) -> Task<T> {
+        let (task, completer) = Task::new();
+        let write: Write<S> = Box::new(move |store: &mut S| {
+            completer.complete(panic::catch_unwind(AssertUnwindSafe(|| f(store))))
+        });
+        let mut writes = self.writes.lock();
+        writes.queue.push_back(write);
+        if !writes.running {
+            writes.running = true;
+            let (store, writes) = (Arc::clone(&self.store), Arc::clone(&self.writes));
+            self.pool.spawn(move || run_writes(&store, &writes));
+        }
+        task
+    }
+}
+
+/// Runs the queued writes until there are none left.
+fn run_writes<S>(store: &RwLock<S>, writes: &Mutex<Writes<S>>) {
+    loop {
+        let write = {
+            let mut writes = writes.lock();
+            match writes.queue.pop_front() {
+                Some(write) => write,
+                None => {
+                    writes.running = false;
+                    return;
+                }
+            }
+        };
+        write(&mut store.write());
+    }
+}
+
+impl<S> Clone for PooledStore<S> {
+    fn clone(&self) -> Self {
+        PooledStore {
+            store: Arc::clone(&self.store),
+            writes: Arc::clone(&self.writes),
+            pool: self.pool.clone(),
+        }
+    }
+}
+
+impl<S: Display> Display for PooledStore<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.store.read().fmt(f)
+    }
+}
+
+impl<S: Store + 'static> AsyncStore for PooledStore<S> {
+    fn get(&self, key: impl AsRef<[u8]>) -> impl Future<Output = Result<Option<Vec<u8>>>> + Send {
+        let (store, key) = (Arc::clone(&self.store), key.as_ref().to_owned());
+        self.pool.run(move || store.read().get(key))
+    }
+
+    fn set(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+    ) -> impl Future<Output = Result<()>> + Send {
+        let (key, value) = (key.as_ref().to_owned(), value.as_ref().to_owned());
+        self.write(move |store| store.set(key, value))
+    }
+
+    fn remove(&mut self, key: impl AsRef<[u8]>) -> impl Future<Output = Result<()>> + Send {
+        let key = key.as_ref().to_owned();
+        self.write(move |store| store.remove(key))
+    }
+
+    fn contains(&mut self, key: impl AsRef<[u8]>) -> impl Future<Output = Result<bool>> + Send {
+        let key = key.as_ref().to_owned();
+        self.write(move |store| store.contains(key))
+    }
+}
+
+impl<S: BatchStore + 'static> AsyncBatchStore for PooledStore<S> {
+    fn get_batch(
+        &self,
+        keys: impl AsRef<[Vec<u8>]>,
+    ) -> impl Future<Output = Result<Vec<Option<Vec<u8>>>>> + Send {
+        let (store, keys) = (Arc::clone(&self.store), keys.as_ref().to_owned());
+        self.pool.run(move || store.read().get_batch(keys))
+    }
+
+    fn set_batch(
+        &mut self,
+        keys: impl AsRef<[Vec<u8>]>,
+        values: impl AsRef<[Vec<u8>]>,
+    ) -> impl Future<Output = Result<()>> + Send {
+        let (keys, values) = (keys.as_ref().to_owned(), values.as_ref().to_owned());
+        self.write(move |store| store.set_batch(keys, values))
+    }
+
+    fn remove_batch(
+        &mut self,
+        keys: impl AsRef<[Vec<u8>]>,
+    ) -> impl Future<Output = Result<()>> + Send {
+        let keys = keys.as_ref().to_owned();
+        self.write(move |store| store.remove_batch(keys))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::result::KvsError;
+    use crate::storage::pool::block_on;
+    use crate::storage::{MemStore, ScanStore, SledStore};
+
+    use tempfile::TempDir;
+
+    #[test]
+    fn runs_on_the_pool() -> Result<()> {
+        let mut store = PooledStore::with_pool(MemStore::open(), ThreadPool::new(2));
+        block_on(store.set("key", "value"))?;
+        assert_eq!(block_on(store.get("key"))?, Some(b"value".to_vec()));
+        assert!(block_on(store.contains("key"))?);
+        block_on(store.remove("key"))?;
+        assert_eq!(block_on(store.get("key"))?, None);
+        assert!(matches!(block_on(store.set("", "value")), Err(KvsError::EmptyKey)));
+
+        let keys: Vec<Vec<u8>> = (0..10).map(|i| format!("key{}", i).into_bytes()).collect();
+        block_on(store.set_batch(&keys, &keys))?;
+        let values = block_on(store.get_batch(&keys))?;
+        assert_eq!(values, keys.iter().cloned().map(Some).collect::<Vec<_>>());
+        block_on(store.remove_batch(&keys))?;
+        assert_eq!(store.store().read().scan(..)?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn writes_keep_their_order() -> Result<()> {
+        let store = PooledStore::with_pool(MemStore::open(), ThreadPool::new(4));
+        // every write is queued before any is awaited, from clones like separate tasks
+        let mut clones = vec![store.clone(); 101];
+        let (last, clones) = clones.split_last_mut().expect("there are clones");
+        let sets: Vec<_> = clones
+            .iter_mut()
+            .enumerate()
+            .map(|(i, store)| store.set("key", i.to_string()))
+            .collect();
+        let contains = last.contains("key");
+        for set in sets {
+            block_on(set)?;
+        }
+        assert!(block_on(contains)?);
+        assert_eq!(block_on(store.get("key"))?, Some(b"99".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_tasks() -> Result<()> {
+        let dir = TempDir::new()?;
+        let store = PooledStore::new(SledStore::open(sled::open(dir.path())?));
+        // every write is queued before any is awaited
+        let mut clones = vec![store.clone(); 100];
+        let tasks: Vec<_> = clones
+            .iter_mut()
+            .enumerate()
+            .map(|(i, store)| store.set(format!("key{}", i), i.to_string()))
+            .collect();
+        for task in tasks {
+            block_on(task)?;
+        }
+        for i in 0..100 {
+            let value = block_on(store.get(format!("key{}", i)))?;
+            assert_eq!(value, Some(i.to_string().into_bytes()));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,300 @@
+//! An in-memory `Vfs` that can inject I/O errors and simulate crashes.
+//!
+//! Every file keeps the bytes written to it and, separately, the bytes that were there at its
+//! last `sync_all`. A process crash keeps the former, a power loss rolls back to the latter.
+//! Changes to directory entries (creating, renaming and removing files) are always durable.
+
+use super::vfs::{Mapping, Vfs, VfsFile};
+
+use parking_lot::Mutex;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// The kinds of operations faults can be injected into.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum FsOp {
+    Write,
+    Sync,
+    Rename,
+    Remove,
+}
+
+/// What happens to an operation hit by a fault.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Fault {
+    /// The operation fails with "no space left on device" and has no effect.
+    NoSpace,
+    /// Only the first half of the buffer is written, then the write fails.
+    ShortWrite,
+    /// Only the first half of the buffer is written, then the process crashes.
+    TornWrite,
+    /// The operation fails with a generic I/O error and has no effect.
+    Error,
+    /// The process crashes before the operation: it and every later operation fail until
+    /// `recover` or `power_loss` is called.
+    Crash,
+}
+
+#[derive(Clone, Default)]
+struct SimFile {
+    data: Vec<u8>,
+    // content as of the last `sync_all`
+    durable: Vec<u8>,
+}
+
+#[derive(Default)]
+struct SimState {
+    dirs: BTreeSet<PathBuf>,
+    files: BTreeMap<PathBuf, SimFile>,
+    // pending faults, as (number of operations to let through, fault)
+    faults: HashMap<FsOp, (usize, Fault)>,
+    crashed: bool,
+}
+
+impl SimState {
+    /// Fails if the process is crashed, or if a fault is due for `op`.
+    fn check(&mut self, op: FsOp) -> io::Result<Option<Fault>> {
+        if self.crashed {
+            return Err(crashed());
+        }
+        let fault = match self.faults.get_mut(&op) {
+            Some((0, fault)) => *fault,
+            Some((skip, _)) => {
+                *skip -= 1;
+                return Ok(None);
+            }
+            None => return Ok(None),
+        };
+        self.faults.remove(&op);
+        match fault {
+            Fault::NoSpace => Err(io::Error::other("no space left on device")),
+            Fault::Error => Err(io::Error::other("injected I/O error")),
+            Fault::Crash => {
+                self.crashed = true;
+                Err(crashed())
+            }
+            Fault::ShortWrite | Fault::TornWrite => Ok(Some(fault)),
+        }
+    }
+
+    fn file(&mut self, path: &Path) -> io::Result<&mut SimFile> {
+        if self.crashed {
+            return Err(crashed());
+        }
+        self.files
+            .get_mut(path)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such file"))
+    }
+}
+
+fn crashed() -> io::Error {
+    io::Error::other("simulated crash")
+}
+
+/// A simulated file system, shared by all of its clones.
+#[derive(Clone, Default)]
+pub(crate) struct SimFs {
+    state: Arc<Mutex<SimState>>,
+}
+
+impl SimFs {
+    pub(crate) fn new() -> Self {
+        SimFs::default()
+    }
+
+    /// Lets `skip` more operations of kind `op` through, then applies `fault` to the next one.
+    pub(crate) fn inject(&self, op: FsOp, skip: usize, fault: Fault) {
+        self.state.lock().faults.insert(op, (skip, fault));
+    }
+
+    /// Restarts after a process crash: everything written so far is kept.
+    pub(crate) fn recover(&self) {
+        let mut state = self.state.lock();
+        state.crashed = false;
+        state.faults.clear();
+    }
+
+    /// Restarts after a power loss: every file rolls back to its last synced content.
+    pub(crate) fn power_loss(&self) {
+        let mut state = self.state.lock();
+        for file in state.files.values_mut() {
+            file.data = file.durable.clone();
+        }
+        state.crashed = false;
+        state.faults.clear();
+    }
+
+    /// Returns the current content of a file.
+    pub(crate) fn read(&self, path: &Path) -> Option<Vec<u8>> {
+        self.state.lock().files.get(path).map(|file| file.data.clone())
+    }
+
+    /// Lists every file, in path order.
+    pub(crate) fn files(&self) -> Vec<PathBuf> {
+        self.state.lock().files.keys().cloned().collect()
+    }
+}
+
+impl Vfs for SimFs {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.state.lock().dirs.insert(path.to_owned());
+        Ok(())
+    }
+
+    fn list_files(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let state = self.state.lock();
+        if !state.dirs.contains(path) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no such directory"));
+        }
+        Ok(state.files.keys().filter(|file| file.parent() == Some(path)).cloned().collect())
+    }
+
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        let mut state = self.state.lock();
+        if state.crashed {
+            return Err(crashed());
+        }
+        let pos = state.files.entry(path.to_owned()).or_default().data.len() as u64;
+        Ok(Box::new(SimHandle { fs: self.clone(), path: path.to_owned(), pos, append: true }))
+    }
+
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        self.state.lock().file(path)?;
+        Ok(Box::new(SimHandle { fs: self.clone(), path: path.to_owned(), pos: 0, append: false }))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        let mut state = self.state.lock();
+        if state.crashed {
+            return Err(crashed());
+        }
+        state.files.entry(path.to_owned()).or_default().data.clear();
+        Ok(Box::new(SimHandle { fs: self.clone(), path: path.to_owned(), pos: 0, append: true }))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state.lock();
+        state.check(FsOp::Rename)?;
+        let file = state
+            .files
+            .remove(from)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such file"))?;
+        state.files.insert(to.to_owned(), file);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock();
+        state.check(FsOp::Remove)?;
+        state.files.remove(path);
+        Ok(())
+    }
+
+    // a copy, since only files that are never written again get linked
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state.lock();
+        let file = state.file(from)?.clone();
+        state.files.insert(to.to_owned(), file);
+        Ok(())
+    }
+
+    fn sync_dir(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct SimHandle {
+    fs: SimFs,
+    path: PathBuf,
+    pos: u64,
+    append: bool,
+}
+
+impl Read for SimHandle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.read_at(buf, self.pos)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for SimHandle {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.append {
+            return Err(io::Error::other("file is read-only"));
+        }
+        let mut state = self.fs.state.lock();
+        let fault = state.check(FsOp::Write)?;
+        let data = &mut state.file(&self.path)?.data;
+        match fault {
+            Some(Fault::ShortWrite) => {
+                data.extend_from_slice(&buf[..buf.len() / 2]);
+                self.pos = data.len() as u64;
+                return Err(io::Error::other("no space left on device"));
+            }
+            Some(Fault::TornWrite) => {
+                data.extend_from_slice(&buf[..buf.len() / 2]);
+                state.crashed = true;
+                return Err(crashed());
+            }
+            _ => {}
+        }
+        data.extend_from_slice(buf);
+        self.pos = data.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for SimHandle {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let mut state = self.fs.state.lock();
+        let len = state.file(&self.path)?.data.len() as i64;
+        let pos = match pos {
+            SeekFrom::Start(pos) => pos as i64,
+            SeekFrom::End(offset) => len + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        if pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before start"));
+        }
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
+
+impl VfsFile for SimHandle {
+    fn sync_all(&self) -> io::Result<()> {
+        let mut state = self.fs.state.lock();
+        state.check(FsOp::Sync)?;
+        let file = state.file(&self.path)?;
+        file.durable = file.data.clone();
+        Ok(())
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        let mut state = self.fs.state.lock();
+        state.file(&self.path)?.data.resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn map(&self) -> io::Result<Arc<Mapping>> {
+        let mut state = self.fs.state.lock();
+        Ok(Arc::new(state.file(&self.path)?.data.clone()))
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let mut state = self.fs.state.lock();
+        let data = &state.file(&self.path)?.data;
+        let start = (offset as usize).min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+}
@@ -1,18 +1,41 @@
 use crate::result::{KvsError, Result};
-use crate::storage::{BatchStore, Store};
+use crate::storage::{
+    create_checkpoint_dir, BatchStore, CheckpointStore, MemoryUsage, MemoryUsageStore, ScanStore,
+    Store,
+};
 
-use sled::{Db, Tree};
+use parking_lot::RwLock;
+use sled::{Db, IVec, Tree};
 
 use std::fmt::Display;
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::sync::Arc;
 
 /// Wrapper of `sled::Db`
+///
+/// Its writes share a lock with checkpoints, which hold them back while the database is
+/// copied, since sled has no snapshots to copy from instead.
 #[derive(Clone)]
-pub struct SledStore(Db);
+pub struct SledStore(Db, Arc<RwLock<()>>);
 
 impl SledStore {
     /// Creates a `SledKvsEngine` from `sled::Db`.
     pub fn open(db: Db) -> Self {
-        SledStore(db)
+        SledStore(db, Arc::new(RwLock::new(())))
+    }
+
+    /// Gets the value of a given key as sled hands it out, without copying it.
+    ///
+    /// Returns `None` if the given key does not exist. Small values are inlined in the
+    /// `IVec`, larger ones are shared with sled's cache.
+    pub fn get_ref(&self, key: impl AsRef<[u8]>) -> Result<Option<IVec>> {
+        let tree: &Tree = &self.0;
+        let key = key.as_ref();
+        if key.is_empty() {
+            return Err(KvsError::EmptyKey);
+        }
+        Ok(tree.get(key)?)
     }
 }
 
@@ -25,12 +48,7 @@ impl Display for SledStore {
 impl Store for SledStore {
     #[inline]
     fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
-        let tree: &Tree = &self.0;
-        let key = key.as_ref().to_owned();
-        if key.is_empty() {
-            return Err(KvsError::EmptyKey);
-        }
-        Ok(tree.get(key)?.map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec()))
+        Ok(self.get_ref(key)?.map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec()))
     }
 
     #[inline]
@@ -40,6 +58,7 @@ impl Store for SledStore {
         if key.is_empty() {
             return Err(KvsError::EmptyKey);
         }
+        let _writing = self.1.read();
         tree.insert(key, value.as_ref())?;
         tree.flush()?;
         Ok(())
@@ -52,6 +71,7 @@ impl Store for SledStore {
         if key.is_empty() {
             return Err(KvsError::EmptyKey);
         }
+        let _writing = self.1.read();
         tree.remove(key)?;
         tree.flush()?;
         Ok(())
@@ -94,6 +114,7 @@ impl BatchStore for SledStore {
                 "The number of keys does not match the number of values".to_string(),
             ));
         }
+        let _writing = self.1.read();
         for i in 0..keys.len() {
             let key = keys[i].to_vec();
             let value = values[i].to_vec();
@@ -107,9 +128,202 @@ impl BatchStore for SledStore {
     fn remove_batch(&mut self, keys: impl AsRef<[Vec<u8>]>) -> Result<()> {
         let tree: &Tree = &self.0;
         let keys = keys.as_ref().to_owned();
+        let _writing = self.1.read();
         for key in keys {
             tree.remove(&key)?;
         }
         Ok(())
     }
 }
+
+impl ScanStore for SledStore {
+    #[inline]
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let tree: &Tree = &self.0;
+        tree.range(range)
+            .map(|pair| {
+                let (key, value) = pair?;
+                Ok((key.to_vec(), value.to_vec()))
+            })
+            .collect()
+    }
+
+    fn scan_limit(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let tree: &Tree = &self.0;
+        tree.range(range)
+            .take(limit)
+            .map(|pair| {
+                let (key, value) = pair?;
+                Ok((key.to_vec(), value.to_vec()))
+            })
+            .collect()
+    }
+}
+
+impl CheckpointStore for SledStore {
+    /// Copies every tree into a new sled database at `dest`, with sled's export.
+    ///
+    /// Writes through the store wait while the trees are read, so that the copy is of a single
+    /// state; reads go on. Writes made to the `Db` other than through the store are not held
+    /// back, and may or may not be in the copy. Open it with `sled::open(dest)`.
+    fn checkpoint(&self, dest: impl AsRef<Path>) -> Result<()> {
+        let dest = dest.as_ref();
+        create_checkpoint_dir(dest)?;
+        let db = sled::open(dest)?;
+        {
+            let _paused = self.1.write();
+            db.import(self.0.export());
+        }
+        db.flush()?;
+        Ok(())
+    }
+}
+
+impl MemoryUsageStore for SledStore {
+    /// Counts the entries of the default tree only.
+    ///
+    /// Sled keeps its own page cache, bounded by the `cache_capacity` it was opened with,
+    /// and does not report how much of it is in use, so every size is left at zero.
+    fn memory_usage(&self) -> MemoryUsage {
+        let tree: &Tree = &self.0;
+        let entries = tree.len() as u64;
+        MemoryUsage { entries, capacity: entries, ..MemoryUsage::default() }
+    }
+}
+
+/// A sled database in a temporary directory, and the last handle opened on it.
+#[cfg(test)]
+struct SledFixture(tempfile::TempDir, Option<Db>);
+
+#[cfg(test)]
+impl Default for SledFixture {
+    fn default() -> Self {
+        let dir = tempfile::TempDir::new().expect("unable to create temporary directory");
+        SledFixture(dir, None)
+    }
+}
+
+#[cfg(test)]
+impl crate::testing::Fixture for SledFixture {
+    type Store = SledStore;
+
+    const PERSISTENT: bool = true;
+
+    fn open(&mut self) -> Result<SledStore> {
+        if let Some(previous) = self.1.take() {
+            previous.flush()?;
+            drop(previous);
+            wait_for_unlock(&self.0.path().join("db"))?;
+        }
+        let db = sled::Config::new().path(self.0.path()).flush_every_ms(None).open()?;
+        self.1 = Some(db.clone());
+        Ok(SledStore::open(db))
+    }
+}
+
+/// Waits for the lock on a sled file to be freed, which background threads of a dropped `Db`
+/// do a while after its last handle is gone.
+///
+/// Fails if the lock is still held after ten seconds, e.g. by a store the test did not drop.
+#[cfg(test)]
+fn wait_for_unlock(path: &Path) -> Result<()> {
+    use fs2::FileExt;
+
+    let file = std::fs::File::open(path)?;
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    loop {
+        match file.try_lock_exclusive() {
+            Ok(()) => return Ok(file.unlock()?),
+            Err(err)
+                if err.kind() == std::io::ErrorKind::WouldBlock
+                    && std::time::Instant::now() < deadline =>
+            {
+                std::thread::sleep(std::time::Duration::from_millis(10))
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+crate::store_test_suite!(suite, SledFixture::default(), [store, batch, scan, persist, model]);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Fixture;
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn checkpoint() -> Result<()> {
+        let mut fixture = SledFixture::default();
+        let mut store = fixture.open()?;
+        let tree = store.0.open_tree("other")?;
+        tree.insert("other", "tree")?;
+        store.set("key1", "value1")?;
+        let mut copy = SledFixture::default();
+        store.checkpoint(copy.0.path())?;
+        store.set("key2", "value2")?;
+        drop(store);
+
+        let restored = copy.open()?;
+        assert_eq!(restored.get("key1")?, Some(b"value1".to_vec()));
+        assert_eq!(restored.get("key2")?, None);
+        assert_eq!(restored.0.open_tree("other")?.get("other")?, Some("tree".into()));
+        Ok(())
+    }
+
+    #[test]
+    fn checkpoint_during_writes() -> Result<()> {
+        let mut fixture = SledFixture::default();
+        let store = fixture.open()?;
+        let done = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let (mut store, done) = (store.clone(), Arc::clone(&done));
+            thread::spawn(move || -> Result<()> {
+                let mut i = 0u64;
+                while !done.load(Ordering::Relaxed) {
+                    let value = i.to_be_bytes().to_vec();
+                    store.set_batch(
+                        vec![b"a".to_vec(), b"z".to_vec()],
+                        vec![value.clone(), value],
+                    )?;
+                    i += 1;
+                }
+                Ok(())
+            })
+        };
+        let copies: Vec<_> = (0..5).map(|_| SledFixture::default()).collect();
+        for copy in &copies {
+            thread::sleep(Duration::from_millis(5));
+            store.checkpoint(copy.0.path())?;
+        }
+        done.store(true, Ordering::Relaxed);
+        writer.join().expect("writer thread panicked")?;
+
+        // both keys of a batch are in a checkpoint, or neither
+        for mut copy in copies {
+            let restored = copy.open()?;
+            assert_eq!(restored.get("a")?, restored.get("z")?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn get_ref() -> Result<()> {
+        let mut fixture = SledFixture::default();
+        let mut store = fixture.open()?;
+        store.set("key", vec![7; 1024])?;
+        assert_eq!(store.get_ref("key")?.as_deref(), Some(&[7; 1024][..]));
+        assert_eq!(store.get_ref("missing")?, None);
+        assert!(matches!(store.get_ref(""), Err(KvsError::EmptyKey)));
+        Ok(())
+    }
+}
@@ -0,0 +1,162 @@
+//! Estimates of the heap memory held by a store.
+
+use std::mem::size_of;
+use std::ops::{Add, AddAssign};
+
+/// How much heap memory a store holds, see [`MemoryUsageStore`](super::MemoryUsageStore).
+///
+/// Sizes are estimates: they count the memory asked of the allocator for the slots and nodes
+/// of hash tables and trees, and for keys and values, but not the bookkeeping of the
+/// allocator itself.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// The number of entries in the in-memory index.
+    pub entries: u64,
+    /// The number of entries the index can hold before it has to grow. For hash tables, this
+    /// is at least `entries`; trees grow a node at a time, so theirs is `entries`.
+    pub capacity: u64,
+    /// The bytes of the index: its hash tables or trees, and the keys in them.
+    pub index: u64,
+    /// The bytes of values held in memory: all of them for in-memory stores, the cached ones
+    /// for the others.
+    pub values: u64,
+    /// The bytes of buffers of writes not applied to the index yet, or not written out yet.
+    pub buffers: u64,
+}
+
+impl MemoryUsage {
+    /// The bytes of the index, values and buffers together.
+    pub fn total(&self) -> u64 {
+        self.index + self.values + self.buffers
+    }
+}
+
+impl Add for MemoryUsage {
+    type Output = MemoryUsage;
+
+    fn add(mut self, other: MemoryUsage) -> MemoryUsage {
+        self += other;
+        self
+    }
+}
+
+impl AddAssign for MemoryUsage {
+    fn add_assign(&mut self, other: MemoryUsage) {
+        self.entries += other.entries;
+        self.capacity += other.capacity;
+        self.index += other.index;
+        self.values += other.values;
+        self.buffers += other.buffers;
+    }
+}
+
+/// Estimates the bytes of the table of a hash map of `(K, V)` pairs with the given capacity,
+/// as laid out by `hashbrown`, which `std` and `griddle` both build on.
+///
+/// See [`griddle_table_bytes`] for a `griddle` map, which may have two tables.
+pub(crate) fn hash_table_bytes<K, V>(capacity: usize) -> u64 {
+    let buckets = match capacity {
+        0 => return 0,
+        _ => buckets(capacity),
+    };
+    // a control byte per bucket, plus a group of them past the end
+    const GROUP: usize = 16;
+    (buckets * (size_of::<(K, V)>() + 1) + GROUP) as u64
+}
+
+/// Estimates the bytes of the tables of a `griddle` map of `(K, V)` pairs with the given
+/// length and capacity.
+///
+/// A `griddle` map grows into a table of twice the buckets, and moves its entries over a few
+/// on each insert, keeping the old table until they are all moved: barring removes, which
+/// may free it sooner, until its length is past the old capacity by an eighth of it.
+#[cfg(feature = "amortized")]
+pub(crate) fn griddle_table_bytes<K, V>(len: usize, capacity: usize) -> u64 {
+    // the entries moved on each insert
+    const MOVED: usize = 8;
+    let old = match capacity {
+        0..=1 => 0,
+        _ => bucket_capacity(buckets(capacity) / 2),
+    };
+    let moving = len > old && len - old < old.div_ceil(MOVED);
+    let old_bytes = if moving { hash_table_bytes::<K, V>(old) } else { 0 };
+    hash_table_bytes::<K, V>(capacity) + old_bytes
+}
+
+/// The buckets of a table with the given capacity, which holds up to 7/8 of them, or one
+/// less when it is tiny.
+fn buckets(capacity: usize) -> usize {
+    match capacity {
+        0..=7 => capacity + 1,
+        _ => capacity / 7 * 8,
+    }
+}
+
+/// The capacity of a table with the given buckets, the inverse of [`buckets`].
+#[cfg(feature = "amortized")]
+fn bucket_capacity(buckets: usize) -> usize {
+    match buckets {
+        0..=8 => buckets - 1,
+        _ => buckets / 8 * 7,
+    }
+}
+
+/// Estimates the bytes of the nodes of a `BTreeMap` of `len` `(K, V)` pairs.
+pub(crate) fn btree_bytes<K, V>(len: usize) -> u64 {
+    // nodes have room for 11 pairs, and are two thirds full on average
+    const SLOTS: usize = 11;
+    let nodes = (len * 3 / 2).div_ceil(SLOTS);
+    // a pair slot per entry, plus the parent link, lengths and child edges of each node
+    (nodes * SLOTS * size_of::<(K, V)>() + nodes * (SLOTS + 2) * size_of::<usize>()) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    #[test]
+    fn hash_table_estimate() {
+        assert_eq!(hash_table_bytes::<u64, u64>(0), 0);
+        let mut map = HashMap::new();
+        for i in 0..1000u64 {
+            map.insert(i, i);
+        }
+        // 1000 entries do not fit the 896 of a table of 1024 buckets, so it has 2048
+        assert_eq!(map.capacity(), 896 * 2);
+        assert_eq!(hash_table_bytes::<u64, u64>(map.capacity()), 2048 * 17 + 16);
+    }
+
+    #[cfg(feature = "amortized")]
+    #[test]
+    fn griddle_resize() {
+        let mut map = griddle::HashMap::with_hasher(std::hash::BuildHasherDefault::<
+            seahash::SeaHasher,
+        >::default());
+        for i in 0..896u64 {
+            map.insert(i, i);
+        }
+        assert_eq!(map.capacity(), 896);
+        let old = hash_table_bytes::<u64, u64>(896);
+        assert_eq!(griddle_table_bytes::<u64, u64>(map.len(), map.capacity()), old);
+
+        // the insert past the capacity grows the map, and each of the 112 inserts from there
+        // moves 8 of the 896 old entries
+        for i in 896..896 + 111 {
+            map.insert(i, i);
+        }
+        assert_eq!(map.capacity(), 1792);
+        let new = hash_table_bytes::<u64, u64>(1792);
+        assert_eq!(griddle_table_bytes::<u64, u64>(map.len(), map.capacity()), new + old);
+        map.insert(896 + 111, 0);
+        assert_eq!(griddle_table_bytes::<u64, u64>(map.len(), map.capacity()), new);
+    }
+
+    #[test]
+    fn sums() {
+        let usage = MemoryUsage { entries: 1, capacity: 2, index: 3, values: 4, buffers: 5 };
+        assert_eq!(usage.total(), 12);
+        assert_eq!((usage + usage).total(), 24);
+    }
+}
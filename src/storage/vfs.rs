@@ -0,0 +1,163 @@
+//! The file system operations the storage engines rely on.
+//!
+//! `RealFs` forwards to `std::fs`; tests swap in the simulated file system from `sim` to inject
+//! I/O errors and crashes.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// The whole content of a file, mapped into memory.
+pub(crate) type Mapping = dyn AsRef<[u8]> + Send + Sync;
+
+/// A file system holding the log files.
+pub(crate) trait Vfs: Send + Sync {
+    /// Creates a directory and all of its missing parents.
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Lists the files (not directories) directly inside a directory.
+    fn list_files(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// Opens a file for appending, creating it if it does not exist.
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn VfsFile>>;
+
+    /// Opens an existing file for reading.
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn VfsFile>>;
+
+    /// Creates a file for writing, truncating it if it exists.
+    fn create(&self, path: &Path) -> io::Result<Box<dyn VfsFile>>;
+
+    /// Renames a file, replacing the destination if it exists.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Removes a file.
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Creates `to` as a new name for the file `from`.
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Flushes the entries of a directory, such as a rename, to durable storage.
+    fn sync_dir(&self, path: &Path) -> io::Result<()>;
+}
+
+/// An open file of a `Vfs`.
+pub(crate) trait VfsFile: Read + Write + Seek + Send + Sync {
+    /// Flushes the file content to durable storage.
+    fn sync_all(&self) -> io::Result<()>;
+
+    /// Truncates or extends the file to `len` bytes.
+    fn set_len(&self, len: u64) -> io::Result<()>;
+
+    /// Reads bytes starting at `offset`, without moving the cursor of the file.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    /// Maps the whole file into memory.
+    ///
+    /// The file must not be changed for as long as the mapping lives.
+    fn map(&self) -> io::Result<Arc<Mapping>>;
+
+    /// Reads exactly `buf.len()` bytes starting at `offset`.
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(buf, offset) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "failed to fill whole buffer",
+                    ))
+                }
+                Ok(len) => {
+                    buf = &mut buf[len..];
+                    offset += len as u64;
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The `Vfs` of the operating system.
+pub(crate) struct RealFs;
+
+impl Vfs for RealFs {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn list_files(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(path)? {
+            let path = entry?.path();
+            if path.is_file() {
+                files.push(path);
+            }
+        }
+        Ok(files)
+    }
+
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        Ok(Box::new(OpenOptions::new().create(true).append(true).open(path)?))
+    }
+
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        Ok(Box::new(File::create(path)?))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::hard_link(from, to)
+    }
+
+    #[cfg(unix)]
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        File::open(path)?.sync_all()
+    }
+
+    // directories can't be opened as files elsewhere
+    #[cfg(not(unix))]
+    fn sync_dir(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl VfsFile for File {
+    fn sync_all(&self) -> io::Result<()> {
+        File::sync_all(self)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn map(&self) -> io::Result<Arc<Mapping>> {
+        // SAFETY: log files are only mapped once sealed, and never written again; they are
+        // only removed, which leaves existing mappings intact
+        let map = unsafe { memmap2::Mmap::map(self)? };
+        Ok(Arc::new(map))
+    }
+
+    #[cfg(unix)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(self, buf, offset)
+    }
+
+    // moves the cursor, which is fine since files read this way are never read otherwise
+    #[cfg(windows)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_read(self, buf, offset)
+    }
+}
@@ -0,0 +1,156 @@
+//! A write-ahead log, holding the writes a store has not saved elsewhere yet: the ones an
+//! `LsmStore` has not flushed to a table, or a persistent `MemStore` has not snapshotted.
+//!
+//! ```text
+//! record := len:u32 checksum:u64 payload
+//! payload := tag:u8 key_len:u32 key [value_len:u32 value]     (tag 0 is a remove)
+//! ```
+//!
+//! The checksum is the `seahash` of the payload. Replay stops at a last record that is cut
+//! short or does not match its checksum, left by a write that failed or was interrupted by a
+//! crash, and the log is truncated there. A record that does not match its checksum with more
+//! after it is corrupt, and fails replay rather than losing the records after it.
+
+use super::vfs::{Vfs, VfsFile};
+use crate::encoding::put_bytes;
+use crate::result::{KvsError, Result};
+
+use std::convert::{TryFrom, TryInto};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const HEADER_LEN: usize = 12;
+
+/// A key and its value, or `None` for a remove.
+pub(super) type Entry = (Vec<u8>, Option<Vec<u8>>);
+
+/// Appends records to a log file.
+pub(super) struct Wal {
+    file: Box<dyn VfsFile>,
+    len: u64,
+}
+
+impl Wal {
+    /// Opens a log, creating it if it does not exist, and returns the entries in it.
+    ///
+    /// # Errors
+    ///
+    /// It fails with `KvsError::InvalidData` if a record other than the last is corrupt, and
+    /// propagates I/O errors.
+    pub(super) fn open(vfs: &dyn Vfs, path: &Path) -> Result<(Wal, Vec<Entry>)> {
+        let mut file = vfs.open_append(path)?;
+        let mut buf = Vec::new();
+        vfs.open_read(path)?.read_to_end(&mut buf)?;
+
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while let Some((entry, len)) = decode(&buf[pos..]).map_err(|err| {
+            KvsError::InvalidData(format!("{} at byte {} of {}", err, pos, path.display()))
+        })? {
+            entries.push(entry);
+            pos += len;
+        }
+        if pos < buf.len() {
+            file.set_len(pos as u64)?;
+        }
+        file.seek(SeekFrom::End(0))?;
+        Ok((Wal { file, len: pos as u64 }, entries))
+    }
+
+    /// Appends entries as a single write.
+    ///
+    /// If the write fails, the log is truncated back to where it was, so that later records
+    /// are not appended after a partial one.
+    ///
+    /// # Errors
+    ///
+    /// It fails with `KvsError::InvalidData` if an entry is too large for a record, writing
+    /// none of them, and propagates I/O errors.
+    pub(super) fn append(&mut self, entries: &[(&[u8], Option<&[u8]>)]) -> Result<()> {
+        let mut buf = Vec::new();
+        for &(key, value) in entries {
+            let mut payload = Vec::new();
+            match value {
+                Some(value) => {
+                    payload.push(1);
+                    put_bytes(&mut payload, key);
+                    put_bytes(&mut payload, value);
+                }
+                None => {
+                    payload.push(0);
+                    put_bytes(&mut payload, key);
+                }
+            }
+            buf.extend_from_slice(&record_len(payload.len())?.to_le_bytes());
+            buf.extend_from_slice(&seahash::hash(&payload).to_le_bytes());
+            buf.extend_from_slice(&payload);
+        }
+        if let Err(err) = self.file.write_all(&buf) {
+            self.file.set_len(self.len)?;
+            return Err(err.into());
+        }
+        self.len += buf.len() as u64;
+        Ok(())
+    }
+
+    /// The length of the log in bytes.
+    pub(super) fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Flushes the log to durable storage.
+    pub(super) fn sync(&self) -> Result<()> {
+        self.file.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Decodes the record at the start of `buf`, returning it and its length, or `None` if there
+/// is none, or only a torn last one: cut short, or not matching its checksum with nothing
+/// after it.
+///
+/// Fails with why the record is corrupt if it does not match its checksum with more after
+/// it, or does not hold an entry.
+fn decode(buf: &[u8]) -> std::result::Result<Option<(Entry, usize)>, &'static str> {
+    let header = match buf.get(..HEADER_LEN) {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let checksum = u64::from_le_bytes(header[4..].try_into().unwrap());
+    let payload = match buf.get(HEADER_LEN..HEADER_LEN + len) {
+        Some(payload) => payload,
+        None => return Ok(None),
+    };
+    if seahash::hash(payload) != checksum {
+        if buf.len() == HEADER_LEN + len {
+            return Ok(None);
+        }
+        return Err("log record does not match its checksum");
+    }
+    match decode_entry(payload) {
+        Some(entry) => Ok(Some((entry, HEADER_LEN + len))),
+        None => Err("log record holds no entry"),
+    }
+}
+
+fn decode_entry(payload: &[u8]) -> Option<Entry> {
+    let (&tag, rest) = payload.split_first()?;
+    let (key, rest) = take_bytes(rest)?;
+    let value = match tag {
+        0 => None,
+        _ => Some(take_bytes(rest)?.0.to_owned()),
+    };
+    Some((key.to_owned(), value))
+}
+
+fn record_len(len: usize) -> Result<u32> {
+    u32::try_from(len).map_err(|_| KvsError::InvalidData("entry is too large to log".to_string()))
+}
+
+/// Splits a length-prefixed byte string off the start of `buf`.
+fn take_bytes(buf: &[u8]) -> Option<(&[u8], &[u8])> {
+    let len = u32::from_le_bytes(buf.get(..4)?.try_into().ok()?) as usize;
+    let bytes = buf.get(4..4 + len)?;
+    Some((bytes, &buf[4 + len..]))
+}
@@ -0,0 +1,378 @@
+//! A conformance test suite for [`Store`] implementations.
+//!
+//! Enable the `testing` feature, implement [`Fixture`] for whatever sets up your store, and
+//! let [`store_test_suite!`](crate::store_test_suite) generate one `#[test]` per check:
+//!
+//! ```
+//! use ritekv::testing::Fixture;
+//! use ritekv::{MemStore, Result};
+//!
+//! struct MyFixture;
+//!
+//! impl Fixture for MyFixture {
+//!     type Store = MemStore;
+//!
+//!     fn open(&mut self) -> Result<MemStore> {
+//!         Ok(MemStore::open())
+//!     }
+//! }
+//!
+//! ritekv::store_test_suite!(my_store, MyFixture, [store, batch, scan]);
+//! # fn main() {}
+//! ```
+//!
+//! The suites are:
+//!
+//! - `store`: point ops, empty keys, large values and concurrent access through [`Store`].
+//! - `batch`: the [`BatchStore`] ops.
+//! - `scan`: the [`ScanStore`] ops.
+//! - `persist`: data written before the store is dropped is seen after it is opened again.
+//! - `model`: random sequences of operations, checked against a `BTreeMap` (see [`test_model`]).
+//!
+//! Each check is also a plain function, so they can be called by hand as well.
+
+mod model;
+
+pub use model::{check_ops, ops, test_model, Op};
+
+use crate::result::{KvsError, Result};
+use crate::storage::{BatchStore, ScanStore, Store};
+
+use parking_lot::RwLock;
+
+use std::ops::Bound;
+use std::sync::Arc;
+use std::thread;
+
+/// Sets up the store under test.
+///
+/// Every generated test creates a fresh fixture, so `open` should start from an empty store.
+/// The `persist` suite calls `open` again after dropping the previous store, and expects the
+/// second store to see what the first one wrote.
+pub trait Fixture {
+    /// The store under test.
+    type Store: Store;
+
+    /// Whether the store keeps its data across `open` calls.
+    ///
+    /// The model test only reopens the store when this is `true`.
+    const PERSISTENT: bool = false;
+
+    /// Opens the store under test.
+    fn open(&mut self) -> Result<Self::Store>;
+
+    /// Runs engine-specific maintenance, such as compaction. Does nothing by default.
+    fn compact(_store: &mut Self::Store) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Generates a module of `#[test]` functions running the conformance suites against a fixture.
+///
+/// The first argument names the generated module, the second is an expression building a
+/// [`Fixture`](crate::testing::Fixture), evaluated once per test. The optional list picks the
+/// suites to run out of `store`, `batch`, `scan`, `persist` and `model`, and defaults to `[store]`.
+#[macro_export]
+macro_rules! store_test_suite {
+    ($name:ident, $fixture:expr) => {
+        $crate::store_test_suite!($name, $fixture, [store]);
+    };
+    ($name:ident, $fixture:expr, [$($suite:ident),+ $(,)?]) => {
+        mod $name {
+            #[allow(unused_imports)]
+            use super::*;
+
+            $($crate::__store_test_suite!($suite, $fixture);)+
+        }
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __store_test_suite {
+    (store, $fixture:expr) => {
+        $crate::__store_test_suite!(@tests $fixture;
+            test_get, test_set, test_remove, test_contains, test_empty_key, test_large_value,
+            test_concurrent);
+    };
+    (batch, $fixture:expr) => {
+        $crate::__store_test_suite!(@tests $fixture;
+            test_get_batch, test_set_batch, test_remove_batch, test_batch_len_mismatch);
+    };
+    (scan, $fixture:expr) => {
+        $crate::__store_test_suite!(@tests $fixture; test_scan, test_scan_prefix);
+    };
+    (persist, $fixture:expr) => {
+        $crate::__store_test_suite!(@tests $fixture; test_reopen, test_reopen_after_remove);
+    };
+    (model, $fixture:expr) => {
+        #[test]
+        fn test_model() -> $crate::Result<()> {
+            $crate::testing::test_model(|| $fixture)
+        }
+    };
+    (@tests $fixture:expr; $($test:ident),+) => {
+        $(
+            #[test]
+            fn $test() -> $crate::Result<()> {
+                $crate::testing::$test(&mut $fixture)
+            }
+        )+
+    };
+}
+
+/// Checks that a set value can be read back, and missing keys read as `None`.
+pub fn test_get<F: Fixture>(fixture: &mut F) -> Result<()> {
+    let mut s = fixture.open()?;
+    s.set(b"a", vec![0x01])?;
+    assert_eq!(Some(vec![0x01]), s.get(b"a")?);
+    assert_eq!(None, s.get(b"b")?);
+    Ok(())
+}
+
+/// Checks that setting a key again overwrites the previous value.
+pub fn test_set<F: Fixture>(fixture: &mut F) -> Result<()> {
+    let mut s = fixture.open()?;
+    s.set(b"a", vec![0x01])?;
+    assert_eq!(Some(vec![0x01]), s.get(b"a")?);
+    s.set(b"a", vec![0x02])?;
+    assert_eq!(Some(vec![0x02]), s.get(b"a")?);
+    Ok(())
+}
+
+/// Checks that removed keys are gone, and removing a missing key is not an error.
+pub fn test_remove<F: Fixture>(fixture: &mut F) -> Result<()> {
+    let mut s = fixture.open()?;
+    s.set(b"a", vec![0x01])?;
+    assert_eq!(Some(vec![0x01]), s.get(b"a")?);
+    s.remove(b"a")?;
+    assert_eq!(None, s.get(b"a")?);
+    s.remove(b"b")?;
+    Ok(())
+}
+
+/// Checks `contains` for present, missing and removed keys.
+pub fn test_contains<F: Fixture>(fixture: &mut F) -> Result<()> {
+    let mut s = fixture.open()?;
+    s.set(b"a", vec![0x01])?;
+    assert!(s.contains(b"a")?);
+    assert!(!s.contains(b"b")?);
+    s.remove(b"a")?;
+    assert!(!s.contains(b"a")?);
+    Ok(())
+}
+
+/// Checks that every point op rejects an empty key with `KvsError::EmptyKey`.
+pub fn test_empty_key<F: Fixture>(fixture: &mut F) -> Result<()> {
+    let mut s = fixture.open()?;
+    assert!(matches!(s.set(b"", vec![0x01]), Err(KvsError::EmptyKey)));
+    assert!(matches!(s.get(b""), Err(KvsError::EmptyKey)));
+    assert!(matches!(s.remove(b""), Err(KvsError::EmptyKey)));
+    assert!(matches!(s.contains(b""), Err(KvsError::EmptyKey)));
+    Ok(())
+}
+
+/// Checks that values of a few MiB round-trip, including when overwritten.
+pub fn test_large_value<F: Fixture>(fixture: &mut F) -> Result<()> {
+    let mut s = fixture.open()?;
+    let first: Vec<u8> = (0..4 << 20).map(|i| b'a' + (i % 26) as u8).collect();
+    let second: Vec<u8> = (0..3 << 20).map(|i| b'A' + (i % 26) as u8).collect();
+    s.set(b"large", &first)?;
+    assert_eq!(Some(first), s.get(b"large")?);
+    s.set(b"large", &second)?;
+    s.set(b"small", b"value")?;
+    assert_eq!(Some(second), s.get(b"large")?);
+    assert_eq!(Some(b"value".to_vec()), s.get(b"small")?);
+    Ok(())
+}
+
+/// Checks that threads sharing a store all see each other's writes.
+pub fn test_concurrent<F: Fixture>(fixture: &mut F) -> Result<()>
+where
+    F::Store: 'static,
+{
+    const THREADS: usize = 8;
+    const KEYS: usize = 100;
+
+    let s = Arc::new(RwLock::new(fixture.open()?));
+    let handles: Vec<_> = (0..THREADS)
+        .map(|t| {
+            let s = Arc::clone(&s);
+            thread::spawn(move || -> Result<()> {
+                for i in 0..KEYS {
+                    let key = format!("key-{}-{}", t, i);
+                    s.write().set(&key, &key)?;
+                    assert_eq!(Some(key.clone().into_bytes()), s.read().get(&key)?);
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("writer thread panicked")?;
+    }
+
+    let s = s.read();
+    for t in 0..THREADS {
+        for i in 0..KEYS {
+            let key = format!("key-{}-{}", t, i);
+            assert_eq!(Some(key.clone().into_bytes()), s.get(&key)?);
+        }
+    }
+    Ok(())
+}
+
+/// Checks that `get_batch` returns values in key order, with `None` for missing keys.
+pub fn test_get_batch<F: Fixture>(fixture: &mut F) -> Result<()>
+where
+    F::Store: BatchStore,
+{
+    let mut s = fixture.open()?;
+    let data1 = b"test1".to_vec();
+    let data2 = b"test2".to_vec();
+    let missing = b"test3".to_vec();
+    s.set(data1.clone(), data1.clone())?;
+    s.set(data2.clone(), data2.clone())?;
+    assert_eq!(
+        s.get_batch(vec![data2.clone(), missing, data1.clone()])?,
+        vec![Some(data2), None, Some(data1)]
+    );
+    Ok(())
+}
+
+/// Checks that `set_batch` writes every pair.
+pub fn test_set_batch<F: Fixture>(fixture: &mut F) -> Result<()>
+where
+    F::Store: BatchStore,
+{
+    let mut s = fixture.open()?;
+    let data1 = b"test1".to_vec();
+    let data2 = b"test2".to_vec();
+    s.set_batch(vec![data1.clone(), data2.clone()], vec![data1.clone(), data2.clone()])?;
+    assert_eq!(s.get(data1.clone())?, Some(data1));
+    assert_eq!(s.get(data2.clone())?, Some(data2));
+    Ok(())
+}
+
+/// Checks that `remove_batch` removes every key, ignoring missing ones.
+pub fn test_remove_batch<F: Fixture>(fixture: &mut F) -> Result<()>
+where
+    F::Store: BatchStore,
+{
+    let mut s = fixture.open()?;
+    let data1 = b"test1".to_vec();
+    let data2 = b"test2".to_vec();
+    let missing = b"test3".to_vec();
+    s.set(data1.clone(), data1.clone())?;
+    s.set(data2.clone(), data2.clone())?;
+    s.remove_batch(&[data1.clone(), missing, data2.clone()])?;
+    assert_eq!(s.get(data1)?, None);
+    assert_eq!(s.get(data2)?, None);
+    Ok(())
+}
+
+/// Checks that `set_batch` rejects mismatched keys and values with `KvsError::InvalidData`.
+pub fn test_batch_len_mismatch<F: Fixture>(fixture: &mut F) -> Result<()>
+where
+    F::Store: BatchStore,
+{
+    let mut s = fixture.open()?;
+    let result = s.set_batch(vec![b"test1".to_vec()], Vec::<Vec<u8>>::new());
+    assert!(matches!(result, Err(KvsError::InvalidData(_))));
+    Ok(())
+}
+
+/// Checks that `scan` honors its bounds and returns pairs in key order.
+pub fn test_scan<F: Fixture>(fixture: &mut F) -> Result<()>
+where
+    F::Store: ScanStore,
+{
+    let mut s = fixture.open()?;
+    for key in &["d", "b", "a", "c", "e"] {
+        s.set(key, key)?;
+    }
+    s.remove(b"c")?;
+    let keys = |pairs: Vec<(Vec<u8>, Vec<u8>)>| -> Vec<Vec<u8>> {
+        pairs
+            .into_iter()
+            .map(|(key, value)| {
+                assert_eq!(key, value);
+                key
+            })
+            .collect()
+    };
+    assert_eq!(keys(s.scan(..)?), vec![b"a".to_vec(), b"b".to_vec(), b"d".to_vec(), b"e".to_vec()]);
+    assert_eq!(keys(s.scan(b"b".to_vec()..b"e".to_vec())?), vec![b"b".to_vec(), b"d".to_vec()]);
+    assert_eq!(keys(s.scan(b"c".to_vec()..)?), vec![b"d".to_vec(), b"e".to_vec()]);
+    assert_eq!(keys(s.scan(..=b"b".to_vec())?), vec![b"a".to_vec(), b"b".to_vec()]);
+    assert!(s.scan(b"x".to_vec()..)?.is_empty());
+    // empty and inverted ranges
+    assert!(s.scan(b"b".to_vec()..b"b".to_vec())?.is_empty());
+    assert!(s.scan((Bound::Excluded(b"b".to_vec()), Bound::Excluded(b"b".to_vec())))?.is_empty());
+    assert!(s.scan(b"d".to_vec()..b"b".to_vec())?.is_empty());
+    assert!(s.scan_limit(b"d".to_vec()..=b"b".to_vec(), 10)?.is_empty());
+
+    assert_eq!(keys(s.scan_limit(.., 2)?), vec![b"a".to_vec(), b"b".to_vec()]);
+    assert_eq!(keys(s.scan_limit(b"c".to_vec().., 1)?), vec![b"d".to_vec()]);
+    assert_eq!(
+        keys(s.scan_limit(b"b".to_vec()..b"e".to_vec(), 10)?),
+        vec![b"b".to_vec(), b"d".to_vec()]
+    );
+    assert!(s.scan_limit(.., 0)?.is_empty());
+    // a start that is not valid UTF-8 still bounds the scan
+    assert_eq!(keys(s.scan_limit(vec![b'b', 0xff].., 1)?), vec![b"d".to_vec()]);
+    Ok(())
+}
+
+/// Checks that `scan_prefix` returns exactly the keys starting with the prefix.
+pub fn test_scan_prefix<F: Fixture>(fixture: &mut F) -> Result<()>
+where
+    F::Store: ScanStore,
+{
+    let mut s = fixture.open()?;
+    for key in &["user:2", "user:1", "use", "users", "video:1"] {
+        s.set(key, "value")?;
+    }
+    let keys: Vec<_> = s.scan_prefix(b"user:")?.into_iter().map(|(key, _)| key).collect();
+    assert_eq!(keys, vec![b"user:1".to_vec(), b"user:2".to_vec()]);
+    assert_eq!(s.scan_prefix(b"")?.len(), 5);
+    assert!(s.scan_prefix(b"zzz")?.is_empty());
+    Ok(())
+}
+
+/// Checks that written values survive closing and reopening the store.
+pub fn test_reopen<F: Fixture>(fixture: &mut F) -> Result<()> {
+    {
+        let mut s = fixture.open()?;
+        for i in 0..100 {
+            s.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        s.set(b"key0", b"overwritten")?;
+    }
+    let s = fixture.open()?;
+    assert_eq!(Some(b"overwritten".to_vec()), s.get(b"key0")?);
+    for i in 1..100 {
+        assert_eq!(Some(format!("value{}", i).into_bytes()), s.get(format!("key{}", i))?);
+    }
+    Ok(())
+}
+
+/// Checks that removed keys stay removed after reopening the store.
+pub fn test_reopen_after_remove<F: Fixture>(fixture: &mut F) -> Result<()> {
+    {
+        let mut s = fixture.open()?;
+        s.set(b"a", b"1")?;
+        s.set(b"b", b"2")?;
+        s.remove(b"a")?;
+    }
+    {
+        let mut s = fixture.open()?;
+        assert_eq!(None, s.get(b"a")?);
+        assert_eq!(Some(b"2".to_vec()), s.get(b"b")?);
+        s.remove(b"b")?;
+    }
+    let mut s = fixture.open()?;
+    assert!(!s.contains(b"a")?);
+    assert!(!s.contains(b"b")?);
+    Ok(())
+}
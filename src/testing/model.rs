@@ -0,0 +1,135 @@
+use super::Fixture;
+use crate::result::Result;
+use crate::storage::{BatchStore, Store};
+
+use proptest::collection::vec;
+use proptest::prelude::*;
+use proptest::test_runner::{Config, TestCaseError, TestError, TestRunner};
+
+use std::collections::{BTreeMap, BTreeSet};
+
+/// An operation applied to both the store under test and the model.
+#[derive(Clone, Debug)]
+pub enum Op {
+    Set(String, String),
+    Remove(String),
+    SetBatch(Vec<(String, String)>),
+    RemoveBatch(Vec<String>),
+    /// Runs `Fixture::compact`.
+    Compact,
+    /// Drops the store and opens it again, if the fixture is persistent.
+    Reopen,
+}
+
+fn key() -> impl Strategy<Value = String> {
+    // a handful of short keys, so that operations keep hitting the same ones
+    "[a-f]{1,2}"
+}
+
+fn value() -> impl Strategy<Value = String> {
+    prop_oneof![
+        4 => "[a-z0-9]{0,16}",
+        // large enough that a string of overwrites crosses the compaction threshold of `DiskStore`
+        1 => (any::<char>(), 0usize..64 * 1024).prop_map(|(c, len)| c.to_string().repeat(len)),
+    ]
+}
+
+/// Generates sequences of operations.
+pub fn ops() -> impl Strategy<Value = Vec<Op>> {
+    let op = prop_oneof![
+        8 => (key(), value()).prop_map(|(k, v)| Op::Set(k, v)),
+        4 => key().prop_map(Op::Remove),
+        2 => vec((key(), value()), 0..8).prop_map(Op::SetBatch),
+        2 => vec(key(), 0..8).prop_map(Op::RemoveBatch),
+        1 => Just(Op::Compact),
+        1 => Just(Op::Reopen),
+    ];
+    vec(op, 0..64)
+}
+
+/// Runs `ops` against a fresh fixture and a `BTreeMap`, checking every key seen so far after
+/// each operation.
+pub fn check_ops<F: Fixture>(mut fixture: F, ops: &[Op]) -> std::result::Result<(), TestCaseError>
+where
+    F::Store: BatchStore,
+{
+    let mut store = fixture.open().map_err(fail)?;
+    let mut model = BTreeMap::new();
+    let mut seen = BTreeSet::new();
+
+    for (step, op) in ops.iter().enumerate() {
+        match op {
+            Op::Set(key, value) => {
+                store.set(key, value).map_err(fail)?;
+                model.insert(key.clone(), value.clone());
+            }
+            Op::Remove(key) => {
+                store.remove(key).map_err(fail)?;
+                model.remove(key);
+            }
+            Op::SetBatch(pairs) => {
+                let keys: Vec<_> = pairs.iter().map(|(k, _)| k.clone().into_bytes()).collect();
+                let values: Vec<_> = pairs.iter().map(|(_, v)| v.clone().into_bytes()).collect();
+                store.set_batch(keys, values).map_err(fail)?;
+                for (key, value) in pairs {
+                    model.insert(key.clone(), value.clone());
+                }
+            }
+            Op::RemoveBatch(keys) => {
+                let batch: Vec<_> = keys.iter().map(|k| k.clone().into_bytes()).collect();
+                store.remove_batch(batch).map_err(fail)?;
+                for key in keys {
+                    model.remove(key);
+                }
+            }
+            Op::Compact => F::compact(&mut store).map_err(fail)?,
+            Op::Reopen => {
+                if F::PERSISTENT {
+                    drop(store);
+                    store = fixture.open().map_err(fail)?;
+                }
+            }
+        }
+        seen.extend(model.keys().cloned());
+
+        let keys: Vec<_> = seen.iter().map(|k| k.clone().into_bytes()).collect();
+        let expected: Vec<_> =
+            seen.iter().map(|k| model.get(k).map(|v| v.clone().into_bytes())).collect();
+        for (key, value) in keys.iter().zip(&expected) {
+            let actual = store.get(key).map_err(fail)?;
+            prop_assert_eq!(&actual, value, "get({:?}) after step {}: {:?}", key, step, op);
+            let contains = store.contains(key).map_err(fail)?;
+            prop_assert_eq!(contains, value.is_some(), "contains({:?}) after step {}", key, step);
+        }
+        let actual = store.get_batch(&keys).map_err(fail)?;
+        prop_assert_eq!(actual, expected, "get_batch after step {}: {:?}", step, op);
+    }
+    Ok(())
+}
+
+fn fail(err: crate::KvsError) -> TestCaseError {
+    TestCaseError::fail(err.to_string())
+}
+
+/// Checks random sequences of operations against a `BTreeMap` model.
+///
+/// `new_fixture` is called once per generated case. Failing cases are shrunk, and the
+/// minimal sequence of operations is reported in the panic message. The number of cases can
+/// be tuned through the `PROPTEST_CASES` environment variable.
+pub fn test_model<F: Fixture>(new_fixture: impl Fn() -> F) -> Result<()>
+where
+    F::Store: BatchStore,
+{
+    let mut config = Config::default();
+    if std::env::var_os("PROPTEST_CASES").is_none() {
+        config.cases = 32;
+    }
+    let mut runner = TestRunner::new(config);
+    match runner.run(&ops(), |ops| check_ops(new_fixture(), &ops)) {
+        Ok(()) => Ok(()),
+        Err(TestError::Fail(reason, ops)) => {
+            panic!("{}\nminimal failing operations: {:#?}", reason, ops)
+        }
+        Err(TestError::Abort(reason)) => panic!("model test aborted: {}", reason),
+    }
+}
@@ -0,0 +1,495 @@
+//! The native protocol between `ritekv-server` and [`RemoteStore`](crate::client::RemoteStore).
+//!
+//! Requests and responses are length-prefixed frames, all integers little-endian. A client
+//! may send any number of requests before reading their responses, which come back in order.
+//!
+//! ```text
+//! frame    := len:u32 body:[u8; len]
+//! request  := op:u8 field*
+//! response := tag:u8 field*
+//! bytes    := len:u32 data:[u8; len]
+//! list     := count:u32 bytes*
+//! option   := 0:u8 | 1:u8 bytes
+//! bound    := 0:u8 | 1:u8 bytes | 2:u8 bytes           (unbounded, included, excluded)
+//! ```
+//!
+//! | op | request                                             | response |
+//! |----|-----------------------------------------------------|----------|
+//! | 0  | ping                                                | done     |
+//! | 1  | get `key:bytes`                                     | value    |
+//! | 2  | set `key:bytes value:bytes`                         | done     |
+//! | 3  | remove `key:bytes`                                  | done     |
+//! | 4  | contains `key:bytes`                                | bool     |
+//! | 5  | get batch `keys:list`                               | values   |
+//! | 6  | set batch `keys:list values:list`                   | done     |
+//! | 7  | remove batch `keys:list`                            | done     |
+//! | 8  | scan `start:bound end:bound limit:u64`              | pairs    |
+//! | 9  | transaction `checks:u32 (key:bytes value:option)* writes:u32 write*` | bool |
+//!
+//! A write of a transaction is `0:u8 key:bytes` to remove a key, or `1:u8 key:bytes
+//! value:bytes` to set it. Responses are tagged: `0` is an error `kind:u8 message:bytes`,
+//! then `1` done, `2` a value `option`, `3` values `count:u32 option*`, `4` a bool `u8` and
+//! `5` pairs `count:u32 (bytes bytes)*`. A scan responds with the first `limit` pairs of the
+//! range, or all of them if `limit` is `u64::MAX`.
+
+use crate::encoding::{put_bytes, read_bytes};
+use crate::result::{KvsError, Result};
+
+use std::convert::{TryFrom, TryInto};
+use std::io::{self, Read};
+use std::ops::Bound;
+
+/// The largest frame read, so that a bad length cannot make a peer allocate without bound.
+pub(crate) const MAX_FRAME_LEN: u32 = 1 << 30;
+
+const ERROR_EMPTY_KEY: u8 = 0;
+const ERROR_INVALID_DATA: u8 = 1;
+const ERROR_OTHER: u8 = 2;
+
+/// A request to the server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Request {
+    Ping,
+    Get(Vec<u8>),
+    Set(Vec<u8>, Vec<u8>),
+    Remove(Vec<u8>),
+    Contains(Vec<u8>),
+    GetBatch(Vec<Vec<u8>>),
+    SetBatch(Vec<Vec<u8>>, Vec<Vec<u8>>),
+    RemoveBatch(Vec<Vec<u8>>),
+    /// Scans the first pairs of a range, up to a limit.
+    Scan(Bound<Vec<u8>>, Bound<Vec<u8>>, usize),
+    /// Applies the writes if every key holds the value it is checked against, `None` meaning
+    /// that it is absent, and responds whether it did.
+    Transaction(Vec<(Vec<u8>, Option<Vec<u8>>)>, Vec<TxnWrite>),
+}
+
+/// A write of a transaction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum TxnWrite {
+    Set(Vec<u8>, Vec<u8>),
+    Remove(Vec<u8>),
+}
+
+/// The response to a request of a [`Pipeline`](crate::client::Pipeline).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Response {
+    /// A write, or a ping, is done.
+    Done,
+    /// The value of a `get`.
+    Value(Option<Vec<u8>>),
+    /// The values of a `get_batch`.
+    Values(Vec<Option<Vec<u8>>>),
+    /// Whether a key is there, for a `contains`.
+    Bool(bool),
+    /// The pairs of a `scan`.
+    Pairs(Vec<(Vec<u8>, Vec<u8>)>),
+}
+
+impl Request {
+    /// The request as a frame.
+    ///
+    /// # Errors
+    ///
+    /// It fails with `io::ErrorKind::InvalidData` if the frame is larger than a peer reads.
+    pub(crate) fn to_frame(&self) -> io::Result<Vec<u8>> {
+        frame(|buf| match self {
+            Request::Ping => buf.push(0),
+            Request::Get(key) => {
+                buf.push(1);
+                put_bytes(buf, key);
+            }
+            Request::Set(key, value) => {
+                buf.push(2);
+                put_bytes(buf, key);
+                put_bytes(buf, value);
+            }
+            Request::Remove(key) => {
+                buf.push(3);
+                put_bytes(buf, key);
+            }
+            Request::Contains(key) => {
+                buf.push(4);
+                put_bytes(buf, key);
+            }
+            Request::GetBatch(keys) => {
+                buf.push(5);
+                put_list(buf, keys);
+            }
+            Request::SetBatch(keys, values) => {
+                buf.push(6);
+                put_list(buf, keys);
+                put_list(buf, values);
+            }
+            Request::RemoveBatch(keys) => {
+                buf.push(7);
+                put_list(buf, keys);
+            }
+            Request::Scan(start, end, limit) => {
+                buf.push(8);
+                put_bound(buf, start);
+                put_bound(buf, end);
+                let limit = u64::try_from(*limit).unwrap_or(u64::MAX);
+                buf.extend_from_slice(&limit.to_le_bytes());
+            }
+            Request::Transaction(checks, writes) => {
+                buf.push(9);
+                put_len(buf, checks.len());
+                for (key, value) in checks {
+                    put_bytes(buf, key);
+                    put_option(buf, value.as_deref());
+                }
+                put_len(buf, writes.len());
+                for write in writes {
+                    match write {
+                        TxnWrite::Remove(key) => {
+                            buf.push(0);
+                            put_bytes(buf, key);
+                        }
+                        TxnWrite::Set(key, value) => {
+                            buf.push(1);
+                            put_bytes(buf, key);
+                            put_bytes(buf, value);
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    /// Parses the body of a frame.
+    pub(crate) fn decode(body: &[u8]) -> Result<Request> {
+        let mut decoder = Decoder(body);
+        let request = match decoder.u8()? {
+            0 => Request::Ping,
+            1 => Request::Get(decoder.bytes()?),
+            2 => Request::Set(decoder.bytes()?, decoder.bytes()?),
+            3 => Request::Remove(decoder.bytes()?),
+            4 => Request::Contains(decoder.bytes()?),
+            5 => Request::GetBatch(decoder.list()?),
+            6 => Request::SetBatch(decoder.list()?, decoder.list()?),
+            7 => Request::RemoveBatch(decoder.list()?),
+            8 => {
+                let (start, end) = (decoder.bound()?, decoder.bound()?);
+                let limit = usize::try_from(decoder.u64()?).unwrap_or(usize::MAX);
+                Request::Scan(start, end, limit)
+            }
+            9 => {
+                let mut checks = Vec::new();
+                for _ in 0..decoder.len()? {
+                    checks.push((decoder.bytes()?, decoder.option()?));
+                }
+                let mut writes = Vec::new();
+                for _ in 0..decoder.len()? {
+                    writes.push(match decoder.u8()? {
+                        0 => TxnWrite::Remove(decoder.bytes()?),
+                        1 => TxnWrite::Set(decoder.bytes()?, decoder.bytes()?),
+                        _ => return Err(protocol_error("unknown write")),
+                    });
+                }
+                Request::Transaction(checks, writes)
+            }
+            _ => return Err(protocol_error("unknown request")),
+        };
+        decoder.finish()?;
+        Ok(request)
+    }
+}
+
+/// The response, or the error, of a request as a frame.
+///
+/// Errors other than `EmptyKey` and `InvalidData` only keep their message. It fails like
+/// [`Request::to_frame`] if the frame is too large.
+pub(crate) fn response_frame(response: &Result<Response>) -> io::Result<Vec<u8>> {
+    frame(|buf| match response {
+        Err(err) => {
+            buf.push(0);
+            let (kind, message) = match err {
+                KvsError::EmptyKey => (ERROR_EMPTY_KEY, String::new()),
+                KvsError::InvalidData(message) => (ERROR_INVALID_DATA, message.clone()),
+                err => (ERROR_OTHER, err.to_string()),
+            };
+            buf.push(kind);
+            put_bytes(buf, message.as_bytes());
+        }
+        Ok(Response::Done) => buf.push(1),
+        Ok(Response::Value(value)) => {
+            buf.push(2);
+            put_option(buf, value.as_deref());
+        }
+        Ok(Response::Values(values)) => {
+            buf.push(3);
+            put_len(buf, values.len());
+            for value in values {
+                put_option(buf, value.as_deref());
+            }
+        }
+        Ok(Response::Bool(b)) => {
+            buf.push(4);
+            buf.push(u8::from(*b));
+        }
+        Ok(Response::Pairs(pairs)) => {
+            buf.push(5);
+            put_len(buf, pairs.len());
+            for (key, value) in pairs {
+                put_bytes(buf, key);
+                put_bytes(buf, value);
+            }
+        }
+    })
+}
+
+/// Parses the body of a response frame: the outer error is a broken frame, the inner one the
+/// error the server sent.
+pub(crate) fn decode_response(body: &[u8]) -> Result<Result<Response>> {
+    let mut decoder = Decoder(body);
+    let response = match decoder.u8()? {
+        0 => {
+            let kind = decoder.u8()?;
+            let message = String::from_utf8_lossy(&decoder.bytes()?).into_owned();
+            Err(match kind {
+                ERROR_EMPTY_KEY => KvsError::EmptyKey,
+                ERROR_INVALID_DATA => KvsError::InvalidData(message),
+                _ => KvsError::Internal(message),
+            })
+        }
+        1 => Ok(Response::Done),
+        2 => Ok(Response::Value(decoder.option()?)),
+        3 => {
+            let mut values = Vec::new();
+            for _ in 0..decoder.len()? {
+                values.push(decoder.option()?);
+            }
+            Ok(Response::Values(values))
+        }
+        4 => Ok(Response::Bool(decoder.u8()? != 0)),
+        5 => {
+            let mut pairs = Vec::new();
+            for _ in 0..decoder.len()? {
+                pairs.push((decoder.bytes()?, decoder.bytes()?));
+            }
+            Ok(Response::Pairs(pairs))
+        }
+        _ => return Err(protocol_error("unknown response")),
+    };
+    decoder.finish()?;
+    Ok(response)
+}
+
+/// Reads the body of the next frame, or `None` if the stream ends before it.
+pub(crate) fn read_frame(reader: &mut impl Read) -> Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    if reader.read(&mut len[..1])? == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut len[1..])?;
+    let len = u32::from_le_bytes(len);
+    if len > MAX_FRAME_LEN {
+        return Err(protocol_error("frame is too large"));
+    }
+    let body = read_bytes(reader, u64::from(len))?;
+    Ok(Some(body.ok_or_else(|| protocol_error("frame is cut short"))?))
+}
+
+fn frame(encode: impl FnOnce(&mut Vec<u8>)) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; 4];
+    encode(&mut buf);
+    let len = u32::try_from(buf.len() - 4)
+        .ok()
+        .filter(|&len| len <= MAX_FRAME_LEN)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "frame is too large"))?;
+    buf[..4].copy_from_slice(&len.to_le_bytes());
+    Ok(buf)
+}
+
+// a length that does not fit makes the whole frame too large, which `frame` rejects
+fn put_len(buf: &mut Vec<u8>, len: usize) {
+    let len = u32::try_from(len).unwrap_or(u32::MAX);
+    buf.extend_from_slice(&len.to_le_bytes());
+}
+
+fn put_list(buf: &mut Vec<u8>, list: &[Vec<u8>]) {
+    put_len(buf, list.len());
+    for bytes in list {
+        put_bytes(buf, bytes);
+    }
+}
+
+fn put_option(buf: &mut Vec<u8>, option: Option<&[u8]>) {
+    match option {
+        None => buf.push(0),
+        Some(bytes) => {
+            buf.push(1);
+            put_bytes(buf, bytes);
+        }
+    }
+}
+
+fn put_bound(buf: &mut Vec<u8>, bound: &Bound<Vec<u8>>) {
+    match bound {
+        Bound::Unbounded => buf.push(0),
+        Bound::Included(bytes) => {
+            buf.push(1);
+            put_bytes(buf, bytes);
+        }
+        Bound::Excluded(bytes) => {
+            buf.push(2);
+            put_bytes(buf, bytes);
+        }
+    }
+}
+
+/// Reads the fields of a frame body.
+struct Decoder<'a>(&'a [u8]);
+
+impl Decoder<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8]> {
+        if self.0.len() < len {
+            return Err(protocol_error("frame is cut short"));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn len(&mut self) -> Result<usize> {
+        let len = u32::from_le_bytes(self.take(4)?.try_into().unwrap());
+        // each item is at least a byte, so a count past the end is a broken frame
+        match len as usize {
+            len if len > self.0.len() => Err(protocol_error("frame is cut short")),
+            len => Ok(len),
+        }
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.len()?;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn list(&mut self) -> Result<Vec<Vec<u8>>> {
+        (0..self.len()?).map(|_| self.bytes()).collect()
+    }
+
+    fn option(&mut self) -> Result<Option<Vec<u8>>> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.bytes()?)),
+            _ => Err(protocol_error("invalid option")),
+        }
+    }
+
+    fn bound(&mut self) -> Result<Bound<Vec<u8>>> {
+        match self.u8()? {
+            0 => Ok(Bound::Unbounded),
+            1 => Ok(Bound::Included(self.bytes()?)),
+            2 => Ok(Bound::Excluded(self.bytes()?)),
+            _ => Err(protocol_error("invalid bound")),
+        }
+    }
+
+    fn finish(&self) -> Result<()> {
+        match self.0 {
+            [] => Ok(()),
+            _ => Err(protocol_error("trailing bytes in frame")),
+        }
+    }
+}
+
+fn protocol_error(message: &str) -> KvsError {
+    KvsError::InvalidData(format!("Protocol error: {}", message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(frame: &[u8]) -> &[u8] {
+        &frame[4..]
+    }
+
+    #[test]
+    fn requests() -> Result<()> {
+        let requests = vec![
+            Request::Ping,
+            Request::Get(b"key".to_vec()),
+            Request::Set(b"key".to_vec(), Vec::new()),
+            Request::Remove(b"key".to_vec()),
+            Request::Contains(b"key".to_vec()),
+            Request::GetBatch(vec![b"a".to_vec(), b"b".to_vec()]),
+            Request::SetBatch(vec![b"a".to_vec()], vec![b"1".to_vec()]),
+            Request::RemoveBatch(Vec::new()),
+            Request::Scan(Bound::Included(b"a".to_vec()), Bound::Unbounded, usize::MAX),
+            Request::Scan(Bound::Unbounded, Bound::Excluded(b"z".to_vec()), 10),
+            Request::Transaction(
+                vec![(b"a".to_vec(), None), (b"b".to_vec(), Some(b"2".to_vec()))],
+                vec![TxnWrite::Set(b"a".to_vec(), b"1".to_vec()), TxnWrite::Remove(b"b".to_vec())],
+            ),
+        ];
+        let mut stream = Vec::new();
+        for request in &requests {
+            stream.extend(request.to_frame()?);
+        }
+        let mut reader = stream.as_slice();
+        for request in requests {
+            let body = read_frame(&mut reader)?.expect("a frame per request");
+            assert_eq!(Request::decode(&body)?, request);
+        }
+        assert_eq!(read_frame(&mut reader)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn responses() -> Result<()> {
+        let responses = vec![
+            Response::Done,
+            Response::Value(Some(b"value".to_vec())),
+            Response::Values(vec![None, Some(Vec::new())]),
+            Response::Bool(true),
+            Response::Pairs(vec![(b"a".to_vec(), b"1".to_vec())]),
+        ];
+        for response in responses {
+            let frame = response_frame(&Ok(response.clone()))?;
+            assert_eq!(decode_response(body(&frame))?.unwrap(), response);
+        }
+
+        let frame = response_frame(&Err(KvsError::EmptyKey))?;
+        assert!(matches!(decode_response(body(&frame))?, Err(KvsError::EmptyKey)));
+        let frame = response_frame(&Err(KvsError::InvalidData("bad".to_string())))?;
+        assert!(
+            matches!(decode_response(body(&frame))?, Err(KvsError::InvalidData(m)) if m == "bad")
+        );
+        let frame = response_frame(&Err(KvsError::Internal("broken".to_string())))?;
+        assert!(matches!(decode_response(body(&frame))?, Err(KvsError::Internal(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn broken_frames() -> Result<()> {
+        let get = Request::Get(b"key".to_vec()).to_frame()?;
+        for bad in [&body(&get)[..4], &[1, 0xff, 0xff, 0xff, 0xff][..], &[42], &[0, 0]] {
+            assert!(matches!(Request::decode(bad), Err(KvsError::InvalidData(_))), "{:?}", bad);
+        }
+        let mut cut = &get[..get.len() - 1];
+        assert!(matches!(read_frame(&mut cut), Err(KvsError::InvalidData(_))));
+        let mut huge = &[0xff, 0xff, 0xff, 0xff][..];
+        assert!(matches!(read_frame(&mut huge), Err(KvsError::InvalidData(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn frames_too_large_to_send() {
+        let value = || vec![0; MAX_FRAME_LEN as usize];
+        let err = Request::Set(b"key".to_vec(), value()).to_frame().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = response_frame(&Ok(Response::Value(Some(value())))).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}